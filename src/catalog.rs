@@ -0,0 +1,1244 @@
+use crate::calvinite_tonic;
+use crate::calvinite_tonic::column_value::Value;
+use crate::calvinite_tonic::run_stmt_err::ErrorCode;
+use crate::calvinite_tonic::{ColumnMetadata, ColumnValue, RecordStorage};
+use crate::common::Record;
+use crate::index::Index;
+use crate::procedure::Procedure;
+use crate::storage::{Keyspace, Storage, StorageErr, WriteBatch};
+use serde::{Deserialize, Serialize};
+use sqlparser::ast;
+use std::fmt;
+use std::ops::{Bound, RangeInclusive};
+use std::sync::{Arc, Mutex};
+
+pub type TableId = u32;
+
+// A raw key and value of the catalog key space
+pub type CatalogEntry = (Vec<u8>, Vec<u8>);
+
+// Table names are never empty, so the empty key is free to hold the next table id
+const NEXT_TABLE_ID_KEY: &[u8] = b"";
+// Procedures share the key space with tables, so peers catch up on both alike. No table name starts
+// with a NUL byte.
+const PROCEDURE_KEY_PREFIX: &[u8] = b"\0procedure\0";
+
+#[derive(thiserror::Error, Debug)]
+pub enum CatalogErr {
+    #[error("table {0} already exists")]
+    TableExists(String),
+    #[error("no such table: {0}")]
+    NoSuchTable(String),
+    #[error("column type {0} is not supported")]
+    UnsupportedType(String),
+    #[error("table {0} needs exactly one INTEGER or BIGINT primary key column")]
+    InvalidPrimaryKey(String),
+    #[error("column {0} is declared more than once")]
+    DuplicateColumn(String),
+    #[error("no such column: {0}")]
+    NoSuchColumn(String),
+    #[error("index {0} already exists")]
+    IndexExists(String),
+    #[error("no such index: {0}")]
+    NoSuchIndex(String),
+    #[error("procedure {0} already exists")]
+    ProcedureExists(String),
+    #[error("no such procedure: {0}")]
+    NoSuchProcedure(String),
+    #[error("index {0} needs exactly one column, which is not the primary key")]
+    InvalidIndex(String),
+    #[error("column {0} cannot be NULL")]
+    NotNull(String),
+    #[error("selecting {0} is not supported")]
+    UnsupportedSelectItem(String),
+    #[error("column reference {0} is ambiguous")]
+    AmbiguousColumn(String),
+    #[error("{0} is not supported, only JOIN and LEFT JOIN with ON are")]
+    UnsupportedJoin(String),
+    #[error("{expected} columns of table {table} were given {actual} values")]
+    WrongValueCount {
+        table: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("{value} is not a valid {column_type} for column {column}")]
+    TypeMismatch {
+        column: String,
+        column_type: ColumnType,
+        value: String,
+    },
+    #[error(transparent)]
+    Storage(#[from] StorageErr),
+    #[error(transparent)]
+    Encoding(#[from] bincode::Error),
+}
+
+impl CatalogErr {
+    /// The code a client is sent when a statement fails with this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CatalogErr::NoSuchTable(_)
+            | CatalogErr::NoSuchColumn(_)
+            | CatalogErr::NoSuchIndex(_)
+            | CatalogErr::NoSuchProcedure(_) => ErrorCode::Notfound,
+            CatalogErr::NotNull(_) => ErrorCode::Constraint,
+            CatalogErr::WrongValueCount { .. } | CatalogErr::TypeMismatch { .. } => {
+                ErrorCode::Mismatch
+            }
+            CatalogErr::TableExists(_)
+            | CatalogErr::UnsupportedType(_)
+            | CatalogErr::InvalidPrimaryKey(_)
+            | CatalogErr::DuplicateColumn(_)
+            | CatalogErr::IndexExists(_)
+            | CatalogErr::InvalidIndex(_)
+            | CatalogErr::ProcedureExists(_)
+            | CatalogErr::UnsupportedSelectItem(_)
+            | CatalogErr::AmbiguousColumn(_)
+            | CatalogErr::UnsupportedJoin(_) => ErrorCode::Error,
+            CatalogErr::Storage(_) => ErrorCode::Ioerr,
+            CatalogErr::Encoding(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColumnType {
+    Integer,
+    BigInt,
+    Text,
+    Boolean,
+    Double,
+    Bytea,
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColumnType::Integer => "INTEGER",
+            ColumnType::BigInt => "BIGINT",
+            ColumnType::Text => "TEXT",
+            ColumnType::Boolean => "BOOLEAN",
+            ColumnType::Double => "DOUBLE",
+            ColumnType::Bytea => "BYTEA",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ColumnType {
+    pub fn from_data_type(data_type: &ast::DataType) -> Result<Self, CatalogErr> {
+        match data_type {
+            ast::DataType::Int(_) => Ok(ColumnType::Integer),
+            ast::DataType::BigInt(_) => Ok(ColumnType::BigInt),
+            ast::DataType::Text => Ok(ColumnType::Text),
+            ast::DataType::Boolean => Ok(ColumnType::Boolean),
+            ast::DataType::Double => Ok(ColumnType::Double),
+            ast::DataType::Bytea => Ok(ColumnType::Bytea),
+            _ => Err(CatalogErr::UnsupportedType(data_type.to_string())),
+        }
+    }
+
+    /// Converts a literal into a value of this type. Literals are never implicitly narrowed,
+    /// e.g. a DOUBLE literal is not a valid INTEGER.
+    pub fn value_from_expr(
+        &self,
+        column: &str,
+        expr: &ast::Expr,
+    ) -> Result<ColumnValue, CatalogErr> {
+        let mismatch = || CatalogErr::TypeMismatch {
+            column: column.to_string(),
+            column_type: *self,
+            value: expr.to_string(),
+        };
+
+        let value = match (self, Self::literal(expr)) {
+            (ColumnType::Integer, Some(Literal::Number(number))) => {
+                Value::Integer(number.parse().map_err(|_| mismatch())?)
+            }
+            (ColumnType::BigInt, Some(Literal::Number(number))) => {
+                Value::Bigint(number.parse().map_err(|_| mismatch())?)
+            }
+            (ColumnType::Double, Some(Literal::Number(number))) => {
+                Value::Double(number.parse().map_err(|_| mismatch())?)
+            }
+            (ColumnType::Text, Some(Literal::Text(text))) => Value::Text(text),
+            (ColumnType::Boolean, Some(Literal::Boolean(boolean))) => Value::Boolean(boolean),
+            (ColumnType::Bytea, Some(Literal::Hex(hex))) => {
+                Value::Bytea(Self::decode_hex(&hex).ok_or_else(mismatch)?)
+            }
+            _ => return Err(mismatch()),
+        };
+
+        Ok(ColumnValue { value: Some(value) })
+    }
+
+    /// Whether `expr` is a literal or NULL, whose value is known before the txn runs.
+    pub fn is_literal(expr: &ast::Expr) -> bool {
+        matches!(expr, ast::Expr::Value(ast::Value::Null)) || Self::literal(expr).is_some()
+    }
+
+    fn literal(expr: &ast::Expr) -> Option<Literal> {
+        match expr {
+            ast::Expr::Value(ast::Value::Number(number, _)) => {
+                Some(Literal::Number(number.clone()))
+            }
+            ast::Expr::UnaryOp {
+                op: ast::UnaryOperator::Minus,
+                expr,
+            } => match Self::literal(expr) {
+                Some(Literal::Number(number)) => Some(Literal::Number(format!("-{}", number))),
+                _ => None,
+            },
+            ast::Expr::Value(ast::Value::SingleQuotedString(text)) => {
+                Some(Literal::Text(text.clone()))
+            }
+            ast::Expr::Value(ast::Value::Boolean(boolean)) => Some(Literal::Boolean(*boolean)),
+            ast::Expr::Value(ast::Value::HexStringLiteral(hex)) => Some(Literal::Hex(hex.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+        if !hex.len().is_multiple_of(2) {
+            return None;
+        }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+            .collect()
+    }
+}
+
+impl From<ColumnType> for calvinite_tonic::ColumnType {
+    fn from(column_type: ColumnType) -> Self {
+        match column_type {
+            ColumnType::Integer => calvinite_tonic::ColumnType::Integer,
+            ColumnType::BigInt => calvinite_tonic::ColumnType::Bigint,
+            ColumnType::Text => calvinite_tonic::ColumnType::Text,
+            ColumnType::Boolean => calvinite_tonic::ColumnType::Boolean,
+            ColumnType::Double => calvinite_tonic::ColumnType::Double,
+            ColumnType::Bytea => calvinite_tonic::ColumnType::Bytea,
+        }
+    }
+}
+
+impl From<calvinite_tonic::ColumnType> for ColumnType {
+    fn from(column_type: calvinite_tonic::ColumnType) -> Self {
+        match column_type {
+            calvinite_tonic::ColumnType::Integer => ColumnType::Integer,
+            calvinite_tonic::ColumnType::Bigint => ColumnType::BigInt,
+            calvinite_tonic::ColumnType::Text => ColumnType::Text,
+            calvinite_tonic::ColumnType::Boolean => ColumnType::Boolean,
+            calvinite_tonic::ColumnType::Double => ColumnType::Double,
+            calvinite_tonic::ColumnType::Bytea => ColumnType::Bytea,
+        }
+    }
+}
+
+enum Literal {
+    Number(String),
+    Text(String),
+    Boolean(bool),
+    Hex(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    pub column_type: ColumnType,
+    /// Primary keys and columns declared `NOT NULL` are never NULL.
+    pub nullable: bool,
+}
+
+impl Column {
+    /// Like [`ColumnType::value_from_expr`], but also accepts `NULL` if the column does.
+    pub fn value_from_expr(&self, expr: &ast::Expr) -> Result<ColumnValue, CatalogErr> {
+        match expr {
+            ast::Expr::Value(ast::Value::Null) => self.null(),
+            _ => self.column_type.value_from_expr(&self.name, expr),
+        }
+    }
+
+    /// Converts a value read from another column into a value of this column. Integers convert
+    /// between widths as long as they fit, and into doubles.
+    pub fn coerce(&self, value: ColumnValue) -> Result<ColumnValue, CatalogErr> {
+        let mismatch = || CatalogErr::TypeMismatch {
+            column: self.name.clone(),
+            column_type: self.column_type,
+            value: value.to_string(),
+        };
+
+        let coerced = match (self.column_type, &value.value) {
+            (_, None) => return self.null(),
+            (ColumnType::Integer, Some(Value::Integer(_)))
+            | (ColumnType::BigInt, Some(Value::Bigint(_)))
+            | (ColumnType::Text, Some(Value::Text(_)))
+            | (ColumnType::Boolean, Some(Value::Boolean(_)))
+            | (ColumnType::Double, Some(Value::Double(_)))
+            | (ColumnType::Bytea, Some(Value::Bytea(_))) => return Ok(value),
+            (ColumnType::Integer, Some(Value::Bigint(bigint))) => {
+                Value::Integer((*bigint).try_into().map_err(|_| mismatch())?)
+            }
+            (ColumnType::BigInt, Some(Value::Integer(integer))) => Value::Bigint(*integer as i64),
+            (ColumnType::Double, Some(Value::Integer(integer))) => Value::Double(*integer as f64),
+            (ColumnType::Double, Some(Value::Bigint(bigint))) => Value::Double(*bigint as f64),
+            _ => return Err(mismatch()),
+        };
+
+        Ok(ColumnValue {
+            value: Some(coerced),
+        })
+    }
+
+    pub fn null(&self) -> Result<ColumnValue, CatalogErr> {
+        match self.nullable {
+            true => Ok(ColumnValue { value: None }),
+            false => Err(CatalogErr::NotNull(self.name.clone())),
+        }
+    }
+
+    pub fn metadata(&self) -> ColumnMetadata {
+        let mut metadata = ColumnMetadata {
+            name: self.name.clone(),
+            column_type: 0,
+            nullable: self.nullable,
+        };
+        metadata.set_column_type(self.column_type.into());
+        metadata
+    }
+}
+
+/// A column in the result of a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectedColumn {
+    /// Index of the column in the rows of the table.
+    pub index: usize,
+    /// The column as it is returned, renamed if the query gave it an alias.
+    pub column: Column,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableSchema {
+    pub id: TableId,
+    pub name: String,
+    pub columns: Vec<Column>,
+    /// Index of the primary key column, which is always an INTEGER or BIGINT.
+    pub primary_key: usize,
+    pub indexes: Vec<Index>,
+    /// Only set for the rows of a join, see [`TableSchema::joined`]: the alias or name of the
+    /// table each column comes from.
+    #[serde(skip)]
+    pub qualifiers: Vec<String>,
+}
+
+impl TableSchema {
+    fn from_create_table(
+        id: TableId,
+        name: &str,
+        column_defs: &[ast::ColumnDef],
+        constraints: &[ast::TableConstraint],
+    ) -> Result<Self, CatalogErr> {
+        let mut columns: Vec<Column> = Vec::new();
+        let mut primary_key_columns = Vec::new();
+
+        for column_def in column_defs {
+            let column_name = column_def.name.value.clone();
+            if columns.iter().any(|column| column.name == column_name) {
+                return Err(CatalogErr::DuplicateColumn(column_name));
+            }
+
+            let is_primary_key = column_def.options.iter().any(|option| {
+                matches!(
+                    option.option,
+                    ast::ColumnOption::Unique { is_primary: true }
+                )
+            });
+            if is_primary_key {
+                primary_key_columns.push(column_name.clone());
+            }
+            let is_not_null = column_def
+                .options
+                .iter()
+                .any(|option| matches!(option.option, ast::ColumnOption::NotNull));
+
+            columns.push(Column {
+                name: column_name,
+                column_type: ColumnType::from_data_type(&column_def.data_type)?,
+                nullable: !is_not_null,
+            });
+        }
+
+        for constraint in constraints {
+            if let ast::TableConstraint::Unique {
+                columns: constraint_columns,
+                is_primary: true,
+                ..
+            } = constraint
+            {
+                primary_key_columns
+                    .extend(constraint_columns.iter().map(|column| column.value.clone()));
+            }
+        }
+
+        let primary_key = match primary_key_columns.as_slice() {
+            [primary_key_column] => columns
+                .iter()
+                .position(|column| {
+                    column.name == *primary_key_column
+                        && matches!(column.column_type, ColumnType::Integer | ColumnType::BigInt)
+                })
+                .ok_or_else(|| CatalogErr::InvalidPrimaryKey(name.to_string()))?,
+            _ => return Err(CatalogErr::InvalidPrimaryKey(name.to_string())),
+        };
+        columns[primary_key].nullable = false;
+
+        Ok(Self {
+            id,
+            name: name.to_string(),
+            columns,
+            primary_key,
+            indexes: Vec::new(),
+            qualifiers: Vec::new(),
+        })
+    }
+
+    /// The schema of the rows of a join, which are the rows of its tables one after another. Each
+    /// table is given along with the alias or name that qualifies its columns.
+    pub fn joined(tables: &[(String, TableSchema)]) -> Self {
+        let mut joined = Self {
+            id: tables.first().map_or(0, |(_, schema)| schema.id),
+            name: String::new(),
+            columns: Vec::new(),
+            primary_key: 0,
+            indexes: Vec::new(),
+            qualifiers: Vec::new(),
+        };
+        for (qualifier, schema) in tables {
+            joined.columns.extend(schema.columns.iter().cloned());
+            joined
+                .qualifiers
+                .extend(schema.columns.iter().map(|_| qualifier.clone()));
+        }
+
+        joined
+    }
+
+    /// Resolves a column reference, a plain column name or one qualified with the name of its
+    /// table. A plain name in a join must name a column of only one of its tables.
+    pub fn column_of(&self, expr: &ast::Expr) -> Result<usize, CatalogErr> {
+        let (qualifier, name) = match expr {
+            ast::Expr::Identifier(ident) => (None, &ident.value),
+            ast::Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+                [qualifier, ident] => (Some(&qualifier.value), &ident.value),
+                _ => return Err(CatalogErr::NoSuchColumn(expr.to_string())),
+            },
+            _ => return Err(CatalogErr::NoSuchColumn(expr.to_string())),
+        };
+
+        if self.qualifiers.is_empty() {
+            return match qualifier {
+                Some(qualifier) if *qualifier != self.name => None,
+                _ => self.column_index(name),
+            }
+            .ok_or_else(|| CatalogErr::NoSuchColumn(expr.to_string()));
+        }
+
+        let mut columns = self.columns.iter().enumerate().filter(|(index, column)| {
+            column.name == *name
+                && qualifier.is_none_or(|qualifier| self.qualifiers.get(*index) == Some(qualifier))
+        });
+        match (columns.next(), columns.next()) {
+            (Some((index, _)), None) => Ok(index),
+            (Some(_), Some(_)) => Err(CatalogErr::AmbiguousColumn(expr.to_string())),
+            (None, _) => Err(CatalogErr::NoSuchColumn(expr.to_string())),
+        }
+    }
+
+    pub fn primary_key_column(&self) -> &Column {
+        &self.columns[self.primary_key]
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    /// The first index on the column at `column_idx`, if any.
+    pub fn index_on(&self, column_idx: usize) -> Option<&Index> {
+        self.indexes.iter().find(|index| index.column == column_idx)
+    }
+
+    pub fn record(&self, id: i64) -> Record {
+        Record {
+            table_id: self.id,
+            id,
+        }
+    }
+
+    /// The records whose primary keys are in `ids`.
+    pub fn record_range(&self, ids: RangeInclusive<i64>) -> RangeInclusive<Record> {
+        self.record(*ids.start())..=self.record(*ids.end())
+    }
+
+    /// Every record the table can hold.
+    pub fn all_records(&self) -> RangeInclusive<Record> {
+        self.record_range(i64::MIN..=i64::MAX)
+    }
+
+    pub fn record_of(&self, row: &RecordStorage) -> Option<Record> {
+        match row.values.get(self.primary_key)?.value {
+            Some(Value::Integer(id)) => Some(self.record(id as i64)),
+            Some(Value::Bigint(id)) => Some(self.record(id)),
+            _ => None,
+        }
+    }
+
+    pub fn metadata(&self) -> Vec<ColumnMetadata> {
+        self.columns.iter().map(Column::metadata).collect()
+    }
+
+    /// Builds a row from one literal per named column, or per column of the table if no
+    /// columns are named. Columns that are left out are NULL.
+    pub fn row_from_exprs(
+        &self,
+        column_names: &[ast::Ident],
+        exprs: &[ast::Expr],
+    ) -> Result<RecordStorage, CatalogErr> {
+        self.row_from(column_names, exprs, Column::value_from_expr)
+    }
+
+    /// Like `row_from_exprs`, but values other than literals, like `NOW()`, are left NULL. They
+    /// are only computed once the txn runs.
+    pub fn row_of_literals(
+        &self,
+        column_names: &[ast::Ident],
+        exprs: &[ast::Expr],
+    ) -> Result<RecordStorage, CatalogErr> {
+        self.row_from(
+            column_names,
+            exprs,
+            |column, expr| match ColumnType::is_literal(expr) {
+                true => column.value_from_expr(expr),
+                false => Ok(ColumnValue { value: None }),
+            },
+        )
+    }
+
+    /// Like `row_from_exprs`, but for values read from other columns, e.g. by `INSERT ... SELECT`.
+    pub fn row_from_values(
+        &self,
+        column_names: &[ast::Ident],
+        values: Vec<ColumnValue>,
+    ) -> Result<RecordStorage, CatalogErr> {
+        self.row_from(column_names, &values, |column, value| {
+            column.coerce(value.clone())
+        })
+    }
+
+    fn row_from<T>(
+        &self,
+        column_names: &[ast::Ident],
+        inputs: &[T],
+        to_value: impl Fn(&Column, &T) -> Result<ColumnValue, CatalogErr>,
+    ) -> Result<RecordStorage, CatalogErr> {
+        let column_indexes = self.insert_column_indexes(column_names)?;
+
+        if inputs.len() != column_indexes.len() {
+            return Err(CatalogErr::WrongValueCount {
+                table: self.name.clone(),
+                expected: column_indexes.len(),
+                actual: inputs.len(),
+            });
+        }
+
+        let mut values = vec![None; self.columns.len()];
+        for (column_idx, input) in column_indexes.into_iter().zip(inputs) {
+            values[column_idx] = Some(to_value(&self.columns[column_idx], input)?);
+        }
+
+        let values = values
+            .into_iter()
+            .zip(&self.columns)
+            .map(|(value, column)| value.map_or_else(|| column.null(), Ok))
+            .collect::<Result<_, _>>()?;
+
+        Ok(RecordStorage { values })
+    }
+
+    /// The column each value of an INSERT goes to: the named columns, or every column of the
+    /// table if no columns are named.
+    pub fn insert_column_indexes(
+        &self,
+        column_names: &[ast::Ident],
+    ) -> Result<Vec<usize>, CatalogErr> {
+        if column_names.is_empty() {
+            return Ok((0..self.columns.len()).collect());
+        }
+
+        let mut column_indexes: Vec<usize> = Vec::new();
+
+        for column_name in column_names {
+            let column_idx = self
+                .column_index(&column_name.value)
+                .ok_or_else(|| CatalogErr::NoSuchColumn(column_name.value.clone()))?;
+            if column_indexes.contains(&column_idx) {
+                return Err(CatalogErr::DuplicateColumn(column_name.value.clone()));
+            }
+            column_indexes.push(column_idx);
+        }
+
+        Ok(column_indexes)
+    }
+
+    /// Resolves the select list of a query on this table or join. Only `*` and plain column
+    /// names, optionally qualified with the table name or aliased, are supported.
+    pub fn projection(
+        &self,
+        select_items: &[ast::SelectItem],
+    ) -> Result<Vec<ProjectedColumn>, CatalogErr> {
+        let mut projection = Vec::new();
+
+        for select_item in select_items {
+            match select_item {
+                ast::SelectItem::Wildcard => {
+                    projection.extend(self.all_columns());
+                }
+                ast::SelectItem::QualifiedWildcard(table_name) => {
+                    let table_name = table_name.to_string();
+                    let columns: Vec<_> = self
+                        .all_columns()
+                        .filter(|projected| match self.qualifiers.get(projected.index) {
+                            Some(qualifier) => *qualifier == table_name,
+                            None => self.qualifiers.is_empty() && table_name == self.name,
+                        })
+                        .collect();
+                    if columns.is_empty() {
+                        return Err(CatalogErr::UnsupportedSelectItem(select_item.to_string()));
+                    }
+                    projection.extend(columns);
+                }
+                ast::SelectItem::UnnamedExpr(expr) => {
+                    projection.push(self.projected_column(expr, None)?);
+                }
+                ast::SelectItem::ExprWithAlias { expr, alias } => {
+                    projection.push(self.projected_column(expr, Some(alias))?);
+                }
+            }
+        }
+
+        Ok(projection)
+    }
+
+    fn all_columns(&self) -> impl Iterator<Item = ProjectedColumn> + '_ {
+        self.columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| ProjectedColumn {
+                index,
+                column: column.clone(),
+            })
+    }
+
+    fn projected_column(
+        &self,
+        expr: &ast::Expr,
+        alias: Option<&ast::Ident>,
+    ) -> Result<ProjectedColumn, CatalogErr> {
+        let index = match expr {
+            ast::Expr::Identifier(_) | ast::Expr::CompoundIdentifier(_) => self.column_of(expr)?,
+            _ => return Err(CatalogErr::UnsupportedSelectItem(expr.to_string())),
+        };
+        let mut column = self.columns[index].clone();
+        if let Some(alias) = alias {
+            column.name = alias.value.clone();
+        }
+
+        Ok(ProjectedColumn { index, column })
+    }
+}
+
+/// Schemas of every table, stored in their own key space next to the records.
+///
+/// Every partition runs every DDL txn in log order, so table ids are assigned identically
+/// everywhere. Changes that depend on what the catalog holds are made one at a time.
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    storage: Storage,
+    changes: Arc<Mutex<()>>,
+}
+
+impl Catalog {
+    pub fn open(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+            changes: Arc::default(),
+        }
+    }
+
+    pub fn table(&self, name: &str) -> Result<Option<TableSchema>, CatalogErr> {
+        match self.storage.get(Keyspace::Catalog, name.as_bytes())? {
+            Some(schema_bytes) => Ok(Some(bincode::deserialize(&schema_bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn require_table(&self, name: &str) -> Result<TableSchema, CatalogErr> {
+        self.table(name)?
+            .ok_or_else(|| CatalogErr::NoSuchTable(name.to_string()))
+    }
+
+    /// Returns the new table, or `None` if it already existed and `if_not_exists` was given.
+    pub fn create_table(
+        &self,
+        name: &str,
+        column_defs: &[ast::ColumnDef],
+        constraints: &[ast::TableConstraint],
+        if_not_exists: bool,
+    ) -> Result<Option<TableSchema>, CatalogErr> {
+        let _changes = self.changes.lock().unwrap();
+        if self
+            .storage
+            .contains_key(Keyspace::Catalog, name.as_bytes())?
+        {
+            return match if_not_exists {
+                true => Ok(None),
+                false => Err(CatalogErr::TableExists(name.to_string())),
+            };
+        }
+
+        let mut batch = WriteBatch::default();
+        let table_id = self.next_id(&mut batch)?;
+        let schema = TableSchema::from_create_table(table_id, name, column_defs, constraints)?;
+        Self::put_table(&mut batch, &schema)?;
+        self.storage.apply(batch)?;
+
+        Ok(Some(schema))
+    }
+
+    /// Returns the new index, or `None` if an index with that name already exists and
+    /// `if_not_exists` was given. The index has no entries yet.
+    pub fn create_index(
+        &self,
+        name: &str,
+        table_name: &str,
+        columns: &[ast::OrderByExpr],
+        unique: bool,
+        if_not_exists: bool,
+    ) -> Result<Option<Index>, CatalogErr> {
+        let _changes = self.changes.lock().unwrap();
+        if self.find_index(name)?.is_some() {
+            return match if_not_exists {
+                true => Ok(None),
+                false => Err(CatalogErr::IndexExists(name.to_string())),
+            };
+        }
+
+        let mut schema = self.require_table(table_name)?;
+        let column = match columns {
+            [ast::OrderByExpr {
+                expr: ast::Expr::Identifier(ident),
+                ..
+            }] => schema
+                .column_index(&ident.value)
+                .ok_or_else(|| CatalogErr::NoSuchColumn(ident.value.clone()))?,
+            _ => return Err(CatalogErr::InvalidIndex(name.to_string())),
+        };
+        if column == schema.primary_key {
+            return Err(CatalogErr::InvalidIndex(name.to_string()));
+        }
+
+        let mut batch = WriteBatch::default();
+        let index = Index {
+            id: self.next_id(&mut batch)?,
+            name: name.to_string(),
+            table_id: schema.id,
+            column,
+            unique,
+        };
+        schema.indexes.push(index.clone());
+        Self::put_table(&mut batch, &schema)?;
+        self.storage.apply(batch)?;
+
+        Ok(Some(index))
+    }
+
+    /// Returns the dropped index, or `None` if it did not exist and `if_exists` was given. Its
+    /// entries are left for the caller to remove.
+    pub fn drop_index(&self, name: &str, if_exists: bool) -> Result<Option<Index>, CatalogErr> {
+        let _changes = self.changes.lock().unwrap();
+        let (mut schema, index) = match self.find_index(name)? {
+            Some(found) => found,
+            None if if_exists => return Ok(None),
+            None => return Err(CatalogErr::NoSuchIndex(name.to_string())),
+        };
+
+        schema.indexes.retain(|other| other.id != index.id);
+        let mut batch = WriteBatch::default();
+        Self::put_table(&mut batch, &schema)?;
+        self.storage.apply(batch)?;
+
+        Ok(Some(index))
+    }
+
+    /// Every table, in name order.
+    pub fn tables(&self) -> Result<Vec<TableSchema>, CatalogErr> {
+        self.entries()?
+            .into_iter()
+            .filter(|(key, _)| Self::is_table_key(key))
+            .map(|(_, schema_bytes)| Ok(bincode::deserialize(&schema_bytes)?))
+            .collect()
+    }
+
+    // Index names are unique across every table
+    fn find_index(&self, name: &str) -> Result<Option<(TableSchema, Index)>, CatalogErr> {
+        for schema in self.tables()? {
+            if let Some(index) = schema.indexes.iter().find(|index| index.name == name) {
+                let index = index.clone();
+                return Ok(Some((schema, index)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Tables and indexes draw their ids from the same sequence
+    fn next_id(&self, batch: &mut WriteBatch) -> Result<TableId, CatalogErr> {
+        let id = match self.storage.get(Keyspace::Catalog, NEXT_TABLE_ID_KEY)? {
+            Some(id_bytes) => TableId::from_be_bytes(id_bytes.as_slice().try_into().unwrap()),
+            None => 0,
+        };
+        batch.put(Keyspace::Catalog, NEXT_TABLE_ID_KEY, (id + 1).to_be_bytes());
+
+        Ok(id)
+    }
+
+    fn put_table(batch: &mut WriteBatch, schema: &TableSchema) -> Result<(), CatalogErr> {
+        batch.put(
+            Keyspace::Catalog,
+            schema.name.as_bytes(),
+            bincode::serialize(schema)?,
+        );
+
+        Ok(())
+    }
+
+    /// Returns the dropped table, or `None` if it did not exist and `if_exists` was given.
+    pub fn drop_table(
+        &self,
+        name: &str,
+        if_exists: bool,
+    ) -> Result<Option<TableSchema>, CatalogErr> {
+        let _changes = self.changes.lock().unwrap();
+        match self.table(name)? {
+            Some(schema) => {
+                let mut batch = WriteBatch::default();
+                batch.delete(Keyspace::Catalog, name.as_bytes());
+                self.storage.apply(batch)?;
+                Ok(Some(schema))
+            }
+            None if if_exists => Ok(None),
+            None => Err(CatalogErr::NoSuchTable(name.to_string())),
+        }
+    }
+
+    pub fn procedure(&self, name: &str) -> Result<Option<Procedure>, CatalogErr> {
+        match self
+            .storage
+            .get(Keyspace::Catalog, &Self::procedure_key(name))?
+        {
+            Some(procedure_bytes) => Ok(Some(bincode::deserialize(&procedure_bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn require_procedure(&self, name: &str) -> Result<Procedure, CatalogErr> {
+        self.procedure(name)?
+            .ok_or_else(|| CatalogErr::NoSuchProcedure(name.to_string()))
+    }
+
+    /// Stores a procedure, replacing one with the same name only if `or_replace` is given.
+    pub fn create_procedure(
+        &self,
+        procedure: &Procedure,
+        or_replace: bool,
+    ) -> Result<(), CatalogErr> {
+        let _changes = self.changes.lock().unwrap();
+        let key = Self::procedure_key(&procedure.name);
+        if !or_replace && self.storage.contains_key(Keyspace::Catalog, &key)? {
+            return Err(CatalogErr::ProcedureExists(procedure.name.clone()));
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.put(Keyspace::Catalog, key, bincode::serialize(procedure)?);
+        self.storage.apply(batch)?;
+
+        Ok(())
+    }
+
+    /// Returns the dropped procedure, or `None` if it did not exist and `if_exists` was given.
+    pub fn drop_procedure(
+        &self,
+        name: &str,
+        if_exists: bool,
+    ) -> Result<Option<Procedure>, CatalogErr> {
+        let _changes = self.changes.lock().unwrap();
+        match self.procedure(name)? {
+            Some(procedure) => {
+                let mut batch = WriteBatch::default();
+                batch.delete(Keyspace::Catalog, Self::procedure_key(name));
+                self.storage.apply(batch)?;
+                Ok(Some(procedure))
+            }
+            None if if_exists => Ok(None),
+            None => Err(CatalogErr::NoSuchProcedure(name.to_string())),
+        }
+    }
+
+    fn procedure_key(name: &str) -> Vec<u8> {
+        [PROCEDURE_KEY_PREFIX, name.as_bytes()].concat()
+    }
+
+    /// Raw catalog entries, to bring a peer that missed DDL txns up to date.
+    pub fn entries(&self) -> Result<Vec<CatalogEntry>, CatalogErr> {
+        Ok(self
+            .storage
+            .scan(Keyspace::Catalog, (Bound::Unbounded, Bound::Unbounded))?)
+    }
+
+    /// Adds a raw catalog entry of a peer to the batch that stores what the peer sent, and returns
+    /// the schema it holds if it is a table.
+    pub fn restore_entry(
+        (key, value): CatalogEntry,
+        batch: &mut WriteBatch,
+    ) -> Result<Option<TableSchema>, CatalogErr> {
+        let schema = match Self::is_table_key(&key) {
+            true => Some(bincode::deserialize(&value)?),
+            false => None,
+        };
+        batch.put(Keyspace::Catalog, key, value);
+        Ok(schema)
+    }
+
+    fn is_table_key(key: &[u8]) -> bool {
+        key != NEXT_TABLE_ID_KEY && !key.starts_with(PROCEDURE_KEY_PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::column_value::Value;
+    use crate::calvinite_tonic::{ColumnValue, RecordStorage};
+    use crate::catalog::{Catalog, CatalogErr, ColumnType, ProjectedColumn, TableSchema};
+    use crate::storage::MemoryEngine;
+    use sqlparser::ast;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn create_table(catalog: &Catalog, sql: &str) -> Result<(), CatalogErr> {
+        match Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .remove(0)
+        {
+            ast::Statement::CreateTable {
+                name,
+                columns,
+                constraints,
+                if_not_exists,
+                ..
+            } => catalog
+                .create_table(&name.to_string(), &columns, &constraints, if_not_exists)
+                .map(|_| ()),
+            _ => panic!("not a CREATE TABLE"),
+        }
+    }
+
+    fn insert(schema: &TableSchema, sql: &str) -> Result<RecordStorage, CatalogErr> {
+        match Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .remove(0)
+        {
+            ast::Statement::Insert {
+                columns, source, ..
+            } => match source.body {
+                ast::SetExpr::Values(ast::Values(mut rows)) => {
+                    schema.row_from_exprs(&columns, &rows.remove(0))
+                }
+                _ => panic!("not a VALUES list"),
+            },
+            _ => panic!("not an INSERT"),
+        }
+    }
+
+    fn select(schema: &TableSchema, sql: &str) -> Result<Vec<ProjectedColumn>, CatalogErr> {
+        match Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .remove(0)
+        {
+            ast::Statement::Query(query) => match query.body {
+                ast::SetExpr::Select(select) => schema.projection(&select.projection),
+                _ => panic!("not a SELECT"),
+            },
+            _ => panic!("not a query"),
+        }
+    }
+
+    fn catalog() -> Catalog {
+        Catalog::open(&MemoryEngine::shared())
+    }
+
+    #[test]
+    fn assigns_table_ids_in_order() {
+        let catalog = catalog();
+
+        create_table(
+            &catalog,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+        )
+        .unwrap();
+        create_table(&catalog, "CREATE TABLE bar (id INTEGER, PRIMARY KEY (id))").unwrap();
+
+        assert_eq!(catalog.require_table("foo").unwrap().id, 0);
+        assert_eq!(catalog.require_table("bar").unwrap().id, 1);
+        assert!(matches!(
+            create_table(&catalog, "CREATE TABLE foo (id BIGINT PRIMARY KEY)"),
+            Err(CatalogErr::TableExists(_))
+        ));
+        create_table(
+            &catalog,
+            "CREATE TABLE IF NOT EXISTS foo (id BIGINT PRIMARY KEY)",
+        )
+        .unwrap();
+
+        // Ids are never reused
+        catalog.drop_table("foo", false).unwrap();
+        create_table(&catalog, "CREATE TABLE foo (id BIGINT PRIMARY KEY)").unwrap();
+        assert_eq!(catalog.require_table("foo").unwrap().id, 2);
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        let catalog = catalog();
+
+        assert!(matches!(
+            create_table(&catalog, "CREATE TABLE foo (id BIGINT, val BIGINT)"),
+            Err(CatalogErr::InvalidPrimaryKey(_))
+        ));
+        assert!(matches!(
+            create_table(&catalog, "CREATE TABLE foo (id TEXT PRIMARY KEY)"),
+            Err(CatalogErr::InvalidPrimaryKey(_))
+        ));
+        assert!(matches!(
+            create_table(
+                &catalog,
+                "CREATE TABLE foo (id BIGINT PRIMARY KEY, id TEXT)"
+            ),
+            Err(CatalogErr::DuplicateColumn(_))
+        ));
+        assert!(matches!(
+            create_table(
+                &catalog,
+                "CREATE TABLE foo (id BIGINT PRIMARY KEY, at DATE)"
+            ),
+            Err(CatalogErr::UnsupportedType(_))
+        ));
+    }
+
+    #[test]
+    fn builds_rows_that_match_the_schema() {
+        let catalog = catalog();
+        create_table(
+            &catalog,
+            "CREATE TABLE foo (id INTEGER PRIMARY KEY, a BIGINT, b TEXT, c BOOLEAN, d DOUBLE, e BYTEA)",
+        )
+        .unwrap();
+        let schema = catalog.require_table("foo").unwrap();
+
+        let row = insert(
+            &schema,
+            "INSERT INTO foo VALUES (1, -2, 'three', true, 4.5, X'0A0b')",
+        )
+        .unwrap();
+
+        let expected_values = vec![
+            Value::Integer(1),
+            Value::Bigint(-2),
+            Value::Text("three".to_string()),
+            Value::Boolean(true),
+            Value::Double(4.5),
+            Value::Bytea(vec![10, 11]),
+        ];
+        assert_eq!(
+            row,
+            RecordStorage {
+                values: expected_values
+                    .into_iter()
+                    .map(|value| ColumnValue { value: Some(value) })
+                    .collect()
+            }
+        );
+        assert_eq!(schema.record_of(&row), Some(schema.record(1)));
+    }
+
+    #[test]
+    fn rejects_rows_that_do_not_match_the_schema() {
+        let catalog = catalog();
+        create_table(
+            &catalog,
+            "CREATE TABLE foo (id INTEGER PRIMARY KEY, b TEXT)",
+        )
+        .unwrap();
+        let schema = catalog.require_table("foo").unwrap();
+
+        assert!(matches!(
+            insert(&schema, "INSERT INTO foo VALUES (1)"),
+            Err(CatalogErr::WrongValueCount { .. })
+        ));
+        assert!(matches!(
+            insert(&schema, "INSERT INTO foo VALUES (1, 2)"),
+            Err(CatalogErr::TypeMismatch {
+                column_type: ColumnType::Text,
+                ..
+            })
+        ));
+        assert!(matches!(
+            insert(&schema, "INSERT INTO foo VALUES (3000000000, 'a')"),
+            Err(CatalogErr::TypeMismatch {
+                column_type: ColumnType::Integer,
+                ..
+            })
+        ));
+        assert!(matches!(
+            insert(&schema, "INSERT INTO foo (id, missing) VALUES (1, 'a')"),
+            Err(CatalogErr::NoSuchColumn(_))
+        ));
+        assert!(matches!(
+            insert(&schema, "INSERT INTO foo (b, b) VALUES ('a', 'b')"),
+            Err(CatalogErr::DuplicateColumn(_))
+        ));
+    }
+
+    #[test]
+    fn fills_in_nulls() {
+        let catalog = catalog();
+        create_table(
+            &catalog,
+            "CREATE TABLE foo (id BIGINT, a TEXT, b TEXT NOT NULL, PRIMARY KEY (id))",
+        )
+        .unwrap();
+        let schema = catalog.require_table("foo").unwrap();
+
+        assert_eq!(
+            schema
+                .columns
+                .iter()
+                .map(|column| column.nullable)
+                .collect::<Vec<_>>(),
+            vec![false, true, false]
+        );
+        assert_eq!(
+            insert(&schema, "INSERT INTO foo (b, id) VALUES ('b', 1)").unwrap(),
+            RecordStorage {
+                values: vec![1i64.into(), ColumnValue { value: None }, "b".into()]
+            }
+        );
+        assert_eq!(
+            insert(&schema, "INSERT INTO foo VALUES (1, NULL, 'b')").unwrap(),
+            insert(&schema, "INSERT INTO foo (id, b) VALUES (1, 'b')").unwrap()
+        );
+        for sql in [
+            "INSERT INTO foo (id) VALUES (1)",
+            "INSERT INTO foo VALUES (1, 'a', NULL)",
+            "INSERT INTO foo (a, b) VALUES ('a', 'b')",
+        ] {
+            assert!(
+                matches!(insert(&schema, sql), Err(CatalogErr::NotNull(_))),
+                "{} should fail",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn coerces_copied_values() {
+        let catalog = catalog();
+        create_table(
+            &catalog,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, small INT, score DOUBLE, name TEXT NOT NULL)",
+        )
+        .unwrap();
+        let schema = catalog.require_table("foo").unwrap();
+
+        assert_eq!(
+            schema
+                .row_from_values(&[], vec![1i32.into(), 2i64.into(), 3i64.into(), "a".into()])
+                .unwrap(),
+            RecordStorage {
+                values: vec![1i64.into(), 2i32.into(), 3.0.into(), "a".into()]
+            }
+        );
+        for values in [
+            vec![1i64.into(), i64::MAX.into(), 0.5.into(), "a".into()],
+            vec![1i64.into(), 2i32.into(), "0.5".into(), "a".into()],
+        ] {
+            assert!(matches!(
+                schema.row_from_values(&[], values),
+                Err(CatalogErr::TypeMismatch { .. })
+            ));
+        }
+        assert!(matches!(
+            schema.row_from_values(
+                &[],
+                vec![
+                    1i64.into(),
+                    2i32.into(),
+                    0.5.into(),
+                    ColumnValue { value: None }
+                ]
+            ),
+            Err(CatalogErr::NotNull(_))
+        ));
+    }
+
+    #[test]
+    fn resolves_projections() {
+        let catalog = catalog();
+        create_table(
+            &catalog,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, a TEXT, b BOOLEAN)",
+        )
+        .unwrap();
+        let schema = catalog.require_table("foo").unwrap();
+
+        let projected = |sql| {
+            select(&schema, sql)
+                .unwrap()
+                .into_iter()
+                .map(|projected| (projected.index, projected.column.name))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            projected("SELECT * FROM foo"),
+            vec![(0, "id".into()), (1, "a".into()), (2, "b".into())]
+        );
+        assert_eq!(
+            projected("SELECT b, foo.id, a AS c, foo.* FROM foo"),
+            vec![
+                (2, "b".into()),
+                (0, "id".into()),
+                (1, "c".into()),
+                (0, "id".into()),
+                (1, "a".into()),
+                (2, "b".into())
+            ]
+        );
+
+        assert!(matches!(
+            select(&schema, "SELECT missing FROM foo"),
+            Err(CatalogErr::NoSuchColumn(_))
+        ));
+        assert!(matches!(
+            select(&schema, "SELECT bar.id FROM foo"),
+            Err(CatalogErr::NoSuchColumn(_))
+        ));
+    }
+}
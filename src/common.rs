@@ -1,33 +1,154 @@
+use std::fmt;
 use std::mem;
+use std::ops::{Bound, RangeInclusive};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::calvinite_tonic::column_value::Value;
+use crate::calvinite_tonic::ColumnValue;
+use crate::catalog::TableId;
+use crate::storage::KeyRange;
 use serde::{Deserialize, Serialize};
 
+/// Microseconds since the Unix epoch by the clock of this node.
+pub fn now_micros() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_micros() as i64)
+}
+
 pub type VirtualNodeType = u16;
 pub const VIRTUAL_NODE_SIZE_BITS: usize = mem::size_of::<VirtualNodeType>();
 
-// TODO: Maybe just make the virtual node
-#[derive(Debug, Clone, Hash, PartialEq, PartialOrd, Eq, Serialize, Deserialize)]
+const TABLE_ID_SIZE: usize = mem::size_of::<TableId>();
+const KEY_SIZE: usize = TABLE_ID_SIZE + mem::size_of::<i64>();
+
+/// Records are ordered by table, then by primary key, like their storage keys.
+#[derive(Debug, Clone, Hash, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
 pub struct Record {
-    pub id: u64,
+    pub table_id: TableId,
+    pub id: i64,
 }
 
 impl Record {
-    fn virtual_node_byte_arry(&self) -> [u8; VIRTUAL_NODE_SIZE_BITS] {
-        let bytes = bincode::serialize(self).unwrap();
-        let digest: [u8; 16] = md5::compute(bytes).into();
-        digest[..VIRTUAL_NODE_SIZE_BITS].try_into().unwrap()
-        // VirtualNodeSize::from_be_bytes(digest);
-    }
-
     pub fn virtual_node(&self) -> VirtualNodeType {
         let bytes = bincode::serialize(self).unwrap();
         let digest: [u8; 16] = md5::compute(bytes).into();
-        VirtualNodeType::from_le_bytes(digest[..VIRTUAL_NODE_SIZE_BITS].try_into().unwrap())
+        VirtualNodeType::from_be_bytes(digest[..VIRTUAL_NODE_SIZE_BITS].try_into().unwrap())
     }
 
+    // The big endian table id, then the big endian primary key with its sign bit flipped, so
+    // storage keys sort like records and the records of a key range are a contiguous range of keys
     pub fn fully_qualified_id_as_bytes(&self) -> Vec<u8> {
-        let virtual_node = self.virtual_node_byte_arry();
-        let bytes = bincode::serialize(self).unwrap();
-        [virtual_node.to_vec(), bytes].concat()
+        let id = (self.id as u64) ^ (1 << 63);
+        [self.table_id.to_be_bytes().as_slice(), &id.to_be_bytes()].concat()
+    }
+
+    /// The record stored under a key built by `fully_qualified_id_as_bytes`.
+    pub fn from_fully_qualified_id(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != KEY_SIZE {
+            return None;
+        }
+        let (table_id, id) = bytes.split_at(TABLE_ID_SIZE);
+        let id = u64::from_be_bytes(id.try_into().ok()?) ^ (1 << 63);
+
+        Some(Self {
+            table_id: TableId::from_be_bytes(table_id.try_into().ok()?),
+            id: id as i64,
+        })
+    }
+
+    /// The storage keys of the records in `range`.
+    pub fn key_range(range: &RangeInclusive<Record>) -> KeyRange {
+        (
+            Bound::Included(range.start().fully_qualified_id_as_bytes()),
+            Bound::Included(range.end().fully_qualified_id_as_bytes()),
+        )
+    }
+}
+
+impl fmt::Display for ColumnValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            None => write!(f, "NULL"),
+            Some(Value::Integer(integer)) => write!(f, "{}", integer),
+            Some(Value::Bigint(bigint)) => write!(f, "{}", bigint),
+            Some(Value::Text(text)) => write!(f, "'{}'", text),
+            Some(Value::Boolean(boolean)) => write!(f, "{}", boolean),
+            Some(Value::Double(double)) => write!(f, "{}", double),
+            Some(Value::Bytea(bytea)) => {
+                write!(f, "X'")?;
+                for byte in bytea {
+                    write!(f, "{:02X}", byte)?;
+                }
+                write!(f, "'")
+            }
+        }
+    }
+}
+
+impl From<i32> for ColumnValue {
+    fn from(value: i32) -> Self {
+        Self {
+            value: Some(Value::Integer(value)),
+        }
     }
-}
\ No newline at end of file
+}
+
+impl From<i64> for ColumnValue {
+    fn from(value: i64) -> Self {
+        Self {
+            value: Some(Value::Bigint(value)),
+        }
+    }
+}
+
+impl From<&str> for ColumnValue {
+    fn from(value: &str) -> Self {
+        Self {
+            value: Some(Value::Text(value.to_string())),
+        }
+    }
+}
+
+impl From<bool> for ColumnValue {
+    fn from(value: bool) -> Self {
+        Self {
+            value: Some(Value::Boolean(value)),
+        }
+    }
+}
+
+impl From<f64> for ColumnValue {
+    fn from(value: f64) -> Self {
+        Self {
+            value: Some(Value::Double(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::Record;
+
+    #[test]
+    fn keys_sort_like_records() {
+        let mut records = Vec::new();
+        for table_id in [0, 1, 256] {
+            for id in [i64::MIN, -256, -1, 0, 1, 255, 256, i64::MAX] {
+                records.push(Record { table_id, id });
+            }
+        }
+
+        let mut keys: Vec<Vec<u8>> = records
+            .iter()
+            .map(Record::fully_qualified_id_as_bytes)
+            .collect();
+        keys.sort();
+
+        let decoded: Vec<Record> = keys
+            .iter()
+            .map(|key| Record::from_fully_qualified_id(key).unwrap())
+            .collect();
+        assert_eq!(decoded, records);
+    }
+}
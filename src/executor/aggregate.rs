@@ -0,0 +1,434 @@
+use crate::calvinite_tonic::column_value::Value;
+use crate::calvinite_tonic::{ColumnMetadata, ColumnValue, RecordStorage};
+use crate::catalog::{CatalogErr, Column, ColumnType, TableSchema};
+use crate::executor::ExecutorErr;
+use crate::expr;
+use crate::expr::{ExprErr, StmtStamp};
+use prost::Message;
+use sqlparser::ast;
+use std::collections::{HashMap, HashSet};
+
+/// A query that folds the rows it selects into groups, one per distinct value of its GROUP BY
+/// expressions, or into a single group if it has none. A group is evaluated as a row of the
+/// table's columns, taken from the first row of the group in primary key order, followed by a
+/// column for each aggregate and for each alias of the select list.
+pub struct Aggregation {
+    /// The schema of the rows of the groups.
+    schema: TableSchema,
+    table_columns: usize,
+    aggregates: Vec<ast::Expr>,
+    group_by: Vec<ast::Expr>,
+    having: Option<ast::Expr>,
+    /// The column of a group row each alias of the select list repeats.
+    aliases: Vec<usize>,
+    /// The columns of the result, and the column of a group row each is taken from.
+    columns: Vec<(usize, Column)>,
+}
+
+impl Aggregation {
+    /// The aggregation of a query, or `None` if it uses neither aggregates nor GROUP BY.
+    pub fn of(
+        schema: &TableSchema,
+        select: &ast::Select,
+        order_by: &[ast::OrderByExpr],
+    ) -> Result<Option<Self>, ExecutorErr> {
+        let mut aggregates = Vec::new();
+        for expr in select
+            .projection
+            .iter()
+            .filter_map(|select_item| match select_item {
+                ast::SelectItem::UnnamedExpr(expr)
+                | ast::SelectItem::ExprWithAlias { expr, .. } => Some(expr),
+                _ => None,
+            })
+            .chain(select.having.iter())
+            .chain(order_by.iter().map(|order_by_expr| &order_by_expr.expr))
+        {
+            find_aggregates(expr, &mut aggregates);
+        }
+        if aggregates.is_empty() && select.group_by.is_empty() && select.having.is_none() {
+            return Ok(None);
+        }
+
+        let mut aggregation = Self {
+            schema: schema.clone(),
+            table_columns: schema.columns.len(),
+            aggregates: Vec::new(),
+            group_by: select.group_by.clone(),
+            having: select.having.clone(),
+            aliases: Vec::new(),
+            columns: Vec::new(),
+        };
+        for aggregate in aggregates {
+            let column = Column {
+                name: aggregate.to_string(),
+                column_type: aggregation.aggregate_type(&aggregate)?,
+                nullable: true,
+            };
+            aggregation.schema.columns.push(column);
+            aggregation.aggregates.push(aggregate);
+        }
+
+        let mut alias_columns = Vec::new();
+        for select_item in select.projection.iter() {
+            let (expr, alias) = match select_item {
+                ast::SelectItem::UnnamedExpr(expr) => (expr, None),
+                ast::SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias)),
+                ast::SelectItem::Wildcard | ast::SelectItem::QualifiedWildcard(_) => {
+                    for projected in schema.projection(std::slice::from_ref(select_item))? {
+                        let column_name = ast::Ident::new(&projected.column.name);
+                        let expr = match schema.qualifiers.get(projected.index) {
+                            Some(qualifier) => ast::Expr::CompoundIdentifier(vec![
+                                ast::Ident::new(qualifier),
+                                column_name,
+                            ]),
+                            None => ast::Expr::Identifier(column_name),
+                        };
+                        aggregation.check_grouped(&expr)?;
+                        aggregation
+                            .columns
+                            .push((projected.index, projected.column));
+                    }
+                    continue;
+                }
+            };
+
+            let index = match aggregation.aggregates.iter().position(|agg| agg == expr) {
+                Some(position) => aggregation.table_columns + position,
+                None => {
+                    aggregation.check_grouped(expr)?;
+                    column_of(expr, schema)
+                        .ok_or_else(|| CatalogErr::UnsupportedSelectItem(select_item.to_string()))?
+                }
+            };
+            let mut column = aggregation.schema.columns[index].clone();
+            if let Some(alias) = alias {
+                column.name = alias.value.clone();
+                if column.name != aggregation.schema.columns[index].name {
+                    aggregation.aliases.push(index);
+                    alias_columns.push(column.clone());
+                }
+            }
+            aggregation.columns.push((index, column));
+        }
+
+        // Aliases name columns of the group rows, so HAVING and ORDER BY can refer to them
+        aggregation.schema.columns.extend(alias_columns);
+        for expr in select
+            .having
+            .iter()
+            .chain(order_by.iter().map(|order_by_expr| &order_by_expr.expr))
+        {
+            aggregation.check_grouped(expr)?;
+        }
+
+        Ok(Some(aggregation))
+    }
+
+    /// The schema ORDER BY evaluates group rows with.
+    pub fn schema(&self) -> &TableSchema {
+        &self.schema
+    }
+
+    pub fn columns(&self) -> Vec<ColumnMetadata> {
+        self.columns
+            .iter()
+            .map(|(_, column)| column.metadata())
+            .collect()
+    }
+
+    /// Folds the rows of the table into a row per group that satisfies HAVING. Groups come in the
+    /// order of their first row.
+    pub fn group_rows(
+        &self,
+        rows: Vec<RecordStorage>,
+        stamp: &StmtStamp,
+    ) -> Result<Vec<RecordStorage>, ExecutorErr> {
+        let mut groups: Vec<Vec<RecordStorage>> = Vec::new();
+        let mut group_positions = HashMap::new();
+        for row in rows {
+            let key = RecordStorage {
+                values: self
+                    .group_by
+                    .iter()
+                    .map(|expr| expr::eval(expr, &self.schema, &row, stamp))
+                    .collect::<Result<_, _>>()?,
+            };
+            let position = *group_positions
+                .entry(key.encode_to_vec())
+                .or_insert_with(|| {
+                    groups.push(Vec::new());
+                    groups.len() - 1
+                });
+            groups[position].push(row);
+        }
+
+        // Without GROUP BY, even no rows at all make a group
+        if groups.is_empty() && self.group_by.is_empty() {
+            groups.push(Vec::new());
+        }
+
+        let mut group_rows = Vec::new();
+        for group in groups {
+            let mut group_row = match group.first() {
+                Some(row) => row.clone(),
+                None => RecordStorage {
+                    values: vec![ColumnValue { value: None }; self.table_columns],
+                },
+            };
+            for aggregate in self.aggregates.iter() {
+                group_row
+                    .values
+                    .push(self.aggregate(aggregate, &group, stamp)?);
+            }
+            for index in self.aliases.iter() {
+                group_row.values.push(group_row.values[*index].clone());
+            }
+
+            let is_match = match &self.having {
+                Some(having) => expr::is_true(having, &self.schema, &group_row, stamp)?,
+                None => true,
+            };
+            if is_match {
+                group_rows.push(group_row);
+            }
+        }
+
+        Ok(group_rows)
+    }
+
+    /// The columns of the result, taken from a group row.
+    pub fn project(&self, group_row: &RecordStorage) -> RecordStorage {
+        RecordStorage {
+            values: self
+                .columns
+                .iter()
+                .map(|(index, _)| group_row.values[*index].clone())
+                .collect(),
+        }
+    }
+
+    // Columns are only evaluated per group if they are grouped by, or aggregated
+    fn check_grouped(&self, expr: &ast::Expr) -> Result<(), ExecutorErr> {
+        let is_grouped = self.group_by.iter().any(|group_by| {
+            group_by == expr
+                || column_of(group_by, &self.schema)
+                    .is_some_and(|index| column_of(expr, &self.schema) == Some(index))
+        });
+        if is_grouped {
+            return Ok(());
+        }
+
+        match expr {
+            ast::Expr::Function(function) if expr::is_aggregate(function) => Ok(()),
+            ast::Expr::Identifier(_) | ast::Expr::CompoundIdentifier(_) => {
+                match column_of(expr, &self.schema) {
+                    Some(index) if index >= self.table_columns => Ok(()),
+                    _ => Err(ExecutorErr::Ungrouped(expr.to_string())),
+                }
+            }
+            _ => expr::operands(expr)
+                .into_iter()
+                .try_for_each(|operand| self.check_grouped(operand)),
+        }
+    }
+
+    fn aggregate_type(&self, aggregate: &ast::Expr) -> Result<ColumnType, ExecutorErr> {
+        let (name, function, arg) = call(aggregate)?;
+        let arg = match (name.as_str(), arg) {
+            ("COUNT", _) => return Ok(ColumnType::BigInt),
+            ("AVG", _) => return Ok(ColumnType::Double),
+            (_, Some(arg)) => arg,
+            (_, None) => return Err(ExprErr::Unsupported(function.to_string()).into()),
+        };
+
+        match (name.as_str(), value_type(arg, &self.schema)?) {
+            ("SUM", ColumnType::Integer | ColumnType::BigInt) => Ok(ColumnType::BigInt),
+            ("SUM", ColumnType::Double) => Ok(ColumnType::Double),
+            ("SUM", _) => Err(ExprErr::InvalidOperands(function.to_string()).into()),
+            (_, column_type) => Ok(column_type),
+        }
+    }
+
+    // Like in SQL, aggregates skip NULLs and are NULL over no values, except COUNT which is 0
+    fn aggregate(
+        &self,
+        aggregate: &ast::Expr,
+        rows: &[RecordStorage],
+        stamp: &StmtStamp,
+    ) -> Result<ColumnValue, ExecutorErr> {
+        let (name, function, arg) = call(aggregate)?;
+        let arg = match arg {
+            Some(arg) => arg,
+            None => {
+                return Ok(ColumnValue {
+                    value: Some(Value::Bigint(rows.len() as i64)),
+                })
+            }
+        };
+
+        let mut values = Vec::new();
+        let mut seen_values = HashSet::new();
+        for row in rows {
+            let value = expr::eval(arg, &self.schema, row, stamp)?;
+            if value.value.is_some()
+                && (!function.distinct || seen_values.insert(value.encode_to_vec()))
+            {
+                values.push(value);
+            }
+        }
+        if values.is_empty() && name != "COUNT" {
+            return Ok(ColumnValue { value: None });
+        }
+
+        let invalid_operands = || ExprErr::InvalidOperands(function.to_string());
+        let value = match name.as_str() {
+            "COUNT" => Value::Bigint(values.len() as i64),
+            "SUM" if self.aggregate_type(aggregate)? == ColumnType::BigInt => {
+                let mut sum = 0i64;
+                for value in values.iter() {
+                    let integer = match value.value {
+                        Some(Value::Integer(integer)) => integer as i64,
+                        Some(Value::Bigint(bigint)) => bigint,
+                        _ => return Err(invalid_operands().into()),
+                    };
+                    sum = sum
+                        .checked_add(integer)
+                        .ok_or_else(|| ExprErr::Overflow(function.to_string()))?;
+                }
+                Value::Bigint(sum)
+            }
+            "SUM" | "AVG" => {
+                let mut sum = 0f64;
+                for value in values.iter() {
+                    sum += match value.value {
+                        Some(Value::Integer(integer)) => integer as f64,
+                        Some(Value::Bigint(bigint)) => bigint as f64,
+                        Some(Value::Double(double)) => double,
+                        _ => return Err(invalid_operands().into()),
+                    };
+                }
+                if name == "AVG" {
+                    sum /= values.len() as f64;
+                }
+                Value::Double(sum)
+            }
+            _ => {
+                let mut values = values.into_iter();
+                let mut extreme = values.next().unwrap();
+                for value in values {
+                    let ordering = expr::compare(&value, &extreme)?;
+                    if (name == "MIN" && ordering.is_some_and(|ordering| ordering.is_lt()))
+                        || (name == "MAX" && ordering.is_some_and(|ordering| ordering.is_gt()))
+                    {
+                        extreme = value;
+                    }
+                }
+                return Ok(extreme);
+            }
+        };
+
+        Ok(ColumnValue { value: Some(value) })
+    }
+}
+
+// The aggregates `expr` computes. Aggregates nested in another one are left to fail once the
+// outer one is computed.
+fn find_aggregates(expr: &ast::Expr, aggregates: &mut Vec<ast::Expr>) {
+    match expr {
+        ast::Expr::Function(function) if expr::is_aggregate(function) => {
+            if !aggregates.contains(expr) {
+                aggregates.push(expr.clone());
+            }
+        }
+        _ => {
+            for operand in expr::operands(expr) {
+                find_aggregates(operand, aggregates);
+            }
+        }
+    }
+}
+
+// The name, call and argument of an aggregate. Only COUNT takes `*`, which leaves no argument.
+fn call(aggregate: &ast::Expr) -> Result<(String, &ast::Function, Option<&ast::Expr>), ExprErr> {
+    let function = match aggregate {
+        ast::Expr::Function(function) if function.over.is_none() => function,
+        _ => return Err(ExprErr::Unsupported(aggregate.to_string())),
+    };
+    let name = function.name.to_string().to_uppercase();
+
+    let arg = match function.args.as_slice() {
+        [ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Wildcard)]
+            if name == "COUNT" && !function.distinct =>
+        {
+            None
+        }
+        [ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(arg))] => Some(arg),
+        [_] => return Err(ExprErr::Unsupported(function.to_string())),
+        _ => {
+            return Err(ExprErr::WrongArgCount {
+                function: name,
+                expected: "1".to_string(),
+            })
+        }
+    };
+
+    Ok((name, function, arg))
+}
+
+fn column_of(expr: &ast::Expr, schema: &TableSchema) -> Option<usize> {
+    schema.column_of(expr).ok()
+}
+
+// The type of the values of an expression an aggregate folds, following how `expr::eval`
+// computes them
+fn value_type(expr: &ast::Expr, schema: &TableSchema) -> Result<ColumnType, ExecutorErr> {
+    let is_integer = |column_type| matches!(column_type, ColumnType::Integer | ColumnType::BigInt);
+
+    match expr {
+        ast::Expr::Identifier(_) | ast::Expr::CompoundIdentifier(_) => {
+            Ok(schema.columns[schema.column_of(expr)?].column_type)
+        }
+        ast::Expr::Nested(expr) => value_type(expr, schema),
+        ast::Expr::UnaryOp {
+            op: ast::UnaryOperator::Plus | ast::UnaryOperator::Minus,
+            expr,
+        } => value_type(expr, schema),
+        ast::Expr::Value(ast::Value::Number(number, _)) => match number.parse::<i64>() {
+            Ok(_) => Ok(ColumnType::BigInt),
+            Err(_) => Ok(ColumnType::Double),
+        },
+        ast::Expr::Value(ast::Value::SingleQuotedString(_)) => Ok(ColumnType::Text),
+        ast::Expr::Value(ast::Value::Boolean(_)) => Ok(ColumnType::Boolean),
+        ast::Expr::BinaryOp {
+            left,
+            op: ast::BinaryOperator::StringConcat,
+            right,
+        } => match (value_type(left, schema)?, value_type(right, schema)?) {
+            (ColumnType::Text, ColumnType::Text) => Ok(ColumnType::Text),
+            _ => Err(ExprErr::InvalidOperands(expr.to_string()).into()),
+        },
+        ast::Expr::BinaryOp {
+            left,
+            op:
+                ast::BinaryOperator::Plus
+                | ast::BinaryOperator::Minus
+                | ast::BinaryOperator::Multiply
+                | ast::BinaryOperator::Divide
+                | ast::BinaryOperator::Modulo,
+            right,
+        } => match (value_type(left, schema)?, value_type(right, schema)?) {
+            (ColumnType::Integer, ColumnType::Integer) => Ok(ColumnType::Integer),
+            (left, right) if is_integer(left) && is_integer(right) => Ok(ColumnType::BigInt),
+            (left, right)
+                if [left, right].iter().all(|column_type| {
+                    is_integer(*column_type) || *column_type == ColumnType::Double
+                }) =>
+            {
+                Ok(ColumnType::Double)
+            }
+            _ => Err(ExprErr::InvalidOperands(expr.to_string()).into()),
+        },
+        _ => Err(ExecutorErr::Unsupported(format!("aggregate of {}", expr))),
+    }
+}
@@ -0,0 +1,125 @@
+use crate::storage::{Keyspace, Storage, StorageErr, WriteBatch};
+
+// Followed by the id of the sequencer that numbered the batches. Txn marks are keyed by UUID.
+const LAST_APPLIED_LSN_KEY_PREFIX: &[u8] = b"last_applied_lsn/";
+
+/// How far this executor got through the global request log, stored next to its rows so that a
+/// restarted node resumes after the last batch it applied.
+///
+/// Txns of a batch finish in no particular order, so a node that stops halfway through a batch
+/// has applied some of its txns but not others. Every txn marks that it ran, atomically with its
+/// writes, and a replayed txn that is marked already is skipped. Once the whole batch has run, its
+/// marks are folded into the last applied LSN.
+///
+/// Every sequencer numbers its own batches, so an LSN is only comparable to the LSNs of the same
+/// sequencer. The last applied LSN is kept for each sequencer.
+#[derive(Clone, Debug)]
+pub struct AppliedLog {
+    storage: Storage,
+}
+
+impl AppliedLog {
+    pub fn open(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
+    }
+
+    /// The LSN of the last request of the last batch numbered by `sequencer_id` that was applied
+    /// in full, or 0.
+    pub fn last_applied_lsn(&self, sequencer_id: &str) -> Result<u64, StorageErr> {
+        Ok(self
+            .storage
+            .get(Keyspace::Applied, &Self::last_applied_lsn_key(sequencer_id))?
+            .and_then(|lsn| lsn.as_slice().try_into().ok())
+            .map_or(0, u64::from_be_bytes))
+    }
+
+    pub fn is_applied(&self, txn_uuid: &str) -> Result<bool, StorageErr> {
+        self.storage
+            .contains_key(Keyspace::Applied, txn_uuid.as_bytes())
+    }
+
+    /// Marks a txn whose writes, if any, did not carry its mark.
+    pub fn mark(&self, txn_uuid: &str) -> Result<(), StorageErr> {
+        let mut marks = WriteBatch::default();
+        Self::add_mark(txn_uuid, &mut marks);
+        self.storage.apply(marks)
+    }
+
+    /// Adds the mark of a txn to the batch of its writes.
+    pub fn add_mark(txn_uuid: &str, batch: &mut WriteBatch) {
+        batch.put(Keyspace::Applied, txn_uuid.as_bytes(), Vec::new());
+    }
+
+    /// Records that every txn of a batch numbered by `sequencer_id`, the last of which has `lsn`,
+    /// was applied.
+    pub fn apply_batch(
+        &self,
+        sequencer_id: &str,
+        lsn: u64,
+        txn_uuids: &[String],
+    ) -> Result<(), StorageErr> {
+        let mut applied = WriteBatch::default();
+        applied.put(
+            Keyspace::Applied,
+            Self::last_applied_lsn_key(sequencer_id),
+            lsn.to_be_bytes(),
+        );
+        for txn_uuid in txn_uuids {
+            applied.delete(Keyspace::Applied, txn_uuid.as_bytes());
+        }
+
+        self.storage.apply(applied)
+    }
+
+    fn last_applied_lsn_key(sequencer_id: &str) -> Vec<u8> {
+        [LAST_APPLIED_LSN_KEY_PREFIX, sequencer_id.as_bytes()].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::applied_log::AppliedLog;
+    use crate::storage::{EngineKind, MemoryEngine, StorageConfig};
+    use uuid::Uuid;
+
+    #[test]
+    fn folds_marks_into_the_last_applied_lsn() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            engine: EngineKind::Sled,
+            data_dir: Some(dir.path().to_path_buf()),
+        };
+        let txn_uuids = [Uuid::new_v4().to_string(), Uuid::new_v4().to_string()];
+
+        {
+            let applied_log = AppliedLog::open(&config.open().unwrap());
+            assert_eq!(applied_log.last_applied_lsn("a").unwrap(), 0);
+
+            applied_log.mark(&txn_uuids[0]).unwrap();
+            applied_log.apply_batch("a", 4, &[]).unwrap();
+            assert!(applied_log.is_applied(&txn_uuids[0]).unwrap());
+            assert!(!applied_log.is_applied(&txn_uuids[1]).unwrap());
+        }
+
+        let applied_log = AppliedLog::open(&config.open().unwrap());
+        assert_eq!(applied_log.last_applied_lsn("a").unwrap(), 4);
+        assert!(applied_log.is_applied(&txn_uuids[0]).unwrap());
+
+        applied_log.apply_batch("a", 6, &txn_uuids).unwrap();
+        assert_eq!(applied_log.last_applied_lsn("a").unwrap(), 6);
+        assert!(!applied_log.is_applied(&txn_uuids[0]).unwrap());
+    }
+
+    #[test]
+    fn keeps_the_last_applied_lsn_of_each_sequencer() {
+        let applied_log = AppliedLog::open(&MemoryEngine::shared());
+
+        applied_log.apply_batch("a", 6, &[]).unwrap();
+        applied_log.apply_batch("b", 2, &[]).unwrap();
+        assert_eq!(applied_log.last_applied_lsn("a").unwrap(), 6);
+        assert_eq!(applied_log.last_applied_lsn("b").unwrap(), 2);
+        assert_eq!(applied_log.last_applied_lsn("c").unwrap(), 0);
+    }
+}
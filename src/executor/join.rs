@@ -0,0 +1,163 @@
+use crate::calvinite_tonic::column_value::Value;
+use crate::calvinite_tonic::{ColumnValue, RecordStorage};
+use crate::catalog::{Catalog, TableSchema};
+use crate::executor::{CachedRecord, ExecutorErr, RecordCache};
+use crate::expr;
+use crate::expr::StmtStamp;
+use crate::stmt_analyzer::{JoinedTable, SqlStmt};
+use sqlparser::ast;
+
+/// A query that joins tables to the rows of its table, one after another. A joined row is the
+/// row of each table one after another, see [`TableSchema::joined`]. A join keyed by primary key
+/// looks up the row each joined row matches in the record cache, like a hash join. Any other join
+/// compares each joined row with every row of its table. A LEFT JOIN pads the joined rows no row
+/// matches with NULLs.
+pub struct Join {
+    table: TableSchema,
+    joins: Vec<JoinedTable>,
+    /// The schema of the joined rows before each join, and after the last one.
+    schemas: Vec<TableSchema>,
+}
+
+impl Join {
+    /// The joins of a query on the table of `schema`, or `None` if it joins no table.
+    pub fn of(
+        catalog: &Catalog,
+        schema: &TableSchema,
+        query: &ast::Query,
+    ) -> Result<Option<Self>, ExecutorErr> {
+        let joins = SqlStmt::find_joins(&ast::Statement::Query(Box::new(query.clone())), catalog)?;
+        if joins.is_empty() {
+            return Ok(None);
+        }
+
+        let mut tables = vec![(schema.name.clone(), schema.clone())];
+        let mut schemas = vec![TableSchema::joined(&tables)];
+        for join in joins.iter() {
+            tables.push((join.qualifier.clone(), join.schema.clone()));
+            schemas.push(TableSchema::joined(&tables));
+        }
+
+        Ok(Some(Self {
+            table: schema.clone(),
+            joins,
+            schemas,
+        }))
+    }
+
+    /// The schema of the joined rows.
+    pub fn schema(&self) -> &TableSchema {
+        &self.schemas[self.joins.len()]
+    }
+
+    /// The joined rows that satisfy `selection`, in primary key order of the query's table. Only
+    /// rows of the table in the range of primary keys `selection` bounds are joined. Rows a key
+    /// looks up that were not read are cached as missing, so the txn can tell whether its
+    /// reconnaissance predicted them.
+    pub fn rows(
+        &self,
+        record_cache: &mut RecordCache,
+        selection: Option<&ast::Expr>,
+        stamp: &StmtStamp,
+    ) -> Result<Vec<RecordStorage>, ExecutorErr> {
+        let ids =
+            selection.and_then(|selection| SqlStmt::find_id_range_in_expr(selection, &self.table));
+        let mut rows = Self::table_rows(record_cache, &self.table)
+            .into_iter()
+            .filter(|(id, _)| ids.as_ref().is_none_or(|ids| ids.contains(id)))
+            .map(|(_, row)| row)
+            .collect::<Vec<_>>();
+
+        for (position, join) in self.joins.iter().enumerate() {
+            let schema = &self.schemas[position + 1];
+            let table_rows = match join.key {
+                Some(_) => Vec::new(),
+                None => Self::table_rows(record_cache, &join.schema),
+            };
+
+            let mut joined_rows = Vec::new();
+            for row in rows {
+                let matches = match &join.key {
+                    Some(key) => Vec::from_iter(Self::lookup(
+                        record_cache,
+                        &join.schema,
+                        key,
+                        &self.schemas[position],
+                        &row,
+                        stamp,
+                    )?),
+                    None => table_rows.iter().map(|(_, row)| row.clone()).collect(),
+                };
+
+                let mut is_matched = false;
+                for other_row in matches {
+                    let mut joined_row = row.clone();
+                    joined_row.values.extend(other_row.values);
+                    if expr::is_true(&join.constraint, schema, &joined_row, stamp)? {
+                        joined_rows.push(joined_row);
+                        is_matched = true;
+                    }
+                }
+                if join.is_outer && !is_matched {
+                    let mut joined_row = row;
+                    joined_row.values.extend(std::iter::repeat_n(
+                        ColumnValue { value: None },
+                        join.schema.columns.len(),
+                    ));
+                    joined_rows.push(joined_row);
+                }
+            }
+            rows = joined_rows;
+        }
+
+        if let Some(selection) = selection {
+            let mut selected_rows = Vec::with_capacity(rows.len());
+            for row in rows {
+                if expr::is_true(selection, self.schema(), &row, stamp)? {
+                    selected_rows.push(row);
+                }
+            }
+            rows = selected_rows;
+        }
+
+        Ok(rows)
+    }
+
+    // The cached rows of a table in primary key order
+    fn table_rows(record_cache: &RecordCache, schema: &TableSchema) -> Vec<(i64, RecordStorage)> {
+        let mut rows: Vec<_> = record_cache
+            .iter()
+            .filter(|(record, _)| record.table_id == schema.id)
+            .filter_map(|(record, cached)| cached.row.clone().map(|row| (record.id, row)))
+            .collect();
+        rows.sort_by_key(|(id, _)| *id);
+
+        rows
+    }
+
+    // The row whose primary key is the value of `key` for a joined row. A NULL key, or a key that
+    // is not an integer, matches no row.
+    fn lookup(
+        record_cache: &mut RecordCache,
+        schema: &TableSchema,
+        key: &ast::Expr,
+        joined_schema: &TableSchema,
+        joined_row: &RecordStorage,
+        stamp: &StmtStamp,
+    ) -> Result<Option<RecordStorage>, ExecutorErr> {
+        let id = match expr::eval(key, joined_schema, joined_row, stamp)?.value {
+            Some(Value::Integer(id)) => i64::from(id),
+            Some(Value::Bigint(id)) => id,
+            _ => return Ok(None),
+        };
+
+        Ok(record_cache
+            .entry(schema.record(id))
+            .or_insert(CachedRecord {
+                row: None,
+                is_dirty: false,
+            })
+            .row
+            .clone())
+    }
+}
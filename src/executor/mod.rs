@@ -1,246 +1,2867 @@
-use crate::calvinite_tonic::run_stmt_response::Result::Success;
+use crate::calvinite_tonic;
+use crate::calvinite_tonic::column_value::Value;
+use crate::calvinite_tonic::run_stmt_err::ErrorCode;
+use crate::calvinite_tonic::run_stmt_response::Result::{Failure, Restart, Success};
 use crate::calvinite_tonic::{
-    RecordStorage, RunStmtRequestWithUuid, RunStmtResponse, RunStmtResults,
+    Reconnaissance, RecordStorage, ResultSet, RunStmtErr, RunStmtRequestWithUuid, RunStmtResponse,
+    RunStmtResults,
 };
+use crate::catalog::{Catalog, CatalogErr, Column, ColumnType, TableId, TableSchema};
 use crate::common::Record;
+use crate::executor::aggregate::Aggregation;
+use crate::executor::applied_log::AppliedLog;
+use crate::executor::join::Join;
+use crate::executor::partition::Partition;
+use crate::executor::partition_map::{PartitionMap, PartitionMapErr};
+use crate::executor::placement::Placement;
+use crate::expr;
+use crate::expr::{ExprErr, StmtStamp};
+use crate::index::{IndexLookup, IndexStore};
+use crate::procedure::{ProcedureDdl, ProcedureErr};
 use crate::stmt_analyzer;
 use crate::stmt_analyzer::SqlStmt;
-use anyhow::anyhow;
+use crate::storage::{Keyspace, MemoryEngine, Storage, StorageConfig, StorageErr, WriteBatch};
+use anyhow::Context;
 use prost::Message;
 use sqlparser::ast;
-use std::collections::HashMap;
+use sqlparser::parser::ParserError;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use uuid::Uuid;
 
+pub mod aggregate;
+mod applied_log;
+mod join;
+pub mod partition;
+pub mod partition_map;
 pub mod peer;
+mod placement;
+pub mod reconnaissance;
+mod script;
 
-#[derive(thiserror::Error, Debug, Clone)]
-pub enum ExecutorErr {}
+#[derive(thiserror::Error, Debug)]
+pub enum ExecutorErr {
+    #[error(transparent)]
+    Parse(#[from] ParserError),
+    #[error(transparent)]
+    Catalog(#[from] CatalogErr),
+    #[error(transparent)]
+    Expr(#[from] ExprErr),
+    #[error(transparent)]
+    Procedure(#[from] ProcedureErr),
+    #[error("duplicate key: table {table} already has a row with id = {id}")]
+    DuplicateKey { table: String, id: i64 },
+    #[error("duplicate value: unique index {index} already has a row with {value}")]
+    UniqueViolation { index: String, value: String },
+    #[error("the primary key {0} cannot be updated")]
+    PrimaryKeyUpdate(String),
+    #[error("the primary key of table {0} cannot be NULL")]
+    NullPrimaryKey(String),
+    #[error("{0} is not supported")]
+    Unsupported(String),
+    #[error("{0} is not a valid number of rows")]
+    InvalidRowCount(String),
+    #[error("subquery {0} returns more than one value")]
+    NotScalar(String),
+    #[error("column {0} must appear in GROUP BY or be used in an aggregate")]
+    Ungrouped(String),
+    #[error("txn aborted: {0}")]
+    Aborted(String),
+    #[error("a schema change must be the only statement of its request")]
+    SchemaChangeNotAlone,
+    #[error("this node does not own any partition, send the query to another node")]
+    NotMember,
+    #[error("repartitioning needs a partitioned executor")]
+    NotPartitioned,
+    #[error(transparent)]
+    InvalidPartitionMap(#[from] PartitionMapErr),
+    #[error(
+        "partition map version {version} does not follow the current version {current_version}"
+    )]
+    StalePartitionMap { version: u64, current_version: u64 },
+    #[error("invalid txn uuid {0}")]
+    InvalidTxnUuid(String),
+    #[error("storage failed: {0}")]
+    Storage(String),
+    #[error("partition failed: {0}")]
+    Partition(String),
+}
+
+impl ExecutorErr {
+    /// The code a client is sent when a txn fails with this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ExecutorErr::Parse(_)
+            | ExecutorErr::Unsupported(_)
+            | ExecutorErr::InvalidRowCount(_)
+            | ExecutorErr::NotScalar(_)
+            | ExecutorErr::Ungrouped(_) => ErrorCode::Error,
+            ExecutorErr::Aborted(_) => ErrorCode::Abort,
+            ExecutorErr::Catalog(err) => err.code(),
+            ExecutorErr::Expr(err) => err.code(),
+            ExecutorErr::Procedure(err) => err.code(),
+            ExecutorErr::DuplicateKey { .. }
+            | ExecutorErr::UniqueViolation { .. }
+            | ExecutorErr::PrimaryKeyUpdate(_)
+            | ExecutorErr::NullPrimaryKey(_) => ErrorCode::Constraint,
+            ExecutorErr::SchemaChangeNotAlone
+            | ExecutorErr::NotMember
+            | ExecutorErr::NotPartitioned => ErrorCode::Misuse,
+            ExecutorErr::InvalidPartitionMap(_) | ExecutorErr::StalePartitionMap { .. } => {
+                ErrorCode::Error
+            }
+            ExecutorErr::InvalidTxnUuid(_) | ExecutorErr::Partition(_) => ErrorCode::Internal,
+            ExecutorErr::Storage(_) => ErrorCode::Ioerr,
+        }
+    }
+
+    // The analyzer reports parse and catalog errors through anyhow
+    fn from_analyzer(err: anyhow::Error) -> Self {
+        match err.downcast::<ParserError>() {
+            Ok(err) => ExecutorErr::Parse(err),
+            Err(err) => match err.downcast::<CatalogErr>() {
+                Ok(err) => ExecutorErr::Catalog(err),
+                Err(err) => ExecutorErr::Storage(err.to_string()),
+            },
+        }
+    }
+}
+
+impl From<StorageErr> for ExecutorErr {
+    fn from(err: StorageErr) -> Self {
+        ExecutorErr::Storage(err.to_string())
+    }
+}
+
+impl From<&ExecutorErr> for RunStmtErr {
+    fn from(err: &ExecutorErr) -> Self {
+        let mut run_stmt_err = RunStmtErr {
+            error_code: 0,
+            detailed_message: err.to_string(),
+        };
+        run_stmt_err.set_error_code(err.code());
+        run_stmt_err
+    }
+}
 
-#[derive(Clone, Debug, Eq, Hash, PartialOrd, PartialEq)]
-struct TouchedRecord {
-    record: Record,
+// A row the txn read or wrote. Later statements of the txn see the writes of earlier ones. A
+// deleted row has no value, nor has a row a join looked up that does not exist.
+#[derive(Clone, Debug, PartialEq)]
+struct CachedRecord {
+    row: Option<RecordStorage>,
     is_dirty: bool,
 }
 
+type RecordCache = HashMap<Record, CachedRecord>;
+
 #[cfg_attr(test, faux::create)]
 #[derive(Clone, Debug)]
 pub struct Executor {
-    storage: sled::Db,
+    storage: Storage,
+    applied_log: AppliedLog,
+    index_store: IndexStore,
+    catalog: Catalog,
+    partition: Option<Partition>,
 }
 
-#[cfg_attr(test, faux::methods)]
 impl Default for Executor {
+    /// An executor on the memory engine, whose data is lost once it is dropped.
     fn default() -> Self {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        dbg!("Creating Sled DB at {}", tmp_dir.path().to_str().unwrap());
-        Self {
-            storage: sled::open(tmp_dir.path()).unwrap(),
-        }
+        Self::with_storage(MemoryEngine::shared())
+    }
+}
+
+// faux only wraps constructors that return `Self`
+impl Executor {
+    /// Opens the executor stored as `config` says, creating it if its data dir is empty. A
+    /// reopened executor keeps its rows and the LSN of the last batch it applied.
+    pub fn open(config: &StorageConfig) -> anyhow::Result<Self> {
+        let storage = config
+            .open()
+            .with_context(|| format!("Failed to open the {} engine", config.engine))?;
+        Ok(Self::with_storage(storage))
+    }
+
+    /// Opens an executor like `open` that only stores the records owned by `partition`. A
+    /// reopened executor also keeps the partition map of the last reconfiguration it applied.
+    pub fn new_partitioned(config: &StorageConfig, partition: Partition) -> anyhow::Result<Self> {
+        let storage = config
+            .open()
+            .with_context(|| format!("Failed to open the {} engine", config.engine))?;
+        partition.restore_partition_map(&storage)?;
+        Ok(Self::from_storage(storage, Some(partition)))
     }
 }
 
 #[cfg_attr(test, faux::methods)]
 impl Executor {
+    pub fn with_storage(storage: Storage) -> Self {
+        Self::from_storage(storage, None)
+    }
+
+    fn from_storage(storage: Storage, partition: Option<Partition>) -> Self {
+        let index_store = IndexStore::open(&storage);
+        let catalog = Catalog::open(&storage);
+        if let Some(partition) = &partition {
+            partition.attach_storage(storage.clone(), index_store.clone(), catalog.clone());
+        }
+
+        Self {
+            applied_log: AppliedLog::open(&storage),
+            index_store,
+            catalog,
+            storage,
+            partition,
+        }
+    }
+
+    pub fn catalog(&self) -> Catalog {
+        self.catalog.clone()
+    }
+
+    /// Predicts the records the dependent statements of `query` touch, across every partition.
+    /// Returns `None` if every statement is keyed by primary key. The prediction is made outside
+    /// of the log order, so it may be stale by the time the txn runs.
+    pub async fn reconnoiter(&self, query: &str) -> anyhow::Result<Option<Reconnaissance>> {
+        let sql_stmt = SqlStmt::from_string(query.to_string(), &self.catalog)?;
+        if !sql_stmt.is_dependent(&self.catalog)? {
+            return Ok(None);
+        }
+
+        let mut reconnaissance =
+            reconnaissance::reconnoiter(&self.storage, &self.index_store, &self.catalog, query)?;
+
+        if let Some(partition) = &self.partition {
+            let remote_reconnaissance = partition.reconnoiter(query).await?;
+            reconnaissance
+                .write_records
+                .extend(remote_reconnaissance.write_records);
+            reconnaissance
+                .read_records
+                .extend(remote_reconnaissance.read_records);
+        }
+
+        Ok(Some(reconnaissance))
+    }
+
+    /// The LSN of the last request of the last batch numbered by `sequencer_id` that was applied
+    /// in full, or 0.
+    pub fn last_applied_lsn(&self, sequencer_id: &str) -> Result<u64, ExecutorErr> {
+        Ok(self.applied_log.last_applied_lsn(sequencer_id)?)
+    }
+
+    /// Records that every txn of a batch numbered by `sequencer_id`, the last of which has `lsn`,
+    /// was applied, so that a restarted node does not apply it again.
+    pub fn apply_batch(
+        &self,
+        sequencer_id: &str,
+        lsn: u64,
+        txn_uuids: &[String],
+    ) -> Result<(), ExecutorErr> {
+        Ok(self.applied_log.apply_batch(sequencer_id, lsn, txn_uuids)?)
+    }
+
     pub async fn execute(
         &self,
         req: RunStmtRequestWithUuid,
     ) -> Result<RunStmtResponse, ExecutorErr> {
         let txn_uuid = req.uuid.clone();
 
-        let sql_stmt = stmt_analyzer::SqlStmt::from_string(req.query).unwrap();
+        // A batch that was replayed after a restart may have been applied in part already. The
+        // client of a replayed txn is no longer waiting for its result.
+        if self.applied_log.is_applied(&txn_uuid)? {
+            return Ok(Self::success(txn_uuid, vec![]));
+        }
 
-        // Load read and write records into local memory
-        let mut record_cache = HashMap::<TouchedRecord, RecordStorage>::new();
+        let res = self.run(req).await;
+        // Whatever other partitions send for the txn from now on, nobody waits for
+        if let (Some(partition), Ok(uuid)) = (&self.partition, Uuid::parse_str(&txn_uuid)) {
+            partition.finish_txn(uuid);
+        }
 
-        for select_record in sql_stmt.selected_records.iter() {
-            let record_bytes = self
-                .storage
-                .get(select_record.fully_qualified_id_as_bytes())
-                .unwrap()
-                .ok_or_else(|| anyhow!("no record exists for {}", select_record.id))
-                .unwrap();
+        let res = res?;
+        if matches!(res.result, Some(Success(_))) && !self.applied_log.is_applied(&txn_uuid)? {
+            self.applied_log.mark(&txn_uuid)?;
+        }
 
-            let record_bytes_buf = bytes::Bytes::from(record_bytes.to_vec());
+        Ok(res)
+    }
 
-            record_cache.insert(
-                TouchedRecord {
-                    record: select_record.clone(),
-                    is_dirty: false,
+    async fn run(&self, req: RunStmtRequestWithUuid) -> Result<RunStmtResponse, ExecutorErr> {
+        let txn_uuid = req.uuid.clone();
+        // Requests logged before txns were stamped run with the zero stamp, on every replica alike
+        let stamp = req.stamp.clone().unwrap_or_default();
+
+        if let Some(partition_map) = req.repartition {
+            return self.repartition(txn_uuid, partition_map).await;
+        }
+
+        // Every partition analyzes the same request, so they all fail it alike
+        let sql_stmt = match stmt_analyzer::SqlStmt::from_request(&req, &self.catalog) {
+            Ok(sql_stmt) => sql_stmt,
+            Err(err) => return Ok(Self::failure(&ExecutorErr::from_analyzer(err))),
+        };
+
+        // Peers waiting to join the partition map apply DDL too, so they know every table
+        if let Some(procedure_ddl) = &sql_stmt.procedure_ddl {
+            return Ok(match self.execute_procedure_ddl(procedure_ddl) {
+                Ok(()) => Self::success(txn_uuid, vec![ResultSet::default()]),
+                Err(err) => Self::failure(&err),
+            });
+        }
+        if sql_stmt.changes_schema() {
+            return Ok(match sql_stmt.ast_stmts.as_slice() {
+                [stmt] => match self.execute_ddl(&txn_uuid, stmt).await {
+                    Ok(()) => Self::success(txn_uuid, vec![ResultSet::default()]),
+                    Err(err) => Self::failure(&err),
                 },
-                RecordStorage::decode(record_bytes_buf).unwrap(),
-            );
+                _ => Self::failure(&ExecutorErr::SchemaChangeNotAlone),
+            });
+        }
+
+        if let Some(partition) = &self.partition {
+            if !partition.is_member() {
+                return Ok(Self::failure(&ExecutorErr::NotMember));
+            }
         }
 
-        for update_record in sql_stmt.updated_records.iter() {
-            let record_bytes = self
-                .storage
-                .get(update_record.fully_qualified_id_as_bytes())
-                .unwrap()
-                .ok_or_else(|| anyhow!("no record exists for {}", update_record.id))
-                .unwrap();
+        // Records that are read before they are written, each read by its owner only. Inserted
+        // records are read to tell whether their primary key is taken.
+        let mut seen_records = HashSet::new();
+        let read_records: Vec<Record> = sql_stmt
+            .selected_records
+            .iter()
+            .chain(sql_stmt.updated_records.iter())
+            .chain(sql_stmt.inserted_records.iter())
+            .chain(sql_stmt.deleted_records.iter())
+            .filter(|record| seen_records.insert((*record).clone()))
+            .cloned()
+            .collect();
 
-            let record_bytes_buf = bytes::Bytes::from(record_bytes.to_vec());
+        let (local_records, remote_records): (Vec<Record>, Vec<Record>) = read_records
+            .into_iter()
+            .partition(|record| self.is_local(record));
 
-            record_cache.insert(
-                TouchedRecord {
-                    record: update_record.clone(),
-                    is_dirty: false,
-                },
-                RecordStorage::decode(record_bytes_buf).unwrap(),
+        let mut reads: Vec<(Record, Option<RecordStorage>)> = local_records
+            .into_iter()
+            .map(|record| {
+                let value = self.read_record(&record)?;
+                Ok((record, value))
+            })
+            .collect::<Result<_, ExecutorErr>>()?;
+
+        // Every partition scans the records it owns in each range
+        let ranges: Vec<&RangeInclusive<Record>> = sql_stmt
+            .read_ranges
+            .iter()
+            .chain(sql_stmt.write_ranges.iter())
+            .collect();
+        for range in ranges.iter() {
+            for (record, row) in self.scan_range(range)? {
+                if self.is_local(&record) && seen_records.insert(record.clone()) {
+                    reads.push((record, Some(row)));
+                }
+            }
+        }
+
+        // Every partition looks up the entries it stores of each value, which point to local rows
+        for lookup in sql_stmt.index_lookups.iter() {
+            for record in self.index_store.lookup(lookup)? {
+                if seen_records.insert(record.clone()) {
+                    reads.push((record.clone(), self.read_record(&record)?));
+                }
+            }
+        }
+
+        // Exchange local reads with the other partitions that evaluate this txn
+        if let Some(partition) = &self.partition {
+            let txn_uuid = Self::parse_txn_uuid(&txn_uuid)?;
+            let scans_ranges = !ranges.is_empty() || !sql_stmt.index_lookups.is_empty();
+            partition
+                .forward_reads(txn_uuid, &reads, scans_ranges)
+                .await
+                .map_err(|err| ExecutorErr::Partition(err.to_string()))?;
+            reads.extend(
+                partition
+                    .wait_for_reads(txn_uuid, &remote_records, scans_ranges)
+                    .await
+                    .map_err(|err| ExecutorErr::Partition(err.to_string()))?,
             );
         }
 
-        dbg!("Record Cache Before Execution: {:?}", record_cache.clone());
+        // Load read and write records into local memory
+        let mut record_cache: RecordCache = reads
+            .into_iter()
+            .filter_map(|(record, value)| {
+                value.map(|row| {
+                    (
+                        record,
+                        CachedRecord {
+                            row: Some(row),
+                            is_dirty: false,
+                        },
+                    )
+                })
+            })
+            .collect();
+
+        // An index lookup only locked the rows its reconnaissance found. If the index points to any
+        // other row now, the prediction was stale, which every partition notices alike.
+        if sql_stmt.index_lookups.iter().any(|lookup| {
+            record_cache.iter().any(|(record, cached)| {
+                record.table_id == lookup.index.table_id
+                    && cached.row.as_ref().is_some_and(|row| lookup.matches(row))
+                    && !sql_stmt.is_locked(record)
+            })
+        }) {
+            return Ok(Self::restart());
+        }
+
+        // Execute every statement in order. A failed statement, a ROLLBACK or a violated ASSERT
+        // aborts the txn: every partition reaches the same verdict, so none of them flushes any
+        // write of the txn. A statement that failed on records it did not lock restarts instead.
+        let mut result_sets = Vec::with_capacity(sql_stmt.ast_stmts.len());
+        for (stmt_index, stmt) in sql_stmt.ast_stmts.iter().enumerate() {
+            let stamp = StmtStamp::new(stamp.clone(), stmt_index);
+            match Self::execute_stmt(&self.catalog, &mut record_cache, stmt, &stamp) {
+                Ok(result_set) => result_sets.push(result_set),
+                Err(_) if Self::is_stale(&sql_stmt, &record_cache) => return Ok(Self::restart()),
+                Err(err) => return Ok(Self::failure(&err)),
+            }
+        }
+
+        if Self::is_stale(&sql_stmt, &record_cache) {
+            return Ok(Self::restart());
+        }
+
+        if let Err(err) = Self::check_unique_indexes(&sql_stmt.index_lookups, &record_cache) {
+            return Ok(Self::failure(&err));
+        }
+
+        // Flush dirty records and their index entries in one batch along with the mark that the
+        // txn was applied, other partitions flush the records they own
+        let schemas = self.schemas_of(&sql_stmt)?;
+        let mut batch = WriteBatch::default();
+        for (record, cached) in record_cache.into_iter() {
+            if !cached.is_dirty || !self.is_local(&record) {
+                continue;
+            }
+
+            if let Some(schema) = schemas.get(&record.table_id) {
+                if !schema.indexes.is_empty() {
+                    IndexStore::update(
+                        &schema.indexes,
+                        &record,
+                        self.read_record(&record)?.as_ref(),
+                        cached.row.as_ref(),
+                        &mut batch,
+                    );
+                }
+            }
+
+            // Partitioned executors keep track of the virtual node of every record they store
+            let key = record.fully_qualified_id_as_bytes();
+            match cached.row {
+                Some(row) => {
+                    batch.put(Keyspace::Records, key, row.encode_to_vec());
+                    if self.partition.is_some() {
+                        Placement::add_record(&record, &mut batch);
+                    }
+                }
+                None => {
+                    batch.delete(Keyspace::Records, key);
+                    if self.partition.is_some() {
+                        Placement::remove_record(&record, &mut batch);
+                    }
+                }
+            }
+        }
+        AppliedLog::add_mark(&txn_uuid, &mut batch);
+        self.storage.apply(batch)?;
+
+        Ok(Self::success(txn_uuid, result_sets))
+    }
+
+    async fn repartition(
+        &self,
+        txn_uuid: String,
+        partition_map: calvinite_tonic::PartitionMap,
+    ) -> Result<RunStmtResponse, ExecutorErr> {
+        let partition = match &self.partition {
+            Some(partition) => partition,
+            None => return Ok(Self::failure(&ExecutorErr::NotPartitioned)),
+        };
+
+        let new_map = match PartitionMap::try_from(partition_map) {
+            Ok(new_map) => new_map,
+            Err(err) => return Ok(Self::failure(&err.into())),
+        };
+
+        // Every partition checks the same maps, so they all reject or apply the change together
+        let current_version = partition.partition_map().version();
+        if new_map.version() != current_version + 1 {
+            return Ok(Self::failure(&ExecutorErr::StalePartitionMap {
+                version: new_map.version(),
+                current_version,
+            }));
+        }
+
+        partition
+            .repartition(
+                Self::parse_txn_uuid(&txn_uuid)?,
+                new_map,
+                &self.storage,
+                &self.catalog,
+            )
+            .await
+            .map_err(|err| ExecutorErr::Partition(err.to_string()))?;
+
+        Ok(Self::success(txn_uuid, vec![ResultSet::default()]))
+    }
+
+    async fn execute_ddl(&self, txn_uuid: &str, stmt: &ast::Statement) -> Result<(), ExecutorErr> {
+        match stmt {
+            ast::Statement::CreateTable {
+                name,
+                columns,
+                constraints,
+                if_not_exists,
+                ..
+            } => {
+                self.catalog.create_table(
+                    &name.to_string(),
+                    columns,
+                    constraints,
+                    *if_not_exists,
+                )?;
+            }
+            ast::Statement::Drop {
+                object_type: ast::ObjectType::Table,
+                if_exists,
+                names,
+                ..
+            } => {
+                for name in names {
+                    if let Some(schema) = self.catalog.drop_table(&name.to_string(), *if_exists)? {
+                        self.delete_records_of(&schema)?;
+                        for index in schema.indexes.iter() {
+                            self.index_store.drop_index(index)?;
+                        }
+                    }
+                }
+            }
+            ast::Statement::CreateIndex {
+                name,
+                table_name,
+                columns,
+                unique,
+                if_not_exists,
+            } => {
+                let index = match self.catalog.create_index(
+                    &name.to_string(),
+                    &table_name.to_string(),
+                    columns,
+                    *unique,
+                    *if_not_exists,
+                )? {
+                    Some(index) => index,
+                    None => return Ok(()),
+                };
+
+                let schema = self.catalog.require_table(&table_name.to_string())?;
+                let rows = self.scan_range(&schema.all_records())?;
+                if let Err(err) = self.check_new_index(txn_uuid, &index, &rows).await {
+                    self.catalog.drop_index(&index.name, false)?;
+                    return Err(err);
+                }
+
+                let mut index_entries = WriteBatch::default();
+                for (record, row) in rows.iter() {
+                    IndexStore::update(
+                        std::slice::from_ref(&index),
+                        record,
+                        None,
+                        Some(row),
+                        &mut index_entries,
+                    );
+                }
+                self.storage.apply(index_entries)?;
+            }
+            ast::Statement::Drop {
+                object_type: ast::ObjectType::Index,
+                if_exists,
+                names,
+                ..
+            } => {
+                for name in names {
+                    if let Some(index) = self.catalog.drop_index(&name.to_string(), *if_exists)? {
+                        self.index_store.drop_index(&index)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn execute_procedure_ddl(&self, procedure_ddl: &ProcedureDdl) -> Result<(), ExecutorErr> {
+        match procedure_ddl {
+            ProcedureDdl::Create {
+                procedure,
+                or_replace,
+            } => {
+                script::check(procedure)?;
+                self.catalog.create_procedure(procedure, *or_replace)?;
+            }
+            ProcedureDdl::Drop { name, if_exists } => {
+                self.catalog.drop_procedure(name, *if_exists)?;
+            }
+        }
+
+        Ok(())
+    }
 
-        // Execute the query
-        let results =
-            Self::execute_stmt(&mut record_cache, sql_stmt.ast_stmts.first().unwrap()).unwrap();
+    // A unique index cannot be created on a column that already holds a value twice. Rows are
+    // spread over the partitions, so every partition that owns some sends the values of its rows
+    // to the others. Peers that are not in the partition map own no rows, and get the catalog of
+    // the others once they join.
+    async fn check_new_index(
+        &self,
+        txn_uuid: &str,
+        index: &crate::index::Index,
+        local_rows: &[(Record, RecordStorage)],
+    ) -> Result<(), ExecutorErr> {
+        if !index.unique {
+            return Ok(());
+        }
 
-        dbg!("Record Cache After Execution: {:?}", record_cache.clone());
+        let mut values: Vec<(Record, Option<RecordStorage>)> = local_rows
+            .iter()
+            .filter_map(|(record, row)| {
+                let value = index.value_of(row)?;
+                Some((
+                    record.clone(),
+                    Some(RecordStorage {
+                        values: vec![value.clone()],
+                    }),
+                ))
+            })
+            .collect();
 
-        // Flush dirty records
-        for (key, value) in record_cache.into_iter() {
-            if key.is_dirty {
-                self.storage.insert(
-                    key.record.fully_qualified_id_as_bytes(),
-                    value.encode_to_vec(),
+        if let Some(partition) = &self.partition {
+            if partition.is_member() {
+                let txn_uuid = Self::parse_txn_uuid(txn_uuid)?;
+                partition
+                    .forward_reads(txn_uuid, &values, true)
+                    .await
+                    .map_err(|err| ExecutorErr::Partition(err.to_string()))?;
+                values.extend(
+                    partition
+                        .wait_for_reads(txn_uuid, &[], true)
+                        .await
+                        .map_err(|err| ExecutorErr::Partition(err.to_string()))?,
                 );
             }
         }
 
-        Ok(RunStmtResponse {
+        // Every partition reports the same duplicate
+        values.sort_by(|(left, _), (right, _)| left.cmp(right));
+        let mut seen_values = HashSet::new();
+        for value in values.into_iter().filter_map(|(_, value)| value) {
+            if !seen_values.insert(value.encode_to_vec()) {
+                return Err(ExecutorErr::UniqueViolation {
+                    index: index.name.clone(),
+                    value: value.values[0].to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // A dependent statement only locked the records its reconnaissance predicted. If it wrote any
+    // other record, or a join looked up any other record, the prediction was stale, which every
+    // partition notices alike.
+    fn is_stale(sql_stmt: &SqlStmt, record_cache: &RecordCache) -> bool {
+        let write_set: HashSet<Record> = sql_stmt.write_set().into_iter().collect();
+        record_cache.iter().any(|(record, cached)| {
+            if cached.is_dirty {
+                !write_set.contains(record)
+                    && !sql_stmt
+                        .write_ranges
+                        .iter()
+                        .any(|range| range.contains(record))
+            } else {
+                cached.row.is_none() && !sql_stmt.is_locked(record)
+            }
+        })
+    }
+
+    // The schemas of the tables the statements of a txn touch, by table id. Procedures write to
+    // tables no statement names, so a txn that executes one gets every schema.
+    fn schemas_of(&self, sql_stmt: &SqlStmt) -> Result<HashMap<TableId, TableSchema>, ExecutorErr> {
+        if sql_stmt
+            .ast_stmts
+            .iter()
+            .any(|stmt| matches!(stmt, ast::Statement::Execute { .. }))
+        {
+            return Ok(self
+                .catalog
+                .tables()?
+                .into_iter()
+                .map(|schema| (schema.id, schema))
+                .collect());
+        }
+
+        let mut schemas = HashMap::new();
+        for table_name in sql_stmt.ast_stmts.iter().filter_map(SqlStmt::table_name) {
+            if let Some(schema) = self.catalog.table(&table_name)? {
+                schemas.insert(schema.id, schema);
+            }
+        }
+
+        Ok(schemas)
+    }
+
+    // A unique index holds each value at most once. Every row that held a value the txn writes to
+    // the index was found by a lookup and read, so each partition counts the rows that hold it
+    // alike.
+    fn check_unique_indexes(
+        index_lookups: &[IndexLookup],
+        record_cache: &RecordCache,
+    ) -> Result<(), ExecutorErr> {
+        for lookup in index_lookups.iter().filter(|lookup| lookup.index.unique) {
+            let holders = record_cache
+                .iter()
+                .filter(|(record, cached)| {
+                    record.table_id == lookup.index.table_id
+                        && cached.row.as_ref().is_some_and(|row| lookup.matches(row))
+                })
+                .count();
+
+            if holders > 1 {
+                return Err(ExecutorErr::UniqueViolation {
+                    index: lookup.index.name.clone(),
+                    value: lookup.value.to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delete_records_of(&self, schema: &TableSchema) -> Result<(), ExecutorErr> {
+        let mut dropped = WriteBatch::default();
+
+        for (key, _) in self
+            .storage
+            .scan(Keyspace::Records, Record::key_range(&schema.all_records()))?
+        {
+            if self.partition.is_some() {
+                if let Some(record) = Record::from_fully_qualified_id(&key) {
+                    Placement::remove_record(&record, &mut dropped);
+                }
+            }
+            dropped.delete(Keyspace::Records, key);
+        }
+
+        self.storage.apply(dropped)?;
+
+        Ok(())
+    }
+
+    fn success(txn_uuid: String, result_sets: Vec<ResultSet>) -> RunStmtResponse {
+        let last_result_set = result_sets.last().cloned().unwrap_or_default();
+
+        RunStmtResponse {
             result: Some(Success(RunStmtResults {
                 uuid: txn_uuid,
-                results,
+                results: last_result_set.rows,
+                columns: last_result_set.columns,
+                rows_affected: last_result_set.rows_affected,
+                result_sets,
             })),
-        })
+        }
+    }
+
+    fn restart() -> RunStmtResponse {
+        RunStmtResponse {
+            result: Some(Restart(calvinite_tonic::Restart {})),
+        }
+    }
+
+    /// The response a client is sent for a txn that failed with `err`.
+    pub fn failure(err: &ExecutorErr) -> RunStmtResponse {
+        RunStmtResponse {
+            result: Some(Failure(err.into())),
+        }
+    }
+
+    fn parse_txn_uuid(txn_uuid: &str) -> Result<Uuid, ExecutorErr> {
+        Uuid::parse_str(txn_uuid).map_err(|_| ExecutorErr::InvalidTxnUuid(txn_uuid.to_string()))
+    }
+
+    fn is_local(&self, record: &Record) -> bool {
+        match &self.partition {
+            Some(partition) => partition.is_local(record),
+            None => true,
+        }
+    }
+
+    fn read_record(&self, record: &Record) -> Result<Option<RecordStorage>, ExecutorErr> {
+        self.storage
+            .get(Keyspace::Records, &record.fully_qualified_id_as_bytes())?
+            .map(|record_bytes| {
+                RecordStorage::decode(record_bytes.as_slice())
+                    .map_err(|err| ExecutorErr::Storage(err.to_string()))
+            })
+            .transpose()
+    }
+
+    fn scan_range(
+        &self,
+        range: &RangeInclusive<Record>,
+    ) -> Result<Vec<(Record, RecordStorage)>, ExecutorErr> {
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.storage
+            .scan(Keyspace::Records, Record::key_range(range))?
+            .into_iter()
+            .map(|(key, value)| {
+                let record = Record::from_fully_qualified_id(&key)
+                    .ok_or_else(|| ExecutorErr::Storage(format!("invalid record key {:?}", key)))?;
+                let row = RecordStorage::decode(value.as_slice())
+                    .map_err(|err| ExecutorErr::Storage(err.to_string()))?;
+                Ok((record, row))
+            })
+            .collect()
     }
 
     fn execute_stmt(
-        record_cache: &mut HashMap<TouchedRecord, RecordStorage>,
+        catalog: &Catalog,
+        record_cache: &mut RecordCache,
         stmt: &ast::Statement,
-    ) -> anyhow::Result<Vec<RecordStorage>> {
+        stamp: &StmtStamp,
+    ) -> Result<ResultSet, ExecutorErr> {
+        match stmt {
+            ast::Statement::Rollback { .. } => return Err(ExecutorErr::Aborted(stmt.to_string())),
+            ast::Statement::Assert { condition, message } => {
+                return Self::execute_assert_stmt(
+                    catalog,
+                    record_cache,
+                    stmt,
+                    condition,
+                    message,
+                    stamp,
+                )
+            }
+            ast::Statement::Execute { name, parameters } => {
+                let procedure = catalog.require_procedure(&name.value)?;
+                let args = procedure.args_of(parameters)?;
+                return script::call(catalog, record_cache, &procedure, &args);
+            }
+            _ => {}
+        }
+
+        let schema = Self::table_schema(catalog, stmt)?;
         match stmt {
-            ast::Statement::Query(query) => Self::execute_query_stmt(record_cache, query),
-            ast::Statement::Insert { source, .. } => {
-                Self::execute_insert_stmt(record_cache, source)
+            ast::Statement::Query(query) => {
+                Self::execute_query_stmt(catalog, record_cache, &schema, query, stamp)
             }
+            ast::Statement::Insert {
+                columns, source, ..
+            } => Self::execute_insert_stmt(catalog, record_cache, &schema, columns, source, stamp),
             ast::Statement::Update {
-                selection: Some(selection),
+                selection,
                 assignments,
                 ..
-            } => Self::execute_update_stmt(record_cache, selection, assignments),
-            _ => Ok(Vec::new()),
+            } => Self::execute_update_stmt(
+                record_cache,
+                &schema,
+                selection.as_ref(),
+                assignments,
+                stamp,
+            ),
+            ast::Statement::Delete { selection, .. } => {
+                Self::execute_delete_stmt(record_cache, &schema, selection.as_ref(), stamp)
+            }
+            _ => Err(ExecutorErr::Unsupported(stmt.to_string())),
+        }
+    }
+
+    // The table a statement reads or writes, named by its alias if the statement gives it one
+    fn table_schema(catalog: &Catalog, stmt: &ast::Statement) -> Result<TableSchema, ExecutorErr> {
+        let mut schema = match SqlStmt::table_name(stmt) {
+            Some(table_name) => catalog.require_table(&table_name)?,
+            None => return Err(ExecutorErr::Unsupported(stmt.to_string())),
+        };
+        if let Some(alias) = SqlStmt::table_alias(stmt) {
+            schema.name = alias;
         }
+
+        Ok(schema)
     }
+
     fn execute_query_stmt(
-        record_cache: &mut HashMap<TouchedRecord, RecordStorage>,
+        catalog: &Catalog,
+        record_cache: &mut RecordCache,
+        schema: &TableSchema,
         query: &ast::Query,
-    ) -> anyhow::Result<Vec<RecordStorage>> {
+        stamp: &StmtStamp,
+    ) -> Result<ResultSet, ExecutorErr> {
         match &query.body {
-            ast::SetExpr::Select(select) => match *select.clone() {
-                ast::Select {
-                    selection: Some(selection),
-                    ..
-                } => {
-                    let record =
-                        SqlStmt::find_id_in_expr(&selection).ok_or(anyhow!("Couldn't find ID"))?;
-                    let record_value = record_cache
-                        .get(&TouchedRecord {
-                            record,
-                            is_dirty: false,
-                        })
-                        .ok_or(anyhow!("expected record"))?;
-                    Ok(vec![record_value.clone()])
+            ast::SetExpr::Select(select) => {
+                if query.fetch.is_some() {
+                    return Err(ExecutorErr::Unsupported(query.to_string()));
                 }
-                _ => Ok(Vec::new()),
-            },
-            _ => Ok(Vec::new()),
+                let offset = match &query.offset {
+                    Some(offset) => Self::row_count(&offset.value)?,
+                    None => 0,
+                };
+                let limit = match &query.limit {
+                    Some(limit) => Self::row_count(limit)?,
+                    None => usize::MAX,
+                };
+
+                let join = Join::of(catalog, schema, query)?;
+                let (schema, mut rows) = match &join {
+                    Some(join) => (
+                        join.schema(),
+                        join.rows(record_cache, select.selection.as_ref(), stamp)?,
+                    ),
+                    None => (
+                        schema,
+                        Self::selected_rows(
+                            record_cache,
+                            schema,
+                            select.selection.as_ref(),
+                            stamp,
+                        )?
+                        .into_iter()
+                        .map(|(_, row)| row)
+                        .collect(),
+                    ),
+                };
+
+                // Aggregates fold the selected rows before they are ordered and limited
+                let (columns, rows) = match Aggregation::of(schema, select, &query.order_by)? {
+                    Some(aggregation) => {
+                        let mut rows = aggregation.group_rows(rows, stamp)?;
+                        Self::order_rows(&mut rows, aggregation.schema(), &query.order_by, stamp)?;
+                        let rows = rows
+                            .iter()
+                            .skip(offset)
+                            .take(limit)
+                            .map(|row| aggregation.project(row))
+                            .collect();
+                        (aggregation.columns(), rows)
+                    }
+                    None => {
+                        let projection = schema.projection(&select.projection)?;
+                        Self::order_rows(&mut rows, schema, &query.order_by, stamp)?;
+                        let rows = rows
+                            .into_iter()
+                            .skip(offset)
+                            .take(limit)
+                            .map(|row| RecordStorage {
+                                values: projection
+                                    .iter()
+                                    .map(|projected| row.values[projected.index].clone())
+                                    .collect(),
+                            })
+                            .collect();
+                        let columns = projection
+                            .iter()
+                            .map(|projected| projected.column.metadata())
+                            .collect();
+                        (columns, rows)
+                    }
+                };
+
+                Ok(ResultSet {
+                    columns,
+                    rows,
+                    rows_affected: 0,
+                })
+            }
+            _ => Err(ExecutorErr::Unsupported(query.to_string())),
         }
     }
 
-    fn execute_insert_stmt(
-        record_cache: &mut HashMap<TouchedRecord, RecordStorage>,
-        source: &ast::Query,
-    ) -> anyhow::Result<Vec<RecordStorage>> {
-        match &source.body {
-            ast::SetExpr::Values(ast::Values(values)) => {
-                // TODO: Parse more than first insert
-                let (key_expr, value_expr) = (&values[0][0], &values[0][1]);
-
-                let key = SqlStmt::expr_to_num(key_expr).ok_or(anyhow!("failed to parse key"))?;
-                let value =
-                    SqlStmt::expr_to_num(value_expr).ok_or(anyhow!("failed to parse value"))?;
-
-                record_cache.insert(
-                    TouchedRecord {
-                        record: Record { id: key },
-                        is_dirty: true,
-                    },
-                    RecordStorage { val: value },
-                );
+    // Rows are sorted by primary key unless the query orders them otherwise. Like in PostgreSQL,
+    // NULLs sort after every other value unless NULLS FIRST is given.
+    fn order_rows(
+        rows: &mut Vec<RecordStorage>,
+        schema: &TableSchema,
+        order_by: &[ast::OrderByExpr],
+        stamp: &StmtStamp,
+    ) -> Result<(), ExecutorErr> {
+        if order_by.is_empty() {
+            return Ok(());
+        }
 
-                Ok(Vec::new())
+        let mut sorted_rows = rows
+            .drain(..)
+            .map(|row| {
+                let sort_key = order_by
+                    .iter()
+                    .map(|order_by_expr| expr::eval(&order_by_expr.expr, schema, &row, stamp))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((sort_key, row))
+            })
+            .collect::<Result<Vec<_>, ExecutorErr>>()?;
+
+        let mut incomparable = None;
+        sorted_rows.sort_by(|(left_key, _), (right_key, _)| {
+            for ((left, right), order_by_expr) in left_key.iter().zip(right_key).zip(order_by) {
+                let asc = order_by_expr.asc.unwrap_or(true);
+                let nulls_first = order_by_expr.nulls_first.unwrap_or(!asc);
+
+                let ordering = match (&left.value, &right.value) {
+                    (None, None) => Ordering::Equal,
+                    (None, Some(_)) if nulls_first => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (Some(_), None) if nulls_first => Ordering::Greater,
+                    (Some(_), None) => Ordering::Less,
+                    _ => {
+                        let ordering = match expr::compare(left, right) {
+                            Ok(ordering) => ordering.unwrap_or(Ordering::Equal),
+                            Err(err) => {
+                                incomparable.get_or_insert(err);
+                                Ordering::Equal
+                            }
+                        };
+                        if asc {
+                            ordering
+                        } else {
+                            ordering.reverse()
+                        }
+                    }
+                };
+
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
             }
-            _ => Ok(Vec::new()),
+            Ordering::Equal
+        });
+
+        if let Some(err) = incomparable {
+            return Err(err.into());
         }
+        rows.extend(sorted_rows.into_iter().map(|(_, row)| row));
+
+        Ok(())
     }
 
-    fn execute_update_stmt(
-        record_cache: &mut HashMap<TouchedRecord, RecordStorage>,
-        selection: &ast::Expr,
-        assignments: &Vec<ast::Assignment>,
-    ) -> anyhow::Result<Vec<RecordStorage>> {
-        let record = SqlStmt::find_id_in_expr(selection).ok_or(anyhow!(""))?;
+    fn row_count(expr: &ast::Expr) -> Result<usize, ExecutorErr> {
+        SqlStmt::expr_to_num(expr)
+            .and_then(|row_count| usize::try_from(row_count).ok())
+            .ok_or_else(|| ExecutorErr::InvalidRowCount(expr.to_string()))
+    }
 
-        let value = SqlStmt::expr_to_num(&assignments[0].value).ok_or(anyhow!(""))?;
+    fn execute_assert_stmt(
+        catalog: &Catalog,
+        record_cache: &mut RecordCache,
+        stmt: &ast::Statement,
+        condition: &ast::Expr,
+        message: &Option<ast::Expr>,
+        stamp: &StmtStamp,
+    ) -> Result<ResultSet, ExecutorErr> {
+        // The condition is evaluated over a row with a column for the value of each subquery
+        let mut schema = TableSchema {
+            id: 0,
+            name: String::new(),
+            columns: Vec::new(),
+            primary_key: 0,
+            indexes: Vec::new(),
+            qualifiers: Vec::new(),
+        };
+        let mut row = RecordStorage::default();
 
-        record_cache.insert(
-            TouchedRecord {
-                record,
-                is_dirty: true,
-            },
-            RecordStorage { val: value },
-        );
+        for (subquery, query) in expr::subqueries(condition)
+            .into_iter()
+            .chain(message.iter().flat_map(expr::subqueries))
+        {
+            let query_stmt = ast::Statement::Query(Box::new(query.clone()));
+            let query_schema = Self::table_schema(catalog, &query_stmt)?;
+            let result_set =
+                Self::execute_query_stmt(catalog, record_cache, &query_schema, query, stamp)?;
 
-        Ok(Vec::new())
-    }
-}
+            let (column_type, value) = match (subquery, result_set.columns.as_slice()) {
+                (ast::Expr::Exists(_), _) => {
+                    (ColumnType::Boolean, (!result_set.rows.is_empty()).into())
+                }
+                (_, [column]) if result_set.rows.len() <= 1 => (
+                    column.column_type().into(),
+                    result_set
+                        .rows
+                        .first()
+                        .map(|row| row.values[0].clone())
+                        .unwrap_or_default(),
+                ),
+                _ => return Err(ExecutorErr::NotScalar(query.to_string())),
+            };
 
-#[cfg(test)]
-mod tests {
-    use crate::calvinite_tonic::{RecordStorage, RunStmtRequestWithUuid};
+            schema.columns.push(Column {
+                name: subquery.to_string(),
+                column_type,
+                nullable: true,
+            });
+            row.values.push(value);
+        }
 
-    use crate::calvinite_tonic::run_stmt_response::Result::Success;
-    use crate::executor::Executor;
+        if expr::is_false(condition, &schema, &row, stamp)? {
+            let message = match message {
+                Some(message) => match expr::eval(message, &schema, &row, stamp)?.value {
+                    Some(Value::Text(text)) => text,
+                    _ => message.to_string(),
+                },
+                None => stmt.to_string(),
+            };
+            return Err(ExecutorErr::Aborted(message));
+        }
 
-    #[tokio::test]
-    async fn executes_write_read() {
-        let ex = Executor::default();
+        Ok(ResultSet::default())
+    }
 
-        let stmt1_uuid = uuid::Uuid::new_v4();
-        let stmt1 = RunStmtRequestWithUuid {
-            query: "INSERT INTO foo VALUES (1, 2)".into(),
-            uuid: stmt1_uuid.to_string(),
-        };
+    fn execute_insert_stmt(
+        catalog: &Catalog,
+        record_cache: &mut RecordCache,
+        schema: &TableSchema,
+        columns: &[ast::Ident],
+        source: &ast::Query,
+        stamp: &StmtStamp,
+    ) -> Result<ResultSet, ExecutorErr> {
+        // A failed row fails the whole txn, so either every row is inserted or none is
+        let rows = Self::rows_to_insert(catalog, record_cache, schema, columns, source, stamp)?;
+        let rows_affected = rows.len() as u64;
+        let computed_columns = Self::computed_columns(schema, columns, source)?;
+        for (record, row) in rows {
+            // Only literal values are checked against a unique index before they are written
+            let computed_unique_index = schema.indexes.iter().find(|index| {
+                index.unique
+                    && computed_columns.contains(&index.column)
+                    && index.value_of(&row).is_some()
+            });
+            if let Some(index) = computed_unique_index {
+                return Err(ExecutorErr::Unsupported(match source.body {
+                    ast::SetExpr::Select(_) => "INSERT ... SELECT into a unique index".to_string(),
+                    _ => format!(
+                        "computed value of {} in a unique index",
+                        schema.columns[index.column].name
+                    ),
+                }));
+            }
+            if record_cache
+                .get(&record)
+                .is_some_and(|cached| cached.row.is_some())
+            {
+                return Err(ExecutorErr::DuplicateKey {
+                    table: schema.name.clone(),
+                    id: record.id,
+                });
+            }
 
-        let query_results1 = ex.execute(stmt1).await.unwrap();
-        if let Some(Success(result)) = query_results1.result {
-            assert_eq!(result.results, Vec::new());
-        } else {
-            panic!("Should always be successful")
+            record_cache.insert(
+                record,
+                CachedRecord {
+                    row: Some(row),
+                    is_dirty: true,
+                },
+            );
         }
 
-        let stmt2_uuid = uuid::Uuid::new_v4();
-        let stmt2 = RunStmtRequestWithUuid {
-            query: "SELECT * FROM foo WHERE id = 1".into(),
-            uuid: stmt2_uuid.to_string(),
+        Ok(ResultSet {
+            rows_affected,
+            ..ResultSet::default()
+        })
+    }
+
+    // The columns an INSERT writes values to that are not literals
+    fn computed_columns(
+        schema: &TableSchema,
+        columns: &[ast::Ident],
+        source: &ast::Query,
+    ) -> Result<Vec<usize>, ExecutorErr> {
+        let values = match &source.body {
+            ast::SetExpr::Values(ast::Values(values)) => values,
+            _ => return Ok((0..schema.columns.len()).collect()),
         };
 
-        let query_results2 = ex.execute(stmt2).await.unwrap();
-        if let Some(Success(result)) = query_results2.result {
-            assert_eq!(result.results, vec![RecordStorage { val: 2 }]);
-        } else {
-            panic!("Should always be successful")
-        }
+        let column_indexes = schema.insert_column_indexes(columns)?;
+        Ok(values
+            .iter()
+            .flat_map(|value| column_indexes.iter().zip(value))
+            .filter(|(_, expr)| !ColumnType::is_literal(expr))
+            .map(|(column_idx, _)| *column_idx)
+            .collect())
+    }
+
+    // The rows an INSERT writes: every row of its VALUES list, or the row its keyed SELECT reads.
+    // Values other than literals, like NOW(), are computed over the row of the literals.
+    fn rows_to_insert(
+        catalog: &Catalog,
+        record_cache: &mut RecordCache,
+        schema: &TableSchema,
+        columns: &[ast::Ident],
+        source: &ast::Query,
+        stamp: &StmtStamp,
+    ) -> Result<Vec<(Record, RecordStorage)>, ExecutorErr> {
+        let rows = match &source.body {
+            ast::SetExpr::Values(ast::Values(values)) => {
+                let column_indexes = schema.insert_column_indexes(columns)?;
+                let mut rows = Vec::with_capacity(values.len());
+                for value in values {
+                    let literals = schema.row_of_literals(columns, value)?;
+                    let mut row = literals.clone();
+                    for (column_idx, expr) in column_indexes.iter().zip(value) {
+                        if ColumnType::is_literal(expr) {
+                            continue;
+                        }
+                        // Records are locked by the primary keys the analyzer reads off literals
+                        if *column_idx == schema.primary_key {
+                            return Err(ExecutorErr::Unsupported(format!(
+                                "computed primary key {}",
+                                expr
+                            )));
+                        }
+                        let column = &schema.columns[*column_idx];
+                        row.values[*column_idx] =
+                            column.coerce(expr::eval(expr, schema, &literals, stamp)?)?;
+                    }
+                    rows.push(row);
+                }
+                rows
+            }
+            ast::SetExpr::Select(_) => {
+                let (source_schema, _) = SqlStmt::insert_source(source, catalog)
+                    .map_err(ExecutorErr::from_analyzer)?
+                    .ok_or_else(|| {
+                        ExecutorErr::Unsupported(
+                            "INSERT ... SELECT without a select keyed by primary key".to_string(),
+                        )
+                    })?;
+
+                Self::execute_query_stmt(catalog, record_cache, &source_schema, source, stamp)?
+                    .rows
+                    .into_iter()
+                    .map(|row| schema.row_from_values(columns, row.values))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            _ => return Err(ExecutorErr::Unsupported(source.to_string())),
+        };
+
+        rows.into_iter()
+            .map(|row| {
+                let record = schema
+                    .record_of(&row)
+                    .ok_or_else(|| ExecutorErr::NullPrimaryKey(schema.name.clone()))?;
+                Ok((record, row))
+            })
+            .collect()
+    }
+
+    fn execute_update_stmt(
+        record_cache: &mut RecordCache,
+        schema: &TableSchema,
+        selection: Option<&ast::Expr>,
+        assignments: &[ast::Assignment],
+        stamp: &StmtStamp,
+    ) -> Result<ResultSet, ExecutorErr> {
+        // Updating rows that do not exist changes nothing
+        let rows = Self::selected_rows(record_cache, schema, selection, stamp)?;
+        let rows_affected = rows.len() as u64;
+        for (record, old_row) in rows {
+            let mut row = old_row.clone();
+            for assignment in assignments {
+                let column_name = assignment
+                    .id
+                    .iter()
+                    .map(|ident| ident.value.clone())
+                    .collect::<Vec<_>>()
+                    .join(".");
+                let column_idx = schema
+                    .column_index(&column_name)
+                    .ok_or_else(|| CatalogErr::NoSuchColumn(column_name.clone()))?;
+                if column_idx == schema.primary_key {
+                    return Err(ExecutorErr::PrimaryKeyUpdate(column_name));
+                }
+
+                // Every assignment sees the row as it was before the UPDATE
+                let value = expr::eval(&assignment.value, schema, &old_row, stamp)?;
+                let column = &schema.columns[column_idx];
+                row.values[column_idx] = column.coerce(value)?;
+
+                // Only literal values are checked against a unique index before they are written
+                if row.values[column_idx].value.is_some()
+                    && row.values[column_idx] != old_row.values[column_idx]
+                    && Self::has_unique_index(schema, column_idx)
+                    && column
+                        .column_type
+                        .value_from_expr(&column.name, &assignment.value)
+                        .is_err()
+                {
+                    return Err(ExecutorErr::Unsupported(format!(
+                        "computed value of {} in a unique index",
+                        column.name
+                    )));
+                }
+            }
+
+            record_cache.insert(
+                record,
+                CachedRecord {
+                    row: Some(row),
+                    is_dirty: true,
+                },
+            );
+        }
+
+        Ok(ResultSet {
+            rows_affected,
+            ..ResultSet::default()
+        })
+    }
+
+    fn has_unique_index(schema: &TableSchema, column_idx: usize) -> bool {
+        schema
+            .indexes
+            .iter()
+            .any(|index| index.unique && index.column == column_idx)
+    }
+
+    fn execute_delete_stmt(
+        record_cache: &mut RecordCache,
+        schema: &TableSchema,
+        selection: Option<&ast::Expr>,
+        stamp: &StmtStamp,
+    ) -> Result<ResultSet, ExecutorErr> {
+        // Deleting rows that do not exist changes nothing
+        let rows = Self::selected_rows(record_cache, schema, selection, stamp)?;
+        let rows_affected = rows.len() as u64;
+        for (record, _) in rows {
+            record_cache.insert(
+                record,
+                CachedRecord {
+                    row: None,
+                    is_dirty: true,
+                },
+            );
+        }
+
+        Ok(ResultSet {
+            rows_affected,
+            ..ResultSet::default()
+        })
+    }
+
+    // The rows of `schema` a SELECT, UPDATE or DELETE predicate matches, in primary key order. A keyed
+    // predicate looks its record up, any other predicate is evaluated on every row that was read.
+    fn selected_rows(
+        record_cache: &RecordCache,
+        schema: &TableSchema,
+        selection: Option<&ast::Expr>,
+        stamp: &StmtStamp,
+    ) -> Result<Vec<(Record, RecordStorage)>, ExecutorErr> {
+        if let Some(record) =
+            selection.and_then(|selection| SqlStmt::find_id_in_expr(selection, schema))
+        {
+            return Ok(record_cache
+                .get(&record)
+                .and_then(|cached| cached.row.clone())
+                .map(|row| (record, row))
+                .into_iter()
+                .collect());
+        }
+
+        let mut rows = Vec::new();
+        for (record, cached) in record_cache.iter() {
+            let row = match &cached.row {
+                Some(row) if record.table_id == schema.id => row,
+                _ => continue,
+            };
+            let is_match = match selection {
+                Some(selection) => expr::is_true(selection, schema, row, stamp)?,
+                None => true,
+            };
+            if is_match {
+                rows.push((record.clone(), row.clone()));
+            }
+        }
+        rows.sort_by_key(|(record, _)| record.id);
+
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::{
+        ColumnMetadata, ColumnType, ColumnValue, Reconnaissance, RecordStorage,
+        RunStmtRequestWithUuid, RunStmtResponse, TxnStamp,
+    };
+
+    use crate::calvinite_tonic::run_stmt_err::ErrorCode;
+    use crate::calvinite_tonic::run_stmt_response::Result::{Failure, Restart, Success};
+    use crate::executor::partition::{Partition, PartitionInbox};
+    use crate::executor::partition_map::PartitionMap;
+    use crate::executor::peer::{Peer, PeerManager};
+    use crate::executor::placement::Placement;
+    use crate::executor::Executor;
+    use crate::storage::{EngineKind, StorageConfig, WriteBatch};
+    use uuid::Uuid;
+
+    // Reconnoiters the query first, like a sequencer does
+    async fn execute(ex: &Executor, query: &str) -> RunStmtResponse {
+        let reconnaissance = ex.reconnoiter(query).await.unwrap_or(None);
+        execute_with_reconnaissance(ex, query, reconnaissance).await
+    }
+
+    async fn execute_with_reconnaissance(
+        ex: &Executor,
+        query: &str,
+        reconnaissance: Option<Reconnaissance>,
+    ) -> RunStmtResponse {
+        let req = RunStmtRequestWithUuid {
+            query: query.into(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            repartition: None,
+            reconnaissance,
+            stamp: None,
+        };
+        ex.execute(req).await.unwrap()
+    }
+
+    // Runs the query as the sequencer stamped it
+    async fn execute_stamped(ex: &Executor, query: &str, stamp: &TxnStamp) -> RunStmtResponse {
+        let req = RunStmtRequestWithUuid {
+            query: query.into(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            repartition: None,
+            reconnaissance: ex.reconnoiter(query).await.unwrap_or(None),
+            stamp: Some(stamp.clone()),
+        };
+        ex.execute(req).await.unwrap()
+    }
+
+    async fn assert_results(ex: &Executor, query: &str, expected_results: Vec<RecordStorage>) {
+        if let Some(Success(result)) = execute(ex, query).await.result {
+            assert_eq!(result.results, expected_results);
+        } else {
+            panic!("Should always be successful")
+        }
+    }
+
+    fn row(id: i64, val: i64) -> RecordStorage {
+        RecordStorage {
+            values: vec![id.into(), val.into()],
+        }
+    }
+
+    #[tokio::test]
+    async fn executes_write_read() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "INSERT INTO foo VALUES (1, 2)", Vec::new()).await;
+        assert_results(&ex, "SELECT * FROM foo WHERE id = 1", vec![row(1, 2)]).await;
+    }
+
+    #[tokio::test]
+    async fn keeps_tables_apart() {
+        let ex = Executor::default();
+
+        for table in ["foo", "bar"] {
+            let create_table =
+                format!("CREATE TABLE {} (id BIGINT PRIMARY KEY, val BIGINT)", table);
+            assert_results(&ex, &create_table, Vec::new()).await;
+        }
+        assert_results(&ex, "INSERT INTO foo VALUES (1, 2)", Vec::new()).await;
+        assert_results(&ex, "INSERT INTO bar VALUES (1, 3)", Vec::new()).await;
+
+        assert_results(&ex, "SELECT * FROM foo WHERE id = 1", vec![row(1, 2)]).await;
+        assert_results(&ex, "SELECT * FROM bar WHERE id = 1", vec![row(1, 3)]).await;
+
+        // Dropping a table drops its rows, even if a table with the same name comes back
+        assert_results(&ex, "DROP TABLE foo", Vec::new()).await;
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "SELECT * FROM foo WHERE id = 1", Vec::new()).await;
+        assert_results(&ex, "SELECT * FROM bar WHERE id = 1", vec![row(1, 3)]).await;
+    }
+
+    #[tokio::test]
+    async fn rejects_stmts_that_do_not_match_the_schema() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "INSERT INTO foo VALUES (1, 2)", Vec::new()).await;
+
+        for query in [
+            "INSERT INTO bar VALUES (1, 2)",
+            "INSERT INTO foo VALUES (2, 'two')",
+            "INSERT INTO foo VALUES (2, 3, 4)",
+            "UPDATE foo SET val = true WHERE id = 1",
+            "UPDATE foo SET missing = 3 WHERE id = 1",
+        ] {
+            assert!(
+                matches!(execute(&ex, query).await.result, Some(Failure(_))),
+                "{} should fail",
+                query
+            );
+        }
+
+        assert_results(&ex, "SELECT * FROM foo WHERE id = 1", vec![row(1, 2)]).await;
+        assert_results(&ex, "SELECT * FROM foo WHERE id = 2", Vec::new()).await;
+    }
+
+    #[tokio::test]
+    async fn runs_stmts_not_keyed_by_primary_key() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        for (id, val) in [(3, 10), (1, 10), (2, 20)] {
+            let insert = format!("INSERT INTO foo VALUES ({}, {})", id, val);
+            assert_results(&ex, &insert, Vec::new()).await;
+        }
+
+        assert_results(
+            &ex,
+            "SELECT * FROM foo WHERE val = 10",
+            vec![row(1, 10), row(3, 10)],
+        )
+        .await;
+        assert_results(&ex, "UPDATE foo SET val = 30 WHERE val < 15", Vec::new()).await;
+        assert_results(
+            &ex,
+            "SELECT * FROM foo",
+            vec![row(1, 30), row(2, 20), row(3, 30)],
+        )
+        .await;
+        assert_results(&ex, "SELECT * FROM foo WHERE val = 10", Vec::new()).await;
+
+        // Predicates on the primary key are dependent too unless they are a single equality
+        assert_results(
+            &ex,
+            "UPDATE foo SET val = 0 WHERE id = 2 OR id = 3",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "UPDATE foo SET val = 0", Vec::new()).await;
+        assert_results(
+            &ex,
+            "SELECT * FROM foo WHERE id >= 1",
+            vec![row(1, 0), row(2, 0), row(3, 0)],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn restarts_txns_with_stale_reconnaissance() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "INSERT INTO foo VALUES (1, 10)", Vec::new()).await;
+        assert_results(&ex, "INSERT INTO foo VALUES (2, 20)", Vec::new()).await;
+
+        let update = "UPDATE foo SET val = 0 WHERE val = 20";
+        let reconnaissance = ex.reconnoiter(update).await.unwrap();
+
+        // Record 1 starts to match after the reconnaissance, which did not lock it for writing
+        assert_results(&ex, "UPDATE foo SET val = 20 WHERE id = 1", Vec::new()).await;
+        assert!(matches!(
+            execute_with_reconnaissance(&ex, update, reconnaissance)
+                .await
+                .result,
+            Some(Restart(_))
+        ));
+        assert_results(&ex, "SELECT * FROM foo", vec![row(1, 20), row(2, 20)]).await;
+
+        assert_results(&ex, update, Vec::new()).await;
+        assert_results(&ex, "SELECT * FROM foo", vec![row(1, 0), row(2, 0)]).await;
+    }
+
+    #[tokio::test]
+    async fn runs_every_stmt_as_one_txn() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+
+        // Later statements see the writes of earlier ones
+        let result = match execute(
+            &ex,
+            "INSERT INTO foo VALUES (1, 100); INSERT INTO foo VALUES (2, 0); UPDATE foo SET val = 50 WHERE id = 1; SELECT * FROM foo WHERE id = 1",
+        )
+        .await
+        .result
+        {
+            Some(Success(result)) => result,
+            _ => panic!("Should always be successful"),
+        };
+        assert_eq!(
+            result
+                .result_sets
+                .iter()
+                .map(|result_set| result_set.rows.clone())
+                .collect::<Vec<_>>(),
+            vec![vec![], vec![], vec![], vec![row(1, 50)]]
+        );
+        assert_eq!(result.results, vec![row(1, 50)]);
+
+        // A failing statement discards the writes of every other statement
+        for query in [
+            "UPDATE foo SET val = 0 WHERE id = 1; UPDATE foo SET missing = 1 WHERE id = 2",
+            "UPDATE foo SET val = 0 WHERE id = 1; CREATE TABLE bar (id BIGINT PRIMARY KEY)",
+        ] {
+            assert!(
+                matches!(execute(&ex, query).await.result, Some(Failure(_))),
+                "{} should fail",
+                query
+            );
+        }
+
+        assert_results(
+            &ex,
+            "SELECT * FROM foo WHERE id = 1; SELECT * FROM foo WHERE id = 2",
+            vec![row(2, 0)],
+        )
+        .await;
+        assert_results(&ex, "SELECT * FROM foo WHERE id = 1", vec![row(1, 50)]).await;
+    }
+
+    #[tokio::test]
+    async fn aborts_txns() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "INSERT INTO foo VALUES (1, 100), (2, 0)", Vec::new()).await;
+
+        // An abort discards the writes of the statements before it and skips the ones after it
+        for (query, message) in [
+            (
+                "UPDATE foo SET val = 0 WHERE id = 1; ROLLBACK; UPDATE foo SET val = 0 WHERE id = 2",
+                "ROLLBACK",
+            ),
+            (
+                "UPDATE foo SET val = val - 150 WHERE id = 1; UPDATE foo SET val = val + 150 WHERE id = 2; ABORT IF (SELECT val FROM foo WHERE id = 1) < 0",
+                "ABORT IF (SELECT val FROM foo WHERE id = 1) < 0",
+            ),
+            (
+                "DELETE FROM foo WHERE id = 1; ASSERT EXISTS (SELECT * FROM foo WHERE val = 100) AS 'no rich row'",
+                "no rich row",
+            ),
+            (
+                "INSERT INTO foo VALUES (3, 0); ASSERT (SELECT val FROM foo WHERE id = 3) = 1",
+                "ASSERT (SELECT val FROM foo WHERE id = 3) = 1",
+            ),
+        ] {
+            match execute(&ex, query).await.result {
+                Some(Failure(err)) => {
+                    assert_eq!(err.error_code(), ErrorCode::Abort, "{}", query);
+                    assert_eq!(err.detailed_message, format!("txn aborted: {}", message));
+                }
+                _ => panic!("{} should abort", query),
+            }
+        }
+        assert_results(&ex, "SELECT * FROM foo", vec![row(1, 100), row(2, 0)]).await;
+
+        // A condition that holds, or is NULL, commits the txn
+        assert_results(
+            &ex,
+            "UPDATE foo SET val = val - 50 WHERE id = 1; UPDATE foo SET val = val + 50 WHERE id = 2; ABORT IF (SELECT val FROM foo WHERE id = 1) < 0; ABORT IF (SELECT val FROM foo WHERE id = 3) < 0",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "SELECT * FROM foo", vec![row(1, 50), row(2, 50)]).await;
+
+        // A subquery in a condition returns at most one value
+        match execute(&ex, "ASSERT (SELECT val FROM foo) > 0")
+            .await
+            .result
+        {
+            Some(Failure(err)) => assert_eq!(err.error_code(), ErrorCode::Error),
+            _ => panic!("a subquery with many rows should fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn orders_and_limits_rows() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "INSERT INTO foo VALUES (-2, 20), (1, NULL), (3, 10), (4, 20), (5, 5)",
+            Vec::new(),
+        )
+        .await;
+
+        let null_row = |id: i64| RecordStorage {
+            values: vec![id.into(), ColumnValue { value: None }],
+        };
+        for (query, expected_results) in [
+            (
+                "SELECT * FROM foo WHERE id BETWEEN -2 AND 3",
+                vec![row(-2, 20), null_row(1), row(3, 10)],
+            ),
+            (
+                "SELECT * FROM foo WHERE id > 3",
+                vec![row(4, 20), row(5, 5)],
+            ),
+            (
+                "SELECT * FROM foo ORDER BY id DESC LIMIT 2",
+                vec![row(5, 5), row(4, 20)],
+            ),
+            (
+                "SELECT * FROM foo ORDER BY val, id DESC",
+                vec![row(5, 5), row(3, 10), row(4, 20), row(-2, 20), null_row(1)],
+            ),
+            (
+                "SELECT * FROM foo ORDER BY val DESC LIMIT 2",
+                vec![null_row(1), row(-2, 20)],
+            ),
+            (
+                "SELECT * FROM foo ORDER BY val NULLS FIRST LIMIT 2 OFFSET 1",
+                vec![row(5, 5), row(3, 10)],
+            ),
+            ("SELECT * FROM foo WHERE id > 5 AND id < 10", vec![]),
+            ("SELECT * FROM foo LIMIT 0", vec![]),
+        ] {
+            match execute(&ex, query).await.result {
+                Some(Success(result)) => assert_eq!(result.results, expected_results, "{}", query),
+                _ => panic!("{} should succeed", query),
+            }
+        }
+
+        // Writes to a range only touch the rows in it
+        assert_results(&ex, "UPDATE foo SET val = 0 WHERE id >= 4", Vec::new()).await;
+        assert_results(&ex, "DELETE FROM foo WHERE id < 1", Vec::new()).await;
+        assert_results(
+            &ex,
+            "SELECT * FROM foo",
+            vec![null_row(1), row(3, 10), row(4, 0), row(5, 0)],
+        )
+        .await;
+
+        for query in [
+            "SELECT * FROM foo LIMIT -1",
+            "SELECT * FROM foo LIMIT 'a'",
+            "SELECT * FROM foo ORDER BY missing",
+        ] {
+            assert!(
+                matches!(execute(&ex, query).await.result, Some(Failure(_))),
+                "{} should fail",
+                query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn projects_columns() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, a TEXT NOT NULL, b DOUBLE)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "INSERT INTO foo (id, a) VALUES (1, 'one')", Vec::new()).await;
+
+        let result = match execute(&ex, "SELECT b, a AS c FROM foo WHERE id = 1")
+            .await
+            .result
+        {
+            Some(Success(result)) => result,
+            _ => panic!("Should always be successful"),
+        };
+        let mut b = ColumnMetadata {
+            name: "b".into(),
+            column_type: 0,
+            nullable: true,
+        };
+        b.set_column_type(ColumnType::Double);
+        let mut c = ColumnMetadata {
+            name: "c".into(),
+            column_type: 0,
+            nullable: false,
+        };
+        c.set_column_type(ColumnType::Text);
+        assert_eq!(result.columns, vec![b, c]);
+        assert_eq!(
+            result.results,
+            vec![RecordStorage {
+                values: vec![ColumnValue { value: None }, "one".into()]
+            }]
+        );
+
+        assert_results(
+            &ex,
+            "UPDATE foo SET b = 2.5, a = 'uno' WHERE id = 1",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT * FROM foo WHERE id = 1",
+            vec![RecordStorage {
+                values: vec![1i64.into(), "uno".into(), 2.5.into()],
+            }],
+        )
+        .await;
+
+        for query in [
+            "UPDATE foo SET a = NULL WHERE id = 1",
+            "SELECT missing FROM foo WHERE id = 1",
+            "INSERT INTO foo (id, b) VALUES (2, 1.5)",
+        ] {
+            assert!(
+                matches!(execute(&ex, query).await.result, Some(Failure(_))),
+                "{} should fail",
+                query
+            );
+        }
+
+        assert_results(&ex, "UPDATE foo SET b = NULL WHERE id = 1", Vec::new()).await;
+        assert_results(
+            &ex,
+            "SELECT b FROM foo WHERE id = 1",
+            vec![RecordStorage {
+                values: vec![ColumnValue { value: None }],
+            }],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn inserts_every_row_or_none() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "INSERT INTO foo VALUES (1, 10), (2, 20)", Vec::new()).await;
+        assert_results(&ex, "SELECT * FROM foo", vec![row(1, 10), row(2, 20)]).await;
+
+        // A taken primary key fails the statement, even if it is taken by an earlier row of it
+        for query in [
+            "INSERT INTO foo VALUES (3, 30), (1, 11)",
+            "INSERT INTO foo VALUES (3, 30), (3, 31)",
+        ] {
+            match execute(&ex, query).await.result {
+                Some(Failure(err)) => assert_eq!(
+                    err.error_code(),
+                    ErrorCode::Constraint,
+                    "{}",
+                    err.detailed_message
+                ),
+                _ => panic!("{} should fail", query),
+            }
+        }
+
+        assert_results(&ex, "SELECT * FROM foo", vec![row(1, 10), row(2, 20)]).await;
+    }
+
+    #[tokio::test]
+    async fn inserts_selected_rows() {
+        let ex = Executor::default();
+
+        for table in ["foo", "bar"] {
+            let create_table =
+                format!("CREATE TABLE {} (id BIGINT PRIMARY KEY, val BIGINT)", table);
+            assert_results(&ex, &create_table, Vec::new()).await;
+        }
+        assert_results(&ex, "INSERT INTO foo VALUES (1, 10)", Vec::new()).await;
+
+        // The inserted key is the selected key, or a value of the selected row
+        assert_results(
+            &ex,
+            "INSERT INTO bar SELECT * FROM foo WHERE id = 1",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "INSERT INTO bar (val, id) SELECT id, val FROM foo WHERE id = 1",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "SELECT * FROM bar", vec![row(1, 10), row(10, 1)]).await;
+
+        for query in [
+            "INSERT INTO bar SELECT * FROM foo WHERE id = 1",
+            "INSERT INTO bar SELECT * FROM foo WHERE val = 10",
+            "INSERT INTO bar SELECT val FROM foo WHERE id = 1",
+        ] {
+            assert!(
+                matches!(execute(&ex, query).await.result, Some(Failure(_))),
+                "{} should fail",
+                query
+            );
+        }
+
+        // Selecting a row that does not exist inserts nothing
+        assert_results(
+            &ex,
+            "INSERT INTO bar SELECT * FROM foo WHERE id = 2",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "SELECT * FROM bar", vec![row(1, 10), row(10, 1)]).await;
+    }
+
+    async fn rows_affected(ex: &Executor, query: &str) -> u64 {
+        match execute(ex, query).await.result {
+            Some(Success(result)) => result.rows_affected,
+            _ => panic!("{} should succeed", query),
+        }
+    }
+
+    #[tokio::test]
+    async fn deletes_rows() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_eq!(
+            rows_affected(&ex, "INSERT INTO foo VALUES (1, 10), (2, 20), (3, 30)").await,
+            3
+        );
+
+        assert_eq!(rows_affected(&ex, "DELETE FROM foo WHERE id = 1").await, 1);
+        assert_eq!(rows_affected(&ex, "DELETE FROM foo WHERE id = 1").await, 0);
+        assert_eq!(
+            rows_affected(&ex, "DELETE FROM foo WHERE val > 25").await,
+            1
+        );
+        assert_results(&ex, "SELECT * FROM foo", vec![row(2, 20)]).await;
+        assert_results(&ex, "SELECT * FROM foo WHERE id = 3", Vec::new()).await;
+
+        // Later statements of the txn do not see the deleted row, so its key can be reused
+        let result = match execute(
+            &ex,
+            "DELETE FROM foo; SELECT * FROM foo WHERE id = 2; INSERT INTO foo VALUES (2, 21); UPDATE foo SET val = 22 WHERE val = 21",
+        )
+        .await
+        .result
+        {
+            Some(Success(result)) => result,
+            _ => panic!("Should always be successful"),
+        };
+        assert_eq!(
+            result
+                .result_sets
+                .iter()
+                .map(|result_set| (result_set.rows.len(), result_set.rows_affected))
+                .collect::<Vec<_>>(),
+            vec![(0, 1), (0, 0), (0, 1), (0, 1)]
+        );
+        assert_results(&ex, "SELECT * FROM foo", vec![row(2, 22)]).await;
+    }
+
+    #[tokio::test]
+    async fn evaluates_assignments() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val INT, name TEXT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "INSERT INTO foo VALUES (1, 1, 'a'), (2, 2, NULL)",
+            Vec::new(),
+        )
+        .await;
+
+        // Every assignment sees the row as it was before the statement
+        assert_eq!(
+            rows_affected(
+                &ex,
+                "UPDATE foo SET val = val * 10 + id, name = CONCAT(name, val) WHERE id = 1"
+            )
+            .await,
+            1
+        );
+        assert_eq!(
+            rows_affected(
+                &ex,
+                "UPDATE foo SET name = COALESCE(UPPER(name), 'none') WHERE val % 2 = 0"
+            )
+            .await,
+            1
+        );
+        assert_results(
+            &ex,
+            "SELECT val, name FROM foo",
+            vec![
+                RecordStorage {
+                    values: vec![11i32.into(), "a1".into()],
+                },
+                RecordStorage {
+                    values: vec![2i32.into(), "none".into()],
+                },
+            ],
+        )
+        .await;
+
+        for query in [
+            "UPDATE foo SET val = val + 2147483647 WHERE id = 2",
+            "UPDATE foo SET val = val / 0 WHERE id = 1",
+            "UPDATE foo SET val = name WHERE id = 1",
+        ] {
+            assert!(
+                matches!(execute(&ex, query).await.result, Some(Failure(_))),
+                "{} should fail",
+                query
+            );
+        }
+    }
+
+    fn user(id: i64, email: &str) -> RecordStorage {
+        RecordStorage {
+            values: vec![id.into(), email.into()],
+        }
+    }
+
+    async fn error_code(ex: &Executor, query: &str) -> ErrorCode {
+        match execute(ex, query).await.result {
+            Some(Failure(err)) => err.error_code(),
+            _ => panic!("{} should fail", query),
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_indexes_in_line_with_rows() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE users (id BIGINT PRIMARY KEY, email TEXT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "INSERT INTO users VALUES (1, 'a@x'), (2, 'b@x'), (3, 'a@x')",
+            Vec::new(),
+        )
+        .await;
+
+        // Existing rows are indexed when the index is created
+        assert_results(&ex, "CREATE INDEX users_email ON users (email)", Vec::new()).await;
+        assert_results(
+            &ex,
+            "SELECT * FROM users WHERE email = 'a@x'",
+            vec![user(1, "a@x"), user(3, "a@x")],
+        )
+        .await;
+
+        assert_eq!(
+            rows_affected(&ex, "UPDATE users SET email = 'c@x' WHERE email = 'a@x'").await,
+            2
+        );
+        assert_eq!(
+            rows_affected(&ex, "DELETE FROM users WHERE 'b@x' = email").await,
+            1
+        );
+        assert_results(&ex, "INSERT INTO users VALUES (4, 'a@x')", Vec::new()).await;
+        assert_results(
+            &ex,
+            "SELECT * FROM users WHERE email = 'a@x'; SELECT * FROM users WHERE email = 'c@x'",
+            vec![user(1, "c@x"), user(3, "c@x")],
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT * FROM users WHERE email = 'a@x'",
+            vec![user(4, "a@x")],
+        )
+        .await;
+        assert_results(&ex, "SELECT * FROM users WHERE email = 'b@x'", Vec::new()).await;
+
+        // Dropping the index falls back to scanning the table
+        assert_results(&ex, "DROP INDEX users_email", Vec::new()).await;
+        assert_results(
+            &ex,
+            "SELECT * FROM users WHERE email = 'c@x'",
+            vec![user(1, "c@x"), user(3, "c@x")],
+        )
+        .await;
+        assert_eq!(
+            error_code(&ex, "DROP INDEX users_email").await,
+            ErrorCode::Notfound
+        );
+    }
+
+    #[tokio::test]
+    async fn aborts_txns_that_break_unique_indexes() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE users (id BIGINT PRIMARY KEY, email TEXT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "INSERT INTO users VALUES (1, 'a@x'), (2, 'a@x'), (3, NULL)",
+            Vec::new(),
+        )
+        .await;
+
+        // An index that would already hold a value twice is not created
+        assert_eq!(
+            error_code(&ex, "CREATE UNIQUE INDEX users_email ON users (email)").await,
+            ErrorCode::Constraint
+        );
+        assert_results(
+            &ex,
+            "UPDATE users SET email = 'b@x' WHERE id = 2",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "CREATE UNIQUE INDEX users_email ON users (email)",
+            Vec::new(),
+        )
+        .await;
+
+        for query in [
+            "INSERT INTO users VALUES (4, 'a@x')",
+            "INSERT INTO users VALUES (4, 'c@x'), (5, 'c@x')",
+            "UPDATE users SET email = 'b@x' WHERE id = 1",
+            "UPDATE users SET email = 'a@x' WHERE id = 3",
+        ] {
+            assert_eq!(
+                error_code(&ex, query).await,
+                ErrorCode::Constraint,
+                "{}",
+                query
+            );
+        }
+
+        // Values can move between rows within a txn, and NULLs are never duplicates
+        assert_results(
+            &ex,
+            "UPDATE users SET email = NULL WHERE id = 1; UPDATE users SET email = 'a@x' WHERE id = 2; INSERT INTO users VALUES (4, NULL)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT * FROM users WHERE email = 'a@x'",
+            vec![user(2, "a@x")],
+        )
+        .await;
+
+        // Computed values cannot be checked ahead of time
+        assert_eq!(
+            error_code(&ex, "UPDATE users SET email = UPPER(email) WHERE id = 2").await,
+            ErrorCode::Error
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_index_lookups_with_stale_reconnaissance() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE users (id BIGINT PRIMARY KEY, email TEXT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "CREATE INDEX users_email ON users (email)", Vec::new()).await;
+        assert_results(
+            &ex,
+            "INSERT INTO users VALUES (1, 'a@x'), (2, 'b@x')",
+            Vec::new(),
+        )
+        .await;
+
+        let delete = "DELETE FROM users WHERE email = 'a@x'";
+        let reconnaissance = ex.reconnoiter(delete).await.unwrap();
+
+        // Record 2 starts to match after the reconnaissance, which did not lock it
+        assert_results(
+            &ex,
+            "UPDATE users SET email = 'a@x' WHERE id = 2",
+            Vec::new(),
+        )
+        .await;
+        assert!(matches!(
+            execute_with_reconnaissance(&ex, delete, reconnaissance)
+                .await
+                .result,
+            Some(Restart(_))
+        ));
+
+        assert_eq!(rows_affected(&ex, delete).await, 2);
+        assert_results(&ex, "SELECT * FROM users", Vec::new()).await;
+    }
+
+    #[tokio::test]
+    async fn aggregates_rows() {
+        let ex = Executor::default();
+
+        assert_results(
+            &ex,
+            "CREATE TABLE orders (id BIGINT PRIMARY KEY, customer TEXT, amount INT, discount DOUBLE)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "INSERT INTO orders VALUES (1, 'b', 10, 0.5), (2, 'a', 20, NULL), (3, 'b', 30, 1.5), (4, NULL, 5, NULL)",
+            Vec::new(),
+        )
+        .await;
+        let null = || ColumnValue { value: None };
+        let values = |values: Vec<ColumnValue>| RecordStorage { values };
+
+        assert_results(
+            &ex,
+            "SELECT COUNT(*), COUNT(customer), COUNT(DISTINCT customer), SUM(amount), MIN(customer), MAX(amount), AVG(discount) FROM orders",
+            vec![values(vec![
+                4i64.into(),
+                3i64.into(),
+                2i64.into(),
+                65i64.into(),
+                "a".into(),
+                30i32.into(),
+                1.0f64.into(),
+            ])],
+        )
+        .await;
+
+        // Groups come in the order of their first row, unless they are ordered otherwise
+        assert_results(
+            &ex,
+            "SELECT customer, COUNT(*), SUM(amount * 2) FROM orders GROUP BY customer",
+            vec![
+                values(vec!["b".into(), 2i64.into(), 80i64.into()]),
+                values(vec!["a".into(), 1i64.into(), 40i64.into()]),
+                values(vec![null(), 1i64.into(), 10i64.into()]),
+            ],
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT customer, SUM(amount) AS total FROM orders WHERE id > 1 GROUP BY customer HAVING COUNT(*) < 2 ORDER BY total DESC LIMIT 1",
+            vec![values(vec!["b".into(), 30i64.into()])],
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT orders.customer FROM orders GROUP BY customer HAVING MAX(discount) > 1",
+            vec![values(vec!["b".into()])],
+        )
+        .await;
+
+        // Aggregates over no rows are NULL, except COUNT
+        assert_results(
+            &ex,
+            "SELECT COUNT(*), SUM(amount), MAX(customer) FROM orders WHERE id = 5",
+            vec![values(vec![0i64.into(), null(), null()])],
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT customer FROM orders WHERE id = 5 GROUP BY customer",
+            Vec::new(),
+        )
+        .await;
+
+        // The result columns are typed after the aggregates
+        match execute(
+            &ex,
+            "SELECT COUNT(*) AS n, SUM(discount), MIN(amount) FROM orders",
+        )
+        .await
+        .result
+        {
+            Some(Success(result)) => assert_eq!(
+                result.result_sets[0]
+                    .columns
+                    .iter()
+                    .map(|column| (column.name.clone(), column.column_type()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    ("n".to_string(), ColumnType::Bigint),
+                    ("SUM(discount)".to_string(), ColumnType::Double),
+                    ("MIN(amount)".to_string(), ColumnType::Integer),
+                ]
+            ),
+            _ => panic!("Should always be successful"),
+        }
+
+        for query in [
+            "SELECT customer, COUNT(*) FROM orders",
+            "SELECT * FROM orders GROUP BY customer",
+            "SELECT customer FROM orders GROUP BY customer ORDER BY amount",
+            "SELECT SUM(customer) FROM orders",
+            "SELECT SUM(MAX(amount)) FROM orders",
+            "SELECT * FROM orders WHERE COUNT(*) > 1",
+            "UPDATE orders SET amount = MAX(amount) WHERE id = 1",
+        ] {
+            assert_ne!(
+                error_code(&ex, query).await,
+                ErrorCode::Unspecified,
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn joins_rows() {
+        let ex = Executor::default();
+
+        for query in [
+            "CREATE TABLE customers (id BIGINT PRIMARY KEY, name TEXT)",
+            "CREATE TABLE orders (id BIGINT PRIMARY KEY, customer_id BIGINT, amount INT)",
+            "INSERT INTO customers VALUES (1, 'ann'), (2, 'bob'), (3, 'cy')",
+            "INSERT INTO orders VALUES (5, 1, 10), (6, 2, 20), (7, 1, 30), (8, NULL, 40), (9, 4, 50)",
+        ] {
+            assert_results(&ex, query, Vec::new()).await;
+        }
+        let null = || ColumnValue { value: None };
+        let values = |values: Vec<ColumnValue>| RecordStorage { values };
+
+        assert_results(
+            &ex,
+            "SELECT orders.id, customers.name FROM orders JOIN customers ON orders.customer_id = customers.id WHERE orders.id = 5",
+            vec![values(vec![5i64.into(), "ann".into()])],
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT * FROM orders JOIN customers ON customers.id = orders.customer_id WHERE orders.id = 6",
+            vec![values(vec![
+                6i64.into(),
+                2i64.into(),
+                20i32.into(),
+                2i64.into(),
+                "bob".into(),
+            ])],
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT c.* FROM orders AS o JOIN customers AS c ON o.customer_id = c.id WHERE o.id = 6",
+            vec![values(vec![2i64.into(), "bob".into()])],
+        )
+        .await;
+
+        // Rows come in primary key order of the table the query selects from
+        assert_results(
+            &ex,
+            "SELECT o.id, c.name FROM orders o JOIN customers c ON o.customer_id = c.id",
+            vec![
+                values(vec![5i64.into(), "ann".into()]),
+                values(vec![6i64.into(), "bob".into()]),
+                values(vec![7i64.into(), "ann".into()]),
+            ],
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT o.id, c.name FROM orders o LEFT JOIN customers c ON o.customer_id = c.id WHERE o.id > 6",
+            vec![
+                values(vec![7i64.into(), "ann".into()]),
+                values(vec![8i64.into(), null()]),
+                values(vec![9i64.into(), null()]),
+            ],
+        )
+        .await;
+
+        // A join that does not look up the primary key compares every pair of rows
+        assert_results(
+            &ex,
+            "SELECT c.name, o.amount FROM customers c JOIN orders o ON o.customer_id = c.id AND o.amount > 15",
+            vec![
+                values(vec!["ann".into(), 30i32.into()]),
+                values(vec!["bob".into(), 20i32.into()]),
+            ],
+        )
+        .await;
+        assert_results(
+            &ex,
+            "SELECT c.name, SUM(o.amount) AS total FROM orders o JOIN customers c ON o.customer_id = c.id GROUP BY c.name ORDER BY total",
+            vec![
+                values(vec!["bob".into(), 20i64.into()]),
+                values(vec!["ann".into(), 40i64.into()]),
+            ],
+        )
+        .await;
+
+        for query in [
+            "SELECT id FROM orders JOIN customers ON orders.customer_id = customers.id",
+            "SELECT * FROM orders RIGHT JOIN customers ON orders.customer_id = customers.id",
+            "SELECT * FROM orders, customers",
+            "SELECT * FROM orders JOIN nope ON orders.customer_id = nope.id",
+        ] {
+            assert_ne!(
+                error_code(&ex, query).await,
+                ErrorCode::Unspecified,
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_joins_with_stale_reconnaissance() {
+        let ex = Executor::default();
+
+        for query in [
+            "CREATE TABLE customers (id BIGINT PRIMARY KEY, name TEXT)",
+            "CREATE TABLE orders (id BIGINT PRIMARY KEY, customer_id BIGINT)",
+            "INSERT INTO customers VALUES (1, 'ann'), (2, 'bob')",
+            "INSERT INTO orders VALUES (5, 1)",
+        ] {
+            assert_results(&ex, query, Vec::new()).await;
+        }
+
+        let select = "SELECT customers.name FROM orders JOIN customers ON orders.customer_id = customers.id WHERE orders.id = 5";
+        let reconnaissance = ex.reconnoiter(select).await.unwrap();
+
+        // The order points to another customer after the reconnaissance, which did not lock it
+        assert_results(
+            &ex,
+            "UPDATE orders SET customer_id = 2 WHERE id = 5",
+            Vec::new(),
+        )
+        .await;
+        assert!(matches!(
+            execute_with_reconnaissance(&ex, select, reconnaissance)
+                .await
+                .result,
+            Some(Restart(_))
+        ));
+
+        assert_results(
+            &ex,
+            select,
+            vec![RecordStorage {
+                values: vec!["bob".into()],
+            }],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn runs_procedures() {
+        let ex = Executor::default();
+
+        for query in [
+            "CREATE TABLE accounts (id BIGINT PRIMARY KEY, balance BIGINT, payee BIGINT)",
+            "INSERT INTO accounts VALUES (1, 100, 2), (2, 50, 3), (3, 0, NULL)",
+            "CREATE PROCEDURE transfer(src, dst, amount) READS accounts(src) WRITES accounts(src), accounts(dst) AS '
+                local from, to = db.get(\"accounts\", src), db.get(\"accounts\", dst)
+                if from.balance < amount then error(\"insufficient funds\") end
+                from.balance = from.balance - amount
+                to.balance = to.balance + amount
+                db.put(\"accounts\", from)
+                db.put(\"accounts\", to)
+                return from.balance'",
+            // Declares no footprint, so its calls are reconnoitered
+            "CREATE PROCEDURE pay_next(id, amount) AS '
+                local account = db.get(\"accounts\", id)
+                local next = db.get(\"accounts\", account.payee)
+                next.balance = next.balance + amount
+                db.put(\"accounts\", next)'",
+        ] {
+            assert_results(&ex, query, Vec::new()).await;
+        }
+
+        assert_results(
+            &ex,
+            "CALL transfer(1, 2, 30)",
+            vec![RecordStorage {
+                values: vec![70i64.into()],
+            }],
+        )
+        .await;
+        assert_eq!(rows_affected(&ex, "CALL pay_next(2, 5)").await, 1);
+        assert_results(
+            &ex,
+            "SELECT id, balance FROM accounts",
+            vec![row(1, 70), row(2, 80), row(3, 5)],
+        )
+        .await;
+
+        // A failed call writes nothing
+        for (query, expected_code) in [
+            ("CALL transfer(1, 2, 1000)", ErrorCode::Abort),
+            ("CALL transfer(1, 2)", ErrorCode::Mismatch),
+            ("CALL missing(1)", ErrorCode::Notfound),
+            ("CALL pay_next(3, 5)", ErrorCode::Abort),
+        ] {
+            assert_eq!(error_code(&ex, query).await, expected_code, "{}", query);
+        }
+        assert_results(
+            &ex,
+            "SELECT id, balance FROM accounts",
+            vec![row(1, 70), row(2, 80), row(3, 5)],
+        )
+        .await;
+
+        // A procedure only touches the records it declares
+        assert_results(
+            &ex,
+            "CREATE OR REPLACE PROCEDURE transfer(src, dst, amount) READS accounts(src) WRITES accounts(src) AS '
+                local from = db.get(\"accounts\", src)
+                db.put(\"accounts\", {id = dst, balance = amount})'",
+            Vec::new(),
+        )
+        .await;
+        assert_eq!(
+            error_code(&ex, "CALL transfer(1, 2, 30)").await,
+            ErrorCode::Misuse
+        );
+
+        assert_results(&ex, "DROP PROCEDURE transfer", Vec::new()).await;
+        assert_eq!(
+            error_code(&ex, "CALL transfer(1, 2, 30)").await,
+            ErrorCode::Notfound
+        );
+        assert_results(&ex, "DROP PROCEDURE IF EXISTS transfer", Vec::new()).await;
+    }
+
+    #[tokio::test]
+    async fn blocks_nondeterministic_apis() {
+        let ex = Executor::default();
+
+        assert_eq!(
+            error_code(&ex, "CREATE PROCEDURE broken() AS 'return ('").await,
+            ErrorCode::Error
+        );
+
+        for (script, expected) in [
+            ("return os == nil and io == nil and require == nil", true.into()),
+            ("return math.random == nil and collectgarbage == nil", true.into()),
+            ("return load == nil and next == nil", true.into()),
+            ("return tostring({}) .. string.format(\"%s\", print)", "tablenil".into()),
+            (
+                "local keys = {} for k in pairs({c = 1, a = 2, [2] = 3, b = 4}) do keys[#keys + 1] = k end return table.concat(keys, \",\")",
+                "2,a,b,c".into(),
+            ),
+        ] {
+            let create = format!("CREATE OR REPLACE PROCEDURE probe() AS '{}'", script);
+            assert_results(&ex, &create, Vec::new()).await;
+            assert_results(
+                &ex,
+                "CALL probe()",
+                vec![RecordStorage {
+                    values: vec![expected],
+                }],
+            )
+            .await;
+        }
+
+        for script in [
+            "return os.time()",
+            "return string.format(\"%p\", {})",
+            "for k in pairs({[{}] = 1}) do end",
+            "while true do end",
+        ] {
+            let create = format!("CREATE OR REPLACE PROCEDURE probe() AS '{}'", script);
+            assert_results(&ex, &create, Vec::new()).await;
+            assert_eq!(
+                error_code(&ex, "CALL probe()").await,
+                ErrorCode::Abort,
+                "{}",
+                script
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_procedures_with_stale_reconnaissance() {
+        let ex = Executor::default();
+
+        for query in [
+            "CREATE TABLE accounts (id BIGINT PRIMARY KEY, balance BIGINT, payee BIGINT)",
+            "INSERT INTO accounts VALUES (1, 100, 2), (2, 50, NULL), (3, 0, NULL)",
+            "CREATE PROCEDURE pay_next(id, amount) AS '
+                local next = db.get(\"accounts\", db.get(\"accounts\", id).payee)
+                next.balance = next.balance + amount
+                db.put(\"accounts\", next)'",
+        ] {
+            assert_results(&ex, query, Vec::new()).await;
+        }
+
+        let call = "CALL pay_next(1, 10)";
+        let reconnaissance = ex.reconnoiter(call).await.unwrap();
+
+        // The account points to another account after the reconnaissance, which did not lock it
+        assert_results(
+            &ex,
+            "UPDATE accounts SET payee = 3 WHERE id = 1",
+            Vec::new(),
+        )
+        .await;
+        assert!(matches!(
+            execute_with_reconnaissance(&ex, call, reconnaissance)
+                .await
+                .result,
+            Some(Restart(_))
+        ));
+
+        assert_results(&ex, call, Vec::new()).await;
+        assert_results(
+            &ex,
+            "SELECT id, balance FROM accounts",
+            vec![row(1, 100), row(2, 50), row(3, 10)],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn computes_functions_from_the_stamp() {
+        let stamp = TxnStamp {
+            timestamp_micros: 951_782_400_000_000,
+            seed: 42,
+        };
+
+        // Replicas that run the same stamped txns hold the same rows
+        let mut replica_rows = Vec::new();
+        for ex in [Executor::default(), Executor::default()] {
+            for query in [
+                "CREATE TABLE events (id BIGINT PRIMARY KEY, token TEXT, at TEXT, weight DOUBLE)",
+                "INSERT INTO events VALUES (1, GEN_RANDOM_UUID(), NOW(), RANDOM()), \
+                 (2, GEN_RANDOM_UUID(), NOW(), RANDOM())",
+                "UPDATE events SET weight = RANDOM() * 10 WHERE weight < 2",
+            ] {
+                match execute_stamped(&ex, query, &stamp).await.result {
+                    Some(Success(_)) => {}
+                    result => panic!("{}: {:?}", query, result),
+                }
+            }
+            match execute(&ex, "SELECT * FROM events").await.result {
+                Some(Success(result)) => replica_rows.push(result.results),
+                result => panic!("{:?}", result),
+            }
+        }
+        assert_eq!(replica_rows[0], replica_rows[1]);
+
+        let rows = &replica_rows[0];
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            assert_eq!(row.values[2], "2000-02-29 00:00:00.000000+00".into());
+        }
+        assert_ne!(rows[0].values[1], rows[1].values[1]);
+        assert_ne!(rows[0].values[3], rows[1].values[3]);
+    }
+
+    #[tokio::test]
+    async fn draws_another_random_value_for_every_call() {
+        let ex = Executor::default();
+        assert_results(
+            &ex,
+            "CREATE TABLE draws (id BIGINT PRIMARY KEY, a DOUBLE, b DOUBLE, c TEXT, d TEXT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(
+            &ex,
+            "INSERT INTO draws VALUES (1, RANDOM(), RANDOM(), GEN_RANDOM_UUID(), GEN_RANDOM_UUID())",
+            Vec::new(),
+        )
+        .await;
+
+        match execute(&ex, "SELECT * FROM draws").await.result {
+            Some(Success(result)) => {
+                let values = &result.results[0].values;
+                assert_ne!(values[1], values[2]);
+                assert_ne!(values[3], values[4]);
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_computed_keys() {
+        let ex = Executor::default();
+
+        for query in [
+            "CREATE TABLE users (id BIGINT PRIMARY KEY, email TEXT, created TEXT)",
+            "CREATE UNIQUE INDEX users_email ON users (email)",
+        ] {
+            assert_results(&ex, query, Vec::new()).await;
+        }
+
+        assert_eq!(
+            error_code(
+                &ex,
+                "INSERT INTO users VALUES (ABS(-1), 'a@example.com', NOW())"
+            )
+            .await,
+            ErrorCode::Error
+        );
+        assert_eq!(
+            error_code(
+                &ex,
+                "INSERT INTO users VALUES (1, GEN_RANDOM_UUID(), NOW())"
+            )
+            .await,
+            ErrorCode::Error
+        );
+        assert_results(
+            &ex,
+            "INSERT INTO users VALUES (1, 'a@example.com', NOW())",
+            Vec::new(),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reopens_its_data_dir() {
+        for engine in [EngineKind::Sled, EngineKind::Lsm] {
+            let data_dir = tempfile::tempdir().unwrap();
+            let config = StorageConfig {
+                engine,
+                data_dir: Some(data_dir.path().to_path_buf()),
+            };
+
+            {
+                let ex = Executor::open(&config).unwrap();
+                assert_eq!(ex.last_applied_lsn("a").unwrap(), 0);
+                assert_results(
+                    &ex,
+                    "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+                    Vec::new(),
+                )
+                .await;
+                assert_results(&ex, "INSERT INTO foo VALUES (1, 2)", Vec::new()).await;
+                ex.apply_batch("a", 2, &[]).unwrap();
+            }
+
+            let ex = Executor::open(&config).unwrap();
+            assert_eq!(ex.last_applied_lsn("a").unwrap(), 2);
+            assert_results(&ex, "SELECT * FROM foo WHERE id = 1", vec![row(1, 2)]).await;
+        }
+    }
+
+    #[test]
+    fn reopened_partition_keeps_its_partition_map() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            engine: EngineKind::Sled,
+            data_dir: Some(data_dir.path().to_path_buf()),
+        };
+        let me = Peer {
+            id: Uuid::new_v4(),
+            address: "http://127.0.0.1:1".into(),
+        };
+        let partition_map = PartitionMap::new(vec![me.clone()]).unwrap();
+        let partition = || {
+            Partition::new(
+                PeerManager::new(me.clone(), partition_map.clone()),
+                PartitionInbox::default(),
+            )
+        };
+
+        // A reconfiguration stores the new map along with the records it moves
+        let reconfigured_map = partition_map
+            .with_peer(Peer {
+                id: Uuid::new_v4(),
+                address: "http://127.0.0.1:2".into(),
+            })
+            .unwrap();
+        {
+            let storage = config.open().unwrap();
+            let mut batch = WriteBatch::default();
+            Placement::set_partition_map(&reconfigured_map, &mut batch);
+            storage.apply(batch).unwrap();
+        }
+
+        let partition = partition();
+        Executor::new_partitioned(&config, partition.clone()).unwrap();
+        assert_eq!(partition.partition_map(), reconfigured_map);
+    }
+
+    #[tokio::test]
+    async fn applies_each_txn_once() {
+        let ex = Executor::default();
+        assert_results(
+            &ex,
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+        assert_results(&ex, "INSERT INTO foo VALUES (1, 2)", Vec::new()).await;
+
+        let req = RunStmtRequestWithUuid {
+            query: "UPDATE foo SET val = val + 1 WHERE id = 1".into(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            repartition: None,
+            reconnaissance: None,
+            stamp: None,
+        };
+        ex.execute(req.clone()).await.unwrap();
+        ex.execute(req.clone()).await.unwrap();
+        assert_results(&ex, "SELECT * FROM foo WHERE id = 1", vec![row(1, 3)]).await;
+
+        // Once its batch is applied in full, the txn is never replayed again
+        ex.apply_batch("a", 3, &[req.uuid]).unwrap();
+        assert_eq!(ex.last_applied_lsn("a").unwrap(), 3);
     }
 }
@@ -0,0 +1,767 @@
+use crate::calvinite_tonic::partition_grpc_service_client::PartitionGrpcServiceClient;
+use crate::calvinite_tonic::partition_grpc_service_server::PartitionGrpcService;
+use crate::calvinite_tonic::{
+    ForwardReadsRequest, ForwardReadsResponse, Reconnaissance, ReconnoiterRequest, RecordStorage,
+    RemoteRead, TransferRecordsRequest, TransferRecordsResponse, TransferredRecord,
+};
+use crate::catalog::{Catalog, TableId, TableSchema};
+use crate::common::{Record, VirtualNodeType};
+use crate::executor::partition_map::PartitionMap;
+use crate::executor::peer::{Peer, PeerManager};
+use crate::executor::placement::Placement;
+use crate::executor::reconnaissance;
+use crate::index::IndexStore;
+use crate::storage::{Keyspace, Storage, WriteBatch};
+use anyhow::anyhow;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Response;
+use uuid::Uuid;
+
+const TRANSFER_CHUNK_SIZE: usize = 256;
+// How long a partition waits before it sends to a peer it could not reach again
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+// How many finished txns an inbox remembers, to drop the messages that arrive after them
+const FINISHED_TXNS_CAPACITY: usize = 4096;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum InboxErr {
+    #[error("the messages for txn {0} are already being waited for")]
+    AlreadyWaiting(Uuid),
+    #[error("txn {0} finished before its messages arrived")]
+    Finished(Uuid),
+}
+
+#[derive(Debug)]
+struct PendingMessages<T> {
+    messages_by_peer: HashMap<Uuid, Vec<T>>,
+    waiter: Option<(HashSet<Uuid>, oneshot::Sender<()>)>,
+}
+
+impl<T> Default for PendingMessages<T> {
+    fn default() -> Self {
+        Self {
+            messages_by_peer: HashMap::new(),
+            waiter: None,
+        }
+    }
+}
+
+impl<T> PendingMessages<T> {
+    fn has_messages_from(&self, peers: &HashSet<Uuid>) -> bool {
+        peers
+            .iter()
+            .all(|peer| self.messages_by_peer.contains_key(peer))
+    }
+}
+
+#[derive(Debug)]
+struct InboxState<T> {
+    pending_by_txn: HashMap<Uuid, PendingMessages<T>>,
+    // The most recently finished txns, oldest first
+    finished_txns: VecDeque<Uuid>,
+    finished_txn_set: HashSet<Uuid>,
+}
+
+/// Messages that other partitions sent for a txn, one batch per peer. Messages can arrive before
+/// this partition has even started the txn, so they are buffered until the txn finishes.
+#[derive(Debug)]
+struct Inbox<T> {
+    inner: Arc<Mutex<InboxState<T>>>,
+}
+
+impl<T> Clone for Inbox<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for Inbox<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(InboxState {
+                pending_by_txn: HashMap::new(),
+                finished_txns: VecDeque::new(),
+                finished_txn_set: HashSet::new(),
+            })),
+        }
+    }
+}
+
+impl<T> Inbox<T> {
+    fn deliver(&self, txn_uuid: Uuid, from_peer: Uuid, messages: Vec<T>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.finished_txn_set.contains(&txn_uuid) {
+            return;
+        }
+        let pending_messages = inner.pending_by_txn.entry(txn_uuid).or_default();
+
+        pending_messages
+            .messages_by_peer
+            .insert(from_peer, messages);
+
+        let waiter_is_ready = match &pending_messages.waiter {
+            Some((from_peers, _)) => pending_messages.has_messages_from(from_peers),
+            None => false,
+        };
+
+        if waiter_is_ready {
+            let (_, waiter) = pending_messages.waiter.take().unwrap();
+            let _ = waiter.send(());
+        }
+    }
+
+    /// Waits until every peer in `from_peers` has sent its messages for the txn and returns them.
+    /// There is no timeout: the outcome of the txn depends on the messages, and every partition
+    /// has to reach the same outcome, so a partition never gives up on them on its own.
+    async fn wait_for(
+        &self,
+        txn_uuid: Uuid,
+        from_peers: HashSet<Uuid>,
+    ) -> Result<Vec<T>, InboxErr> {
+        if from_peers.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let receiver = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.finished_txn_set.contains(&txn_uuid) {
+                return Err(InboxErr::Finished(txn_uuid));
+            }
+            let pending_messages = inner.pending_by_txn.entry(txn_uuid).or_default();
+
+            if pending_messages.has_messages_from(&from_peers) {
+                None
+            } else if pending_messages.waiter.is_some() {
+                return Err(InboxErr::AlreadyWaiting(txn_uuid));
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                pending_messages.waiter = Some((from_peers.clone(), sender));
+                Some(receiver)
+            }
+        };
+
+        // The sender is dropped if the txn was finished while it waited
+        if let Some(receiver) = receiver {
+            receiver.await.map_err(|_| InboxErr::Finished(txn_uuid))?;
+        }
+
+        let pending_messages = self
+            .inner
+            .lock()
+            .unwrap()
+            .pending_by_txn
+            .remove(&txn_uuid)
+            .ok_or(InboxErr::Finished(txn_uuid))?;
+
+        Ok(pending_messages
+            .messages_by_peer
+            .into_values()
+            .flatten()
+            .collect())
+    }
+
+    /// Runs `f` unless the txn has finished. The txn cannot finish until `f` returns.
+    fn unless_finished<R>(&self, txn_uuid: Uuid, f: impl FnOnce() -> R) -> Option<R> {
+        let inner = self.inner.lock().unwrap();
+        match inner.finished_txn_set.contains(&txn_uuid) {
+            true => None,
+            false => Some(f()),
+        }
+    }
+
+    /// Drops every message of a txn that finished, committed or not, along with any that arrive
+    /// later. Peers send their messages to every partition, whether it ends up waiting for them
+    /// or not.
+    fn finish(&self, txn_uuid: Uuid) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending_by_txn.remove(&txn_uuid);
+
+        if inner.finished_txn_set.insert(txn_uuid) {
+            inner.finished_txns.push_back(txn_uuid);
+        }
+        if inner.finished_txns.len() > FINISHED_TXNS_CAPACITY {
+            if let Some(oldest) = inner.finished_txns.pop_front() {
+                inner.finished_txn_set.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Receives what other partitions send to this one: the records they read for a txn, the
+/// records they hand over during a reconfiguration, and reconnaissance queries.
+#[derive(Debug, Clone, Default)]
+pub struct PartitionInbox {
+    reads: Inbox<RemoteRead>,
+    // Peers that finished handing over their records for a txn
+    transfers: Inbox<()>,
+    // Set once the executor of this partition has opened its storage
+    storage: Arc<OnceLock<(Storage, IndexStore, Catalog)>>,
+}
+
+#[tonic::async_trait]
+impl PartitionGrpcService for PartitionInbox {
+    async fn forward_reads(
+        &self,
+        request: tonic::Request<ForwardReadsRequest>,
+    ) -> Result<tonic::Response<ForwardReadsResponse>, tonic::Status> {
+        let req = request.into_inner();
+
+        let txn_uuid = Uuid::parse_str(&req.txn_uuid)
+            .map_err(|err| tonic::Status::invalid_argument(err.to_string()))?;
+        let from_peer = Uuid::parse_str(&req.from_peer)
+            .map_err(|err| tonic::Status::invalid_argument(err.to_string()))?;
+
+        self.reads.deliver(txn_uuid, from_peer, req.reads);
+
+        Ok(Response::new(ForwardReadsResponse {}))
+    }
+
+    async fn transfer_records(
+        &self,
+        request: tonic::Request<tonic::Streaming<TransferRecordsRequest>>,
+    ) -> Result<tonic::Response<TransferRecordsResponse>, tonic::Status> {
+        let (storage, _, catalog) = self
+            .storage
+            .get()
+            .ok_or_else(|| tonic::Status::unavailable("the partition has no storage yet"))?;
+
+        let mut stream = request.into_inner();
+        while let Some(req) = stream.message().await? {
+            let txn_uuid = Uuid::parse_str(&req.txn_uuid)
+                .map_err(|err| tonic::Status::invalid_argument(err.to_string()))?;
+            let from_peer = Uuid::parse_str(&req.from_peer)
+                .map_err(|err| tonic::Status::invalid_argument(err.to_string()))?;
+
+            // Each chunk is stored as it arrives. A peer sends its chunks again if the transfer
+            // failed part way, but chunks that arrive after the reconfiguration finished are
+            // dropped, since later txns may have changed their records since.
+            self.transfers
+                .unless_finished(txn_uuid, || {
+                    store_transferred_records(storage, catalog, req.records)
+                })
+                .transpose()
+                .map_err(|err| tonic::Status::internal(err.to_string()))?;
+
+            if req.last {
+                self.transfers.deliver(txn_uuid, from_peer, Vec::new());
+            }
+        }
+
+        Ok(Response::new(TransferRecordsResponse {}))
+    }
+
+    async fn reconnoiter(
+        &self,
+        request: tonic::Request<ReconnoiterRequest>,
+    ) -> Result<tonic::Response<Reconnaissance>, tonic::Status> {
+        let (storage, index_store, catalog) = self
+            .storage
+            .get()
+            .ok_or_else(|| tonic::Status::unavailable("the partition has no storage yet"))?;
+
+        let reconnaissance =
+            reconnaissance::reconnoiter(storage, index_store, catalog, &request.into_inner().query)
+                .map_err(|err| tonic::Status::invalid_argument(err.to_string()))?;
+
+        Ok(Response::new(reconnaissance))
+    }
+}
+
+/// This partition's view of the cluster. Every partition evaluates every txn so that whichever
+/// partition accepted the request can answer it, but only the owner of a record reads, locks
+/// and writes it. Owners forward what they read to every other partition.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    peer_manager: PeerManager,
+    inbox: PartitionInbox,
+    peer_channels: Arc<Mutex<HashMap<Uuid, Channel>>>,
+}
+
+impl Partition {
+    /// `inbox` must be served as this partition's `PartitionGrpcService`.
+    pub fn new(peer_manager: PeerManager, inbox: PartitionInbox) -> Self {
+        Self {
+            peer_manager,
+            inbox,
+            peer_channels: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_local(&self, record: &Record) -> bool {
+        self.peer_manager.is_local(record)
+    }
+
+    pub fn is_member(&self) -> bool {
+        self.peer_manager.is_member()
+    }
+
+    pub fn partition_map(&self) -> PartitionMap {
+        self.peer_manager.partition_map()
+    }
+
+    /// Lets other partitions reconnoiter the records this partition stores.
+    pub fn attach_storage(&self, storage: Storage, index_store: IndexStore, catalog: Catalog) {
+        let _ = self.inbox.storage.set((storage, index_store, catalog));
+    }
+
+    /// Drops whatever other partitions sent for a txn once it finished on this partition.
+    pub fn finish_txn(&self, txn_uuid: Uuid) {
+        self.inbox.reads.finish(txn_uuid);
+        self.inbox.transfers.finish(txn_uuid);
+    }
+
+    /// Switches to the partition map of the last reconfiguration stored in `storage`, if it is
+    /// newer than the one this partition started with. Drops the records of every virtual node
+    /// this partition does not own, which a crash during a reconfiguration leaves behind.
+    pub fn restore_partition_map(&self, storage: &Storage) -> anyhow::Result<()> {
+        if let Some(partition_map) = Placement::open(storage).partition_map()? {
+            if partition_map.version() > self.peer_manager.partition_map().version() {
+                self.peer_manager.set_partition_map(partition_map);
+            }
+        }
+
+        let partition_map = self.peer_manager.partition_map();
+        let schemas = schemas_by_id(&Catalog::open(storage))?;
+        for virtual_node in 0..=VirtualNodeType::MAX {
+            if partition_map.peer_for_virtual_node(virtual_node).id != self.peer_manager.me.id {
+                drop_records(storage, &schemas, virtual_node)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn client_for(&self, peer: &Peer) -> PartitionGrpcServiceClient<Channel> {
+        let channel = self
+            .peer_channels
+            .lock()
+            .unwrap()
+            .entry(peer.id)
+            .or_insert_with(|| {
+                Endpoint::from_shared(peer.address.clone())
+                    .unwrap()
+                    .connect_lazy()
+            })
+            .clone();
+
+        PartitionGrpcServiceClient::new(channel)
+    }
+
+    /// Sends the records this partition read for a txn to every other partition. Every partition
+    /// waits for the reads of a txn that scans key ranges, so they are sent even if there are none.
+    pub async fn forward_reads(
+        &self,
+        txn_uuid: Uuid,
+        local_reads: &[(Record, Option<RecordStorage>)],
+        scans_ranges: bool,
+    ) -> anyhow::Result<()> {
+        if local_reads.is_empty() && !scans_ranges {
+            return Ok(());
+        }
+
+        let reads: Vec<RemoteRead> = local_reads
+            .iter()
+            .map(|(record, value)| {
+                Ok(RemoteRead {
+                    record: bincode::serialize(record)?,
+                    value: value.clone(),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        for peer in self.peer_manager.remote_peers() {
+            let req = ForwardReadsRequest {
+                txn_uuid: txn_uuid.to_string(),
+                from_peer: self.peer_manager.me.id.to_string(),
+                reads: reads.clone(),
+            };
+            Self::send_until_received(&peer, || {
+                let mut client = self.client_for(&peer);
+                let req = req.clone();
+                async move { client.forward_reads(req).await }
+            })
+            .await;
+        }
+
+        Ok(())
+    }
+
+    // Sends to a peer until it has received the request. A partition waits for what its peers
+    // send for a txn rather than decide the outcome of the txn without it, so a peer that cannot
+    // be reached holds up the txn instead of failing it.
+    async fn send_until_received<T, F>(
+        peer: &Peer,
+        mut send: impl FnMut() -> F,
+    ) -> tonic::Response<T>
+    where
+        F: std::future::Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+    {
+        loop {
+            match send().await {
+                Ok(res) => return res,
+                Err(err) => {
+                    eprintln!("Failed to send to peer {}, retrying: {}", peer.address, err);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Reconnoiters a query on every other partition and merges their predictions.
+    pub async fn reconnoiter(&self, query: &str) -> anyhow::Result<Reconnaissance> {
+        let mut reconnaissance = Reconnaissance::default();
+
+        for peer in self.peer_manager.remote_peers() {
+            let remote_reconnaissance = self
+                .client_for(&peer)
+                .reconnoiter(ReconnoiterRequest {
+                    query: query.to_string(),
+                })
+                .await?
+                .into_inner();
+
+            reconnaissance
+                .write_records
+                .extend(remote_reconnaissance.write_records);
+            reconnaissance
+                .read_records
+                .extend(remote_reconnaissance.read_records);
+        }
+
+        Ok(reconnaissance)
+    }
+
+    /// Waits for the owners of `remote_records` to forward their reads for a txn, or for every
+    /// other partition if the txn scans key ranges.
+    pub async fn wait_for_reads(
+        &self,
+        txn_uuid: Uuid,
+        remote_records: &[Record],
+        scans_ranges: bool,
+    ) -> anyhow::Result<Vec<(Record, Option<RecordStorage>)>> {
+        let from_peers = if scans_ranges {
+            self.peer_manager
+                .remote_peers()
+                .iter()
+                .map(|peer| peer.id)
+                .collect()
+        } else {
+            remote_records
+                .iter()
+                .map(|record| self.peer_manager.peer_for_record(record).id)
+                .collect()
+        };
+
+        self.inbox
+            .reads
+            .wait_for(txn_uuid, from_peers)
+            .await?
+            .into_iter()
+            .map(|remote_read| {
+                Ok((
+                    bincode::deserialize(&remote_read.record)?,
+                    remote_read.value,
+                ))
+            })
+            .collect()
+    }
+
+    /// Hands the records of every virtual node that changes owner to its new owner, then switches
+    /// to `new_map`. Every partition runs this at the same point of the log: after every earlier
+    /// txn has finished and before any later txn has been scheduled.
+    pub async fn repartition(
+        &self,
+        txn_uuid: Uuid,
+        new_map: PartitionMap,
+        storage: &Storage,
+        catalog: &Catalog,
+    ) -> anyhow::Result<()> {
+        let me = self.peer_manager.me.id;
+        let old_map = self.peer_manager.partition_map();
+        let moved_ranges = old_map.moved_ranges(&new_map);
+
+        let mut outgoing_ranges: BTreeMap<Uuid, Vec<RangeInclusive<VirtualNodeType>>> =
+            BTreeMap::new();
+        let mut incoming_from = HashSet::new();
+
+        for (range, from, to) in moved_ranges.iter() {
+            if *to == me {
+                incoming_from.insert(*from);
+            }
+            if *from == me {
+                outgoing_ranges.entry(*to).or_default().push(range.clone());
+            }
+        }
+
+        // Only the virtual nodes this partition hands over are scanned, one at a time, so no more
+        // than one virtual node is held in memory
+        let placement = Placement::open(storage);
+        for (to, ranges) in outgoing_ranges.iter() {
+            let peer = new_map
+                .peer(to)
+                .ok_or_else(|| anyhow!("peer {} is not in the new partition map", to))?;
+
+            // Peers that are joining have missed every DDL txn from before they started
+            if !old_map.contains(to) {
+                let catalog_entries: Vec<TransferredRecord> = catalog
+                    .entries()?
+                    .into_iter()
+                    .map(|(key, value)| TransferredRecord {
+                        key,
+                        value,
+                        is_catalog_entry: true,
+                    })
+                    .collect();
+                self.transfer_records(txn_uuid, peer, catalog_entries, false)
+                    .await;
+            }
+
+            for virtual_node in ranges.iter().flat_map(|range| range.clone()) {
+                let records: Vec<TransferredRecord> = placement
+                    .records_in(&(virtual_node..=virtual_node))?
+                    .into_iter()
+                    .map(|(key, value)| TransferredRecord {
+                        key,
+                        value,
+                        is_catalog_entry: false,
+                    })
+                    .collect();
+                if !records.is_empty() {
+                    self.transfer_records(txn_uuid, peer, records, false).await;
+                }
+            }
+
+            // The receiver waits for the end of a transfer from every previous owner, even one
+            // that had no records to hand over
+            self.transfer_records(txn_uuid, peer, Vec::new(), true)
+                .await;
+        }
+
+        // The records received were stored as they arrived
+        self.inbox
+            .transfers
+            .wait_for(txn_uuid, incoming_from)
+            .await?;
+        self.inbox.transfers.finish(txn_uuid);
+
+        let mut batch = WriteBatch::default();
+        Placement::set_partition_map(&new_map, &mut batch);
+        storage.apply(batch)?;
+
+        self.peer_manager.set_partition_map(new_map);
+
+        // Records handed over are only dropped once the new map is stored, so every record has an
+        // owner that stores it at any point. If this partition crashes before it has dropped them
+        // all, it drops the rest when it restarts.
+        let schemas = schemas_by_id(catalog)?;
+        for range in outgoing_ranges.values().flatten() {
+            for virtual_node in range.clone() {
+                drop_records(storage, &schemas, virtual_node)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Streams records to a peer in chunks, until the peer has received every chunk
+    async fn transfer_records(
+        &self,
+        txn_uuid: Uuid,
+        peer: &Peer,
+        records: Vec<TransferredRecord>,
+        last: bool,
+    ) {
+        let transfer_request = |records: &[TransferredRecord], last: bool| TransferRecordsRequest {
+            txn_uuid: txn_uuid.to_string(),
+            from_peer: self.peer_manager.me.id.to_string(),
+            records: records.to_vec(),
+            last,
+        };
+
+        let mut chunks: Vec<_> = records
+            .chunks(TRANSFER_CHUNK_SIZE)
+            .map(|records| transfer_request(records, false))
+            .collect();
+        if last {
+            chunks.push(transfer_request(&[], true));
+        }
+
+        Self::send_until_received(peer, || {
+            let mut client = self.client_for(peer);
+            let chunks = chunks.clone();
+            async move { client.transfer_records(tokio_stream::iter(chunks)).await }
+        })
+        .await;
+    }
+}
+
+fn schemas_by_id(catalog: &Catalog) -> anyhow::Result<HashMap<TableId, TableSchema>> {
+    Ok(catalog
+        .tables()?
+        .into_iter()
+        .map(|schema| (schema.id, schema))
+        .collect())
+}
+
+// Stores a chunk of records handed over by a peer at once, along with their placements and index
+// entries. Catalog entries come before the records of the tables they hold.
+fn store_transferred_records(
+    storage: &Storage,
+    catalog: &Catalog,
+    records: Vec<TransferredRecord>,
+) -> anyhow::Result<()> {
+    let mut schemas = schemas_by_id(catalog)?;
+    let mut batch = WriteBatch::default();
+
+    for record in records {
+        if record.is_catalog_entry {
+            if let Some(schema) = Catalog::restore_entry((record.key, record.value), &mut batch)? {
+                schemas.insert(schema.id, schema);
+            }
+            continue;
+        }
+
+        let key = Record::from_fully_qualified_id(&record.key)
+            .ok_or_else(|| anyhow!("invalid record key {:?}", record.key))?;
+        if let Some(schema) = schemas.get(&key.table_id) {
+            let row = RecordStorage::decode(record.value.as_slice())?;
+            IndexStore::update(&schema.indexes, &key, None, Some(&row), &mut batch);
+        }
+        batch.put(Keyspace::Records, record.key, record.value);
+        Placement::add_record(&key, &mut batch);
+    }
+
+    storage.apply(batch)?;
+    Ok(())
+}
+
+// Deletes the records of a virtual node at once, along with their placements and index entries
+fn drop_records(
+    storage: &Storage,
+    schemas: &HashMap<TableId, TableSchema>,
+    virtual_node: VirtualNodeType,
+) -> anyhow::Result<()> {
+    let mut batch = WriteBatch::default();
+
+    for (key, value) in Placement::open(storage).records_in(&(virtual_node..=virtual_node))? {
+        let record = Record::from_fully_qualified_id(&key)
+            .ok_or_else(|| anyhow!("invalid record key {:?}", key))?;
+        if let Some(schema) = schemas.get(&record.table_id) {
+            let row = RecordStorage::decode(value.as_slice())?;
+            IndexStore::update(&schema.indexes, &record, Some(&row), None, &mut batch);
+        }
+        batch.delete(Keyspace::Records, key.as_slice());
+        Placement::remove_record(&record, &mut batch);
+    }
+
+    storage.apply(batch)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::partition::{Inbox, InboxErr};
+    use std::collections::HashSet;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn returns_messages_delivered_before_waiting() {
+        let inbox = Inbox::default();
+        let txn_uuid = Uuid::new_v4();
+        let peer = Uuid::new_v4();
+
+        inbox.deliver(txn_uuid, peer, vec![1]);
+
+        assert_eq!(
+            inbox.wait_for(txn_uuid, HashSet::from([peer])).await,
+            Ok(vec![1])
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_for_every_peer() {
+        let inbox = Inbox::default();
+        let txn_uuid = Uuid::new_v4();
+        let peer1 = Uuid::new_v4();
+        let peer2 = Uuid::new_v4();
+
+        let mut waiter = {
+            let inbox = inbox.clone();
+            tokio::spawn(async move {
+                inbox
+                    .wait_for(txn_uuid, HashSet::from([peer1, peer2]))
+                    .await
+            })
+        };
+
+        inbox.deliver(txn_uuid, peer1, vec![1]);
+        assert!(tokio::time::timeout(Duration::from_millis(20), &mut waiter)
+            .await
+            .is_err());
+
+        inbox.deliver(txn_uuid, peer2, vec![2]);
+
+        let mut messages = waiter.await.unwrap().unwrap();
+        messages.sort();
+        assert_eq!(messages, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_second_waiter() {
+        let inbox = Inbox::<i32>::default();
+        let txn_uuid = Uuid::new_v4();
+        let peer = Uuid::new_v4();
+
+        let mut waiter = {
+            let inbox = inbox.clone();
+            tokio::spawn(async move { inbox.wait_for(txn_uuid, HashSet::from([peer])).await })
+        };
+        assert!(tokio::time::timeout(Duration::from_millis(20), &mut waiter)
+            .await
+            .is_err());
+
+        assert_eq!(
+            inbox.wait_for(txn_uuid, HashSet::from([peer])).await,
+            Err(InboxErr::AlreadyWaiting(txn_uuid))
+        );
+
+        inbox.deliver(txn_uuid, peer, vec![1]);
+        assert_eq!(waiter.await.unwrap(), Ok(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn drops_the_messages_of_finished_txns() {
+        let inbox = Inbox::default();
+        let txn_uuid = Uuid::new_v4();
+        let peer = Uuid::new_v4();
+
+        inbox.deliver(txn_uuid, peer, vec![1]);
+        inbox.finish(txn_uuid);
+        inbox.deliver(txn_uuid, peer, vec![2]);
+
+        assert!(inbox.inner.lock().unwrap().pending_by_txn.is_empty());
+        assert_eq!(
+            inbox.wait_for(txn_uuid, HashSet::from([peer])).await,
+            Err(InboxErr::Finished(txn_uuid))
+        );
+    }
+
+    #[test]
+    fn runs_nothing_for_finished_txns() {
+        let inbox: Inbox<()> = Inbox::default();
+        let txn_uuid = Uuid::new_v4();
+
+        assert_eq!(inbox.unless_finished(txn_uuid, || 1), Some(1));
+        inbox.finish(txn_uuid);
+        assert_eq!(inbox.unless_finished(txn_uuid, || 2), None);
+    }
+}
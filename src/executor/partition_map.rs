@@ -0,0 +1,371 @@
+use crate::calvinite_tonic;
+use crate::common::{Record, VirtualNodeType};
+use crate::executor::peer::Peer;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use uuid::Uuid;
+
+/// How many points on the ring each peer claims. More tokens spread the virtual nodes more evenly.
+pub const TOKENS_PER_PEER: u32 = 128;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum PartitionMapErr {
+    #[error("a partition map needs at least one peer")]
+    NoPeers,
+    #[error("peer {0} is already in the partition map")]
+    AlreadyMember(Uuid),
+    #[error("peer {0} is not in the partition map")]
+    NotMember(Uuid),
+    #[error("invalid peer id {0}")]
+    InvalidPeerId(String),
+}
+
+/// Consistent hashing ring that assigns every virtual node to a peer.
+///
+/// Each peer claims `TOKENS_PER_PEER` points on the ring, derived only from its id, and owns the
+/// virtual nodes up to and including each of its points. Adding a peer therefore only moves
+/// virtual nodes to the new peer, and removing one only moves the virtual nodes it owned.
+///
+/// Every change bumps the version, so nodes can agree on which map a txn ran under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionMap {
+    version: u64,
+    peers: BTreeMap<Uuid, Peer>,
+    ring: BTreeMap<VirtualNodeType, Uuid>,
+}
+
+impl PartitionMap {
+    pub fn new(peers: Vec<Peer>) -> Result<Self, PartitionMapErr> {
+        Self::with_version(0, peers)
+    }
+
+    fn with_version(version: u64, peers: Vec<Peer>) -> Result<Self, PartitionMapErr> {
+        if peers.is_empty() {
+            return Err(PartitionMapErr::NoPeers);
+        }
+
+        let mut peers_by_id = BTreeMap::new();
+        for peer in peers {
+            if peers_by_id.contains_key(&peer.id) {
+                return Err(PartitionMapErr::AlreadyMember(peer.id));
+            }
+            peers_by_id.insert(peer.id, peer);
+        }
+
+        let mut ring = BTreeMap::new();
+        for peer_id in peers_by_id.keys() {
+            for token in Self::tokens_for(peer_id) {
+                // Ties go to the lowest id, which keeps the ring independent of insertion order
+                ring.entry(token)
+                    .and_modify(|owner: &mut Uuid| *owner = (*owner).min(*peer_id))
+                    .or_insert(*peer_id);
+            }
+        }
+
+        Ok(Self {
+            version,
+            peers: peers_by_id,
+            ring,
+        })
+    }
+
+    fn tokens_for(peer_id: &Uuid) -> impl Iterator<Item = VirtualNodeType> + '_ {
+        (0..TOKENS_PER_PEER).map(move |i| {
+            let digest: [u8; 16] =
+                md5::compute([peer_id.as_bytes(), &i.to_le_bytes()[..]].concat()).into();
+            VirtualNodeType::from_le_bytes(
+                digest[..std::mem::size_of::<VirtualNodeType>()]
+                    .try_into()
+                    .unwrap(),
+            )
+        })
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Every peer in the map, ordered by id.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.values().cloned().collect()
+    }
+
+    pub fn contains(&self, peer_id: &Uuid) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+
+    pub fn peer(&self, peer_id: &Uuid) -> Option<&Peer> {
+        self.peers.get(peer_id)
+    }
+
+    pub fn with_peer(&self, peer: Peer) -> Result<Self, PartitionMapErr> {
+        if self.contains(&peer.id) {
+            return Err(PartitionMapErr::AlreadyMember(peer.id));
+        }
+
+        let mut peers = self.peers();
+        peers.push(peer);
+        Self::with_version(self.version + 1, peers)
+    }
+
+    pub fn without_peer(&self, peer_id: &Uuid) -> Result<Self, PartitionMapErr> {
+        if !self.contains(peer_id) {
+            return Err(PartitionMapErr::NotMember(*peer_id));
+        }
+
+        let peers = self
+            .peers()
+            .into_iter()
+            .filter(|peer| peer.id != *peer_id)
+            .collect();
+        Self::with_version(self.version + 1, peers)
+    }
+
+    pub fn peer_for_virtual_node(&self, virtual_node: VirtualNodeType) -> &Peer {
+        // The owner of a virtual node is the first token at or after it, wrapping around the ring
+        let (_, peer_id) = self
+            .ring
+            .range(virtual_node..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .unwrap();
+        &self.peers[peer_id]
+    }
+
+    pub fn peer_for_record(&self, record: &Record) -> &Peer {
+        self.peer_for_virtual_node(record.virtual_node())
+    }
+
+    /// The contiguous ranges of virtual nodes each peer owns, in ring order.
+    pub fn ranges(&self) -> Vec<(RangeInclusive<VirtualNodeType>, Uuid)> {
+        let mut ranges = Vec::new();
+        let mut start = VirtualNodeType::MIN;
+
+        for (token, peer_id) in self.ring.iter() {
+            ranges.push((start..=*token, *peer_id));
+            if *token == VirtualNodeType::MAX {
+                return ranges;
+            }
+            start = token + 1;
+        }
+
+        // The tail of the ring wraps around to the owner of the first token
+        let (_, first_peer_id) = self.ring.iter().next().unwrap();
+        ranges.push((start..=VirtualNodeType::MAX, *first_peer_id));
+        ranges
+    }
+
+    // The owner of every virtual node, indexed by virtual node
+    fn owners(&self) -> Vec<Uuid> {
+        self.ranges()
+            .into_iter()
+            .flat_map(|(range, peer_id)| range.map(move |_| peer_id))
+            .collect()
+    }
+
+    /// The ranges of virtual nodes that change owner when going from this map to `new_map`, as
+    /// `(range, old owner, new owner)`.
+    pub fn moved_ranges(
+        &self,
+        new_map: &PartitionMap,
+    ) -> Vec<(RangeInclusive<VirtualNodeType>, Uuid, Uuid)> {
+        let mut moved_ranges: Vec<(RangeInclusive<VirtualNodeType>, Uuid, Uuid)> = Vec::new();
+
+        let owner_changes = self.owners().into_iter().zip(new_map.owners());
+        for (virtual_node, (old_owner, new_owner)) in owner_changes.enumerate() {
+            if old_owner == new_owner {
+                continue;
+            }
+
+            let virtual_node = virtual_node as VirtualNodeType;
+            match moved_ranges.last_mut() {
+                Some((range, from, to))
+                    if *from == old_owner
+                        && *to == new_owner
+                        && *range.end() + 1 == virtual_node =>
+                {
+                    *range = *range.start()..=virtual_node;
+                }
+                _ => moved_ranges.push((virtual_node..=virtual_node, old_owner, new_owner)),
+            }
+        }
+
+        moved_ranges
+    }
+}
+
+impl From<&PartitionMap> for calvinite_tonic::PartitionMap {
+    fn from(partition_map: &PartitionMap) -> Self {
+        Self {
+            version: partition_map.version,
+            peers: partition_map
+                .peers
+                .values()
+                .map(|peer| calvinite_tonic::PartitionPeer {
+                    id: peer.id.to_string(),
+                    address: peer.address.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<calvinite_tonic::PartitionMap> for PartitionMap {
+    type Error = PartitionMapErr;
+
+    fn try_from(partition_map: calvinite_tonic::PartitionMap) -> Result<Self, Self::Error> {
+        let peers = partition_map
+            .peers
+            .into_iter()
+            .map(|peer| {
+                Ok(Peer {
+                    id: Uuid::parse_str(&peer.id)
+                        .map_err(|_| PartitionMapErr::InvalidPeerId(peer.id.clone()))?,
+                    address: peer.address,
+                })
+            })
+            .collect::<Result<_, PartitionMapErr>>()?;
+
+        Self::with_version(partition_map.version, peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic;
+    use crate::common::VirtualNodeType;
+    use crate::executor::partition_map::{PartitionMap, PartitionMapErr};
+    use crate::executor::peer::Peer;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    fn peer(id: u128) -> Peer {
+        Peer {
+            id: Uuid::from_u128(id),
+            address: format!("http://peer-{}", id),
+        }
+    }
+
+    fn peer_ids() -> impl Strategy<Value = Vec<u128>> {
+        prop::collection::hash_set(any::<u128>(), 1..8).prop_map(|ids| ids.into_iter().collect())
+    }
+
+    #[test]
+    fn ranges_cover_the_ring_and_agree_with_lookup() {
+        let partition_map = PartitionMap::new(vec![peer(1), peer(2), peer(3)]).unwrap();
+
+        let owners = partition_map.owners();
+        assert_eq!(owners.len(), VirtualNodeType::MAX as usize + 1);
+
+        for (virtual_node, owner) in owners.iter().enumerate() {
+            assert_eq!(
+                partition_map
+                    .peer_for_virtual_node(virtual_node as VirtualNodeType)
+                    .id,
+                *owner
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_does_not_depend_on_peer_order() {
+        let partition_map = PartitionMap::new(vec![peer(1), peer(2), peer(3)]).unwrap();
+        let reordered = PartitionMap::new(vec![peer(3), peer(1), peer(2)]).unwrap();
+
+        assert_eq!(partition_map, reordered);
+    }
+
+    #[test]
+    fn membership_changes_bump_the_version() {
+        let partition_map = PartitionMap::new(vec![peer(1)]).unwrap();
+        assert_eq!(partition_map.version(), 0);
+
+        let partition_map = partition_map.with_peer(peer(2)).unwrap();
+        assert_eq!(partition_map.version(), 1);
+        assert_eq!(
+            partition_map.with_peer(peer(2)),
+            Err(PartitionMapErr::AlreadyMember(Uuid::from_u128(2)))
+        );
+
+        let partition_map = partition_map.without_peer(&Uuid::from_u128(1)).unwrap();
+        assert_eq!(partition_map.version(), 2);
+        assert_eq!(
+            partition_map.without_peer(&Uuid::from_u128(2)),
+            Err(PartitionMapErr::NoPeers)
+        );
+    }
+
+    #[test]
+    fn moved_ranges_cover_every_owner_change() {
+        let before = PartitionMap::new(vec![peer(1), peer(2)]).unwrap();
+        let after = before.with_peer(peer(3)).unwrap();
+
+        let mut moved = vec![None; VirtualNodeType::MAX as usize + 1];
+        for (range, from, to) in before.moved_ranges(&after) {
+            assert_eq!(to, Uuid::from_u128(3));
+            for virtual_node in range {
+                moved[virtual_node as usize] = Some(from);
+            }
+        }
+
+        for (virtual_node, (old_owner, new_owner)) in
+            before.owners().into_iter().zip(after.owners()).enumerate()
+        {
+            let expected = (old_owner != new_owner).then_some(old_owner);
+            assert_eq!(moved[virtual_node], expected);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_protobuf() {
+        let partition_map = PartitionMap::new(vec![peer(1), peer(2)])
+            .unwrap()
+            .with_peer(peer(3))
+            .unwrap();
+
+        let message = calvinite_tonic::PartitionMap::from(&partition_map);
+        assert_eq!(PartitionMap::try_from(message).unwrap(), partition_map);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn every_peer_owns_a_fair_share(ids in peer_ids()) {
+            let partition_map = PartitionMap::new(ids.iter().map(|id| peer(*id)).collect()).unwrap();
+            let owners = partition_map.owners();
+            let fair_share = owners.len() / ids.len();
+
+            for id in ids {
+                let share = owners.iter().filter(|owner| **owner == Uuid::from_u128(id)).count();
+                prop_assert!(share > fair_share / 2, "{} is below half of {}", share, fair_share);
+                prop_assert!(share < fair_share * 3 / 2, "{} is above 1.5x {}", share, fair_share);
+            }
+        }
+
+        #[test]
+        fn adding_a_peer_only_moves_virtual_nodes_to_it(ids in peer_ids(), new_id in any::<u128>()) {
+            prop_assume!(!ids.contains(&new_id));
+
+            let before = PartitionMap::new(ids.iter().map(|id| peer(*id)).collect()).unwrap();
+            let after = before.with_peer(peer(new_id)).unwrap();
+
+            for (old_owner, new_owner) in before.owners().into_iter().zip(after.owners()) {
+                prop_assert!(old_owner == new_owner || new_owner == Uuid::from_u128(new_id));
+            }
+        }
+
+        #[test]
+        fn removing_a_peer_only_moves_its_virtual_nodes(ids in peer_ids(), removed_idx in any::<prop::sample::Index>()) {
+            prop_assume!(ids.len() > 1);
+            let removed_id = Uuid::from_u128(ids[removed_idx.index(ids.len())]);
+
+            let before = PartitionMap::new(ids.iter().map(|id| peer(*id)).collect()).unwrap();
+            let after = before.without_peer(&removed_id).unwrap();
+
+            for (old_owner, new_owner) in before.owners().into_iter().zip(after.owners()) {
+                prop_assert!(old_owner == new_owner || old_owner == removed_id);
+            }
+        }
+    }
+}
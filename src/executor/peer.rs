@@ -1,31 +1,61 @@
-use std::{collections::HashMap, ops::Range};
-
-use crate::common::{Record, VirtualNodeType};
-use std::num::Wrapping;
+use crate::common::Record;
+use crate::executor::partition_map::PartitionMap;
+use std::sync::{Arc, RwLock};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, PartialOrd, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, PartialOrd, Eq)]
 pub struct Peer {
     pub id: Uuid,
+    pub address: String,
 }
 
+/// Clones share the partition map, so a reconfiguration is seen by every component of a node.
 #[derive(Debug, Clone)]
 pub struct PeerManager {
     pub me: Peer,
-    pub local_peers: Vec<Peer>, // Dev only!, also this includes yourself
+    partition_map: Arc<RwLock<PartitionMap>>,
 }
 
 impl PeerManager {
-    pub fn get_ordered_peers(&self) -> Vec<Peer> {
-        let mut sorted_peers = self.local_peers.clone();
-        sorted_peers.sort_by_key(|p| p.id.as_u128());
-        sorted_peers
+    pub fn new(me: Peer, partition_map: PartitionMap) -> Self {
+        Self {
+            me,
+            partition_map: Arc::new(RwLock::new(partition_map)),
+        }
+    }
+
+    pub fn partition_map(&self) -> PartitionMap {
+        self.partition_map.read().unwrap().clone()
+    }
+
+    pub fn set_partition_map(&self, partition_map: PartitionMap) {
+        *self.partition_map.write().unwrap() = partition_map;
+    }
+
+    /// Peers outside of the partition map own no records, e.g. while they wait to be added.
+    pub fn is_member(&self) -> bool {
+        self.partition_map.read().unwrap().contains(&self.me.id)
     }
 
     pub fn peer_for_record(&self, record: &Record) -> Peer {
-        let ordered_peers = self.get_ordered_peers();
-        let virtual_node = record.virtual_node();
-        let idx = virtual_node / ordered_peers.len() as VirtualNodeType;
-        ordered_peers[idx as usize]
+        self.partition_map
+            .read()
+            .unwrap()
+            .peer_for_record(record)
+            .clone()
+    }
+
+    pub fn is_local(&self, record: &Record) -> bool {
+        self.peer_for_record(record) == self.me
     }
-}
\ No newline at end of file
+
+    pub fn remote_peers(&self) -> Vec<Peer> {
+        self.partition_map
+            .read()
+            .unwrap()
+            .peers()
+            .into_iter()
+            .filter(|peer| *peer != self.me)
+            .collect()
+    }
+}
@@ -0,0 +1,166 @@
+use crate::calvinite_tonic;
+use crate::common::{Record, VirtualNodeType};
+use crate::executor::partition_map::PartitionMap;
+use crate::storage::{KeyValue, Keyspace, Storage, StorageErr, WriteBatch};
+use anyhow::anyhow;
+use prost::Message;
+use std::ops::{Bound, RangeInclusive};
+
+const RECORD_PREFIX: u8 = 0;
+const PARTITION_MAP_KEY: &[u8] = &[1];
+
+/// Where the records of a partitioned executor are placed: the partition map they are placed by,
+/// and the key of every stored record by virtual node.
+///
+/// Storage keys are sorted by table and primary key, so the records of a virtual node are
+/// scattered over every table. Keeping their keys by virtual node too lets a reconfiguration scan
+/// only the virtual nodes that change owner.
+#[derive(Clone, Debug)]
+pub struct Placement {
+    storage: Storage,
+}
+
+impl Placement {
+    pub fn open(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
+    }
+
+    /// The partition map of the last reconfiguration this executor applied, if any.
+    pub fn partition_map(&self) -> anyhow::Result<Option<PartitionMap>> {
+        self.storage
+            .get(Keyspace::Placement, PARTITION_MAP_KEY)?
+            .map(|bytes| {
+                let partition_map = calvinite_tonic::PartitionMap::decode(bytes.as_slice())?;
+                PartitionMap::try_from(partition_map)
+                    .map_err(|err| anyhow!("stored partition map is invalid: {}", err))
+            })
+            .transpose()
+    }
+
+    /// Every stored record whose virtual node is in `virtual_nodes`, as its storage key and row.
+    pub fn records_in(
+        &self,
+        virtual_nodes: &RangeInclusive<VirtualNodeType>,
+    ) -> Result<Vec<KeyValue>, StorageErr> {
+        let start = Bound::Included(Self::virtual_node_prefix(*virtual_nodes.start()));
+        let end = match virtual_nodes.end().checked_add(1) {
+            Some(next) => Bound::Excluded(Self::virtual_node_prefix(next)),
+            None => Bound::Excluded(vec![RECORD_PREFIX + 1]),
+        };
+
+        let mut records = Vec::new();
+        for (key, _) in self.storage.scan(Keyspace::Placement, (start, end))? {
+            let record_key = &key[1 + std::mem::size_of::<VirtualNodeType>()..];
+            if let Some(row) = self.storage.get(Keyspace::Records, record_key)? {
+                records.push((record_key.to_vec(), row));
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Adds the switch to `partition_map` to the batch that moves the records.
+    pub fn set_partition_map(partition_map: &PartitionMap, batch: &mut WriteBatch) {
+        batch.put(
+            Keyspace::Placement,
+            PARTITION_MAP_KEY,
+            calvinite_tonic::PartitionMap::from(partition_map).encode_to_vec(),
+        );
+    }
+
+    /// Adds the placement of a record to the batch that stores it.
+    pub fn add_record(record: &Record, batch: &mut WriteBatch) {
+        batch.put(Keyspace::Placement, Self::record_key(record), Vec::new());
+    }
+
+    /// Adds the removal of the placement of a record to the batch that deletes it.
+    pub fn remove_record(record: &Record, batch: &mut WriteBatch) {
+        batch.delete(Keyspace::Placement, Self::record_key(record));
+    }
+
+    fn virtual_node_prefix(virtual_node: VirtualNodeType) -> Vec<u8> {
+        [[RECORD_PREFIX].as_slice(), &virtual_node.to_be_bytes()].concat()
+    }
+
+    fn record_key(record: &Record) -> Vec<u8> {
+        [
+            Self::virtual_node_prefix(record.virtual_node()),
+            record.fully_qualified_id_as_bytes(),
+        ]
+        .concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Record, VirtualNodeType};
+    use crate::executor::partition_map::PartitionMap;
+    use crate::executor::peer::Peer;
+    use crate::executor::placement::Placement;
+    use crate::storage::{Keyspace, MemoryEngine, WriteBatch};
+    use uuid::Uuid;
+
+    #[test]
+    fn scans_the_records_of_a_range_of_virtual_nodes() {
+        let storage = MemoryEngine::shared();
+        let placement = Placement::open(&storage);
+
+        let records: Vec<Record> = (0..2)
+            .flat_map(|table_id| (0..32).map(move |id| Record { table_id, id }))
+            .collect();
+        let mut batch = WriteBatch::default();
+        for record in records.iter() {
+            batch.put(
+                Keyspace::Records,
+                record.fully_qualified_id_as_bytes(),
+                record.id.to_be_bytes(),
+            );
+            Placement::add_record(record, &mut batch);
+        }
+        Placement::remove_record(&records[0], &mut batch);
+        batch.delete(Keyspace::Records, records[0].fully_qualified_id_as_bytes());
+        storage.apply(batch).unwrap();
+
+        let lower_half = 0..=VirtualNodeType::MAX / 2;
+        let upper_half = VirtualNodeType::MAX / 2 + 1..=VirtualNodeType::MAX;
+        let mut scanned: Vec<Record> = [lower_half.clone(), upper_half]
+            .iter()
+            .flat_map(|range| placement.records_in(range).unwrap())
+            .map(|(key, _)| Record::from_fully_qualified_id(&key).unwrap())
+            .collect();
+        scanned.sort();
+
+        assert_eq!(scanned, records[1..]);
+        assert!(placement
+            .records_in(&lower_half)
+            .unwrap()
+            .iter()
+            .all(|(key, _)| lower_half
+                .contains(&Record::from_fully_qualified_id(key).unwrap().virtual_node())));
+    }
+
+    #[test]
+    fn stores_the_partition_map() {
+        let storage = MemoryEngine::shared();
+        let placement = Placement::open(&storage);
+        assert_eq!(placement.partition_map().unwrap(), None);
+
+        let partition_map = PartitionMap::new(vec![Peer {
+            id: Uuid::new_v4(),
+            address: "http://127.0.0.1:1".into(),
+        }])
+        .unwrap()
+        .with_peer(Peer {
+            id: Uuid::new_v4(),
+            address: "http://127.0.0.1:2".into(),
+        })
+        .unwrap();
+        let mut batch = WriteBatch::default();
+        Placement::set_partition_map(&partition_map, &mut batch);
+        storage.apply(batch).unwrap();
+
+        assert_eq!(placement.partition_map().unwrap(), Some(partition_map));
+    }
+}
@@ -0,0 +1,534 @@
+use crate::calvinite_tonic::{Reconnaissance, RecordStorage, TxnStamp};
+use crate::catalog::{Catalog, TableSchema};
+use crate::common;
+use crate::common::Record;
+use crate::executor::{script, CachedRecord, Executor, RecordCache};
+use crate::expr;
+use crate::expr::StmtStamp;
+use crate::index::IndexStore;
+use crate::procedure::Procedure;
+use crate::stmt_analyzer::SqlStmt;
+use crate::storage::{Keyspace, Storage};
+use prost::Message;
+use sqlparser::ast;
+use std::ops::RangeInclusive;
+
+/// Predicts the records the dependent statements of `query` write, from the records in `storage`
+/// only. An UPDATE or DELETE writes the rows its predicate matches, or every row its index lookup
+/// finds. An `INSERT ... SELECT` that does not copy the primary key of its source row writes the
+/// records computed from that row. Other index lookups, of a SELECT or of the values written to a
+/// unique index, read the rows they find. A SELECT that joins a table by primary key reads the
+/// rows its joins look up. A CALL of a procedure that declares no footprint writes the rows its
+/// script writes, and reads the other rows it looks up.
+///
+/// No locks are taken, so the prediction reflects whatever txns happen to have been applied.
+/// Txns restart if they would write a record that was not predicted, including a row inserted
+/// after the reconnaissance, if an index lookup finds a record that was not predicted, or if a
+/// join or script looks up a record that was not predicted. Each partition only predicts from the
+/// rows it stores, so a join keyed by a column of a row another partition stores, or a script
+/// that branches on such a row, is not predicted.
+pub fn reconnoiter(
+    storage: &Storage,
+    index_store: &IndexStore,
+    catalog: &Catalog,
+    query: &str,
+) -> anyhow::Result<Reconnaissance> {
+    let sql_stmt = SqlStmt::from_string(query.to_string(), catalog)?;
+    let mut reconnaissance = Reconnaissance::default();
+    // Txns are only stamped once they are sequenced, so predictions are made as of now
+    let stamp = TxnStamp {
+        timestamp_micros: common::now_micros(),
+        seed: 0,
+    };
+
+    for (stmt_index, stmt) in SqlStmt::with_subqueries(&sql_stmt.ast_stmts)
+        .iter()
+        .enumerate()
+    {
+        let stamp = StmtStamp::new(stamp.clone(), stmt_index);
+        if let Some((procedure, parameters)) = SqlStmt::called_procedure(stmt, catalog)? {
+            if procedure.footprint.is_none() {
+                // Calls with arguments that cannot be evaluated fail the txn once it runs
+                if let Ok(args) = procedure.args_of(parameters) {
+                    let (read_records, write_records) =
+                        called_records(storage, catalog, &procedure, &args)?;
+                    for record in read_records {
+                        reconnaissance
+                            .read_records
+                            .push(bincode::serialize(&record)?);
+                    }
+                    for record in write_records {
+                        reconnaissance
+                            .write_records
+                            .push(bincode::serialize(&record)?);
+                    }
+                }
+            }
+            continue;
+        }
+
+        let schema = match SqlStmt::table_schema(stmt, catalog)? {
+            Some(schema) => schema,
+            None => continue,
+        };
+        if !SqlStmt::is_dependent_stmt(stmt, catalog)? {
+            continue;
+        }
+
+        let index_scan = SqlStmt::find_index_scan(stmt, &schema);
+        let mut read_records = Vec::new();
+        let mut write_records = Vec::new();
+
+        for lookup in SqlStmt::find_unique_checks(stmt, &schema) {
+            read_records.extend(index_store.lookup(&lookup)?);
+        }
+
+        match stmt {
+            ast::Statement::Query(query) => {
+                if let Some(index_scan) = index_scan {
+                    read_records.extend(index_store.lookup(&index_scan)?);
+                }
+                read_records.extend(joined_records(
+                    storage, catalog, &schema, stmt, query, &stamp,
+                )?);
+            }
+            ast::Statement::Insert {
+                columns, source, ..
+            } if SqlStmt::insert_source(source, catalog)?.is_some() => {
+                write_records.extend(inserted_records(
+                    storage, catalog, &schema, columns, source, &stamp,
+                )?);
+            }
+            ast::Statement::Update { selection, .. } | ast::Statement::Delete { selection, .. } => {
+                let is_keyed = selection.as_ref().is_some_and(|selection| {
+                    SqlStmt::find_id_range_in_expr(selection, &schema).is_some()
+                });
+
+                if let Some(index_scan) = index_scan {
+                    write_records.extend(index_store.lookup(&index_scan)?);
+                } else if !is_keyed {
+                    for (record, row) in table_rows(storage, &schema.all_records())? {
+                        let is_match = match selection {
+                            Some(selection) => expr::is_true(selection, &schema, &row, &stamp)?,
+                            None => true,
+                        };
+                        if is_match {
+                            write_records.push(record);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        for record in read_records {
+            reconnaissance
+                .read_records
+                .push(bincode::serialize(&record)?);
+        }
+        for record in write_records {
+            reconnaissance
+                .write_records
+                .push(bincode::serialize(&record)?);
+        }
+    }
+
+    Ok(reconnaissance)
+}
+
+// Only the partition that owns the source row predicts the records inserted from it
+fn inserted_records(
+    storage: &Storage,
+    catalog: &Catalog,
+    schema: &TableSchema,
+    columns: &[ast::Ident],
+    source: &ast::Query,
+    stamp: &StmtStamp,
+) -> anyhow::Result<Vec<Record>> {
+    let mut record_cache = RecordCache::new();
+    if let Some((_, source_record)) = SqlStmt::insert_source(source, catalog)? {
+        if let Some(value) = storage.get(
+            Keyspace::Records,
+            &source_record.fully_qualified_id_as_bytes(),
+        )? {
+            record_cache.insert(
+                source_record,
+                CachedRecord {
+                    row: Some(RecordStorage::decode(value.as_slice())?),
+                    is_dirty: false,
+                },
+            );
+        }
+    }
+
+    // Rows that cannot be inserted fail the txn once it runs
+    Ok(
+        Executor::rows_to_insert(catalog, &mut record_cache, schema, columns, source, stamp)
+            .map(|rows| rows.into_iter().map(|(record, _)| record).collect())
+            .unwrap_or_default(),
+    )
+}
+
+// The records the joins of a query look up by primary key. The query runs on the rows it reads
+// until its joins look up no record that was not read yet, as each row read may hold the key of
+// another one.
+fn joined_records(
+    storage: &Storage,
+    catalog: &Catalog,
+    schema: &TableSchema,
+    stmt: &ast::Statement,
+    query: &ast::Query,
+    stamp: &StmtStamp,
+) -> anyhow::Result<Vec<Record>> {
+    // Joins that cannot be evaluated fail the txn once it runs
+    let joins = SqlStmt::find_joins(stmt, catalog).unwrap_or_default();
+    if joins.iter().all(|join| join.key.is_none()) {
+        return Ok(Vec::new());
+    }
+
+    let ids = match &query.body {
+        ast::SetExpr::Select(select) => select
+            .selection
+            .as_ref()
+            .and_then(|selection| SqlStmt::find_id_range_in_expr(selection, schema)),
+        _ => None,
+    };
+    let mut ranges = vec![schema.record_range(ids.unwrap_or(i64::MIN..=i64::MAX))];
+    ranges.extend(
+        joins
+            .iter()
+            .filter(|join| join.key.is_none())
+            .map(|join| join.schema.all_records()),
+    );
+
+    let mut record_cache = RecordCache::new();
+    for range in ranges.iter() {
+        for (record, row) in table_rows(storage, range)? {
+            record_cache.insert(
+                record,
+                CachedRecord {
+                    row: Some(row),
+                    is_dirty: false,
+                },
+            );
+        }
+    }
+
+    let mut joined_records = Vec::new();
+    loop {
+        let mut looked_up_records = record_cache.clone();
+        let _ = Executor::execute_query_stmt(catalog, &mut looked_up_records, schema, query, stamp);
+
+        let new_records: Vec<Record> = looked_up_records
+            .into_keys()
+            .filter(|record| !record_cache.contains_key(record))
+            .collect();
+        if new_records.is_empty() {
+            return Ok(joined_records);
+        }
+
+        for record in new_records {
+            let row = storage
+                .get(Keyspace::Records, &record.fully_qualified_id_as_bytes())?
+                .map(|value| RecordStorage::decode(value.as_slice()))
+                .transpose()?;
+            record_cache.insert(
+                record.clone(),
+                CachedRecord {
+                    row,
+                    is_dirty: false,
+                },
+            );
+            joined_records.push(record);
+        }
+    }
+}
+
+// The records the script of a procedure reads and writes. Like a query with joins, it runs on the
+// rows it reads until it looks up no record that was not read yet.
+fn called_records(
+    storage: &Storage,
+    catalog: &Catalog,
+    procedure: &Procedure,
+    args: &RecordStorage,
+) -> anyhow::Result<(Vec<Record>, Vec<Record>)> {
+    let mut record_cache = RecordCache::new();
+    loop {
+        let mut looked_up_records = record_cache.clone();
+        let _ = script::call(catalog, &mut looked_up_records, procedure, args);
+
+        let new_records: Vec<Record> = looked_up_records
+            .keys()
+            .filter(|record| !record_cache.contains_key(record))
+            .cloned()
+            .collect();
+        if new_records.is_empty() {
+            let (mut write_records, mut read_records): (Vec<_>, Vec<_>) = looked_up_records
+                .into_iter()
+                .partition(|(_, cached)| cached.is_dirty);
+            write_records.sort_by(|(a, _), (b, _)| a.cmp(b));
+            read_records.sort_by(|(a, _), (b, _)| a.cmp(b));
+            return Ok((
+                read_records.into_iter().map(|(record, _)| record).collect(),
+                write_records
+                    .into_iter()
+                    .map(|(record, _)| record)
+                    .collect(),
+            ));
+        }
+
+        for record in new_records {
+            let row = storage
+                .get(Keyspace::Records, &record.fully_qualified_id_as_bytes())?
+                .map(|value| RecordStorage::decode(value.as_slice()))
+                .transpose()?;
+            record_cache.insert(
+                record,
+                CachedRecord {
+                    row,
+                    is_dirty: false,
+                },
+            );
+        }
+    }
+}
+
+fn table_rows(
+    storage: &Storage,
+    range: &RangeInclusive<Record>,
+) -> anyhow::Result<Vec<(Record, RecordStorage)>> {
+    let mut rows = Vec::new();
+    if range.is_empty() {
+        return Ok(rows);
+    }
+
+    for (key, value) in storage.scan(Keyspace::Records, Record::key_range(range))? {
+        let record = Record::from_fully_qualified_id(&key)
+            .ok_or_else(|| anyhow::anyhow!("invalid record key {:?}", key))?;
+        rows.push((record, RecordStorage::decode(value.as_slice())?));
+    }
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::RecordStorage;
+    use crate::catalog::Catalog;
+    use crate::common::Record;
+    use crate::executor::reconnaissance::reconnoiter;
+    use crate::index::IndexStore;
+    use crate::procedure::{Footprint, KeyRef, Procedure};
+    use crate::storage::{Keyspace, MemoryEngine, Storage, WriteBatch};
+    use prost::Message;
+    use sqlparser::ast;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn put_row(storage: &Storage, record: Record, row: RecordStorage) {
+        let mut batch = WriteBatch::default();
+        batch.put(
+            Keyspace::Records,
+            record.fully_qualified_id_as_bytes(),
+            row.encode_to_vec(),
+        );
+        storage.apply(batch).unwrap();
+    }
+
+    fn storage_with_foo() -> (Storage, IndexStore, Catalog) {
+        let storage = MemoryEngine::shared();
+        let index_store = IndexStore::open(&storage);
+        let catalog = Catalog::open(&storage);
+
+        for create_table in [
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            "CREATE TABLE bar (id BIGINT PRIMARY KEY, val BIGINT)",
+        ] {
+            if let ast::Statement::CreateTable {
+                name,
+                columns,
+                constraints,
+                ..
+            } = Parser::parse_sql(&GenericDialect {}, create_table)
+                .unwrap()
+                .remove(0)
+            {
+                catalog
+                    .create_table(&name.to_string(), &columns, &constraints, false)
+                    .unwrap();
+            }
+        }
+
+        for (table_id, id, val) in [(0, 1, 10), (0, 2, 20), (0, 3, 10), (1, 4, 10)] {
+            let row = RecordStorage {
+                values: vec![id.into(), (val as i64).into()],
+            };
+            put_row(&storage, Record { table_id, id }, row);
+        }
+
+        (storage, index_store, catalog)
+    }
+
+    fn ids(records: &[Vec<u8>]) -> Vec<i64> {
+        let mut ids: Vec<i64> = records
+            .iter()
+            .map(|record| bincode::deserialize::<Record>(record).unwrap().id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn predicts_records_of_dependent_stmts() {
+        let (storage, index_store, catalog) = storage_with_foo();
+        let reconnoiter = |query| reconnoiter(&storage, &index_store, &catalog, query).unwrap();
+
+        // Queries scan key ranges under range locks instead
+        let reconnaissance = reconnoiter("SELECT * FROM foo WHERE val = 10");
+        assert!(reconnaissance.write_records.is_empty());
+
+        let reconnaissance = reconnoiter("UPDATE foo SET val = 0 WHERE val = 10");
+        assert_eq!(ids(&reconnaissance.write_records), vec![1, 3]);
+
+        let reconnaissance = reconnoiter("UPDATE bar SET val = 0");
+        assert_eq!(ids(&reconnaissance.write_records), vec![4]);
+
+        let reconnaissance = reconnoiter("DELETE FROM foo WHERE val > 10");
+        assert_eq!(ids(&reconnaissance.write_records), vec![2]);
+    }
+
+    #[test]
+    fn predicts_records_of_index_lookups() {
+        let (storage, index_store, catalog) = storage_with_foo();
+        let columns = [ast::OrderByExpr {
+            expr: ast::Expr::Identifier("val".into()),
+            asc: None,
+            nulls_first: None,
+        }];
+        let index = catalog
+            .create_index("foo_val", "foo", &columns, true, false)
+            .unwrap()
+            .unwrap();
+
+        let mut entries = WriteBatch::default();
+        for id in [1, 2, 3] {
+            let record = Record { table_id: 0, id };
+            let row = RecordStorage::decode(
+                storage
+                    .get(Keyspace::Records, &record.fully_qualified_id_as_bytes())
+                    .unwrap()
+                    .unwrap()
+                    .as_slice(),
+            )
+            .unwrap();
+            IndexStore::update(
+                std::slice::from_ref(&index),
+                &record,
+                None,
+                Some(&row),
+                &mut entries,
+            );
+        }
+        storage.apply(entries).unwrap();
+        let reconnoiter = |query| reconnoiter(&storage, &index_store, &catalog, query).unwrap();
+
+        let reconnaissance = reconnoiter("SELECT * FROM foo WHERE val = 10");
+        assert_eq!(ids(&reconnaissance.read_records), vec![1, 3]);
+        assert!(reconnaissance.write_records.is_empty());
+
+        let reconnaissance = reconnoiter("DELETE FROM foo WHERE 20 = val");
+        assert_eq!(ids(&reconnaissance.write_records), vec![2]);
+
+        // Rows that already hold a value written to a unique index are read
+        let reconnaissance = reconnoiter("INSERT INTO foo VALUES (5, 20)");
+        assert_eq!(ids(&reconnaissance.read_records), vec![2]);
+        let reconnaissance = reconnoiter("UPDATE foo SET val = 10 WHERE id = 2");
+        assert_eq!(ids(&reconnaissance.read_records), vec![1, 3]);
+        assert!(reconnaissance.write_records.is_empty());
+    }
+
+    #[test]
+    fn predicts_records_of_joins() {
+        let (storage, index_store, catalog) = storage_with_foo();
+        let row = RecordStorage {
+            values: vec![10i64.into(), 3i64.into()],
+        };
+        put_row(
+            &storage,
+            Record {
+                table_id: 0,
+                id: 10,
+            },
+            row,
+        );
+        let reconnoiter = |query| reconnoiter(&storage, &index_store, &catalog, query).unwrap();
+
+        // Rows that do not exist are read too, so that no txn inserts them in the meantime
+        let reconnaissance =
+            reconnoiter("SELECT * FROM foo JOIN bar ON bar.id = foo.val WHERE foo.id = 1");
+        assert_eq!(ids(&reconnaissance.read_records), vec![10]);
+
+        // Each row read may hold the key of another one
+        let reconnaissance = reconnoiter(
+            "SELECT * FROM bar JOIN foo AS a ON a.id = bar.val JOIN foo AS b ON b.id = a.val",
+        );
+        assert_eq!(ids(&reconnaissance.read_records), vec![3, 10]);
+
+        let reconnaissance = reconnoiter("SELECT * FROM bar JOIN foo ON foo.val = bar.val");
+        assert!(reconnaissance.read_records.is_empty());
+    }
+
+    #[test]
+    fn predicts_records_of_procedures() {
+        let (storage, index_store, catalog) = storage_with_foo();
+        for (name, footprint) in [
+            ("chase", None),
+            (
+                "declared",
+                Some(Footprint {
+                    reads: Vec::new(),
+                    writes: vec![KeyRef {
+                        table: "foo".to_string(),
+                        key: "id".to_string(),
+                    }],
+                }),
+            ),
+        ] {
+            let procedure = Procedure {
+                name: name.to_string(),
+                params: vec!["id".to_string()],
+                footprint,
+                script: "local row = db.get(\"foo\", id)
+                    local other = db.get(\"foo\", row.val)
+                    row.val = row.val + 1
+                    db.put(\"foo\", row)"
+                    .to_string(),
+            };
+            catalog.create_procedure(&procedure, false).unwrap();
+        }
+        let reconnoiter = |query| reconnoiter(&storage, &index_store, &catalog, query).unwrap();
+
+        // Rows the script looks up that do not exist are read too
+        let reconnaissance = reconnoiter("CALL chase(1)");
+        assert_eq!(ids(&reconnaissance.read_records), vec![10]);
+        assert_eq!(ids(&reconnaissance.write_records), vec![1]);
+
+        let reconnaissance = reconnoiter("CALL declared(1)");
+        assert!(reconnaissance.read_records.is_empty());
+        assert!(reconnaissance.write_records.is_empty());
+    }
+
+    #[test]
+    fn skips_keyed_stmts() {
+        let (storage, index_store, catalog) = storage_with_foo();
+
+        for query in [
+            "UPDATE foo SET val = 0 WHERE id = 1",
+            "UPDATE foo SET val = 0 WHERE id BETWEEN 1 AND 2",
+            "DELETE FROM foo WHERE id > 1 AND val = 10",
+        ] {
+            let reconnaissance = reconnoiter(&storage, &index_store, &catalog, query).unwrap();
+            assert!(reconnaissance.write_records.is_empty(), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use crate::calvinite_tonic::column_value::Value;
+use crate::calvinite_tonic::{ColumnValue, RecordStorage, ResultSet};
+use crate::catalog::{Catalog, CatalogErr, Column, ColumnType, TableSchema};
+use crate::common::Record;
+use crate::executor::{CachedRecord, ExecutorErr, RecordCache};
+use crate::procedure::{Procedure, ProcedureErr};
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+// Every call gets the same budget, so a runaway script fails alike on every partition
+const MAX_INSTRUCTIONS: u64 = 10_000_000;
+const INSTRUCTIONS_PER_HOOK: u32 = 1_000;
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+// Only the table, string, math and utf8 libraries are loaded, so scripts cannot reach the
+// clock, the file system or any other I/O. What remains that could differ between partitions is
+// removed or replaced: random numbers, loading code, the collector, and anything that exposes
+// addresses or the hash order of tables.
+const SANDBOX: &str = r#"
+local next, type, error, select, tostring, sort = next, type, error, select, tostring, table.sort
+local find, gsub, format, pack, unpack = string.find, string.gsub, string.format, table.pack, table.unpack
+
+print, load, loadfile, dofile, collectgarbage, require = nil, nil, nil, nil, nil, nil
+math.random, math.randomseed, string.dump = nil, nil, nil
+
+-- Keys are ordered by type, then by value
+local function before(a, b)
+  local ta, tb = type(a), type(b)
+  if ta ~= tb then return ta < tb end
+  if ta == "boolean" then return not a and b end
+  return a < b
+end
+
+function pairs(t)
+  local keys = {}
+  for k in next, t do
+    local tk = type(k)
+    if tk ~= "number" and tk ~= "string" and tk ~= "boolean" then
+      error("pairs over a table keyed by a " .. tk .. " has no deterministic order", 2)
+    end
+    keys[#keys + 1] = k
+  end
+  sort(keys, before)
+  local i = 0
+  return function()
+    i = i + 1
+    local k = keys[i]
+    if k ~= nil then return k, t[k] end
+  end, t, nil
+end
+_G.next = nil
+
+local function opaque(v)
+  local tv = type(v)
+  if tv == "table" or tv == "function" or tv == "userdata" or tv == "thread" then return tv end
+  return v
+end
+
+_G.tostring = function(v) return tostring(opaque(v)) end
+
+string.format = function(fmt, ...)
+  if find(gsub(fmt, "%%%%", ""), "%%[-+ #0%d%.]*p") then
+    error("string.format %p has no deterministic result", 2)
+  end
+  local args = pack(...)
+  for i = 1, args.n do args[i] = opaque(args[i]) end
+  return format(fmt, unpack(args, 1, args.n))
+end
+"#;
+
+/// Checks that the script of a procedure compiles, so a CREATE PROCEDURE fails rather than
+/// every call of it.
+pub fn check(procedure: &Procedure) -> Result<(), ProcedureErr> {
+    let lua = sandbox().map_err(|err| failed(procedure, &err))?;
+    lua.load(chunk(procedure))
+        .set_name(&procedure.name)
+        .into_function()
+        .map_err(|err| ProcedureErr::Invalid {
+            procedure: procedure.name.clone(),
+            reason: reason(&err),
+        })?;
+
+    Ok(())
+}
+
+/// Runs a procedure on the rows of the record cache. The script reads and writes rows with
+///
+/// - `db.get(table, id)`, the row with primary key `id` as a table keyed by column name, or nil,
+/// - `db.put(table, row)`, which inserts the row or replaces the row with its primary key,
+/// - `db.delete(table, id)`, which deletes the row with primary key `id` if there is one.
+///
+/// A procedure that declares its footprint may only touch the records it declares. Rows a
+/// script looks up that were not read are cached as missing, so the txn can tell whether its
+/// reconnaissance predicted them. A value the script returns, other than nil, is the single row
+/// of the result, in a column named after the procedure.
+pub fn call(
+    catalog: &Catalog,
+    record_cache: &mut RecordCache,
+    procedure: &Procedure,
+    args: &RecordStorage,
+) -> Result<ResultSet, ExecutorErr> {
+    let footprint = procedure
+        .footprint_of(catalog, args)?
+        .map(|(reads, writes)| {
+            let writes: HashSet<Record> = writes.into_iter().collect();
+            let touched: HashSet<Record> = reads.into_iter().chain(writes.clone()).collect();
+            (touched, writes)
+        });
+
+    let lua = sandbox().map_err(|err| failed(procedure, &err))?;
+    let host = Host {
+        catalog,
+        procedure,
+        footprint,
+        record_cache: RefCell::new(record_cache),
+        written: RefCell::new(HashSet::new()),
+        err: RefCell::new(None),
+    };
+
+    let returned = lua.scope(|scope| {
+        let db = lua.create_table()?;
+        db.set(
+            "get",
+            scope.create_function(|lua, (table, id): (String, i64)| {
+                host.catch(host.get(lua, &table, id))
+            })?,
+        )?;
+        db.set(
+            "put",
+            scope.create_function(|_, (table, row): (String, mlua::Table)| {
+                host.catch(host.put(&table, row))
+            })?,
+        )?;
+        db.set(
+            "delete",
+            scope.create_function(|_, (table, id): (String, i64)| {
+                host.catch(host.delete(&table, id))
+            })?,
+        )?;
+        lua.globals().set("db", db)?;
+
+        let args = args
+            .values
+            .iter()
+            .map(|arg| lua_value(&lua, arg))
+            .collect::<mlua::Result<mlua::MultiValue>>()?;
+        let returned: mlua::Value = lua
+            .load(chunk(procedure))
+            .set_name(&procedure.name)
+            .call(args)?;
+        column_value(&returned)
+    });
+
+    // A script may catch the error of a host function, but the call fails all the same
+    if let Some(err) = host.err.into_inner() {
+        return Err(err);
+    }
+    let returned = returned.map_err(|err| failed(procedure, &err))?;
+
+    let (columns, rows) = match returned {
+        Some((column_type, value)) => {
+            let column = Column {
+                name: procedure.name.clone(),
+                column_type,
+                nullable: false,
+            };
+            (
+                vec![column.metadata()],
+                vec![RecordStorage {
+                    values: vec![value],
+                }],
+            )
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+
+    Ok(ResultSet {
+        columns,
+        rows,
+        rows_affected: host.written.into_inner().len() as u64,
+    })
+}
+
+// A fresh interpreter per call, so no state survives from one txn to the next
+fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8,
+        LuaOptions::default(),
+    )?;
+    lua.load(SANDBOX).set_name("sandbox").exec()?;
+
+    let instructions = Cell::new(0);
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_HOOK),
+        move |_, _| {
+            instructions.set(instructions.get() + u64::from(INSTRUCTIONS_PER_HOOK));
+            match instructions.get() > MAX_INSTRUCTIONS {
+                true => Err(mlua::Error::RuntimeError(format!(
+                    "ran for more than {} instructions",
+                    MAX_INSTRUCTIONS
+                ))),
+                false => Ok(()),
+            }
+        },
+    );
+    lua.set_memory_limit(MEMORY_LIMIT)?;
+
+    Ok(lua)
+}
+
+// The parameters are locals of the script, declared on its first line so line numbers in errors
+// match the script
+fn chunk(procedure: &Procedure) -> String {
+    match procedure.params.is_empty() {
+        true => procedure.script.clone(),
+        false => format!(
+            "local {} = ...; {}",
+            procedure.params.join(", "),
+            procedure.script
+        ),
+    }
+}
+
+fn failed(procedure: &Procedure, err: &mlua::Error) -> ProcedureErr {
+    ProcedureErr::Failed {
+        procedure: procedure.name.clone(),
+        reason: reason(err),
+    }
+}
+
+fn reason(err: &mlua::Error) -> String {
+    match err {
+        mlua::Error::RuntimeError(message) | mlua::Error::SyntaxError { message, .. } => {
+            message.clone()
+        }
+        mlua::Error::CallbackError { cause, .. } => reason(cause),
+        err => err.to_string(),
+    }
+}
+
+struct Host<'a> {
+    catalog: &'a Catalog,
+    procedure: &'a Procedure,
+    /// Every record the procedure declares, and the records it declares it writes.
+    footprint: Option<(HashSet<Record>, HashSet<Record>)>,
+    record_cache: RefCell<&'a mut RecordCache>,
+    written: RefCell<HashSet<Record>>,
+    /// The first error of a host function.
+    err: RefCell<Option<ExecutorErr>>,
+}
+
+impl Host<'_> {
+    fn catch<T>(&self, result: Result<T, ExecutorErr>) -> mlua::Result<T> {
+        result.map_err(|err| {
+            let message = err.to_string();
+            self.err.borrow_mut().get_or_insert(err);
+            mlua::Error::RuntimeError(message)
+        })
+    }
+
+    fn get<'lua>(
+        &self,
+        lua: &'lua Lua,
+        table: &str,
+        id: i64,
+    ) -> Result<Option<mlua::Table<'lua>>, ExecutorErr> {
+        let schema = self.catalog.require_table(table)?;
+        let record = self.touch(&schema, id, false)?;
+        let row = self
+            .record_cache
+            .borrow_mut()
+            .entry(record)
+            .or_insert(CachedRecord {
+                row: None,
+                is_dirty: false,
+            })
+            .row
+            .clone();
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let lua_row = lua.create_table().map_err(Self::host_err)?;
+        for (column, value) in schema.columns.iter().zip(row.values.iter()) {
+            let value = lua_value(lua, value).map_err(Self::host_err)?;
+            lua_row
+                .set(column.name.as_str(), value)
+                .map_err(Self::host_err)?;
+        }
+
+        Ok(Some(lua_row))
+    }
+
+    fn put(&self, table: &str, lua_row: mlua::Table) -> Result<(), ExecutorErr> {
+        let schema = self.catalog.require_table(table)?;
+
+        // Columns are checked in name order, so every partition reports the same one
+        let mut names = Vec::new();
+        for pair in lua_row.clone().pairs::<mlua::Value, mlua::Value>() {
+            let (name, _) = pair.map_err(Self::host_err)?;
+            match name {
+                mlua::Value::String(name) => names.push(name.to_string_lossy().into_owned()),
+                name => return Err(CatalogErr::NoSuchColumn(format!("{:?}", name)).into()),
+            }
+        }
+        names.sort();
+        if let Some(name) = names
+            .iter()
+            .find(|name| schema.column_index(name).is_none())
+        {
+            return Err(CatalogErr::NoSuchColumn(name.clone()).into());
+        }
+
+        let values = schema
+            .columns
+            .iter()
+            .map(|column| {
+                let value = lua_row
+                    .raw_get(column.name.as_str())
+                    .map_err(Self::host_err)?;
+                value_of(column, &value)
+            })
+            .collect::<Result<_, ExecutorErr>>()?;
+        let row = schema.row_from_values(&[], values)?;
+        let record = schema
+            .record_of(&row)
+            .ok_or_else(|| ExecutorErr::NullPrimaryKey(schema.name.clone()))?;
+        let record = self.touch(&schema, record.id, true)?;
+
+        // Only literal values are checked against a unique index before they are written
+        let mut record_cache = self.record_cache.borrow_mut();
+        let old_row = record_cache
+            .get(&record)
+            .and_then(|cached| cached.row.as_ref());
+        if schema.indexes.iter().any(|index| {
+            index.unique
+                && index.value_of(&row).is_some()
+                && old_row.and_then(|old_row| index.value_of(old_row)) != index.value_of(&row)
+        }) {
+            return Err(ExecutorErr::Unsupported(
+                "db.put of a value in a unique index".to_string(),
+            ));
+        }
+
+        record_cache.insert(
+            record.clone(),
+            CachedRecord {
+                row: Some(row),
+                is_dirty: true,
+            },
+        );
+        self.written.borrow_mut().insert(record);
+
+        Ok(())
+    }
+
+    fn delete(&self, table: &str, id: i64) -> Result<(), ExecutorErr> {
+        let schema = self.catalog.require_table(table)?;
+        let record = self.touch(&schema, id, true)?;
+
+        // Deleting a row that does not exist changes nothing
+        let mut record_cache = self.record_cache.borrow_mut();
+        let cached = record_cache.entry(record.clone()).or_insert(CachedRecord {
+            row: None,
+            is_dirty: false,
+        });
+        if cached.row.is_some() {
+            cached.row = None;
+            cached.is_dirty = true;
+            self.written.borrow_mut().insert(record);
+        }
+
+        Ok(())
+    }
+
+    // The record of a row the script touches, if its footprint allows it
+    fn touch(&self, schema: &TableSchema, id: i64, is_write: bool) -> Result<Record, ExecutorErr> {
+        let record = schema.record(id);
+        if let Some((touched, writes)) = &self.footprint {
+            let declared = match is_write {
+                true => writes,
+                false => touched,
+            };
+            if !declared.contains(&record) {
+                return Err(ProcedureErr::OutsideFootprint {
+                    procedure: self.procedure.name.clone(),
+                    table: schema.name.clone(),
+                    id,
+                }
+                .into());
+            }
+        }
+
+        Ok(record)
+    }
+
+    fn host_err(err: mlua::Error) -> ExecutorErr {
+        ExecutorErr::Storage(err.to_string())
+    }
+}
+
+fn lua_value<'lua>(lua: &'lua Lua, value: &ColumnValue) -> mlua::Result<mlua::Value<'lua>> {
+    Ok(match &value.value {
+        None => mlua::Value::Nil,
+        Some(Value::Integer(integer)) => mlua::Value::Integer(i64::from(*integer)),
+        Some(Value::Bigint(bigint)) => mlua::Value::Integer(*bigint),
+        Some(Value::Double(double)) => mlua::Value::Number(*double),
+        Some(Value::Boolean(boolean)) => mlua::Value::Boolean(*boolean),
+        Some(Value::Text(text)) => mlua::Value::String(lua.create_string(text)?),
+        Some(Value::Bytea(bytea)) => mlua::Value::String(lua.create_string(bytea)?),
+    })
+}
+
+// Lua integers are BIGINTs and Lua floats are DOUBLEs, which coerce like values read from other
+// columns. Lua strings are TEXT, or BYTEA for a BYTEA column.
+fn value_of(column: &Column, value: &mlua::Value) -> Result<ColumnValue, ExecutorErr> {
+    let mismatch = |value: String| CatalogErr::TypeMismatch {
+        column: column.name.clone(),
+        column_type: column.column_type,
+        value,
+    };
+
+    let value = match value {
+        mlua::Value::Nil => None,
+        mlua::Value::Integer(integer) => Some(Value::Bigint(*integer)),
+        mlua::Value::Number(number) => Some(Value::Double(*number)),
+        mlua::Value::Boolean(boolean) => Some(Value::Boolean(*boolean)),
+        mlua::Value::String(string) if column.column_type == ColumnType::Bytea => {
+            Some(Value::Bytea(string.as_bytes().to_vec()))
+        }
+        mlua::Value::String(string) => Some(Value::Text(
+            string
+                .to_str()
+                .map_err(|_| mismatch(string.to_string_lossy().into_owned()))?
+                .to_string(),
+        )),
+        value => return Err(mismatch(format!("a Lua {}", value.type_name())).into()),
+    };
+
+    Ok(column.coerce(ColumnValue { value })?)
+}
+
+// The type and value of what a script returns
+fn column_value(value: &mlua::Value) -> mlua::Result<Option<(ColumnType, ColumnValue)>> {
+    let (column_type, value) = match value {
+        mlua::Value::Nil => return Ok(None),
+        mlua::Value::Integer(integer) => (ColumnType::BigInt, Value::Bigint(*integer)),
+        mlua::Value::Number(number) => (ColumnType::Double, Value::Double(*number)),
+        mlua::Value::Boolean(boolean) => (ColumnType::Boolean, Value::Boolean(*boolean)),
+        mlua::Value::String(string) => {
+            (ColumnType::Text, Value::Text(string.to_str()?.to_string()))
+        }
+        value => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "a procedure cannot return a {}",
+                value.type_name()
+            )))
+        }
+    };
+
+    Ok(Some((column_type, ColumnValue { value: Some(value) })))
+}
@@ -0,0 +1,1128 @@
+use crate::calvinite_tonic::column_value::Value;
+use crate::calvinite_tonic::run_stmt_err::ErrorCode;
+use crate::calvinite_tonic::{ColumnValue, RecordStorage, TxnStamp};
+use crate::catalog::{CatalogErr, ColumnType, TableSchema};
+use prost::Message;
+use sqlparser::ast;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExprErr {
+    #[error("no such column: {0}")]
+    NoSuchColumn(String),
+    #[error("column reference {0} is ambiguous")]
+    AmbiguousColumn(String),
+    #[error("{0} is not supported in expressions")]
+    Unsupported(String),
+    #[error("{0} is not a valid number")]
+    InvalidNumber(String),
+    #[error("{left} and {right} cannot be compared")]
+    Incomparable { left: String, right: String },
+    #[error("{0} is not a boolean")]
+    NotBoolean(String),
+    #[error("{0} has operands of the wrong type")]
+    InvalidOperands(String),
+    #[error("{0} overflows")]
+    Overflow(String),
+    #[error("{0} divides by zero")]
+    DivisionByZero(String),
+    #[error("{function} takes {expected} arguments")]
+    WrongArgCount { function: String, expected: String },
+    #[error("no such function: {0}")]
+    NoSuchFunction(String),
+    #[error("aggregate {0} is only allowed in the select list, HAVING and ORDER BY of a query")]
+    MisplacedAggregate(String),
+}
+
+impl ExprErr {
+    /// The code a client is sent when a statement fails with this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ExprErr::NoSuchColumn(_) | ExprErr::NoSuchFunction(_) => ErrorCode::Notfound,
+            ExprErr::Incomparable { .. } | ExprErr::NotBoolean(_) | ExprErr::InvalidOperands(_) => {
+                ErrorCode::Mismatch
+            }
+            ExprErr::Unsupported(_)
+            | ExprErr::AmbiguousColumn(_)
+            | ExprErr::InvalidNumber(_)
+            | ExprErr::Overflow(_)
+            | ExprErr::DivisionByZero(_)
+            | ExprErr::WrongArgCount { .. }
+            | ExprErr::MisplacedAggregate(_) => ErrorCode::Error,
+        }
+    }
+}
+
+/// What the stamped functions of one statement of a txn are computed from, like NOW() or
+/// RANDOM(): the stamp of the txn and the position of the statement in it.
+///
+/// Rows are evaluated in no particular order, so random values are not drawn from one sequence
+/// for the whole statement. Every row draws its own values instead, computed from the row and
+/// from how many values it drew before. Rows that are alike still draw different values, and
+/// whichever of them draws first makes no difference.
+#[derive(Debug, Default)]
+pub struct StmtStamp {
+    txn_stamp: TxnStamp,
+    stmt_index: u64,
+    // How many random values were drawn for each row so far, by the hash of the row
+    draws: RefCell<HashMap<[u8; 16], u64>>,
+}
+
+impl StmtStamp {
+    pub fn new(txn_stamp: TxnStamp, stmt_index: usize) -> Self {
+        Self {
+            txn_stamp,
+            stmt_index: stmt_index as u64,
+            draws: RefCell::default(),
+        }
+    }
+}
+
+/// Evaluates an expression over a row of `schema`. Evaluation only depends on the expression, the
+/// row and the stamp of the statement, so every partition and replica computes the same value.
+pub fn eval(
+    expr: &ast::Expr,
+    schema: &TableSchema,
+    row: &RecordStorage,
+    stamp: &StmtStamp,
+) -> Result<ColumnValue, ExprErr> {
+    let value = match expr {
+        ast::Expr::Identifier(_) | ast::Expr::CompoundIdentifier(_) => {
+            return column_ref(expr, schema, row)
+        }
+        ast::Expr::Value(value) => return literal(value),
+        ast::Expr::Nested(expr) => return eval(expr, schema, row, stamp),
+        ast::Expr::IsNull(expr) => Some(Value::Boolean(
+            eval(expr, schema, row, stamp)?.value.is_none(),
+        )),
+        ast::Expr::IsNotNull(expr) => Some(Value::Boolean(
+            eval(expr, schema, row, stamp)?.value.is_some(),
+        )),
+        ast::Expr::UnaryOp { op, expr } => match (op, eval(expr, schema, row, stamp)?.value) {
+            (_, None) => None,
+            (ast::UnaryOperator::Not, Some(Value::Boolean(boolean))) => {
+                Some(Value::Boolean(!boolean))
+            }
+            (ast::UnaryOperator::Plus, Some(value)) if double(&value).is_some() => Some(value),
+            (ast::UnaryOperator::Minus, Some(Value::Integer(integer))) => Some(Value::Integer(
+                integer
+                    .checked_neg()
+                    .ok_or_else(|| ExprErr::Overflow(expr.to_string()))?,
+            )),
+            (ast::UnaryOperator::Minus, Some(Value::Bigint(bigint))) => Some(Value::Bigint(
+                bigint
+                    .checked_neg()
+                    .ok_or_else(|| ExprErr::Overflow(expr.to_string()))?,
+            )),
+            (ast::UnaryOperator::Minus, Some(Value::Double(double))) => {
+                Some(Value::Double(-double))
+            }
+            _ => return Err(ExprErr::Unsupported(expr.to_string())),
+        },
+        ast::Expr::BinaryOp { left, op, right } => match op {
+            ast::BinaryOperator::And => and(
+                to_bool(eval(left, schema, row, stamp)?, left)?,
+                to_bool(eval(right, schema, row, stamp)?, right)?,
+            )
+            .map(Value::Boolean),
+            ast::BinaryOperator::Or => or(
+                to_bool(eval(left, schema, row, stamp)?, left)?,
+                to_bool(eval(right, schema, row, stamp)?, right)?,
+            )
+            .map(Value::Boolean),
+            ast::BinaryOperator::Plus
+            | ast::BinaryOperator::Minus
+            | ast::BinaryOperator::Multiply
+            | ast::BinaryOperator::Divide
+            | ast::BinaryOperator::Modulo
+            | ast::BinaryOperator::StringConcat => {
+                return arithmetic(
+                    op,
+                    eval(left, schema, row, stamp)?,
+                    eval(right, schema, row, stamp)?,
+                    expr,
+                )
+            }
+            _ => {
+                let ordering = compare(
+                    &eval(left, schema, row, stamp)?,
+                    &eval(right, schema, row, stamp)?,
+                )?;
+                match ordering {
+                    Some(ordering) => Some(Value::Boolean(comparison(op, ordering, expr)?)),
+                    None => None,
+                }
+            }
+        },
+        ast::Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => {
+            let value = eval(expr, schema, row, stamp)?;
+            let is_between = and(
+                compare(&value, &eval(low, schema, row, stamp)?)?.map(|ordering| ordering.is_ge()),
+                compare(&value, &eval(high, schema, row, stamp)?)?.map(|ordering| ordering.is_le()),
+            );
+            is_between.map(|is_between| Value::Boolean(is_between != *negated))
+        }
+        ast::Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let value = eval(expr, schema, row, stamp)?;
+            // Like a chain of ORs: a NULL comparison makes a miss unknown
+            let mut is_in = Some(false);
+            for item in list {
+                let is_equal = compare(&value, &eval(item, schema, row, stamp)?)?
+                    .map(|ordering| ordering == Ordering::Equal);
+                is_in = or(is_in, is_equal);
+            }
+            is_in.map(|is_in| Value::Boolean(is_in != *negated))
+        }
+        ast::Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            return case(
+                operand.as_deref(),
+                conditions,
+                results,
+                else_result.as_deref(),
+                schema,
+                row,
+                stamp,
+            )
+        }
+        // Aggregates fold many rows, so callers compute them first and add their values to the row
+        // as columns named after the call
+        ast::Expr::Function(function) if is_aggregate(function) => {
+            return column(&expr.to_string(), schema, row)
+                .map_err(|_| ExprErr::MisplacedAggregate(expr.to_string()))
+        }
+        ast::Expr::Function(function) => return call(function, schema, row, stamp),
+        ast::Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+        } => {
+            let text = match to_text(eval(expr, schema, row, stamp)?, expr)? {
+                Some(text) => text,
+                None => return Ok(ColumnValue { value: None }),
+            };
+            let from = match substring_from {
+                Some(from) => to_integer(eval(from, schema, row, stamp)?, from)?,
+                None => Some(1),
+            };
+            let count = match substring_for {
+                Some(count) => to_integer(eval(count, schema, row, stamp)?, count)?,
+                None => Some(i64::MAX),
+            };
+            match (from, count) {
+                (Some(from), Some(count)) => Some(Value::Text(substring(&text, from, count))),
+                _ => None,
+            }
+        }
+        ast::Expr::Trim { expr, trim_where } => {
+            let text = to_text(eval(expr, schema, row, stamp)?, expr)?;
+            let (trim_where, chars) = match trim_where {
+                Some((trim_where, chars)) => (
+                    trim_where,
+                    to_text(eval(chars, schema, row, stamp)?, chars)?,
+                ),
+                None => (&ast::TrimWhereField::Both, Some(" ".to_string())),
+            };
+            match (text, chars) {
+                (Some(text), Some(chars)) => {
+                    let is_trimmed = |c: char| chars.contains(c);
+                    Some(Value::Text(match trim_where {
+                        ast::TrimWhereField::Both => text.trim_matches(is_trimmed).to_string(),
+                        ast::TrimWhereField::Leading => {
+                            text.trim_start_matches(is_trimmed).to_string()
+                        }
+                        ast::TrimWhereField::Trailing => {
+                            text.trim_end_matches(is_trimmed).to_string()
+                        }
+                    }))
+                }
+                _ => None,
+            }
+        }
+        // Subqueries read other rows, so callers evaluate them first and add their values to the
+        // row as columns named after the subquery
+        ast::Expr::Subquery(_) | ast::Expr::Exists(_) => {
+            return column(&expr.to_string(), schema, row)
+        }
+        _ => return Err(ExprErr::Unsupported(expr.to_string())),
+    };
+
+    Ok(ColumnValue { value })
+}
+
+/// The scalar and `EXISTS` subqueries `expr` evaluates, along with the query of each. Subqueries
+/// nested in another subquery are left out.
+pub fn subqueries(expr: &ast::Expr) -> Vec<(&ast::Expr, &ast::Query)> {
+    match expr {
+        ast::Expr::Subquery(query) | ast::Expr::Exists(query) => vec![(expr, query)],
+        _ => operands(expr).into_iter().flat_map(subqueries).collect(),
+    }
+}
+
+/// The expressions `expr` evaluates to compute its value, other than subqueries.
+pub fn operands(expr: &ast::Expr) -> Vec<&ast::Expr> {
+    match expr {
+        ast::Expr::Nested(expr)
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::IsNotNull(expr)
+        | ast::Expr::UnaryOp { expr, .. } => vec![expr],
+        ast::Expr::BinaryOp { left, right, .. } => vec![left, right],
+        ast::Expr::Between {
+            expr, low, high, ..
+        } => vec![expr, low, high],
+        ast::Expr::InList { expr, list, .. } => {
+            std::iter::once(expr.as_ref()).chain(list).collect()
+        }
+        ast::Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => operand
+            .iter()
+            .chain(else_result.iter())
+            .map(AsRef::as_ref)
+            .chain(conditions)
+            .chain(results)
+            .collect(),
+        ast::Expr::Function(function) => function
+            .args
+            .iter()
+            .filter_map(|arg| match arg {
+                ast::FunctionArg::Named {
+                    arg: ast::FunctionArgExpr::Expr(arg),
+                    ..
+                }
+                | ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(arg)) => Some(arg),
+                _ => None,
+            })
+            .collect(),
+        ast::Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+        } => std::iter::once(expr)
+            .chain(substring_from.iter())
+            .chain(substring_for.iter())
+            .map(AsRef::as_ref)
+            .collect(),
+        ast::Expr::Trim { expr, trim_where } => std::iter::once(expr)
+            .chain(trim_where.iter().map(|(_, chars)| chars))
+            .map(AsRef::as_ref)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Whether a call is to an aggregate, which is computed over a group of rows rather than a row.
+pub fn is_aggregate(function: &ast::Function) -> bool {
+    matches!(
+        function.name.to_string().to_uppercase().as_str(),
+        "COUNT" | "SUM" | "MIN" | "MAX" | "AVG"
+    )
+}
+
+/// Whether `expr` calls a function whose value is computed from the stamp of the txn, like NOW()
+/// or RANDOM().
+pub fn is_stamped(expr: &ast::Expr) -> bool {
+    match expr {
+        ast::Expr::Function(function) => {
+            matches!(
+                function.name.to_string().to_uppercase().as_str(),
+                "NOW" | "CURRENT_TIMESTAMP" | "CURRENT_DATE" | "RANDOM" | "GEN_RANDOM_UUID"
+            ) || operands(expr).into_iter().any(is_stamped)
+        }
+        _ => operands(expr).into_iter().any(is_stamped),
+    }
+}
+
+/// Whether a predicate holds for a row. NULL does not satisfy a predicate.
+pub fn is_true(
+    expr: &ast::Expr,
+    schema: &TableSchema,
+    row: &RecordStorage,
+    stamp: &StmtStamp,
+) -> Result<bool, ExprErr> {
+    Ok(to_bool(eval(expr, schema, row, stamp)?, expr)?.unwrap_or(false))
+}
+
+/// Whether a condition is violated for a row. Like a CHECK constraint, NULL does not violate it.
+pub fn is_false(
+    expr: &ast::Expr,
+    schema: &TableSchema,
+    row: &RecordStorage,
+    stamp: &StmtStamp,
+) -> Result<bool, ExprErr> {
+    Ok(!to_bool(eval(expr, schema, row, stamp)?, expr)?.unwrap_or(true))
+}
+
+fn column_ref(
+    expr: &ast::Expr,
+    schema: &TableSchema,
+    row: &RecordStorage,
+) -> Result<ColumnValue, ExprErr> {
+    let column_idx = schema.column_of(expr).map_err(|err| match err {
+        CatalogErr::AmbiguousColumn(column) => ExprErr::AmbiguousColumn(column),
+        _ => ExprErr::NoSuchColumn(expr.to_string()),
+    })?;
+    row.values
+        .get(column_idx)
+        .cloned()
+        .ok_or_else(|| ExprErr::NoSuchColumn(expr.to_string()))
+}
+
+fn column(name: &str, schema: &TableSchema, row: &RecordStorage) -> Result<ColumnValue, ExprErr> {
+    schema
+        .column_index(name)
+        .and_then(|column_idx| row.values.get(column_idx))
+        .cloned()
+        .ok_or_else(|| ExprErr::NoSuchColumn(name.to_string()))
+}
+
+/// The SQL literal of a value, which evaluates back to the value. Doubles keep their fraction or
+/// exponent, so they are not read back as integers.
+pub fn literal_of(value: &ColumnValue) -> ast::Expr {
+    let value = match &value.value {
+        None => ast::Value::Null,
+        Some(Value::Integer(integer)) => ast::Value::Number(integer.to_string(), false),
+        Some(Value::Bigint(bigint)) => ast::Value::Number(bigint.to_string(), false),
+        Some(Value::Double(double)) => ast::Value::Number(format!("{:?}", double), false),
+        Some(Value::Text(text)) => ast::Value::SingleQuotedString(text.clone()),
+        Some(Value::Boolean(boolean)) => ast::Value::Boolean(*boolean),
+        Some(Value::Bytea(bytea)) => {
+            ast::Value::HexStringLiteral(bytea.iter().map(|byte| format!("{:02X}", byte)).collect())
+        }
+    };
+
+    ast::Expr::Value(value)
+}
+
+fn literal(value: &ast::Value) -> Result<ColumnValue, ExprErr> {
+    let value = match value {
+        ast::Value::Number(number, _) => match number.parse() {
+            Ok(bigint) => Some(Value::Bigint(bigint)),
+            Err(_) => Some(Value::Double(
+                number
+                    .parse()
+                    .map_err(|_| ExprErr::InvalidNumber(number.clone()))?,
+            )),
+        },
+        ast::Value::SingleQuotedString(text) => Some(Value::Text(text.clone())),
+        ast::Value::HexStringLiteral(hex) => Some(Value::Bytea(
+            ColumnType::decode_hex(hex).ok_or_else(|| ExprErr::Unsupported(value.to_string()))?,
+        )),
+        ast::Value::Boolean(boolean) => Some(Value::Boolean(*boolean)),
+        ast::Value::Null => None,
+        _ => return Err(ExprErr::Unsupported(value.to_string())),
+    };
+
+    Ok(ColumnValue { value })
+}
+
+fn to_bool(value: ColumnValue, expr: &ast::Expr) -> Result<Option<bool>, ExprErr> {
+    match value.value {
+        Some(Value::Boolean(boolean)) => Ok(Some(boolean)),
+        None => Ok(None),
+        Some(_) => Err(ExprErr::NotBoolean(expr.to_string())),
+    }
+}
+
+fn to_text(value: ColumnValue, expr: &ast::Expr) -> Result<Option<String>, ExprErr> {
+    match value.value {
+        Some(Value::Text(text)) => Ok(Some(text)),
+        None => Ok(None),
+        Some(_) => Err(ExprErr::InvalidOperands(expr.to_string())),
+    }
+}
+
+fn to_integer(value: ColumnValue, expr: &ast::Expr) -> Result<Option<i64>, ExprErr> {
+    match value.value {
+        Some(value) => integer(&value)
+            .map(Some)
+            .ok_or_else(|| ExprErr::InvalidOperands(expr.to_string())),
+        None => Ok(None),
+    }
+}
+
+// Three valued logic: NULL is unknown, so it only decides the result if the other side does not
+fn and(left: Option<bool>, right: Option<bool>) -> Option<bool> {
+    match (left, right) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+fn or(left: Option<bool>, right: Option<bool>) -> Option<bool> {
+    match (left, right) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+// Two INTEGERs stay INTEGER, other integers widen to BIGINT and any DOUBLE makes the result a
+// DOUBLE. Integer overflow and division by zero are errors rather than platform dependent values.
+fn arithmetic(
+    op: &ast::BinaryOperator,
+    left: ColumnValue,
+    right: ColumnValue,
+    expr: &ast::Expr,
+) -> Result<ColumnValue, ExprErr> {
+    let (left, right) = match (left.value, right.value) {
+        (Some(left), Some(right)) => (left, right),
+        _ => return Ok(ColumnValue { value: None }),
+    };
+    let overflow = || ExprErr::Overflow(expr.to_string());
+    let division_by_zero = || ExprErr::DivisionByZero(expr.to_string());
+
+    let value = match (op, &left, &right) {
+        (ast::BinaryOperator::StringConcat, Value::Text(left), Value::Text(right)) => {
+            Value::Text(format!("{}{}", left, right))
+        }
+        (ast::BinaryOperator::StringConcat, _, _) => {
+            return Err(ExprErr::InvalidOperands(expr.to_string()))
+        }
+        (_, Value::Integer(left), Value::Integer(right)) => {
+            let (left, right) = (*left, *right);
+            Value::Integer(match op {
+                ast::BinaryOperator::Plus => left.checked_add(right).ok_or_else(overflow)?,
+                ast::BinaryOperator::Minus => left.checked_sub(right).ok_or_else(overflow)?,
+                ast::BinaryOperator::Multiply => left.checked_mul(right).ok_or_else(overflow)?,
+                _ if right == 0 => return Err(division_by_zero()),
+                ast::BinaryOperator::Divide => left.checked_div(right).ok_or_else(overflow)?,
+                _ => left.checked_rem(right).ok_or_else(overflow)?,
+            })
+        }
+        _ => match (integer(&left), integer(&right)) {
+            (Some(left), Some(right)) => Value::Bigint(match op {
+                ast::BinaryOperator::Plus => left.checked_add(right).ok_or_else(overflow)?,
+                ast::BinaryOperator::Minus => left.checked_sub(right).ok_or_else(overflow)?,
+                ast::BinaryOperator::Multiply => left.checked_mul(right).ok_or_else(overflow)?,
+                _ if right == 0 => return Err(division_by_zero()),
+                ast::BinaryOperator::Divide => left.checked_div(right).ok_or_else(overflow)?,
+                _ => left.checked_rem(right).ok_or_else(overflow)?,
+            }),
+            _ => match (double(&left), double(&right)) {
+                (Some(left), Some(right)) => Value::Double(match op {
+                    ast::BinaryOperator::Plus => left + right,
+                    ast::BinaryOperator::Minus => left - right,
+                    ast::BinaryOperator::Multiply => left * right,
+                    _ if right == 0.0 => return Err(division_by_zero()),
+                    ast::BinaryOperator::Divide => left / right,
+                    _ => left % right,
+                }),
+                _ => return Err(ExprErr::InvalidOperands(expr.to_string())),
+            },
+        },
+    };
+
+    Ok(ColumnValue { value: Some(value) })
+}
+
+// `CASE <operand> WHEN <value> ...` compares the operand with each value, `CASE WHEN
+// <condition> ...` takes the first condition that holds
+fn case(
+    operand: Option<&ast::Expr>,
+    conditions: &[ast::Expr],
+    results: &[ast::Expr],
+    else_result: Option<&ast::Expr>,
+    schema: &TableSchema,
+    row: &RecordStorage,
+    stamp: &StmtStamp,
+) -> Result<ColumnValue, ExprErr> {
+    let operand = operand
+        .map(|operand| eval(operand, schema, row, stamp))
+        .transpose()?;
+
+    for (condition, result) in conditions.iter().zip(results) {
+        let is_match = match &operand {
+            Some(operand) => {
+                compare(operand, &eval(condition, schema, row, stamp)?)? == Some(Ordering::Equal)
+            }
+            None => is_true(condition, schema, row, stamp)?,
+        };
+        if is_match {
+            return eval(result, schema, row, stamp);
+        }
+    }
+
+    match else_result {
+        Some(else_result) => eval(else_result, schema, row, stamp),
+        None => Ok(ColumnValue { value: None }),
+    }
+}
+
+fn call(
+    function: &ast::Function,
+    schema: &TableSchema,
+    row: &RecordStorage,
+    stamp: &StmtStamp,
+) -> Result<ColumnValue, ExprErr> {
+    let name = function.name.to_string().to_uppercase();
+    if function.over.is_some() || function.distinct {
+        return Err(ExprErr::Unsupported(function.to_string()));
+    }
+
+    let args = function
+        .args
+        .iter()
+        .map(|arg| match arg {
+            ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(arg)) => Ok(arg),
+            _ => Err(ExprErr::Unsupported(arg.to_string())),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let wrong_arg_count = |expected: &str| ExprErr::WrongArgCount {
+        function: name.clone(),
+        expected: expected.to_string(),
+    };
+
+    let value = match (name.as_str(), args.as_slice()) {
+        ("COALESCE", []) => return Err(wrong_arg_count("at least 1")),
+        ("COALESCE", args) => {
+            for arg in args {
+                let value = eval(arg, schema, row, stamp)?;
+                if value.value.is_some() {
+                    return Ok(value);
+                }
+            }
+            None
+        }
+        ("NULLIF", [left, right]) => {
+            let value = eval(left, schema, row, stamp)?;
+            match compare(&value, &eval(right, schema, row, stamp)?)? {
+                Some(Ordering::Equal) => None,
+                _ => value.value,
+            }
+        }
+        ("UPPER", [arg]) => to_text(eval(arg, schema, row, stamp)?, arg)?
+            .map(|text| Value::Text(text.to_uppercase())),
+        ("LOWER", [arg]) => to_text(eval(arg, schema, row, stamp)?, arg)?
+            .map(|text| Value::Text(text.to_lowercase())),
+        ("LENGTH", [arg]) => to_text(eval(arg, schema, row, stamp)?, arg)?
+            .map(|text| Value::Bigint(text.chars().count() as i64)),
+        // Numbers and booleans are written out and NULL arguments are skipped, like Postgres does
+        ("CONCAT", args) => {
+            let mut concatenated = String::new();
+            for arg in args {
+                match eval(arg, schema, row, stamp)?.value {
+                    Some(Value::Text(text)) => concatenated.push_str(&text),
+                    Some(Value::Integer(integer)) => concatenated.push_str(&integer.to_string()),
+                    Some(Value::Bigint(bigint)) => concatenated.push_str(&bigint.to_string()),
+                    Some(Value::Double(double)) => concatenated.push_str(&double.to_string()),
+                    Some(Value::Boolean(boolean)) => concatenated.push_str(&boolean.to_string()),
+                    Some(Value::Bytea(_)) => return Err(ExprErr::InvalidOperands(arg.to_string())),
+                    None => {}
+                }
+            }
+            Some(Value::Text(concatenated))
+        }
+        ("ABS", [arg]) => match eval(arg, schema, row, stamp)?.value {
+            None => None,
+            Some(Value::Integer(integer)) => Some(Value::Integer(
+                integer
+                    .checked_abs()
+                    .ok_or_else(|| ExprErr::Overflow(function.to_string()))?,
+            )),
+            Some(Value::Bigint(bigint)) => Some(Value::Bigint(
+                bigint
+                    .checked_abs()
+                    .ok_or_else(|| ExprErr::Overflow(function.to_string()))?,
+            )),
+            Some(Value::Double(double)) => Some(Value::Double(double.abs())),
+            Some(_) => return Err(ExprErr::InvalidOperands(function.to_string())),
+        },
+        ("NOW" | "CURRENT_TIMESTAMP", []) => {
+            Some(Value::Text(timestamp(stamp.txn_stamp.timestamp_micros)))
+        }
+        ("CURRENT_DATE", []) => Some(Value::Text(
+            timestamp(stamp.txn_stamp.timestamp_micros)[..10].to_string(),
+        )),
+        // Every call draws another value, see `StmtStamp`
+        ("RANDOM", []) => {
+            let bytes = random_bytes(function, schema, row, stamp);
+            let bits = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+            Some(Value::Double((bits >> 11) as f64 / (1u64 << 53) as f64))
+        }
+        // A version 4 UUID
+        ("GEN_RANDOM_UUID", []) => {
+            let mut bytes = random_bytes(function, schema, row, stamp);
+            bytes[6] = (bytes[6] & 0x0f) | 0x40;
+            bytes[8] = (bytes[8] & 0x3f) | 0x80;
+            Some(Value::Text(uuid::Uuid::from_bytes(bytes).to_string()))
+        }
+        ("NULLIF", _) => return Err(wrong_arg_count("2")),
+        ("NOW" | "CURRENT_TIMESTAMP" | "CURRENT_DATE" | "RANDOM" | "GEN_RANDOM_UUID", _) => {
+            return Err(wrong_arg_count("0"))
+        }
+        ("UPPER" | "LOWER" | "LENGTH" | "ABS", _) => return Err(wrong_arg_count("1")),
+        _ => return Err(ExprErr::NoSuchFunction(name)),
+    };
+
+    Ok(ColumnValue { value })
+}
+
+// Hashes the seed of the txn with the statement, the function and the row it is evaluated over,
+// and with how many values the row drew before, so that the values differ between calls, rows,
+// statements and txns
+fn random_bytes(
+    function: &ast::Function,
+    schema: &TableSchema,
+    row: &RecordStorage,
+    stamp: &StmtStamp,
+) -> [u8; 16] {
+    let mut input = stamp.txn_stamp.seed.to_le_bytes().to_vec();
+    input.extend(stamp.stmt_index.to_le_bytes());
+    input.extend(schema.name.as_bytes());
+    input.extend(function.to_string().as_bytes());
+    input.extend(row.encode_to_vec());
+
+    let mut draws = stamp.draws.borrow_mut();
+    let draw = draws.entry(md5::compute(&input).0).or_default();
+    input.extend(draw.to_le_bytes());
+    *draw += 1;
+
+    md5::compute(input).0
+}
+
+// Timestamps are TEXT in UTC, like `2022-03-01 12:30:00.000000+00`, so they sort in time order
+fn timestamp(micros: i64) -> String {
+    let seconds = micros.div_euclid(1_000_000);
+    let second_of_day = seconds.rem_euclid(86_400);
+
+    // Days since the epoch into a date of the proleptic Gregorian calendar, whose 400 year eras
+    // start on March 1st
+    let days = seconds.div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = match month_from_march {
+        0..=9 => month_from_march + 3,
+        _ => month_from_march - 9,
+    };
+    let year = era * 400 + year_of_era + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}+00",
+        year,
+        month,
+        day,
+        second_of_day / 3600,
+        second_of_day % 3600 / 60,
+        second_of_day % 60,
+        micros.rem_euclid(1_000_000)
+    )
+}
+
+// Characters are counted from 1, like SQL does. Starting before the text shortens the count.
+fn substring(text: &str, from: i64, count: i64) -> String {
+    let end = from.saturating_add(count.max(0));
+    let from = from.max(1);
+    if end <= from {
+        return String::new();
+    }
+
+    text.chars()
+        .skip((from - 1) as usize)
+        .take((end - from) as usize)
+        .collect()
+}
+
+/// Orders two values of comparable types. Integers of any width and doubles compare as numbers.
+/// Returns `None` if either value is NULL.
+pub fn compare(left: &ColumnValue, right: &ColumnValue) -> Result<Option<Ordering>, ExprErr> {
+    let ordering = match (&left.value, &right.value) {
+        (None, _) | (_, None) => return Ok(None),
+        (Some(Value::Text(left)), Some(Value::Text(right))) => left.cmp(right),
+        (Some(Value::Boolean(left)), Some(Value::Boolean(right))) => left.cmp(right),
+        (Some(Value::Bytea(left)), Some(Value::Bytea(right))) => left.cmp(right),
+        (Some(left_value), Some(right_value)) => {
+            match (integer(left_value), integer(right_value)) {
+                (Some(left), Some(right)) => left.cmp(&right),
+                _ => match (double(left_value), double(right_value)) {
+                    (Some(left), Some(right)) => left.total_cmp(&right),
+                    _ => {
+                        return Err(ExprErr::Incomparable {
+                            left: format!("{:?}", left_value),
+                            right: format!("{:?}", right_value),
+                        })
+                    }
+                },
+            }
+        }
+    };
+
+    Ok(Some(ordering))
+}
+
+fn integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::Integer(integer) => Some(*integer as i64),
+        Value::Bigint(bigint) => Some(*bigint),
+        _ => None,
+    }
+}
+
+fn double(value: &Value) -> Option<f64> {
+    match value {
+        Value::Double(double) => Some(*double),
+        _ => integer(value).map(|integer| integer as f64),
+    }
+}
+
+fn comparison(
+    op: &ast::BinaryOperator,
+    ordering: Ordering,
+    expr: &ast::Expr,
+) -> Result<bool, ExprErr> {
+    match op {
+        ast::BinaryOperator::Eq => Ok(ordering == Ordering::Equal),
+        ast::BinaryOperator::NotEq => Ok(ordering != Ordering::Equal),
+        ast::BinaryOperator::Lt => Ok(ordering == Ordering::Less),
+        ast::BinaryOperator::LtEq => Ok(ordering != Ordering::Greater),
+        ast::BinaryOperator::Gt => Ok(ordering == Ordering::Greater),
+        ast::BinaryOperator::GtEq => Ok(ordering != Ordering::Less),
+        _ => Err(ExprErr::Unsupported(expr.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::column_value::Value;
+    use crate::calvinite_tonic::{ColumnValue, RecordStorage, TxnStamp};
+    use crate::catalog::{Column, ColumnType, TableSchema};
+    use crate::expr::{eval, is_true, ExprErr, StmtStamp};
+    use sqlparser::ast;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn schema() -> TableSchema {
+        let column = |name: &str, column_type| Column {
+            name: name.to_string(),
+            column_type,
+            nullable: true,
+        };
+        TableSchema {
+            id: 0,
+            name: "foo".to_string(),
+            columns: vec![
+                column("id", ColumnType::BigInt),
+                column("val", ColumnType::Integer),
+                column("name", ColumnType::Text),
+                column("score", ColumnType::Double),
+            ],
+            primary_key: 0,
+            indexes: Vec::new(),
+            qualifiers: Vec::new(),
+        }
+    }
+
+    fn predicate(sql: &str) -> ast::Expr {
+        let select = format!("SELECT * FROM foo WHERE {}", sql);
+        match Parser::parse_sql(&GenericDialect {}, &select)
+            .unwrap()
+            .remove(0)
+        {
+            ast::Statement::Query(query) => match query.body {
+                ast::SetExpr::Select(select) => select.selection.unwrap(),
+                _ => panic!("not a SELECT"),
+            },
+            _ => panic!("not a query"),
+        }
+    }
+
+    #[test]
+    fn evaluates_predicates() {
+        let schema = schema();
+        let row = RecordStorage {
+            values: vec![
+                1i64.into(),
+                2i32.into(),
+                "two".into(),
+                ColumnValue { value: None },
+            ],
+        };
+
+        for (sql, expected) in [
+            ("val = 2", true),
+            ("val > 2", false),
+            ("val >= 2 AND name = 'two'", true),
+            ("foo.val < 1 OR NOT name <> 'two'", true),
+            ("val = 1.5", false),
+            ("val < -1", false),
+            ("(id = 1)", true),
+            ("score = 1", false),
+            ("score <> 1", false),
+            ("score IS NULL", true),
+            ("score IS NOT NULL OR val = 2", true),
+            ("score = 1 OR val = 2", true),
+            ("NOT (score = 1 AND val = 3)", true),
+        ] {
+            assert_eq!(
+                is_true(&predicate(sql), &schema, &row, &StmtStamp::default()).unwrap(),
+                expected,
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let schema = schema();
+        let row = RecordStorage {
+            values: vec![
+                1i64.into(),
+                2i32.into(),
+                " Two ".into(),
+                ColumnValue { value: None },
+            ],
+        };
+        let null = ColumnValue { value: None };
+
+        for (sql, expected) in [
+            ("val + 1", 3i64.into()),
+            ("val * val", 4i32.into()),
+            ("val * val - 1", 3i64.into()),
+            ("id * 7 / 2", 3i64.into()),
+            ("7 % val", 1i64.into()),
+            ("val / 4.0", 0.5.into()),
+            ("-val + +id", (-1i64).into()),
+            ("score + 1", null.clone()),
+            ("name || '!'", " Two !".into()),
+            ("val BETWEEN 1 AND 2", true.into()),
+            ("val NOT IN (1, 3)", true.into()),
+            ("val IN (1, score)", null.clone()),
+            (
+                "CASE val WHEN 1 THEN 'one' WHEN 2 THEN 'two' END",
+                "two".into(),
+            ),
+            (
+                "CASE WHEN score > 0 THEN 'pos' ELSE 'other' END",
+                "other".into(),
+            ),
+            ("CASE WHEN val > 5 THEN 1 END", null.clone()),
+            ("COALESCE(score, val, 0)", 2i32.into()),
+            ("NULLIF(val, 2)", null.clone()),
+            ("UPPER(TRIM(name))", "TWO".into()),
+            ("lower(name)", " two ".into()),
+            ("LENGTH(name)", 5i64.into()),
+            ("CONCAT(name, score, val, 'x')", " Two 2x".into()),
+            ("ABS(-val)", 2i32.into()),
+            ("SUBSTRING(name FROM 2 FOR 3)", "Two".into()),
+            ("TRIM(LEADING ' ' FROM name)", "Two ".into()),
+        ] {
+            assert_eq!(
+                eval(&predicate(sql), &schema, &row, &StmtStamp::default()).unwrap(),
+                expected,
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn evaluates_stamped_functions() {
+        let schema = schema();
+        let row = |id: i64| RecordStorage {
+            values: vec![
+                id.into(),
+                2i32.into(),
+                "two".into(),
+                ColumnValue { value: None },
+            ],
+        };
+        let stamp = TxnStamp {
+            timestamp_micros: 951_782_400_123_456,
+            seed: 7,
+        };
+        let eval_text = |sql: &str, row: &RecordStorage, stamp: &StmtStamp| match eval(
+            &predicate(sql),
+            &schema,
+            row,
+            stamp,
+        )
+        .unwrap()
+        .value
+        {
+            Some(Value::Text(text)) => text,
+            value => panic!("{} is {:?}", sql, value),
+        };
+
+        let stmt_stamp = |stamp: &TxnStamp, stmt_index| StmtStamp::new(stamp.clone(), stmt_index);
+
+        assert_eq!(
+            eval_text("NOW()", &row(1), &stmt_stamp(&stamp, 0)),
+            "2000-02-29 00:00:00.123456+00"
+        );
+        assert_eq!(
+            eval_text("CURRENT_TIMESTAMP", &row(1), &stmt_stamp(&stamp, 0)),
+            "2000-02-29 00:00:00.123456+00"
+        );
+        assert_eq!(
+            eval_text("CURRENT_DATE", &row(1), &stmt_stamp(&stamp, 0)),
+            "2000-02-29"
+        );
+        let before_epoch = TxnStamp {
+            timestamp_micros: -1,
+            seed: 7,
+        };
+        assert_eq!(
+            eval_text("NOW()", &row(1), &stmt_stamp(&before_epoch, 0)),
+            "1969-12-31 23:59:59.999999+00"
+        );
+
+        // Random values only depend on the seed, the statement, the row and the values it drew
+        let uuid = eval_text("GEN_RANDOM_UUID()", &row(1), &stmt_stamp(&stamp, 0));
+        assert_eq!(
+            uuid::Uuid::parse_str(&uuid).unwrap().get_version_num(),
+            4,
+            "{}",
+            uuid
+        );
+        assert_eq!(
+            eval_text("GEN_RANDOM_UUID()", &row(1), &stmt_stamp(&stamp, 0)),
+            uuid
+        );
+        assert_ne!(
+            eval_text("GEN_RANDOM_UUID()", &row(2), &stmt_stamp(&stamp, 0)),
+            uuid
+        );
+        assert_ne!(
+            eval_text("GEN_RANDOM_UUID()", &row(1), &stmt_stamp(&stamp, 1)),
+            uuid
+        );
+        let reseeded = TxnStamp {
+            seed: 8,
+            ..stamp.clone()
+        };
+        assert_ne!(
+            eval_text("GEN_RANDOM_UUID()", &row(1), &stmt_stamp(&reseeded, 0)),
+            uuid
+        );
+
+        // Every call draws another value, even over the same row
+        let drawn = stmt_stamp(&stamp, 0);
+        assert_eq!(eval_text("GEN_RANDOM_UUID()", &row(1), &drawn), uuid);
+        assert_ne!(eval_text("GEN_RANDOM_UUID()", &row(1), &drawn), uuid);
+
+        let random = |row: &RecordStorage, stamp: &StmtStamp| match eval(
+            &predicate("RANDOM()"),
+            &schema,
+            row,
+            stamp,
+        )
+        .unwrap()
+        .value
+        {
+            Some(Value::Double(random)) => random,
+            value => panic!("RANDOM() is {:?}", value),
+        };
+        let value = random(&row(1), &stmt_stamp(&stamp, 0));
+        assert!((0.0..1.0).contains(&value), "{}", value);
+        assert_eq!(random(&row(1), &stmt_stamp(&stamp, 0)), value);
+        assert_ne!(random(&row(2), &stmt_stamp(&stamp, 0)), value);
+        assert_ne!(random(&row(1), &stmt_stamp(&reseeded, 0)), value);
+        assert_eq!(
+            eval(
+                &predicate("RANDOM() = RANDOM()"),
+                &schema,
+                &row(1),
+                &stmt_stamp(&stamp, 0)
+            )
+            .unwrap(),
+            false.into()
+        );
+
+        assert!(matches!(
+            eval(
+                &predicate("NOW(1)"),
+                &schema,
+                &row(1),
+                &stmt_stamp(&stamp, 0)
+            ),
+            Err(ExprErr::WrongArgCount { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        let schema = schema();
+        let row = RecordStorage {
+            values: vec![i64::MAX.into(), 2i32.into(), "two".into(), 0.5.into()],
+        };
+
+        for (sql, is_expected_err) in [
+            (
+                "id + 1",
+                (|err| matches!(err, ExprErr::Overflow(_))) as fn(&ExprErr) -> bool,
+            ),
+            ("val / 0", |err| matches!(err, ExprErr::DivisionByZero(_))),
+            ("score % 0", |err| matches!(err, ExprErr::DivisionByZero(_))),
+            ("name + 1", |err| matches!(err, ExprErr::InvalidOperands(_))),
+            ("val || 'a'", |err| {
+                matches!(err, ExprErr::InvalidOperands(_))
+            }),
+            ("UPPER(val)", |err| {
+                matches!(err, ExprErr::InvalidOperands(_))
+            }),
+            ("UPPER(name, name)", |err| {
+                matches!(err, ExprErr::WrongArgCount { .. })
+            }),
+            ("COALESCE()", |err| {
+                matches!(err, ExprErr::WrongArgCount { .. })
+            }),
+            ("GREATEST(val)", |err| {
+                matches!(err, ExprErr::NoSuchFunction(_))
+            }),
+        ] {
+            let err = eval(&predicate(sql), &schema, &row, &StmtStamp::default()).unwrap_err();
+            assert!(is_expected_err(&err), "{}: {}", sql, err);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_predicates() {
+        let schema = schema();
+        let row = RecordStorage {
+            values: vec![1i64.into(), 2i32.into(), "two".into(), 0.5.into()],
+        };
+
+        assert!(matches!(
+            is_true(
+                &predicate("missing = 1"),
+                &schema,
+                &row,
+                &StmtStamp::default()
+            ),
+            Err(ExprErr::NoSuchColumn(_))
+        ));
+        assert!(matches!(
+            is_true(&predicate("name = 1"), &schema, &row, &StmtStamp::default()),
+            Err(ExprErr::Incomparable { .. })
+        ));
+        assert!(matches!(
+            is_true(&predicate("val"), &schema, &row, &StmtStamp::default()),
+            Err(ExprErr::NotBoolean(_))
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::calvinite_tonic::{ColumnValue, RecordStorage};
+use crate::catalog::TableId;
+use crate::common::Record;
+use crate::storage::{Keyspace, Storage, StorageErr, WriteBatch};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::mem;
+use std::ops::RangeInclusive;
+
+const ID_SIZE: usize = mem::size_of::<i64>();
+
+/// A secondary index on one column of a table, other than its primary key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Index {
+    /// Drawn from the same sequence as table ids, so the records an index locks belong to no
+    /// table.
+    pub id: TableId,
+    pub name: String,
+    pub table_id: TableId,
+    /// Index of the indexed column in the rows of the table.
+    pub column: usize,
+    /// A unique index holds every value at most once. Like in PostgreSQL, NULLs are not equal
+    /// to each other, so any number of rows can have one.
+    pub unique: bool,
+}
+
+impl Index {
+    /// The value of the indexed column of `row`. NULLs are not indexed.
+    pub fn value_of<'a>(&self, row: &'a RecordStorage) -> Option<&'a ColumnValue> {
+        row.values
+            .get(self.column)
+            .filter(|value| value.value.is_some())
+    }
+
+    /// The lock on the entries of `value`: a record of the index's own key space, keyed by a hash
+    /// of the value. Values whose hashes collide are locked together.
+    pub fn value_lock(&self, value: &ColumnValue) -> RangeInclusive<Record> {
+        let digest: [u8; 16] = md5::compute(value.encode_to_vec()).into();
+        let record = Record {
+            table_id: self.id,
+            id: i64::from_be_bytes(digest[..ID_SIZE].try_into().unwrap()),
+        };
+        record.clone()..=record
+    }
+
+    /// The lock on the entries of every value, for txns that cannot tell which values they write.
+    pub fn lock_all(&self) -> RangeInclusive<Record> {
+        Record {
+            table_id: self.id,
+            id: i64::MIN,
+        }..=Record {
+            table_id: self.id,
+            id: i64::MAX,
+        }
+    }
+
+    // The index id, then the length and encoding of the value, so no value's keys are a prefix of
+    // another's
+    fn prefix(&self, value: &ColumnValue) -> Vec<u8> {
+        let value = value.encode_to_vec();
+        [
+            self.id.to_be_bytes().as_slice(),
+            &(value.len() as u32).to_be_bytes(),
+            &value,
+        ]
+        .concat()
+    }
+
+    fn key(&self, value: &ColumnValue, record: &Record) -> Vec<u8> {
+        [self.prefix(value), record.id.to_be_bytes().to_vec()].concat()
+    }
+}
+
+/// An equality on an indexed column, resolved through the index rather than by scanning the
+/// table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexLookup {
+    pub index: Index,
+    pub value: ColumnValue,
+}
+
+impl IndexLookup {
+    pub fn lock(&self) -> RangeInclusive<Record> {
+        self.index.value_lock(&self.value)
+    }
+
+    /// Whether `row`, a row of the indexed table, is one this lookup finds.
+    pub fn matches(&self, row: &RecordStorage) -> bool {
+        self.index.value_of(row) == Some(&self.value)
+    }
+}
+
+/// The entries of every index, stored in their own key space next to the records. Every partition
+/// indexes the rows it stores, so an entry always lives with its row and is written in the same
+/// txn.
+#[derive(Debug, Clone)]
+pub struct IndexStore {
+    storage: Storage,
+}
+
+impl IndexStore {
+    pub fn open(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
+    }
+
+    /// The records stored here whose indexed column holds the value of `lookup`.
+    pub fn lookup(&self, lookup: &IndexLookup) -> Result<Vec<Record>, StorageErr> {
+        let prefix = lookup.index.prefix(&lookup.value);
+
+        Ok(self
+            .storage
+            .scan_prefix(Keyspace::Indexes, &prefix)?
+            .into_iter()
+            .map(|(key, _)| Record {
+                table_id: lookup.index.table_id,
+                id: i64::from_be_bytes(key[prefix.len()..].try_into().unwrap()),
+            })
+            .collect())
+    }
+
+    /// Adds to `batch` the entries of `indexes` that change when a record goes from `old_row` to
+    /// `new_row`. A missing row is one that does not exist.
+    pub fn update(
+        indexes: &[Index],
+        record: &Record,
+        old_row: Option<&RecordStorage>,
+        new_row: Option<&RecordStorage>,
+        batch: &mut WriteBatch,
+    ) {
+        for index in indexes {
+            let old_value = old_row.and_then(|row| index.value_of(row));
+            let new_value = new_row.and_then(|row| index.value_of(row));
+            if old_value == new_value {
+                continue;
+            }
+
+            if let Some(old_value) = old_value {
+                batch.delete(Keyspace::Indexes, index.key(old_value, record));
+            }
+            if let Some(new_value) = new_value {
+                batch.put(Keyspace::Indexes, index.key(new_value, record), Vec::new());
+            }
+        }
+    }
+
+    /// Removes every entry of `index`.
+    pub fn drop_index(&self, index: &Index) -> Result<(), StorageErr> {
+        let mut dropped = WriteBatch::default();
+        for (key, _) in self
+            .storage
+            .scan_prefix(Keyspace::Indexes, &index.id.to_be_bytes())?
+        {
+            dropped.delete(Keyspace::Indexes, key);
+        }
+
+        self.storage.apply(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::{ColumnValue, RecordStorage};
+    use crate::common::Record;
+    use crate::index::{Index, IndexLookup, IndexStore};
+    use crate::storage::{MemoryEngine, WriteBatch};
+
+    fn index(id: u32) -> Index {
+        Index {
+            id,
+            name: format!("idx_{}", id),
+            table_id: 0,
+            column: 1,
+            unique: false,
+        }
+    }
+
+    fn row(id: i64, val: Option<&str>) -> RecordStorage {
+        RecordStorage {
+            values: vec![
+                id.into(),
+                val.map_or(ColumnValue { value: None }, Into::into),
+            ],
+        }
+    }
+
+    fn lookup(index_store: &IndexStore, index: &Index, value: &str) -> Vec<i64> {
+        let mut ids: Vec<i64> = index_store
+            .lookup(&IndexLookup {
+                index: index.clone(),
+                value: value.into(),
+            })
+            .unwrap()
+            .into_iter()
+            .map(|record| record.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn keeps_entries_in_line_with_rows() {
+        let storage = MemoryEngine::shared();
+        let index_store = IndexStore::open(&storage);
+        let indexes = [index(1), index(2)];
+        let record = |id| Record { table_id: 0, id };
+
+        let mut entries = WriteBatch::default();
+        for (id, val) in [(1, Some("a")), (2, Some("a")), (3, Some("ab")), (4, None)] {
+            IndexStore::update(
+                &indexes,
+                &record(id),
+                None,
+                Some(&row(id, val)),
+                &mut entries,
+            );
+        }
+        storage.apply(entries).unwrap();
+
+        assert_eq!(lookup(&index_store, &indexes[0], "a"), vec![1, 2]);
+        assert_eq!(lookup(&index_store, &indexes[0], "ab"), vec![3]);
+        assert_eq!(lookup(&index_store, &indexes[1], "a"), vec![1, 2]);
+
+        let mut entries = WriteBatch::default();
+        IndexStore::update(
+            &indexes,
+            &record(1),
+            Some(&row(1, Some("a"))),
+            Some(&row(1, Some("b"))),
+            &mut entries,
+        );
+        IndexStore::update(
+            &indexes,
+            &record(2),
+            Some(&row(2, Some("a"))),
+            None,
+            &mut entries,
+        );
+        storage.apply(entries).unwrap();
+
+        assert_eq!(lookup(&index_store, &indexes[0], "a"), Vec::<i64>::new());
+        assert_eq!(lookup(&index_store, &indexes[0], "b"), vec![1]);
+
+        index_store.drop_index(&indexes[0]).unwrap();
+        assert_eq!(lookup(&index_store, &indexes[0], "b"), Vec::<i64>::new());
+        assert_eq!(lookup(&index_store, &indexes[1], "b"), vec![1]);
+    }
+
+    #[test]
+    fn locks_values_within_the_index() {
+        let (first, second) = (index(1), index(2));
+        let value: ColumnValue = "a".into();
+
+        assert_eq!(first.value_lock(&value), first.value_lock(&value));
+        assert!(first.lock_all().contains(first.value_lock(&value).start()));
+        assert!(!second.lock_all().contains(first.value_lock(&value).start()));
+    }
+}
@@ -1,11 +1,17 @@
 extern crate core;
 
+pub mod catalog;
 pub mod common;
 pub mod executor;
+pub mod expr;
+pub mod index;
+pub mod procedure;
+pub mod raft;
 pub mod scheduler;
 pub mod sequencer;
 pub mod stmt_analyzer;
+pub mod storage;
 
 pub mod calvinite_tonic {
     tonic::include_proto!("calvinite"); // The string specified here must match the proto package name
-}
\ No newline at end of file
+}
@@ -0,0 +1,123 @@
+use calvinite::calvinite_tonic::raft_grpc_service_server::RaftGrpcServiceServer;
+use calvinite::calvinite_tonic::sequencer_grpc_service_server::SequencerGrpcServiceServer;
+use calvinite::executor::Executor;
+use calvinite::raft::raft_log::RaftLog;
+use calvinite::raft::RaftNode;
+use calvinite::scheduler::Scheduler;
+use calvinite::sequencer::global_request_log::GlobalRequestLog;
+use calvinite::sequencer::request_log::RequestLog;
+use calvinite::sequencer::SequencerServer;
+use calvinite::storage::StorageConfig;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tonic::transport::Server;
+
+const USAGE: &str = "usage: calvinite --listen <host:port> [--peer <http://host:port>]... \
+    [--epoch-ms <ms>] [--engine <sled|memory|lsm>] [--data-dir <path>]";
+
+// Where a node keeps its rows, its request log and its Raft state within its data dir
+const STORAGE_DIR: &str = "storage";
+const REQUEST_LOG_DIR: &str = "requests";
+const RAFT_DIR: &str = "raft";
+
+// How many batches the sequencer may fall behind the global request log before appends wait
+const GLOBAL_REQ_LOG_CAPACITY: usize = 1024;
+
+/// Runs a single sequencer node. Nodes started with each other as `--peer`s form a Raft cluster
+/// that replicates the global request log, a node without peers keeps a durable request log of
+/// its own instead. Rows are stored with sled unless another `--engine` is given. Without a
+/// `--data-dir`, the node starts empty every time and must not rejoin its cluster once stopped.
+#[derive(Debug)]
+struct NodeConfig {
+    listen: SocketAddr,
+    peers: Vec<String>,
+    epoch_duration: Option<Duration>,
+    storage: StorageConfig,
+    data_dir: Option<PathBuf>,
+}
+
+impl NodeConfig {
+    fn from_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut listen = None;
+        let mut peers = Vec::new();
+        let mut epoch_duration = None;
+        let mut storage = StorageConfig::default();
+        let mut data_dir: Option<PathBuf> = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow::anyhow!(USAGE));
+            match arg.as_str() {
+                "--listen" => listen = Some(value()?.parse()?),
+                "--peer" => peers.push(value()?),
+                "--epoch-ms" => epoch_duration = Some(Duration::from_millis(value()?.parse()?)),
+                "--engine" => storage.engine = value()?.parse()?,
+                "--data-dir" => data_dir = Some(value()?.into()),
+                _ => return Err(anyhow::anyhow!(USAGE)),
+            }
+        }
+
+        storage.data_dir = data_dir.as_ref().map(|data_dir| data_dir.join(STORAGE_DIR));
+
+        Ok(Self {
+            listen: listen.ok_or_else(|| anyhow::anyhow!(USAGE))?,
+            peers,
+            epoch_duration,
+            storage,
+            data_dir,
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = NodeConfig::from_args(std::env::args().skip(1))?;
+
+    let global_req_log = GlobalRequestLog::new(GLOBAL_REQ_LOG_CAPACITY);
+
+    let raft_node = if config.peers.is_empty() {
+        None
+    } else {
+        let raft_node = RaftNode::new(
+            format!("http://{}", config.listen),
+            config.peers,
+            global_req_log.clone(),
+        );
+        Some(match &config.data_dir {
+            Some(data_dir) => raft_node.with_raft_log(RaftLog::open(data_dir.join(RAFT_DIR))?)?,
+            None => raft_node,
+        })
+    };
+
+    let sequencer_server = match config.epoch_duration {
+        Some(epoch_duration) => SequencerServer::new_with_epochs(global_req_log, epoch_duration),
+        None => SequencerServer::new(global_req_log),
+    };
+    let sequencer_server = match (&raft_node, &config.data_dir) {
+        (Some(raft_node), _) => sequencer_server.with_raft(raft_node.clone()),
+        (None, Some(data_dir)) => {
+            sequencer_server.with_request_log(RequestLog::open(data_dir.join(REQUEST_LOG_DIR))?)
+        }
+        (None, None) => sequencer_server,
+    };
+
+    // Requests that were logged but not applied before the node stopped run before any new one
+    let executor = Executor::open(&config.storage)?;
+    let mut sequencer = sequencer_server.build_sequencer(Scheduler::new(executor));
+    sequencer.replay().await?;
+    tokio::spawn(async move {
+        sequencer.serve().await;
+    });
+
+    if let Some(raft_node) = &raft_node {
+        raft_node.start();
+    }
+
+    Server::builder()
+        .add_optional_service(raft_node.map(RaftGrpcServiceServer::new))
+        .add_service(SequencerGrpcServiceServer::new(sequencer_server))
+        .serve(config.listen)
+        .await?;
+
+    Ok(())
+}
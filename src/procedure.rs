@@ -0,0 +1,376 @@
+use crate::calvinite_tonic::column_value::Value;
+use crate::calvinite_tonic::run_stmt_err::ErrorCode;
+use crate::calvinite_tonic::RecordStorage;
+use crate::catalog::{Catalog, CatalogErr, Column, ColumnType, TableSchema};
+use crate::common::Record;
+use crate::expr;
+use crate::expr::{ExprErr, StmtStamp};
+use serde::{Deserialize, Serialize};
+use sqlparser::ast;
+use sqlparser::dialect::GenericDialect;
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+/// The records a call reads, and the records it writes.
+pub type CallRecords = (Vec<Record>, Vec<Record>);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProcedureErr {
+    #[error(transparent)]
+    Catalog(#[from] CatalogErr),
+    #[error(transparent)]
+    Expr(#[from] ExprErr),
+    #[error("procedure {procedure} takes {expected} arguments but was given {actual}")]
+    WrongArgumentCount {
+        procedure: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("argument {0} is computed when the txn runs, after its call was analyzed")]
+    StampedArgument(String),
+    #[error("{0} is not a valid primary key")]
+    InvalidKey(String),
+    #[error("procedure {procedure} touched {table}({id}), which its footprint does not declare")]
+    OutsideFootprint {
+        procedure: String,
+        table: String,
+        id: i64,
+    },
+    #[error("invalid procedure {procedure}: {reason}")]
+    Invalid { procedure: String, reason: String },
+    #[error("procedure {procedure} failed: {reason}")]
+    Failed { procedure: String, reason: String },
+}
+
+impl ProcedureErr {
+    /// The code a client is sent when a call fails with this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProcedureErr::Catalog(err) => err.code(),
+            ProcedureErr::Expr(err) => err.code(),
+            ProcedureErr::WrongArgumentCount { .. } | ProcedureErr::InvalidKey(_) => {
+                ErrorCode::Mismatch
+            }
+            ProcedureErr::OutsideFootprint { .. } => ErrorCode::Misuse,
+            ProcedureErr::StampedArgument(_) | ProcedureErr::Invalid { .. } => ErrorCode::Error,
+            ProcedureErr::Failed { .. } => ErrorCode::Abort,
+        }
+    }
+}
+
+/// A Lua script stored in the catalog that a txn runs with `CALL <name>(<args>)`. The script
+/// reads and writes rows through host functions, so it can branch on what it reads, which plain
+/// SQL in one request cannot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Procedure {
+    pub name: String,
+    /// The names the script refers to its arguments by.
+    pub params: Vec<String>,
+    /// The records every call reads and writes, if the procedure declares them. Calls of a
+    /// procedure without a footprint are reconnoitered instead.
+    pub footprint: Option<Footprint>,
+    pub script: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Footprint {
+    pub reads: Vec<KeyRef>,
+    pub writes: Vec<KeyRef>,
+}
+
+/// A record of a footprint: a table, and the primary key of the record as an SQL expression
+/// over the parameters of the procedure.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyRef {
+    pub table: String,
+    pub key: String,
+}
+
+impl KeyRef {
+    /// The key expression, which was valid SQL when the procedure was created.
+    pub fn expr(&self) -> Result<ast::Expr, ParserError> {
+        let dialect = GenericDialect {};
+        Parser::new(Tokenizer::new(&dialect, &self.key).tokenize()?, &dialect).parse_expr()
+    }
+}
+
+/// A statement that creates or drops a procedure. Like any schema change, it must be the only
+/// statement of its request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcedureDdl {
+    Create {
+        procedure: Procedure,
+        or_replace: bool,
+    },
+    Drop {
+        name: String,
+        if_exists: bool,
+    },
+}
+
+impl ProcedureDdl {
+    /// Parses
+    ///
+    /// ```sql
+    /// CREATE [OR REPLACE] PROCEDURE <name> ([<param>, ...])
+    ///     [READS <table>(<key>), ...] [WRITES <table>(<key>), ...]
+    ///     AS '<script>'
+    /// ```
+    ///
+    /// and `DROP PROCEDURE [IF EXISTS] <name>`. Returns `None` if `sql` starts with any other
+    /// statement.
+    pub fn parse(sql: &str) -> Result<Option<Self>, ParserError> {
+        let dialect = GenericDialect {};
+        let mut parser = Parser::new(Tokenizer::new(&dialect, sql).tokenize()?, &dialect);
+
+        let ddl = if parser.parse_keyword(Keyword::CREATE) {
+            let or_replace = parser.parse_keywords(&[Keyword::OR, Keyword::REPLACE]);
+            if !parser.parse_keyword(Keyword::PROCEDURE) {
+                return Ok(None);
+            }
+            ProcedureDdl::Create {
+                procedure: Self::parse_procedure(&mut parser)?,
+                or_replace,
+            }
+        } else if parser.parse_keywords(&[Keyword::DROP, Keyword::PROCEDURE]) {
+            let if_exists = parser.parse_keywords(&[Keyword::IF, Keyword::EXISTS]);
+            ProcedureDdl::Drop {
+                name: parser.parse_identifier()?.value,
+                if_exists,
+            }
+        } else {
+            return Ok(None);
+        };
+
+        while parser.consume_token(&Token::SemiColon) {}
+        match parser.peek_token() {
+            Token::EOF => Ok(Some(ddl)),
+            _ => Err(ParserError::ParserError(
+                "a schema change must be the only statement of its request".to_string(),
+            )),
+        }
+    }
+
+    fn parse_procedure(parser: &mut Parser) -> Result<Procedure, ParserError> {
+        let name = parser.parse_identifier()?.value;
+
+        parser.expect_token(&Token::LParen)?;
+        let params = match parser.consume_token(&Token::RParen) {
+            true => Vec::new(),
+            false => {
+                let params = parser.parse_comma_separated(Parser::parse_identifier)?;
+                parser.expect_token(&Token::RParen)?;
+                params.into_iter().map(|param| param.value).collect()
+            }
+        };
+
+        let mut footprint = None;
+        if parser.parse_keyword(Keyword::READS) {
+            footprint
+                .get_or_insert_with(Footprint::default)
+                .reads
+                .extend(parser.parse_comma_separated(Self::parse_key_ref)?);
+        }
+        if Self::parse_word(parser, "WRITES") {
+            footprint
+                .get_or_insert_with(Footprint::default)
+                .writes
+                .extend(parser.parse_comma_separated(Self::parse_key_ref)?);
+        }
+
+        parser.expect_keyword(Keyword::AS)?;
+        let script = parser.parse_literal_string()?;
+
+        Ok(Procedure {
+            name,
+            params,
+            footprint,
+            script,
+        })
+    }
+
+    fn parse_key_ref(parser: &mut Parser) -> Result<KeyRef, ParserError> {
+        let table = parser.parse_identifier()?.value;
+        parser.expect_token(&Token::LParen)?;
+        let key = parser.parse_expr()?;
+        parser.expect_token(&Token::RParen)?;
+
+        Ok(KeyRef {
+            table,
+            key: key.to_string(),
+        })
+    }
+
+    // WRITES is no keyword of the SQL dialect
+    fn parse_word(parser: &mut Parser, word: &str) -> bool {
+        match parser.peek_token() {
+            Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word) => {
+                parser.next_token();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Procedure {
+    /// Evaluates the arguments of a call, one per parameter. Calls are analyzed before the txn is
+    /// stamped, so arguments cannot be computed from its stamp.
+    pub fn args_of(&self, exprs: &[ast::Expr]) -> Result<RecordStorage, ProcedureErr> {
+        if exprs.len() != self.params.len() {
+            return Err(ProcedureErr::WrongArgumentCount {
+                procedure: self.name.clone(),
+                expected: self.params.len(),
+                actual: exprs.len(),
+            });
+        }
+
+        if let Some(arg) = exprs.iter().find(|arg| expr::is_stamped(arg)) {
+            return Err(ProcedureErr::StampedArgument(arg.to_string()));
+        }
+
+        let no_columns = TableSchema::joined(&[]);
+        let values = exprs
+            .iter()
+            .map(|arg| {
+                expr::eval(
+                    arg,
+                    &no_columns,
+                    &RecordStorage::default(),
+                    &StmtStamp::default(),
+                )
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(RecordStorage { values })
+    }
+
+    /// The records a call with `args` reads and writes, if the procedure declares its footprint.
+    pub fn footprint_of(
+        &self,
+        catalog: &Catalog,
+        args: &RecordStorage,
+    ) -> Result<Option<CallRecords>, ProcedureErr> {
+        let footprint = match &self.footprint {
+            Some(footprint) => footprint,
+            None => return Ok(None),
+        };
+
+        // Keys are evaluated over a row with a column for each argument
+        let mut params = TableSchema::joined(&[]);
+        for (param, arg) in self.params.iter().zip(args.values.iter()) {
+            params.columns.push(Column {
+                name: param.clone(),
+                column_type: match arg.value {
+                    Some(Value::Integer(_)) => ColumnType::Integer,
+                    Some(Value::Double(_)) => ColumnType::Double,
+                    Some(Value::Text(_)) => ColumnType::Text,
+                    Some(Value::Boolean(_)) => ColumnType::Boolean,
+                    Some(Value::Bytea(_)) => ColumnType::Bytea,
+                    Some(Value::Bigint(_)) | None => ColumnType::BigInt,
+                },
+                nullable: true,
+            });
+        }
+
+        let records_of = |key_refs: &[KeyRef]| {
+            key_refs
+                .iter()
+                .map(|key_ref| {
+                    let schema = catalog.require_table(&key_ref.table)?;
+                    let key = key_ref
+                        .expr()
+                        .map_err(|_| ProcedureErr::InvalidKey(key_ref.key.clone()))?;
+                    let id = match expr::eval(&key, &params, args, &StmtStamp::default())?.value {
+                        Some(Value::Integer(id)) => i64::from(id),
+                        Some(Value::Bigint(id)) => id,
+                        _ => return Err(ProcedureErr::InvalidKey(key_ref.key.clone())),
+                    };
+                    Ok(schema.record(id))
+                })
+                .collect::<Result<Vec<_>, ProcedureErr>>()
+        };
+
+        Ok(Some((
+            records_of(&footprint.reads)?,
+            records_of(&footprint.writes)?,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::procedure::{Footprint, KeyRef, Procedure, ProcedureDdl};
+
+    #[test]
+    fn parses_procedure_ddl() {
+        assert_eq!(
+            ProcedureDdl::parse(
+                "CREATE OR REPLACE PROCEDURE transfer(src, dst) READS accounts(src) \
+                 WRITES accounts(src), accounts(dst + 1) AS 'return src';"
+            )
+            .unwrap(),
+            Some(ProcedureDdl::Create {
+                procedure: Procedure {
+                    name: "transfer".to_string(),
+                    params: vec!["src".to_string(), "dst".to_string()],
+                    footprint: Some(Footprint {
+                        reads: vec![KeyRef {
+                            table: "accounts".to_string(),
+                            key: "src".to_string(),
+                        }],
+                        writes: vec![
+                            KeyRef {
+                                table: "accounts".to_string(),
+                                key: "src".to_string(),
+                            },
+                            KeyRef {
+                                table: "accounts".to_string(),
+                                key: "dst + 1".to_string(),
+                            },
+                        ],
+                    }),
+                    script: "return src".to_string(),
+                },
+                or_replace: true,
+            })
+        );
+
+        assert_eq!(
+            ProcedureDdl::parse("CREATE PROCEDURE noop() AS ''").unwrap(),
+            Some(ProcedureDdl::Create {
+                procedure: Procedure {
+                    name: "noop".to_string(),
+                    params: Vec::new(),
+                    footprint: None,
+                    script: String::new(),
+                },
+                or_replace: false,
+            })
+        );
+        assert_eq!(
+            ProcedureDdl::parse("DROP PROCEDURE IF EXISTS noop").unwrap(),
+            Some(ProcedureDdl::Drop {
+                name: "noop".to_string(),
+                if_exists: true,
+            })
+        );
+
+        for sql in [
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY)",
+            "DROP TABLE foo",
+            "SELECT 1",
+        ] {
+            assert_eq!(ProcedureDdl::parse(sql).unwrap(), None, "{}", sql);
+        }
+        for sql in [
+            "CREATE PROCEDURE noop AS ''",
+            "CREATE PROCEDURE noop() WRITES accounts AS ''",
+            "CREATE PROCEDURE noop() AS return",
+            "DROP PROCEDURE noop; SELECT 1",
+        ] {
+            assert!(ProcedureDdl::parse(sql).is_err(), "{}", sql);
+        }
+    }
+}
@@ -0,0 +1,907 @@
+use crate::calvinite_tonic::raft_grpc_service_client::RaftGrpcServiceClient;
+use crate::calvinite_tonic::raft_grpc_service_server::RaftGrpcService;
+use crate::calvinite_tonic::{
+    AppendEntriesRequest, AppendEntriesResponse, EpochBatch, RaftEntry, RaftStatusRequest,
+    RaftStatusResponse, RequestVoteRequest, RequestVoteResponse,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use tonic::transport::{Channel, Endpoint};
+use tonic::Response;
+use uuid::Uuid;
+
+use crate::raft::raft_log::{HardState, RaftLog};
+use crate::sequencer::global_request_log::GlobalRequestLog;
+
+pub mod raft_log;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+const MIN_ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+const RPC_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+/// The sequencer id of every committed batch. The Raft log numbers the batches, whichever node
+/// proposed them, so every node numbers them alike.
+pub const SEQUENCER_ID: &str = "raft";
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum RaftErr {
+    #[error("not the leader, the current leader is {leader_id:?}")]
+    NotLeader { leader_id: Option<String> },
+    #[error("could not persist raft state: {0}")]
+    Storage(String),
+}
+
+impl From<RaftErr> for tonic::Status {
+    fn from(err: RaftErr) -> Self {
+        tonic::Status::unavailable(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug)]
+struct RaftState {
+    current_term: u64,
+    voted_for: Option<String>,
+    // The entry at log index i is stored at log[i - 1], so index 0 means "no entry"
+    log: Vec<RaftEntry>,
+    commit_index: u64,
+    last_applied: u64,
+    role: Role,
+    leader_id: Option<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    election_deadline: Instant,
+    // Committed batches are renumbered as they are applied, so every node numbers them the same
+    next_epoch: u64,
+    next_lsn: u64,
+    raft_log: Option<RaftLog>,
+}
+
+impl RaftState {
+    fn new() -> Self {
+        Self {
+            current_term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader_id: None,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_deadline: Self::random_election_deadline(),
+            next_epoch: 0,
+            next_lsn: 1,
+            raft_log: None,
+        }
+    }
+
+    fn random_election_deadline() -> Instant {
+        let jitter_ms = (Uuid::new_v4().as_u128() % MIN_ELECTION_TIMEOUT.as_millis()) as u64;
+        Instant::now() + MIN_ELECTION_TIMEOUT + Duration::from_millis(jitter_ms)
+    }
+
+    fn last_log_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            _ => self.log[index as usize - 1].term,
+        }
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.term_at(self.last_log_index())
+    }
+
+    // The term and vote are stored before any other node is told about them
+    fn set_term_and_vote(&mut self, term: u64, voted_for: Option<String>) -> Result<(), RaftErr> {
+        if let Some(raft_log) = &self.raft_log {
+            raft_log
+                .save_hard_state(&HardState {
+                    current_term: term,
+                    voted_for: voted_for.clone(),
+                })
+                .map_err(|err| RaftErr::Storage(err.to_string()))?;
+        }
+
+        self.current_term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+
+    fn append_to_log(&mut self, entries: Vec<RaftEntry>) -> Result<(), RaftErr> {
+        if let Some(raft_log) = &mut self.raft_log {
+            raft_log
+                .append(&entries)
+                .map_err(|err| RaftErr::Storage(err.to_string()))?;
+        }
+
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    // Drops every entry after the first `len` ones. Returns the batches of the dropped entries,
+    // which can never be committed.
+    fn truncate_log(&mut self, len: u64) -> Result<Vec<EpochBatch>, RaftErr> {
+        if let Some(raft_log) = &mut self.raft_log {
+            raft_log
+                .truncate(len as usize)
+                .map_err(|err| RaftErr::Storage(err.to_string()))?;
+        }
+
+        Ok(self
+            .log
+            .drain(len as usize..)
+            .flat_map(|entry| entry.batch)
+            .collect())
+    }
+
+    fn become_follower(&mut self, term: u64) -> Result<(), RaftErr> {
+        self.role = Role::Follower;
+        self.election_deadline = Self::random_election_deadline();
+
+        if term > self.current_term {
+            self.set_term_and_vote(term, None)?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, id: &str, peers: &[String]) -> Result<(), RaftErr> {
+        // Entries from earlier terms can only be committed behind an entry of the current term
+        self.append_to_log(vec![RaftEntry {
+            term: self.current_term,
+            batch: None,
+        }])?;
+
+        self.role = Role::Leader;
+        self.leader_id = Some(id.to_string());
+
+        for peer in peers.iter() {
+            self.next_index.insert(peer.clone(), self.last_log_index());
+            self.match_index.insert(peer.clone(), 0);
+        }
+        Ok(())
+    }
+
+    // Commits the highest entry of the current term that a majority of the cluster has stored
+    fn advance_commit_index(&mut self, cluster_size: usize) {
+        for index in ((self.commit_index + 1)..=self.last_log_index()).rev() {
+            if self.term_at(index) != self.current_term {
+                break;
+            }
+
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|match_index| **match_index >= index)
+                .count();
+
+            if replicas * 2 > cluster_size {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    // Takes the batches of the entries that were committed since the last call, numbered in log
+    // order
+    fn take_committed(&mut self) -> Vec<EpochBatch> {
+        let mut batches = Vec::new();
+
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+
+            let requests = match &self.log[self.last_applied as usize - 1].batch {
+                Some(batch) if !batch.requests.is_empty() => batch.requests.clone(),
+                _ => continue,
+            };
+
+            let batch = EpochBatch {
+                epoch: self.next_epoch,
+                first_lsn: self.next_lsn,
+                requests,
+                sequencer_id: SEQUENCER_ID.to_string(),
+            };
+
+            self.next_epoch += 1;
+            self.next_lsn += batch.requests.len() as u64;
+            batches.push(batch);
+        }
+
+        batches
+    }
+}
+
+/// One member of a Raft cluster that replicates the global request log.
+///
+/// Batches are proposed on the leader and, once committed, appended in log order to the local
+/// `global_req_log` that `Sequencer`s subscribe to. Proposed batches that a new leader overwrites
+/// are delivered to `subscribe_dropped` instead. Nodes are identified by their gRPC address.
+/// Without a `RaftLog`, Raft state is only kept in memory, so a node that is restarted must not
+/// rejoin its cluster under the same id.
+#[derive(Debug, Clone)]
+pub struct RaftNode {
+    id: String,
+    peers: Vec<String>,
+    peer_channels: HashMap<String, Channel>,
+    inner: Arc<Mutex<RaftState>>,
+    global_req_log: GlobalRequestLog,
+    dropped_txs: Arc<Mutex<Vec<mpsc::UnboundedSender<EpochBatch>>>>,
+    replicate_notify: Arc<Notify>,
+    commit_notify: Arc<Notify>,
+}
+
+impl RaftNode {
+    /// `peers` lists the addresses of every other member of the cluster.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn new(id: String, peers: Vec<String>, global_req_log: GlobalRequestLog) -> Self {
+        let peer_channels = peers
+            .iter()
+            .map(|peer| {
+                let channel = Endpoint::from_shared(peer.clone())
+                    .unwrap()
+                    .timeout(RPC_TIMEOUT)
+                    .connect_timeout(RPC_TIMEOUT)
+                    .connect_lazy();
+                (peer.clone(), channel)
+            })
+            .collect();
+
+        Self {
+            id,
+            peers,
+            peer_channels,
+            inner: Arc::new(Mutex::new(RaftState::new())),
+            global_req_log,
+            dropped_txs: Arc::new(Mutex::new(Vec::new())),
+            replicate_notify: Arc::new(Notify::new()),
+            commit_notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Keeps the term, vote and log of this node in `raft_log`, starting from what it stored
+    /// before the node stopped. Must be called before `start`.
+    pub fn with_raft_log(self, raft_log: RaftLog) -> anyhow::Result<Self> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let hard_state = raft_log.hard_state()?;
+            inner.current_term = hard_state.current_term;
+            inner.voted_for = hard_state.voted_for;
+            inner.log = raft_log.entries()?;
+            inner.raft_log = Some(raft_log);
+        }
+
+        Ok(self)
+    }
+
+    /// Skips the batches at the start of the log that were applied up to and including
+    /// `last_applied_lsn` before the node stopped. They are not delivered again, and the batches
+    /// after them are numbered as they were the first time. Must be called before `start`.
+    pub fn skip_applied(&self, last_applied_lsn: u64) {
+        let mut inner = self.inner.lock().unwrap();
+
+        for index in (inner.last_applied + 1)..=inner.last_log_index() {
+            let batch_len = match &inner.log[index as usize - 1].batch {
+                Some(batch) if !batch.requests.is_empty() => batch.requests.len() as u64,
+                _ => continue,
+            };
+            if inner.next_lsn + batch_len - 1 > last_applied_lsn {
+                break;
+            }
+
+            // Only committed batches were ever applied
+            inner.last_applied = index;
+            inner.commit_index = inner.commit_index.max(index);
+            inner.next_epoch += 1;
+            inner.next_lsn += batch_len;
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn global_req_log(&self) -> GlobalRequestLog {
+        self.global_req_log.clone()
+    }
+
+    /// Receives every proposed batch that was overwritten and will never be committed.
+    pub fn subscribe_dropped(&self) -> mpsc::UnboundedReceiver<EpochBatch> {
+        let (dropped_tx, dropped_rx) = mpsc::unbounded_channel();
+        self.dropped_txs.lock().unwrap().push(dropped_tx);
+        dropped_rx
+    }
+
+    pub fn leader_id(&self) -> Option<String> {
+        self.inner.lock().unwrap().leader_id.clone()
+    }
+
+    /// Starts the background tasks that run elections, replicate the log and append committed
+    /// batches to the global request log.
+    pub fn start(&self) {
+        let raft_node = self.clone();
+        tokio::spawn(async move { raft_node.append_committed().await });
+
+        let raft_node = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => {}
+                    _ = raft_node.replicate_notify.notified() => {}
+                }
+
+                let (role, election_timed_out) = {
+                    let inner = raft_node.inner.lock().unwrap();
+                    (inner.role, Instant::now() >= inner.election_deadline)
+                };
+
+                match role {
+                    Role::Leader => raft_node.replicate_to_peers(),
+                    _ if election_timed_out => raft_node.run_election().await,
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    /// Appends a batch to the log. Only the leader accepts proposals.
+    pub fn propose(&self, batch: EpochBatch) -> Result<(), RaftErr> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+
+            if inner.role != Role::Leader {
+                return Err(RaftErr::NotLeader {
+                    leader_id: inner.leader_id.clone(),
+                });
+            }
+
+            let term = inner.current_term;
+            inner.append_to_log(vec![RaftEntry {
+                term,
+                batch: Some(batch),
+            }])?;
+
+            inner.advance_commit_index(self.cluster_size());
+        }
+
+        self.commit_notify.notify_one();
+        self.replicate_notify.notify_one();
+        Ok(())
+    }
+
+    // Appends every committed batch to the global request log, waiting for room whenever the
+    // sequencers fall behind. Committed entries stay in the Raft log until then.
+    async fn append_committed(&self) {
+        loop {
+            self.commit_notify.notified().await;
+
+            let batches = self.inner.lock().unwrap().take_committed();
+            for batch in batches {
+                // Nobody may be subscribed yet, which is fine since the log is kept
+                let _ = self.global_req_log.append(batch).await;
+            }
+        }
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.peers.len() + 1
+    }
+
+    fn client(&self, peer: &str) -> RaftGrpcServiceClient<Channel> {
+        RaftGrpcServiceClient::new(self.peer_channels[peer].clone())
+    }
+
+    async fn run_election(&self) {
+        let req = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.election_deadline = RaftState::random_election_deadline();
+
+            let term = inner.current_term + 1;
+            if let Err(err) = inner.set_term_and_vote(term, Some(self.id.clone())) {
+                eprintln!("Failed to start an election: {}", err);
+                return;
+            }
+            inner.role = Role::Candidate;
+            inner.leader_id = None;
+
+            RequestVoteRequest {
+                term: inner.current_term,
+                candidate_id: self.id.clone(),
+                last_log_index: inner.last_log_index(),
+                last_log_term: inner.last_log_term(),
+            }
+        };
+
+        let handles: Vec<_> = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let mut client = self.client(peer);
+                let req = req.clone();
+                tokio::spawn(async move { client.request_vote(req).await })
+            })
+            .collect();
+
+        let mut votes = 1;
+        for handle in handles {
+            if let Ok(Ok(res)) = handle.await {
+                let res = res.into_inner();
+                if res.term > req.term {
+                    if let Err(err) = self.inner.lock().unwrap().become_follower(res.term) {
+                        eprintln!("Failed to follow a newer term: {}", err);
+                    }
+                    return;
+                }
+                if res.vote_granted {
+                    votes += 1;
+                }
+            }
+        }
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let still_candidate = inner.role == Role::Candidate && inner.current_term == req.term;
+
+            if !still_candidate || votes * 2 <= self.cluster_size() {
+                return;
+            }
+
+            if let Err(err) = inner.become_leader(&self.id, &self.peers) {
+                eprintln!("Failed to become the leader: {}", err);
+                return;
+            }
+            inner.advance_commit_index(self.cluster_size());
+        }
+
+        self.commit_notify.notify_one();
+        self.replicate_to_peers();
+    }
+
+    fn replicate_to_peers(&self) {
+        for peer in self.peers.iter() {
+            let raft_node = self.clone();
+            let peer = peer.clone();
+            tokio::spawn(async move { raft_node.replicate_to(&peer).await });
+        }
+    }
+
+    async fn replicate_to(&self, peer: &str) {
+        let req = {
+            let inner = self.inner.lock().unwrap();
+            if inner.role != Role::Leader {
+                return;
+            }
+
+            let prev_log_index = inner.next_index[peer] - 1;
+            let entries: Vec<RaftEntry> = inner.log[prev_log_index as usize..]
+                .iter()
+                .take(MAX_ENTRIES_PER_APPEND)
+                .cloned()
+                .collect();
+
+            AppendEntriesRequest {
+                term: inner.current_term,
+                leader_id: self.id.clone(),
+                prev_log_index,
+                prev_log_term: inner.term_at(prev_log_index),
+                entries,
+                leader_commit: inner.commit_index,
+            }
+        };
+
+        let res = match self.client(peer).append_entries(req.clone()).await {
+            Ok(res) => res.into_inner(),
+            Err(_) => return,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+
+        if res.term > inner.current_term {
+            if let Err(err) = inner.become_follower(res.term) {
+                eprintln!("Failed to follow a newer term: {}", err);
+            }
+            return;
+        }
+
+        if inner.role != Role::Leader || inner.current_term != req.term {
+            return;
+        }
+
+        if res.success {
+            // Responses can arrive out of order, so never move match_index backwards
+            let match_index = inner.match_index[peer].max(res.match_index);
+            inner.match_index.insert(peer.to_string(), match_index);
+            inner.next_index.insert(peer.to_string(), match_index + 1);
+
+            inner.advance_commit_index(self.cluster_size());
+            self.commit_notify.notify_one();
+
+            if match_index < inner.last_log_index() {
+                self.replicate_notify.notify_one();
+            }
+        } else {
+            let next_index = inner.next_index[peer].min(res.match_index + 1).max(1);
+            inner.next_index.insert(peer.to_string(), next_index);
+            self.replicate_notify.notify_one();
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RaftGrpcService for RaftNode {
+    async fn request_vote(
+        &self,
+        request: tonic::Request<RequestVoteRequest>,
+    ) -> Result<tonic::Response<RequestVoteResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let mut inner = self.inner.lock().unwrap();
+
+        if req.term > inner.current_term {
+            inner.become_follower(req.term)?;
+        }
+
+        let candidate_is_up_to_date = (req.last_log_term, req.last_log_index)
+            >= (inner.last_log_term(), inner.last_log_index());
+        let can_vote_for_candidate = match &inner.voted_for {
+            None => true,
+            Some(voted_for) => *voted_for == req.candidate_id,
+        };
+
+        let vote_granted =
+            req.term == inner.current_term && candidate_is_up_to_date && can_vote_for_candidate;
+
+        if vote_granted {
+            inner.set_term_and_vote(req.term, Some(req.candidate_id))?;
+            inner.election_deadline = RaftState::random_election_deadline();
+        }
+
+        Ok(Response::new(RequestVoteResponse {
+            term: inner.current_term,
+            vote_granted,
+        }))
+    }
+
+    async fn append_entries(
+        &self,
+        request: tonic::Request<AppendEntriesRequest>,
+    ) -> Result<tonic::Response<AppendEntriesResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let mut inner = self.inner.lock().unwrap();
+
+        if req.term < inner.current_term {
+            return Ok(Response::new(AppendEntriesResponse {
+                term: inner.current_term,
+                success: false,
+                match_index: 0,
+            }));
+        }
+
+        inner.become_follower(req.term)?;
+        inner.leader_id = Some(req.leader_id);
+
+        // Our log has to contain the leader's previous entry before we can append after it
+        if req.prev_log_index > inner.last_log_index() {
+            return Ok(Response::new(AppendEntriesResponse {
+                term: inner.current_term,
+                success: false,
+                match_index: inner.last_log_index(),
+            }));
+        }
+
+        if inner.term_at(req.prev_log_index) != req.prev_log_term {
+            // Committed entries always match, so the leader can safely retry from there
+            return Ok(Response::new(AppendEntriesResponse {
+                term: inner.current_term,
+                success: false,
+                match_index: inner.commit_index.min(req.prev_log_index - 1),
+            }));
+        }
+
+        let last_new_index = req.prev_log_index + req.entries.len() as u64;
+
+        let mut new_entries = Vec::new();
+        for (offset, entry) in req.entries.into_iter().enumerate() {
+            let index = req.prev_log_index + 1 + offset as u64;
+            if index <= inner.last_log_index() {
+                if inner.term_at(index) == entry.term {
+                    continue;
+                }
+
+                // Clients of the overwritten proposals would otherwise wait for them forever
+                let dropped = inner.truncate_log(index - 1)?;
+                self.dropped_txs.lock().unwrap().retain(|dropped_tx| {
+                    dropped
+                        .iter()
+                        .all(|batch| dropped_tx.send(batch.clone()).is_ok())
+                });
+            }
+            new_entries.push(entry);
+        }
+        // Stored before the leader is told, since it counts them towards a majority
+        inner.append_to_log(new_entries)?;
+
+        if req.leader_commit > inner.commit_index {
+            inner.commit_index = req.leader_commit.min(last_new_index);
+            self.commit_notify.notify_one();
+        }
+
+        Ok(Response::new(AppendEntriesResponse {
+            term: inner.current_term,
+            success: true,
+            match_index: last_new_index,
+        }))
+    }
+
+    async fn status(
+        &self,
+        _request: tonic::Request<RaftStatusRequest>,
+    ) -> Result<tonic::Response<RaftStatusResponse>, tonic::Status> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(Response::new(RaftStatusResponse {
+            id: self.id.clone(),
+            term: inner.current_term,
+            leader_id: inner.leader_id.clone().unwrap_or_default(),
+            commit_index: inner.commit_index,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::raft_grpc_service_server::{
+        RaftGrpcService, RaftGrpcServiceServer,
+    };
+    use crate::calvinite_tonic::{
+        AppendEntriesRequest, EpochBatch, RaftEntry, RequestVoteRequest, RunStmtRequestWithUuid,
+    };
+    use crate::raft::raft_log::RaftLog;
+    use crate::raft::{RaftErr, RaftNode};
+    use crate::sequencer::global_request_log::GlobalRequestLog;
+    use std::path::Path;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tonic::transport::Server;
+    use tonic::Request;
+
+    fn batch(query: &str) -> EpochBatch {
+        EpochBatch {
+            epoch: 0,
+            requests: vec![RunStmtRequestWithUuid {
+                query: query.to_string(),
+                uuid: uuid::Uuid::new_v4().to_string(),
+                repartition: None,
+                reconnaissance: None,
+                stamp: None,
+            }],
+            first_lsn: 0,
+            sequencer_id: String::new(),
+        }
+    }
+
+    async fn start_cluster(size: usize) -> Vec<(RaftNode, mpsc::Receiver<EpochBatch>)> {
+        let mut listeners = Vec::new();
+        for _ in 0..size {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+
+        let addresses: Vec<String> = listeners
+            .iter()
+            .map(|listener| format!("http://{}", listener.local_addr().unwrap()))
+            .collect();
+
+        let mut nodes = Vec::new();
+        for (listener, address) in listeners.into_iter().zip(addresses.iter()) {
+            let peers = addresses
+                .iter()
+                .filter(|peer| *peer != address)
+                .cloned()
+                .collect();
+            let global_req_log = GlobalRequestLog::new(16);
+            let applied_rx = global_req_log.subscribe();
+            let raft_node = RaftNode::new(address.clone(), peers, global_req_log);
+
+            let raft_service = raft_node.clone();
+            let listener_stream = tokio_stream::wrappers::TcpListenerStream::new(listener);
+            tokio::spawn(async move {
+                Server::builder()
+                    .add_service(RaftGrpcServiceServer::new(raft_service))
+                    .serve_with_incoming(listener_stream)
+                    .await
+                    .unwrap();
+            });
+
+            raft_node.start();
+            nodes.push((raft_node, applied_rx));
+        }
+
+        nodes
+    }
+
+    async fn wait_for_leader(nodes: &[(RaftNode, mpsc::Receiver<EpochBatch>)]) -> usize {
+        loop {
+            for (idx, (node, _)) in nodes.iter().enumerate() {
+                if node.leader_id().as_deref() == Some(node.id()) {
+                    return idx;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn single_node_commits_immediately() {
+        let mut nodes = start_cluster(1).await;
+        wait_for_leader(&nodes).await;
+
+        nodes[0].0.propose(batch("SELECT 1")).unwrap();
+
+        let applied = nodes[0].1.recv().await.unwrap();
+        assert_eq!(applied.epoch, 0);
+        assert_eq!(applied.first_lsn, 1);
+        assert_eq!(applied.requests[0].query, "SELECT 1");
+    }
+
+    #[tokio::test]
+    async fn committed_entries_are_applied_in_order_on_every_node() {
+        let mut nodes = start_cluster(3).await;
+        let leader_idx = wait_for_leader(&nodes).await;
+
+        let follower_idx = (leader_idx + 1) % nodes.len();
+        assert!(matches!(
+            nodes[follower_idx].0.propose(batch("SELECT 0")),
+            Err(RaftErr::NotLeader { .. })
+        ));
+
+        let queries = ["SELECT 1", "SELECT 2", "SELECT 3"];
+        for query in queries {
+            nodes[leader_idx].0.propose(batch(query)).unwrap();
+        }
+
+        for (_, applied_rx) in nodes.iter_mut() {
+            for (epoch, query) in queries.iter().enumerate() {
+                let applied = tokio::time::timeout(Duration::from_secs(5), applied_rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(applied.epoch, epoch as u64);
+                assert_eq!(applied.requests[0].query, *query);
+            }
+        }
+    }
+
+    fn lone_node(raft_log_dir: Option<&Path>) -> RaftNode {
+        let raft_node = RaftNode::new(
+            "http://127.0.0.1:1".into(),
+            vec![],
+            GlobalRequestLog::new(16),
+        );
+        match raft_log_dir {
+            Some(raft_log_dir) => raft_node
+                .with_raft_log(RaftLog::open(raft_log_dir).unwrap())
+                .unwrap(),
+            None => raft_node,
+        }
+    }
+
+    fn append_request(term: u64, batches: Vec<EpochBatch>) -> Request<AppendEntriesRequest> {
+        Request::new(AppendEntriesRequest {
+            term,
+            leader_id: "http://127.0.0.1:2".into(),
+            prev_log_index: 0,
+            prev_log_term: 0,
+            entries: batches
+                .into_iter()
+                .map(|batch| RaftEntry {
+                    term,
+                    batch: Some(batch),
+                })
+                .collect(),
+            leader_commit: 0,
+        })
+    }
+
+    async fn vote_granted(raft_node: &RaftNode, term: u64, candidate_id: &str) -> bool {
+        let req = RequestVoteRequest {
+            term,
+            candidate_id: candidate_id.to_string(),
+            last_log_index: 1,
+            last_log_term: 2,
+        };
+        let res = raft_node.request_vote(Request::new(req)).await.unwrap();
+        res.into_inner().vote_granted
+    }
+
+    #[tokio::test]
+    async fn restarted_node_keeps_its_term_vote_and_log() {
+        let raft_log_dir = tempfile::tempdir().unwrap();
+
+        {
+            let raft_node = lone_node(Some(raft_log_dir.path()));
+            raft_node
+                .append_entries(append_request(2, vec![batch("SELECT 1")]))
+                .await
+                .unwrap();
+            assert!(vote_granted(&raft_node, 3, "http://127.0.0.1:3").await);
+        }
+
+        // Another candidate of the same term must not win this node's vote as well
+        let raft_node = lone_node(Some(raft_log_dir.path()));
+        assert!(!vote_granted(&raft_node, 3, "http://127.0.0.1:4").await);
+
+        let status = raft_node
+            .status(Request::new(Default::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.term, 3);
+        assert_eq!(raft_node.inner.lock().unwrap().last_log_term(), 2);
+    }
+
+    #[tokio::test]
+    async fn restarted_node_skips_the_batches_it_applied() {
+        let raft_log_dir = tempfile::tempdir().unwrap();
+        let batches = vec![batch("SELECT 1"), batch("SELECT 2"), batch("SELECT 3")];
+
+        {
+            let raft_node = lone_node(Some(raft_log_dir.path()));
+            raft_node
+                .append_entries(append_request(1, batches.clone()))
+                .await
+                .unwrap();
+        }
+
+        let global_req_log = GlobalRequestLog::new(16);
+        let mut applied_rx = global_req_log.subscribe();
+        let raft_node = RaftNode::new("http://127.0.0.1:1".into(), vec![], global_req_log)
+            .with_raft_log(RaftLog::open(raft_log_dir.path()).unwrap())
+            .unwrap();
+        raft_node.skip_applied(2);
+        raft_node.start();
+
+        let mut commit_request = append_request(1, vec![]);
+        commit_request.get_mut().prev_log_index = 3;
+        commit_request.get_mut().prev_log_term = 1;
+        commit_request.get_mut().leader_commit = 3;
+        raft_node.append_entries(commit_request).await.unwrap();
+
+        let applied = applied_rx.recv().await.unwrap();
+        assert_eq!(applied.epoch, 2);
+        assert_eq!(applied.first_lsn, 3);
+        assert_eq!(applied.requests, batches[2].requests);
+        assert!(applied_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn overwritten_proposals_are_dropped() {
+        let raft_node = lone_node(None);
+        let mut dropped_rx = raft_node.subscribe_dropped();
+
+        let overwritten = batch("SELECT 1");
+        raft_node
+            .append_entries(append_request(1, vec![overwritten.clone()]))
+            .await
+            .unwrap();
+        raft_node
+            .append_entries(append_request(2, vec![batch("SELECT 2")]))
+            .await
+            .unwrap();
+
+        assert_eq!(dropped_rx.try_recv().unwrap(), overwritten);
+        assert!(dropped_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::calvinite_tonic::RaftEntry;
+use anyhow::anyhow;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+const LOG_FILE_NAME: &str = "raft.log";
+const HARD_STATE_FILE_NAME: &str = "hard_state";
+
+/// The term a node is in and whom it voted for in that term.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub current_term: u64,
+    pub voted_for: Option<String>,
+}
+
+/// Durable copy of what a Raft node may not forget across restarts: its term, its vote and its
+/// log. A node that forgot them could vote twice in a term, or lose entries the leader counted
+/// as stored on it.
+///
+/// Entries are appended as length delimited `RaftEntry`s. Every change is fsync'd before it
+/// returns, and the term and vote are replaced atomically.
+#[derive(Debug)]
+pub struct RaftLog {
+    dir: PathBuf,
+    file: File,
+    // Where every entry starts in the file, so the log can be cut back to any entry
+    entry_offsets: Vec<u64>,
+    file_len: u64,
+}
+
+impl RaftLog {
+    pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE_NAME))?;
+
+        // An entry torn by a crash was never acknowledged to the leader, so it is safe to drop
+        let (_, entry_offsets, file_len) = Self::read_entries(&mut file)?;
+        file.set_len(file_len)?;
+        file.sync_all()?;
+
+        Ok(Self {
+            dir,
+            file,
+            entry_offsets,
+            file_len,
+        })
+    }
+
+    pub fn hard_state(&self) -> anyhow::Result<HardState> {
+        match fs::read(self.dir.join(HARD_STATE_FILE_NAME)) {
+            Ok(bytes) => bincode::deserialize(&bytes)
+                .map_err(|err| anyhow!("raft hard state is corrupt: {}", err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HardState::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save_hard_state(&self, hard_state: &HardState) -> anyhow::Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", HARD_STATE_FILE_NAME));
+
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&bincode::serialize(hard_state)?)?;
+        tmp_file.sync_all()?;
+
+        fs::rename(tmp_path, self.dir.join(HARD_STATE_FILE_NAME))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Returns every stored entry, the entry at log index i at position i - 1.
+    pub fn entries(&self) -> anyhow::Result<Vec<RaftEntry>> {
+        let mut file = File::open(self.dir.join(LOG_FILE_NAME))?;
+        Ok(Self::read_entries(&mut file)?.0)
+    }
+
+    pub fn append(&mut self, entries: &[RaftEntry]) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        let mut entry_offsets = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            entry_offsets.push(self.file_len + buf.len() as u64);
+            entry.encode_length_delimited(&mut buf)?;
+        }
+
+        let write_result = self
+            .file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data());
+
+        // Never leave a partial entry behind that later appends would be written after
+        if let Err(err) = write_result {
+            let _ = self.file.set_len(self.file_len);
+            return Err(err.into());
+        }
+
+        self.file_len += buf.len() as u64;
+        self.entry_offsets.extend(entry_offsets);
+
+        Ok(())
+    }
+
+    /// Drops every entry after the first `len` ones.
+    pub fn truncate(&mut self, len: usize) -> anyhow::Result<()> {
+        let file_len = match self.entry_offsets.get(len) {
+            Some(offset) => *offset,
+            None => return Ok(()),
+        };
+
+        self.file.set_len(file_len)?;
+        self.file.sync_data()?;
+
+        self.file_len = file_len;
+        self.entry_offsets.truncate(len);
+
+        Ok(())
+    }
+
+    // Returns every complete entry, where each of them starts and the length of the file prefix
+    // they occupy.
+    fn read_entries(file: &mut File) -> anyhow::Result<(Vec<RaftEntry>, Vec<u64>, u64)> {
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut entries = Vec::new();
+        let mut entry_offsets = Vec::new();
+        let mut buf = bytes.as_slice();
+        while !buf.is_empty() {
+            // The decoder may consume part of a torn entry before failing, so decode from a copy
+            let mut entry_buf = buf;
+            match RaftEntry::decode_length_delimited(&mut entry_buf) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    entry_offsets.push((bytes.len() - buf.len()) as u64);
+                    buf = entry_buf;
+                }
+                Err(_) => break,
+            }
+        }
+
+        Ok((entries, entry_offsets, (bytes.len() - buf.len()) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::{EpochBatch, RaftEntry, RunStmtRequestWithUuid};
+    use crate::raft::raft_log::{HardState, RaftLog, LOG_FILE_NAME};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    fn entry(term: u64, query: &str) -> RaftEntry {
+        RaftEntry {
+            term,
+            batch: Some(EpochBatch {
+                epoch: 0,
+                requests: vec![RunStmtRequestWithUuid {
+                    query: query.to_string(),
+                    uuid: uuid::Uuid::new_v4().to_string(),
+                    repartition: None,
+                    reconnaissance: None,
+                    stamp: None,
+                }],
+                first_lsn: 0,
+                sequencer_id: String::new(),
+            }),
+        }
+    }
+
+    #[test]
+    fn reopened_log_keeps_hard_state_and_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let hard_state = HardState {
+            current_term: 3,
+            voted_for: Some("http://127.0.0.1:1".into()),
+        };
+        let entries = [
+            entry(1, "SELECT 1"),
+            entry(2, "SELECT 2"),
+            entry(3, "SELECT 3"),
+        ];
+
+        {
+            let mut raft_log = RaftLog::open(dir.path()).unwrap();
+            assert_eq!(raft_log.hard_state().unwrap(), HardState::default());
+            raft_log.save_hard_state(&hard_state).unwrap();
+            raft_log.append(&entries[..2]).unwrap();
+            raft_log.truncate(1).unwrap();
+            raft_log.append(&entries[2..]).unwrap();
+        }
+
+        // Simulate a crash half way through writing the next entry
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(LOG_FILE_NAME))
+            .unwrap();
+        file.write_all(&[42, 1, 2, 3]).unwrap();
+
+        let mut raft_log = RaftLog::open(dir.path()).unwrap();
+        assert_eq!(raft_log.hard_state().unwrap(), hard_state);
+        assert_eq!(
+            raft_log.entries().unwrap(),
+            vec![entries[0].clone(), entries[2].clone()]
+        );
+
+        raft_log.truncate(1).unwrap();
+        raft_log.append(&entries[1..2]).unwrap();
+        assert_eq!(raft_log.entries().unwrap(), entries[..2]);
+    }
+}
@@ -1,117 +1,191 @@
-use std::collections::{HashMap, HashSet};
-
-use std::hash::Hash;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::RangeInclusive;
 
 use uuid::Uuid;
 
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl LockMode {
+    fn conflicts_with(self, other: LockMode) -> bool {
+        self == LockMode::Exclusive || other == LockMode::Exclusive
+    }
+}
+
+/// Grants record locks and range locks over intervals of records in the order txns are put.
+/// A txn waits for every earlier unfinished txn that asked for a conflicting lock: an overlapping
+/// lock where either one is exclusive. A range lock also covers records that do not exist yet, so
+/// a txn that scans a range never misses a record inserted by a txn ordered before it.
 #[derive(Debug, Clone)]
 pub struct LockManager<R> {
-    ordered_pending_txns_for_record_lock: HashMap<R, Vec<Uuid>>,
-    pending_record_locks_for_txn: HashMap<Uuid, HashSet<R>>,
+    ordered_pending_txns_for_record_lock: BTreeMap<R, Vec<(Uuid, LockMode)>>,
+    ordered_pending_txns_for_range_lock: Vec<(Uuid, RangeInclusive<R>, LockMode)>,
+    blocking_txns_for_txn: HashMap<Uuid, HashSet<Uuid>>,
+    blocked_txns_for_txn: HashMap<Uuid, HashSet<Uuid>>,
     all_record_locks_for_txn: HashMap<Uuid, Vec<R>>,
 }
 
-impl<R: Hash + Eq + Clone> LockManager<R> {
+impl<R: Ord + Clone> Default for LockManager<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Ord + Clone> LockManager<R> {
     pub fn new() -> Self {
         Self {
-            ordered_pending_txns_for_record_lock: HashMap::new(),
-            pending_record_locks_for_txn: HashMap::new(),
+            ordered_pending_txns_for_record_lock: BTreeMap::new(),
+            ordered_pending_txns_for_range_lock: Vec::new(),
+            blocking_txns_for_txn: HashMap::new(),
+            blocked_txns_for_txn: HashMap::new(),
             all_record_locks_for_txn: HashMap::new(),
         }
     }
 
-    pub fn put_txn(&mut self, txn_uuid: Uuid, record_locks: Vec<R>) {
-        // Store all held record locks for this txn so we know what to free when txn completes
-        self.all_record_locks_for_txn
-            .insert(txn_uuid, record_locks.clone());
+    pub fn put_txn(
+        &mut self,
+        txn_uuid: Uuid,
+        record_locks: Vec<(R, LockMode)>,
+        range_locks: Vec<(RangeInclusive<R>, LockMode)>,
+    ) {
+        // A txn that both reads and writes a record only needs the exclusive lock
+        let mut mode_for_record_lock = BTreeMap::<R, LockMode>::new();
+        for (record_lock, mode) in record_locks.into_iter() {
+            let held_mode = mode_for_record_lock
+                .entry(record_lock)
+                .or_insert(LockMode::Shared);
+            if mode == LockMode::Exclusive {
+                *held_mode = LockMode::Exclusive;
+            }
+        }
+        let range_locks: Vec<_> = range_locks
+            .into_iter()
+            .filter(|(range_lock, _)| !range_lock.is_empty())
+            .collect();
 
-        // Add this txn as pending to all impacted record locks
-        for record_lock in record_locks.clone().into_iter() {
-            let mut pending_txn_for_record_lock = self
+        // Find the earlier txns that asked for a conflicting lock
+        let mut blocking_txns = HashSet::new();
+        for (record_lock, mode) in mode_for_record_lock.iter() {
+            if let Some(pending_txns) = self.ordered_pending_txns_for_record_lock.get(record_lock) {
+                blocking_txns.extend(Self::conflicting_txns(pending_txns, *mode));
+            }
+            blocking_txns.extend(
+                self.ordered_pending_txns_for_range_lock
+                    .iter()
+                    .filter(|(_, range_lock, pending_mode)| {
+                        range_lock.contains(record_lock) && pending_mode.conflicts_with(*mode)
+                    })
+                    .map(|(pending_txn_uuid, _, _)| *pending_txn_uuid),
+            );
+        }
+        for (range_lock, mode) in range_locks.iter() {
+            for (_, pending_txns) in self
                 .ordered_pending_txns_for_record_lock
-                .remove(&record_lock)
-                .unwrap_or_default();
-
-            pending_txn_for_record_lock.push(txn_uuid);
-
-            self.ordered_pending_txns_for_record_lock
-                .insert(record_lock, pending_txn_for_record_lock);
+                .range(range_lock.clone())
+            {
+                blocking_txns.extend(Self::conflicting_txns(pending_txns, *mode));
+            }
+            blocking_txns.extend(
+                self.ordered_pending_txns_for_range_lock
+                    .iter()
+                    .filter(|(_, pending_range_lock, pending_mode)| {
+                        Self::overlap(range_lock, pending_range_lock)
+                            && pending_mode.conflicts_with(*mode)
+                    })
+                    .map(|(pending_txn_uuid, _, _)| *pending_txn_uuid),
+            );
         }
+        blocking_txns.remove(&txn_uuid);
 
-        // Store a list of impacted record locks that are already held and we'll need before we can start this txn
-        let pending_record_locks_for_txn: Vec<R> = record_locks
-            .into_iter()
-            .filter(|record_lock| {
-                let pending_txns_for_record_lock = self
-                    .ordered_pending_txns_for_record_lock
-                    .get(record_lock)
-                    .unwrap();
-
-                assert_eq!(pending_txns_for_record_lock.last().unwrap(), &txn_uuid);
+        for blocking_txn_uuid in blocking_txns.iter() {
+            self.blocked_txns_for_txn
+                .entry(*blocking_txn_uuid)
+                .or_default()
+                .insert(txn_uuid);
+        }
+        self.blocking_txns_for_txn.insert(txn_uuid, blocking_txns);
 
-                *pending_txns_for_record_lock.first().unwrap() != txn_uuid
-            })
-            .collect();
+        // Store all held record locks for this txn so we know what to free when txn completes
+        self.all_record_locks_for_txn
+            .insert(txn_uuid, mode_for_record_lock.keys().cloned().collect());
 
-        self.pending_record_locks_for_txn.insert(
-            txn_uuid,
-            HashSet::from_iter(pending_record_locks_for_txn.iter().cloned()),
-        );
+        // Add this txn as pending to all impacted locks
+        for (record_lock, mode) in mode_for_record_lock.into_iter() {
+            self.ordered_pending_txns_for_record_lock
+                .entry(record_lock)
+                .or_default()
+                .push((txn_uuid, mode));
+        }
+        for (range_lock, mode) in range_locks.into_iter() {
+            self.ordered_pending_txns_for_range_lock
+                .push((txn_uuid, range_lock, mode));
+        }
     }
 
     pub fn pop_ready_txns(&mut self) -> Vec<Uuid> {
         let ready_txns: Vec<Uuid> = self
-            .pending_record_locks_for_txn
+            .blocking_txns_for_txn
             .iter()
-            .filter(|(_, record_locks)| record_locks.is_empty())
+            .filter(|(_, blocking_txns)| blocking_txns.is_empty())
             .map(|(uuid, _)| *uuid)
             .collect();
 
         for ready_txn_uuid in ready_txns.iter() {
-            self.pending_record_locks_for_txn.remove(ready_txn_uuid);
+            self.blocking_txns_for_txn.remove(ready_txn_uuid);
         }
 
         ready_txns
     }
 
     pub fn complete_txn(&mut self, uuid: Uuid) {
-        let record_locks_held_by_txn = self.all_record_locks_for_txn.remove(&uuid).unwrap();
+        // Invariant: this txn must currently hold its locks
+        assert!(!self.blocking_txns_for_txn.contains_key(&uuid));
 
+        let record_locks_held_by_txn = self.all_record_locks_for_txn.remove(&uuid).unwrap();
         for record_lock in record_locks_held_by_txn.iter() {
-            let mut pending_txns_for_record_lock = self
+            if let Some(pending_txns_for_record_lock) = self
                 .ordered_pending_txns_for_record_lock
-                .remove(record_lock)
-                .unwrap_or_default();
-
-            // Invariant: the first txn for this lock should always be this txn
-            assert_eq!(pending_txns_for_record_lock[0], uuid);
-
-            pending_txns_for_record_lock.remove(0);
-
-            // If another txn is waiting for the lock, tell it the lock has been acquired
-            if !pending_txns_for_record_lock.is_empty() {
-                let next_pending_txn_uuid = pending_txns_for_record_lock[0];
-                let mut pending_record_locks_for_next_pending_txn = self
-                    .pending_record_locks_for_txn
-                    .remove(&next_pending_txn_uuid)
-                    .unwrap();
-
-                pending_record_locks_for_next_pending_txn.remove(record_lock);
-                self.pending_record_locks_for_txn.insert(
-                    next_pending_txn_uuid,
-                    pending_record_locks_for_next_pending_txn,
-                );
+                .get_mut(record_lock)
+            {
+                pending_txns_for_record_lock.retain(|(txn_uuid, _)| *txn_uuid != uuid);
+                if pending_txns_for_record_lock.is_empty() {
+                    self.ordered_pending_txns_for_record_lock
+                        .remove(record_lock);
+                }
             }
+        }
+        self.ordered_pending_txns_for_range_lock
+            .retain(|(txn_uuid, _, _)| *txn_uuid != uuid);
 
-            self.ordered_pending_txns_for_record_lock
-                .insert(record_lock.clone(), pending_txns_for_record_lock);
+        // Txns that waited for this one may now hold all of their locks
+        for blocked_txn_uuid in self.blocked_txns_for_txn.remove(&uuid).unwrap_or_default() {
+            if let Some(blocking_txns) = self.blocking_txns_for_txn.get_mut(&blocked_txn_uuid) {
+                blocking_txns.remove(&uuid);
+            }
         }
     }
+
+    fn conflicting_txns(
+        pending_txns: &[(Uuid, LockMode)],
+        mode: LockMode,
+    ) -> impl Iterator<Item = Uuid> + '_ {
+        pending_txns
+            .iter()
+            .filter(move |(_, pending_mode)| pending_mode.conflicts_with(mode))
+            .map(|(txn_uuid, _)| *txn_uuid)
+    }
+
+    fn overlap(left: &RangeInclusive<R>, right: &RangeInclusive<R>) -> bool {
+        left.start() <= right.end() && right.start() <= left.end()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::scheduler::lock_manager::LockManager;
+    use crate::scheduler::lock_manager::{LockManager, LockMode};
     use uuid::Uuid;
 
     #[test]
@@ -120,7 +194,7 @@ mod tests {
 
         let txn_uuid = Uuid::new_v4();
 
-        lm.put_txn(txn_uuid, vec![0]);
+        lm.put_txn(txn_uuid, vec![(0, LockMode::Exclusive)], Vec::new());
 
         assert_eq!(lm.pop_ready_txns(), vec![txn_uuid]);
     }
@@ -132,13 +206,15 @@ mod tests {
         let txn1_uuid = Uuid::new_v4();
         let txn2_uuid = Uuid::new_v4();
 
-        lm.put_txn(txn1_uuid, vec![1]);
-        lm.put_txn(txn2_uuid, vec![2]);
+        lm.put_txn(txn1_uuid, vec![(1, LockMode::Exclusive)], Vec::new());
+        lm.put_txn(txn2_uuid, vec![(2, LockMode::Exclusive)], Vec::new());
 
-        assert_eq!(
-            lm.pop_ready_txns().sort(),
-            vec![txn1_uuid, txn2_uuid].sort()
-        );
+        let mut ready_txns = lm.pop_ready_txns();
+        ready_txns.sort();
+        let mut expected_txns = vec![txn1_uuid, txn2_uuid];
+        expected_txns.sort();
+
+        assert_eq!(ready_txns, expected_txns);
     }
 
     #[test]
@@ -148,8 +224,8 @@ mod tests {
         let txn1_uuid = Uuid::new_v4();
         let txn2_uuid = Uuid::new_v4();
 
-        lm.put_txn(txn1_uuid, vec![1]);
-        lm.put_txn(txn2_uuid, vec![1]);
+        lm.put_txn(txn1_uuid, vec![(1, LockMode::Exclusive)], Vec::new());
+        lm.put_txn(txn2_uuid, vec![(1, LockMode::Exclusive)], Vec::new());
 
         assert_eq!(lm.pop_ready_txns(), vec![txn1_uuid]);
 
@@ -161,4 +237,135 @@ mod tests {
 
         assert_eq!(lm.pop_ready_txns(), vec![]);
     }
+
+    #[test]
+    fn shared_txns_proceed_together() {
+        let mut lm = LockManager::<u32>::new();
+
+        let txn1_uuid = Uuid::new_v4();
+        let txn2_uuid = Uuid::new_v4();
+
+        lm.put_txn(txn1_uuid, vec![(1, LockMode::Shared)], Vec::new());
+        lm.put_txn(txn2_uuid, vec![(1, LockMode::Shared)], Vec::new());
+
+        let mut ready_txns = lm.pop_ready_txns();
+        ready_txns.sort();
+        let mut expected_txns = vec![txn1_uuid, txn2_uuid];
+        expected_txns.sort();
+
+        assert_eq!(ready_txns, expected_txns);
+    }
+
+    #[test]
+    fn exclusive_txn_waits_for_all_earlier_shared_txns() {
+        let mut lm = LockManager::<u32>::new();
+
+        let txn1_uuid = Uuid::new_v4();
+        let txn2_uuid = Uuid::new_v4();
+        let txn3_uuid = Uuid::new_v4();
+        let txn4_uuid = Uuid::new_v4();
+
+        lm.put_txn(txn1_uuid, vec![(1, LockMode::Shared)], Vec::new());
+        lm.put_txn(txn2_uuid, vec![(1, LockMode::Shared)], Vec::new());
+        lm.put_txn(txn3_uuid, vec![(1, LockMode::Exclusive)], Vec::new());
+        lm.put_txn(txn4_uuid, vec![(1, LockMode::Shared)], Vec::new());
+
+        assert_eq!(lm.pop_ready_txns().len(), 2);
+
+        // Shared holders may complete out of order
+        lm.complete_txn(txn2_uuid);
+        assert_eq!(lm.pop_ready_txns(), vec![]);
+
+        lm.complete_txn(txn1_uuid);
+        assert_eq!(lm.pop_ready_txns(), vec![txn3_uuid]);
+
+        lm.complete_txn(txn3_uuid);
+        assert_eq!(lm.pop_ready_txns(), vec![txn4_uuid]);
+    }
+
+    #[test]
+    fn read_and_write_of_same_record_takes_exclusive_lock() {
+        let mut lm = LockManager::<u32>::new();
+
+        let txn1_uuid = Uuid::new_v4();
+        let txn2_uuid = Uuid::new_v4();
+
+        lm.put_txn(
+            txn1_uuid,
+            vec![(1, LockMode::Shared), (1, LockMode::Exclusive)],
+            Vec::new(),
+        );
+        lm.put_txn(txn2_uuid, vec![(1, LockMode::Shared)], Vec::new());
+
+        assert_eq!(lm.pop_ready_txns(), vec![txn1_uuid]);
+
+        lm.complete_txn(txn1_uuid);
+
+        assert_eq!(lm.pop_ready_txns(), vec![txn2_uuid]);
+    }
+
+    #[test]
+    fn range_lock_blocks_later_writes_in_range() {
+        let mut lm = LockManager::<u32>::new();
+
+        let txn1_uuid = Uuid::new_v4();
+        let txn2_uuid = Uuid::new_v4();
+        let txn3_uuid = Uuid::new_v4();
+        let txn4_uuid = Uuid::new_v4();
+
+        lm.put_txn(txn1_uuid, Vec::new(), vec![(1..=10, LockMode::Shared)]);
+        // A record that does not exist yet is covered by the range too
+        lm.put_txn(txn2_uuid, vec![(5, LockMode::Exclusive)], Vec::new());
+        lm.put_txn(txn3_uuid, vec![(11, LockMode::Exclusive)], Vec::new());
+        lm.put_txn(txn4_uuid, Vec::new(), vec![(3..=4, LockMode::Shared)]);
+
+        let mut ready_txns = lm.pop_ready_txns();
+        ready_txns.sort();
+        let mut expected_txns = vec![txn1_uuid, txn3_uuid, txn4_uuid];
+        expected_txns.sort();
+        assert_eq!(ready_txns, expected_txns);
+
+        lm.complete_txn(txn1_uuid);
+        assert_eq!(lm.pop_ready_txns(), vec![txn2_uuid]);
+    }
+
+    #[test]
+    fn range_lock_waits_for_earlier_writes_in_range() {
+        let mut lm = LockManager::<u32>::new();
+
+        let txn1_uuid = Uuid::new_v4();
+        let txn2_uuid = Uuid::new_v4();
+        let txn3_uuid = Uuid::new_v4();
+
+        lm.put_txn(txn1_uuid, vec![(5, LockMode::Exclusive)], Vec::new());
+        lm.put_txn(txn2_uuid, Vec::new(), vec![(1..=3, LockMode::Exclusive)]);
+        lm.put_txn(txn3_uuid, Vec::new(), vec![(3..=7, LockMode::Exclusive)]);
+
+        let mut ready_txns = lm.pop_ready_txns();
+        ready_txns.sort();
+        let mut expected_txns = vec![txn1_uuid, txn2_uuid];
+        expected_txns.sort();
+        assert_eq!(ready_txns, expected_txns);
+
+        lm.complete_txn(txn1_uuid);
+        assert_eq!(lm.pop_ready_txns(), vec![]);
+
+        lm.complete_txn(txn2_uuid);
+        assert_eq!(lm.pop_ready_txns(), vec![txn3_uuid]);
+    }
+
+    #[test]
+    fn txn_does_not_wait_for_itself() {
+        let mut lm = LockManager::<u32>::new();
+
+        let txn_uuid = Uuid::new_v4();
+
+        lm.put_txn(
+            txn_uuid,
+            vec![(5, LockMode::Exclusive)],
+            vec![(1..=10, LockMode::Shared)],
+        );
+
+        assert_eq!(lm.pop_ready_txns(), vec![txn_uuid]);
+    }
 }
@@ -1,11 +1,14 @@
-use crate::calvinite_tonic::{RunStmtRequestWithUuid, RunStmtResponse};
+use crate::calvinite_tonic::{EpochBatch, RunStmtRequestWithUuid, RunStmtResponse};
+use crate::catalog::Catalog;
 use crate::common::Record;
-use crate::executor::Executor;
-use crate::scheduler::lock_manager::LockManager;
+use crate::executor::peer::PeerManager;
+use crate::executor::{Executor, ExecutorErr};
+use crate::scheduler::lock_manager::{LockManager, LockMode};
 use crate::stmt_analyzer;
 
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::ops::RangeInclusive;
 use std::sync::{Arc, Mutex};
 use tokio::sync;
 use uuid::Uuid;
@@ -13,19 +16,48 @@ use uuid::Uuid;
 pub mod lock_manager;
 
 #[derive(thiserror::Error, Debug, Clone)]
-pub enum SchedulerErr {}
+pub enum SchedulerErr {
+    #[error("invalid txn uuid {0}")]
+    InvalidTxnUuid(String),
+    #[error("txn {0} was dropped before it could run")]
+    TxnDropped(Uuid),
+    #[error("a txn task failed: {0}")]
+    TxnPanicked(String),
+}
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct SchedulerData {
     lock_manager: LockManager<Record>,
     pending_txns: HashMap<Uuid, sync::oneshot::Sender<()>>,
 }
 
-impl Default for SchedulerData {
-    fn default() -> Self {
-        Self {
-            lock_manager: LockManager::new(),
-            pending_txns: HashMap::default(),
+impl SchedulerData {
+    fn complete_txn(&mut self, txn_uuid: Uuid) {
+        self.lock_manager.complete_txn(txn_uuid);
+        self.start_ready_txns();
+    }
+
+    fn start_ready_txns(&mut self) {
+        let pending_txns = self.lock_manager.pop_ready_txns();
+        for pending_txn in pending_txns {
+            // A txn whose task has gone away does not need to be started
+            if let Some(txn_notifier) = self.pending_txns.remove(&pending_txn) {
+                let _ = txn_notifier.send(());
+            }
+        }
+    }
+}
+
+// Releases the locks of a started txn once its task ends, however it ends
+struct RunningTxn {
+    inner: Arc<Mutex<SchedulerData>>,
+    txn_uuid: Uuid,
+}
+
+impl Drop for RunningTxn {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.complete_txn(self.txn_uuid);
         }
     }
 }
@@ -35,15 +67,14 @@ impl Default for SchedulerData {
 pub struct Scheduler {
     inner: Arc<Mutex<SchedulerData>>,
     executor: Executor,
+    catalog: Catalog,
+    peer_manager: Option<PeerManager>,
 }
 
 #[cfg_attr(test, faux::methods)]
 impl Default for Scheduler {
     fn default() -> Self {
-        Self {
-            inner: Arc::new(Mutex::new(SchedulerData::default())),
-            executor: Executor::default(),
-        }
+        Self::new(Executor::default())
     }
 }
 
@@ -51,7 +82,27 @@ impl Default for Scheduler {
 impl Scheduler {
     pub fn new(executor: Executor) -> Self {
         let inner = Arc::new(Mutex::new(SchedulerData::default()));
-        Self { inner, executor }
+        Self {
+            inner,
+            catalog: executor.catalog(),
+            executor,
+            peer_manager: None,
+        }
+    }
+
+    /// Builds a scheduler that only locks the records owned by this partition.
+    pub fn new_partitioned(executor: Executor, peer_manager: PeerManager) -> Self {
+        let inner = Arc::new(Mutex::new(SchedulerData::default()));
+        Self {
+            inner,
+            catalog: executor.catalog(),
+            executor,
+            peer_manager: Some(peer_manager),
+        }
+    }
+
+    pub fn executor(&self) -> Executor {
+        self.executor.clone()
     }
 
     // Submits a txn for execution. Txn will be run when it is safe. Returns result of txn.
@@ -59,64 +110,197 @@ impl Scheduler {
         &self,
         req: RunStmtRequestWithUuid,
     ) -> Result<RunStmtResponse, SchedulerErr> {
-        let txn_uuid = Uuid::parse_str(&req.uuid).unwrap();
+        let txn_uuid = Self::parse_txn_uuid(&req.uuid)?;
+        let receiver = self.enqueue_txn(txn_uuid, &req);
+        self.run_txn(txn_uuid, req, receiver).await
+    }
 
+    // Submits every txn of an epoch batch for execution. The lock requests of the whole batch are
+    // queued in log order before any txn starts, then txns run as soon as it is safe.
+    // Reconfiguration and DDL txns are barriers: every earlier txn finishes before the partition
+    // map or catalog changes, and every later txn is locked under the new one.
+    // Returns the results of the txns in log order.
+    pub async fn submit_batch(
+        &self,
+        batch: EpochBatch,
+    ) -> Result<Vec<RunStmtResponse>, SchedulerErr> {
+        let mut results = Vec::with_capacity(batch.requests.len());
+        let mut txns = Vec::new();
+
+        for req in batch.requests {
+            if self.is_barrier(&req) {
+                results.extend(self.run_concurrently(std::mem::take(&mut txns)).await?);
+                results.push(Self::response_of(self.executor.execute(req).await));
+            } else {
+                txns.push(req);
+            }
+        }
+        results.extend(self.run_concurrently(txns).await?);
+
+        Ok(results)
+    }
+
+    async fn run_concurrently(
+        &self,
+        requests: Vec<RunStmtRequestWithUuid>,
+    ) -> Result<Vec<RunStmtResponse>, SchedulerErr> {
+        // Nothing is queued unless every txn can be, so no txn is left holding its locks
+        let txn_uuids = requests
+            .iter()
+            .map(|req| Self::parse_txn_uuid(&req.uuid))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let handles: Vec<_> = requests
+            .into_iter()
+            .zip(txn_uuids)
+            .map(|(req, txn_uuid)| {
+                let receiver = self.enqueue_txn(txn_uuid, &req);
+                let scheduler = self.clone();
+                tokio::spawn(async move { scheduler.run_txn(txn_uuid, req, receiver).await })
+            })
+            .collect();
+
+        // Every txn runs to the end before a failure is returned
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            results.push(
+                handle
+                    .await
+                    .map_err(|err| SchedulerErr::TxnPanicked(err.to_string()))
+                    .and_then(|result| result),
+            );
+        }
+
+        results.into_iter().collect()
+    }
+
+    fn is_barrier(&self, req: &RunStmtRequestWithUuid) -> bool {
+        req.repartition.is_some()
+            || stmt_analyzer::SqlStmt::from_string(req.query.clone(), &self.catalog)
+                .map(|sql_stmt| sql_stmt.changes_schema())
+                .unwrap_or(false)
+    }
+
+    // Queues the lock requests of a txn. The returned receiver fires once the txn holds all of its locks.
+    fn enqueue_txn(
+        &self,
+        txn_uuid: Uuid,
+        req: &RunStmtRequestWithUuid,
+    ) -> sync::oneshot::Receiver<()> {
         // TODO: Better naming
         let (sender, receiver) = sync::oneshot::channel();
 
-        let sql_stmt = stmt_analyzer::SqlStmt::from_string(req.query.clone()).unwrap();
-        let impacted_records = sql_stmt.inserted_records;
-
-        dbg!(
-            "Impacted Records of {:?} <-> {:?} are {:?}",
-            txn_uuid,
-            req.query.clone(),
-            impacted_records.clone()
-        );
+        // A request that cannot be analyzed locks nothing, the executor reports why it failed
+        let (impacted_records, impacted_ranges) =
+            match stmt_analyzer::SqlStmt::from_request(req, &self.catalog) {
+                Ok(sql_stmt) => (
+                    self.record_locks_for_stmt(&sql_stmt),
+                    Self::range_locks_for_stmt(&sql_stmt),
+                ),
+                Err(_) => (Vec::new(), Vec::new()),
+            };
 
         // Insert the txn and start any ready-to-go txns
         {
             let mut inner = self.inner.lock().unwrap();
 
             inner.pending_txns.insert(txn_uuid, sender);
-            inner.lock_manager.put_txn(txn_uuid, impacted_records);
-
-            let pending_txns = inner.lock_manager.pop_ready_txns();
-            for pending_txn in pending_txns {
-                let txn_notifier = inner.pending_txns.remove(&pending_txn).unwrap();
-                txn_notifier.send(()).unwrap();
-            }
+            inner
+                .lock_manager
+                .put_txn(txn_uuid, impacted_records, impacted_ranges);
+            inner.start_ready_txns();
         }
 
+        receiver
+    }
+
+    async fn run_txn(
+        &self,
+        txn_uuid: Uuid,
+        req: RunStmtRequestWithUuid,
+        receiver: sync::oneshot::Receiver<()>,
+    ) -> Result<RunStmtResponse, SchedulerErr> {
         // Wait for this txn to be started
-        let _ = receiver.await.unwrap();
+        receiver
+            .await
+            .map_err(|_| SchedulerErr::TxnDropped(txn_uuid))?;
 
-        let res = self.executor.execute(req).await.unwrap();
+        // Completes this txn and starts any ready-to-go txns, even if the txn panics
+        let _running_txn = RunningTxn {
+            inner: self.inner.clone(),
+            txn_uuid,
+        };
 
-        // Complete this txn and start any ready-to-go txns
-        {
-            let mut inner = self.inner.lock().unwrap();
+        Ok(Self::response_of(self.executor.execute(req).await))
+    }
 
-            inner.lock_manager.complete_txn(txn_uuid);
-            let pending_txns = inner.lock_manager.pop_ready_txns();
-            for pending_txn in pending_txns {
-                let txn_notifier = inner.pending_txns.remove(&pending_txn).unwrap();
-                txn_notifier.send(()).unwrap();
-            }
-        }
+    fn parse_txn_uuid(txn_uuid: &str) -> Result<Uuid, SchedulerErr> {
+        Uuid::parse_str(txn_uuid).map_err(|_| SchedulerErr::InvalidTxnUuid(txn_uuid.to_string()))
+    }
+
+    // A txn that fails to execute fails on its own, the rest of its batch still runs
+    fn response_of(res: Result<RunStmtResponse, ExecutorErr>) -> RunStmtResponse {
+        res.unwrap_or_else(|err| Executor::failure(&err))
+    }
+
+    // Other partitions lock the records they own
+    fn record_locks_for_stmt(&self, sql_stmt: &stmt_analyzer::SqlStmt) -> Vec<(Record, LockMode)> {
+        let read_locks = sql_stmt
+            .read_set()
+            .into_iter()
+            .map(|record| (record, LockMode::Shared));
+
+        let write_locks = sql_stmt
+            .write_set()
+            .into_iter()
+            .map(|record| (record, LockMode::Exclusive));
 
-        Ok(res)
+        read_locks
+            .chain(write_locks)
+            .filter(|(record, _)| match &self.peer_manager {
+                Some(peer_manager) => peer_manager.is_local(record),
+                None => true,
+            })
+            .collect()
+    }
+
+    // Every partition locks the whole range, as it owns some of the records in it
+    fn range_locks_for_stmt(
+        sql_stmt: &stmt_analyzer::SqlStmt,
+    ) -> Vec<(RangeInclusive<Record>, LockMode)> {
+        let read_locks = sql_stmt
+            .read_ranges
+            .iter()
+            .map(|range| (range.clone(), LockMode::Shared));
+
+        let write_locks = sql_stmt
+            .write_ranges
+            .iter()
+            .map(|range| (range.clone(), LockMode::Exclusive));
+
+        read_locks.chain(write_locks).collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::calvinite_tonic::run_stmt_response::Result::Success;
-    use crate::calvinite_tonic::{RunStmtRequestWithUuid, RunStmtResponse, RunStmtResults};
+    use crate::calvinite_tonic::run_stmt_err::ErrorCode;
+    use crate::calvinite_tonic::run_stmt_response::Result::{Failure, Success};
+    use crate::calvinite_tonic::{
+        EpochBatch, RecordStorage, RunStmtRequestWithUuid, RunStmtResponse, RunStmtResults,
+    };
+    use crate::catalog::Catalog;
     use crate::executor::Executor;
     use crate::scheduler::Scheduler;
+    use crate::storage::MemoryEngine;
     use faux::when;
 
+    fn row(id: i64, val: i64) -> RecordStorage {
+        RecordStorage {
+            values: vec![id.into(), val.into()],
+        }
+    }
+
     #[tokio::test]
     async fn scheduler_executes_single_stmt() {
         let mut executor = Executor::faux();
@@ -126,14 +310,23 @@ mod tests {
         let req = RunStmtRequestWithUuid {
             query: "".to_string(),
             uuid: txn_uuid.clone(),
+            repartition: None,
+            reconnaissance: None,
+            stamp: None,
         };
 
-        when!(executor.execute).then_return(Ok(RunStmtResponse {
+        let catalog = Catalog::open(&MemoryEngine::shared());
+        when!(executor.catalog).then_return(catalog);
+        let response = RunStmtResponse {
             result: Some(Success(RunStmtResults {
                 uuid: txn_uuid.clone(),
                 results: vec![],
+                columns: vec![],
+                result_sets: vec![],
+                rows_affected: 0,
             })),
-        }));
+        };
+        when!(executor.execute).then(move |_| Ok(response.clone()));
 
         let scheduler = Scheduler::new(executor);
 
@@ -145,4 +338,136 @@ mod tests {
             panic!("Results were supposed to be successful")
         }
     }
+
+    #[tokio::test]
+    async fn scheduler_executes_batch_in_log_order() {
+        let scheduler = Scheduler::new(Executor::default());
+
+        // Statements after the CREATE TABLE are scheduled once the table exists
+        let queries = [
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            "INSERT INTO foo VALUES (1, 2)",
+            "SELECT * FROM foo WHERE id = 1",
+            "UPDATE foo SET val = 3 WHERE id = 1",
+            "SELECT * FROM foo WHERE id = 1",
+        ];
+
+        let batch = EpochBatch {
+            epoch: 0,
+            requests: queries
+                .iter()
+                .map(|query| RunStmtRequestWithUuid {
+                    query: query.to_string(),
+                    uuid: uuid::Uuid::new_v4().to_string(),
+                    repartition: None,
+                    reconnaissance: None,
+                    stamp: None,
+                })
+                .collect(),
+            first_lsn: 1,
+            sequencer_id: String::new(),
+        };
+
+        let results: Vec<Vec<RecordStorage>> = scheduler
+            .submit_batch(batch)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|res| match res.result {
+                Some(Success(result)) => result.results,
+                _ => panic!("Results were supposed to be successful"),
+            })
+            .collect();
+
+        assert_eq!(
+            results,
+            vec![vec![], vec![], vec![row(1, 2)], vec![], vec![row(1, 3)],]
+        );
+    }
+
+    #[tokio::test]
+    async fn scheduler_releases_locks_of_aborted_txns() {
+        let scheduler = Scheduler::new(Executor::default());
+
+        // Every txn after the first locks the same record
+        let queries = [
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            "INSERT INTO foo VALUES (1, 2)",
+            "UPDATE foo SET val = 3 WHERE id = 1; ROLLBACK",
+            "UPDATE foo SET val = 4 WHERE id = 1; ABORT IF (SELECT val FROM foo WHERE id = 1) = 4",
+            "SELECT * FROM foo WHERE id = 1",
+        ];
+
+        let batch = EpochBatch {
+            epoch: 0,
+            requests: queries
+                .iter()
+                .map(|query| RunStmtRequestWithUuid {
+                    query: query.to_string(),
+                    uuid: uuid::Uuid::new_v4().to_string(),
+                    repartition: None,
+                    reconnaissance: None,
+                    stamp: None,
+                })
+                .collect(),
+            first_lsn: 1,
+            sequencer_id: String::new(),
+        };
+
+        let results = scheduler.submit_batch(batch).await.unwrap();
+
+        for res in &results[2..4] {
+            match &res.result {
+                Some(Failure(err)) => assert_eq!(err.error_code(), ErrorCode::Abort),
+                _ => panic!("Txn was supposed to abort"),
+            }
+        }
+        match &results[4].result {
+            Some(Success(result)) => assert_eq!(result.results, vec![row(1, 2)]),
+            _ => panic!("Results were supposed to be successful"),
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduler_queues_no_txn_of_a_batch_with_an_invalid_txn_uuid() {
+        let scheduler = Scheduler::new(Executor::default());
+        let req = |query: &str| RunStmtRequestWithUuid {
+            query: query.to_string(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            repartition: None,
+            reconnaissance: None,
+            stamp: None,
+        };
+
+        let setup = [
+            "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+            "INSERT INTO foo VALUES (1, 2)",
+        ];
+        for query in setup {
+            scheduler.submit_txn(req(query)).await.unwrap();
+        }
+
+        let mut invalid = req("UPDATE foo SET val = 4 WHERE id = 1");
+        invalid.uuid = "not a uuid".into();
+        let batch = EpochBatch {
+            epoch: 0,
+            requests: vec![req("UPDATE foo SET val = 3 WHERE id = 1"), invalid],
+            first_lsn: 1,
+            sequencer_id: String::new(),
+        };
+        assert!(scheduler.submit_batch(batch).await.is_err());
+
+        // The valid txn of the batch must not hold on to its lock
+        let res = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            scheduler.submit_txn(req("SELECT * FROM foo WHERE id = 1")),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        match res.result {
+            Some(Success(result)) => assert_eq!(result.results, vec![row(1, 2)]),
+            _ => panic!("Results were supposed to be successful"),
+        }
+    }
 }
@@ -0,0 +1,111 @@
+use crate::calvinite_tonic::EpochBatch;
+use anyhow::anyhow;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{self, mpsc};
+
+/// Hands every batch of the global request log to every subscribed sequencer, in log order.
+///
+/// Each subscriber has a queue of `capacity` batches. Appending waits until every queue has room,
+/// so a sequencer that falls behind slows the log down rather than missing batches.
+#[derive(Debug, Clone)]
+pub struct GlobalRequestLog {
+    capacity: usize,
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<EpochBatch>>>>,
+    // Held while a batch is handed out, so every subscriber receives the batches in one order
+    append_lock: Arc<sync::Mutex<()>>,
+}
+
+impl GlobalRequestLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            append_lock: Arc::new(sync::Mutex::new(())),
+        }
+    }
+
+    /// Receives every batch appended from now on. The log is closed once the `GlobalRequestLog`
+    /// and all its clones have gone away.
+    pub fn subscribe(&self) -> mpsc::Receiver<EpochBatch> {
+        let (tx, rx) = mpsc::channel(self.capacity);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Appends a batch once every subscriber has room for it. Fails if nobody received it.
+    pub async fn append(&self, batch: EpochBatch) -> anyhow::Result<()> {
+        let _appending = self.append_lock.lock().await;
+
+        let subscribers = self.subscribers.lock().unwrap().clone();
+        let mut received = false;
+        for subscriber in subscribers.iter() {
+            received |= subscriber.send(batch.clone()).await.is_ok();
+        }
+
+        // Sequencers that stopped do not receive any more batches
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| !subscriber.is_closed());
+
+        match received {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "no sequencer is subscribed to the global request log"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::EpochBatch;
+    use crate::sequencer::global_request_log::GlobalRequestLog;
+    use std::time::Duration;
+
+    fn batch(epoch: u64) -> EpochBatch {
+        EpochBatch {
+            epoch,
+            requests: vec![],
+            first_lsn: epoch + 1,
+            sequencer_id: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_slowest_subscriber() {
+        let global_request_log = GlobalRequestLog::new(1);
+        let mut fast_rx = global_request_log.subscribe();
+        let mut slow_rx = global_request_log.subscribe();
+
+        global_request_log.append(batch(0)).await.unwrap();
+        assert_eq!(fast_rx.recv().await.unwrap(), batch(0));
+
+        // The slow subscriber has not taken the first batch yet, so there is no room for another
+        let mut appending = tokio::spawn({
+            let global_request_log = global_request_log.clone();
+            async move { global_request_log.append(batch(1)).await }
+        });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut appending)
+                .await
+                .is_err()
+        );
+
+        assert_eq!(slow_rx.recv().await.unwrap(), batch(0));
+        appending.await.unwrap().unwrap();
+        assert_eq!(slow_rx.recv().await.unwrap(), batch(1));
+        assert_eq!(fast_rx.recv().await.unwrap(), batch(1));
+    }
+
+    #[tokio::test]
+    async fn fails_once_every_subscriber_stopped() {
+        let global_request_log = GlobalRequestLog::new(1);
+        assert!(global_request_log.append(batch(0)).await.is_err());
+
+        let rx = global_request_log.subscribe();
+        global_request_log.append(batch(0)).await.unwrap();
+        drop(rx);
+        assert!(global_request_log.append(batch(1)).await.is_err());
+    }
+}
@@ -1,47 +1,323 @@
+use crate::calvinite_tonic::sequencer_grpc_service_client::SequencerGrpcServiceClient;
 use crate::calvinite_tonic::sequencer_grpc_service_server::SequencerGrpcService;
-use crate::calvinite_tonic::{RunStmtRequest, RunStmtRequestWithUuid, RunStmtResponse};
+use crate::calvinite_tonic::{
+    CallProcedureRequest, EpochBatch, RepartitionRequest, RunStmtRequest, RunStmtRequestWithUuid,
+    RunStmtResponse, TxnStamp,
+};
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync;
-use tokio::sync::broadcast::{Receiver, Sender};
-
+use tokio::sync::mpsc::Receiver;
+
+use crate::calvinite_tonic::run_stmt_err::ErrorCode;
+use crate::calvinite_tonic::run_stmt_response::Result::{Failure, Restart};
+use crate::calvinite_tonic::RunStmtErr;
+use crate::common;
+use crate::executor::Executor;
+use crate::expr;
+use crate::raft::{self, RaftNode};
 use crate::scheduler::Scheduler;
+use crate::sequencer::global_request_log::GlobalRequestLog;
+use crate::sequencer::request_log::RequestLog;
+use sqlparser::ast;
 
+use tonic::transport::Channel;
 use tonic::Response;
 use uuid::Uuid;
 
+pub mod global_request_log;
+pub mod request_log;
+
+// How often a dependent txn is reconnoitered and sequenced again before the client is told it
+// could not run. Each restart means another txn changed its records in the meantime.
+const MAX_RESTARTS: usize = 8;
+
+// How many batches a sequencer runs before it drops the requests its executor has applied from
+// the request log.
+const CHECKPOINT_INTERVAL: u64 = 64;
+
+type FinishedTxnNotifier = Arc<Mutex<HashMap<Uuid, sync::oneshot::Sender<RunStmtResponse>>>>;
+
+type SharedRequestLog = Arc<Mutex<RequestLog>>;
+
+// Runs `op` on a blocking thread, since the request log reads, writes and fsyncs files
+async fn on_request_log<T: Send + 'static>(
+    request_log: &SharedRequestLog,
+    op: impl FnOnce(&mut RequestLog) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let request_log = request_log.clone();
+    tokio::task::spawn_blocking(move || op(&mut request_log.lock().unwrap())).await?
+}
+
 #[derive(Debug)]
 pub struct Sequencer {
     scheduler: Scheduler,
-    global_req_log_rx: Receiver<RunStmtRequestWithUuid>,
-    finished_txn_notifier: Arc<Mutex<HashMap<Uuid, sync::oneshot::Sender<RunStmtResponse>>>>,
+    global_req_log_rx: Receiver<EpochBatch>,
+    finished_txn_notifier: FinishedTxnNotifier,
+    request_log: Option<SharedRequestLog>,
+    // The sequencer that numbers the batches of the request log
+    sequencer_id: String,
+    replayed_through_lsn: u64,
+    batches_since_checkpoint: u64,
 }
 
 impl Sequencer {
+    /// Rebuilds executor state by re-running every logged request after the last checkpoint or
+    /// the last batch the executor applied, in the same epoch batches they were originally
+    /// sequenced in. Must be called before `serve`.
+    pub async fn replay(&mut self) -> anyhow::Result<()> {
+        let request_log = match &self.request_log {
+            Some(request_log) => request_log.clone(),
+            None => return Ok(()),
+        };
+
+        let replayed_through_lsn = self.replayed_through_lsn;
+        let entries = on_request_log(&request_log, move |request_log| {
+            request_log.entries_after(request_log.last_checkpoint()?.max(replayed_through_lsn))
+        })
+        .await?;
+
+        for mut batch in RequestLog::batches_of(entries) {
+            batch.sequencer_id = self.sequencer_id.clone();
+            self.replayed_through_lsn = batch.first_lsn + batch.requests.len() as u64 - 1;
+            let txn_uuids = Self::txn_uuids_of(&batch);
+            self.scheduler.submit_batch(batch).await?;
+            self.scheduler.executor().apply_batch(
+                &self.sequencer_id,
+                self.replayed_through_lsn,
+                &txn_uuids,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs every batch of the global request log until the log is closed. A batch that fails
+    /// to run fails the txns of its clients, later batches still run.
     pub async fn serve(&mut self) {
         loop {
-            let req = self.global_req_log_rx.recv().await.unwrap();
+            let batch = match self.global_req_log_rx.recv().await {
+                Some(batch) => batch,
+                None => return,
+            };
+
+            // Batches logged while replay was reading the log arrive here a second time. Batches
+            // of other sequencers are numbered on their own, so their LSNs tell nothing about it.
+            if batch.sequencer_id == self.sequencer_id
+                && batch.first_lsn <= self.replayed_through_lsn
+            {
+                continue;
+            }
 
-            let uuid = Uuid::parse_str(&req.uuid).unwrap();
+            let sequencer_id = batch.sequencer_id.clone();
+            let last_lsn = batch.first_lsn + batch.requests.len() as u64 - 1;
+            let txn_uuids = Self::txn_uuids_of(&batch);
+            let uuids: Vec<Option<Uuid>> = txn_uuids
+                .iter()
+                .map(|txn_uuid| Uuid::parse_str(txn_uuid).ok())
+                .collect();
+
+            let results = match self.scheduler.submit_batch(batch).await {
+                Ok(results) => results,
+                Err(err) => {
+                    let mut run_stmt_err = RunStmtErr {
+                        error_code: 0,
+                        detailed_message: err.to_string(),
+                    };
+                    run_stmt_err.set_error_code(ErrorCode::Internal);
+                    vec![
+                        RunStmtResponse {
+                            result: Some(Failure(run_stmt_err)),
+                        };
+                        uuids.len()
+                    ]
+                }
+            };
 
-            let res = self.scheduler.submit_txn(req).await.unwrap();
+            // A node that stops now resumes after this batch, even if some of its txns failed
+            if let Err(err) =
+                self.scheduler
+                    .executor()
+                    .apply_batch(&sequencer_id, last_lsn, &txn_uuids)
+            {
+                eprintln!("Failed to record the last applied LSN: {}", err);
+            }
+            self.checkpoint_if_due().await;
 
-            // If SequencerServer is local, notify that the txn is complete
+            // If SequencerServer is local, notify that the txn is complete. Clients that have
+            // gone away are not waiting for their result.
             {
                 let mut finished_txn_notifier = self.finished_txn_notifier.lock().unwrap();
-                if let Some(tx) = finished_txn_notifier.remove(&uuid) {
-                    tx.send(res).unwrap();
+                for (uuid, res) in uuids.into_iter().zip(results) {
+                    if let Some(tx) = uuid.and_then(|uuid| finished_txn_notifier.remove(&uuid)) {
+                        let _ = tx.send(res);
+                    }
+                }
+            }
+        }
+    }
+
+    // Every few batches, drops the requests the executor has applied from the request log
+    async fn checkpoint_if_due(&mut self) {
+        let request_log = match &self.request_log {
+            Some(request_log) => request_log,
+            None => return,
+        };
+
+        self.batches_since_checkpoint += 1;
+        if self.batches_since_checkpoint < CHECKPOINT_INTERVAL {
+            return;
+        }
+        self.batches_since_checkpoint = 0;
+
+        let checkpointed = match self
+            .scheduler
+            .executor()
+            .last_applied_lsn(&self.sequencer_id)
+        {
+            Ok(lsn) => {
+                on_request_log(request_log, move |request_log| request_log.checkpoint(lsn)).await
+            }
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = checkpointed {
+            eprintln!("Failed to checkpoint the request log: {:#}", err);
+        }
+    }
+
+    fn txn_uuids_of(batch: &EpochBatch) -> Vec<String> {
+        batch.requests.iter().map(|req| req.uuid.clone()).collect()
+    }
+}
+
+/// Requests that have been accepted but not yet appended to the global request log, and where
+/// to append them.
+#[derive(Debug)]
+struct PendingEpoch {
+    // Stamped on every batch, since other sequencers number their batches on their own
+    sequencer_id: String,
+    next_epoch: u64,
+    next_lsn: u64,
+    // Commit timestamp of the last stamped txn
+    last_timestamp_micros: i64,
+    requests: Vec<RunStmtRequestWithUuid>,
+    global_req_log: GlobalRequestLog,
+    request_log: Option<SharedRequestLog>,
+    raft_node: Option<RaftNode>,
+    // Held while a batch is appended, so batches reach the log in the order they are numbered
+    append_lock: Arc<sync::Mutex<()>>,
+}
+
+impl PendingEpoch {
+    fn new(global_req_log: GlobalRequestLog) -> Self {
+        Self {
+            sequencer_id: Uuid::new_v4().to_string(),
+            next_epoch: 0,
+            next_lsn: 1,
+            last_timestamp_micros: 0,
+            requests: Vec::new(),
+            global_req_log,
+            request_log: None,
+            raft_node: None,
+            append_lock: Arc::new(sync::Mutex::new(())),
+        }
+    }
+
+    // Numbers `requests` as the next batch and appends it to the global request log. With a
+    // durable request log the batch is fsync'd first. With Raft the batch is proposed to the
+    // replicated log and reaches the global request log once committed. On failure the requests
+    // are dropped and removed from the request log again.
+    async fn sequence_batch(
+        pending_epoch: &Mutex<Self>,
+        requests: Vec<RunStmtRequestWithUuid>,
+    ) -> anyhow::Result<()> {
+        let append_lock = pending_epoch.lock().unwrap().append_lock.clone();
+        let _appending = append_lock.lock().await;
+
+        let (batch, global_req_log, request_log, raft_node) = {
+            let mut pending_epoch = pending_epoch.lock().unwrap();
+            (
+                pending_epoch.next_batch(requests),
+                pending_epoch.global_req_log.clone(),
+                pending_epoch.request_log.clone(),
+                pending_epoch.raft_node.clone(),
+            )
+        };
+
+        if let Some(request_log) = &request_log {
+            let batch = batch.clone();
+            on_request_log(request_log, move |request_log| {
+                request_log.append_batch(&batch)
+            })
+            .await?;
+        }
+
+        let handed_on = match &raft_node {
+            Some(raft_node) => raft_node
+                .propose(batch.clone())
+                .map_err(anyhow::Error::from),
+            None => global_req_log.append(batch.clone()).await,
+        };
+
+        if let Err(err) = handed_on {
+            if let Some(request_log) = &request_log {
+                let batch = batch.clone();
+                let unlogged = on_request_log(request_log, move |request_log| {
+                    request_log.remove_last_batch(&batch)
+                })
+                .await;
+                if let Err(log_err) = unlogged {
+                    return Err(err.context(format!(
+                        "the request log still holds the batch: {}",
+                        log_err
+                    )));
                 }
             }
+            return Err(err);
+        }
+
+        let mut pending_epoch = pending_epoch.lock().unwrap();
+        pending_epoch.next_epoch += 1;
+        pending_epoch.next_lsn += batch.requests.len() as u64;
+
+        Ok(())
+    }
+
+    // Stamps `requests` and numbers them after the last batch that was appended
+    fn next_batch(&mut self, mut requests: Vec<RunStmtRequestWithUuid>) -> EpochBatch {
+        for req in requests.iter_mut() {
+            req.stamp = Some(self.next_stamp());
+        }
+
+        EpochBatch {
+            epoch: self.next_epoch,
+            requests,
+            first_lsn: self.next_lsn,
+            sequencer_id: self.sequencer_id.clone(),
+        }
+    }
+
+    // Txns are stamped in log order. Their timestamps strictly increase even if the clock goes
+    // back, as long as the sequencer does not restart.
+    fn next_stamp(&mut self) -> TxnStamp {
+        self.last_timestamp_micros = common::now_micros().max(self.last_timestamp_micros + 1);
+
+        TxnStamp {
+            timestamp_micros: self.last_timestamp_micros,
+            seed: Uuid::new_v4().as_u64_pair().0,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct SequencerServer {
-    global_req_log_tx: Sender<RunStmtRequestWithUuid>,
-    finished_txn_notifier: Arc<Mutex<HashMap<Uuid, sync::oneshot::Sender<RunStmtResponse>>>>,
+    finished_txn_notifier: FinishedTxnNotifier,
+    pending_epoch: Arc<Mutex<PendingEpoch>>,
+    epoch_duration: Option<Duration>,
+    // Runs the reconnaissance of dependent txns before they are sequenced
+    executor: Mutex<Option<Executor>>,
 }
 
 impl SequencerServer {
@@ -49,29 +325,217 @@ impl SequencerServer {
         self.build_sequencer(Scheduler::default())
     }
 
+    /// Dependent txns are reconnoitered against the executor of the last sequencer built. The
+    /// sequencer resumes after the last batch its executor applied.
     pub fn build_sequencer(&self, scheduler: Scheduler) -> Sequencer {
-        let global_req_log_rx = self.global_req_log_tx.subscribe();
+        let mut pending_epoch = self.pending_epoch.lock().unwrap();
+        let executor = scheduler.executor();
+        let last_applied_lsn = executor
+            .last_applied_lsn(&pending_epoch.sequencer_id)
+            .unwrap_or_default();
+        *self.executor.lock().unwrap() = Some(executor);
+
+        pending_epoch.next_lsn = pending_epoch.next_lsn.max(last_applied_lsn + 1);
+        // Raft delivers the batches of its log from the start again after a restart
+        if let Some(raft_node) = &pending_epoch.raft_node {
+            raft_node.skip_applied(last_applied_lsn);
+        }
+        let global_req_log_rx = pending_epoch.global_req_log.subscribe();
         let finished_txn_notifier = self.finished_txn_notifier.clone();
+        let request_log = pending_epoch.request_log.clone();
+        let sequencer_id = pending_epoch.sequencer_id.clone();
 
         Sequencer {
             scheduler,
             global_req_log_rx,
             finished_txn_notifier,
+            request_log,
+            sequencer_id,
+            replayed_through_lsn: last_applied_lsn,
+            batches_since_checkpoint: 0,
+        }
+    }
+
+    /// Durably logs every batch before it is handed to sequencers. Sequencers built afterwards
+    /// can `replay` the log to recover.
+    pub fn with_request_log(self, request_log: RequestLog) -> Self {
+        {
+            let mut pending_epoch = self.pending_epoch.lock().unwrap();
+            pending_epoch.next_epoch = request_log.next_epoch();
+            pending_epoch.next_lsn = request_log.next_lsn();
+            pending_epoch.sequencer_id = request_log.sequencer_id().to_string();
+            pending_epoch.request_log = Some(Arc::new(Mutex::new(request_log)));
+        }
+
+        self
+    }
+
+    /// Replicates the global request log with Raft. Batches are proposed to the Raft log and
+    /// sequencers built afterwards receive them from `raft_node` once they are committed.
+    /// Requests sent to a follower are forwarded to the leader.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn with_raft(self, raft_node: RaftNode) -> Self {
+        let mut dropped_rx = raft_node.subscribe_dropped();
+        let finished_txn_notifier = Arc::downgrade(&self.finished_txn_notifier);
+
+        // A batch a new leader overwrote never reaches the global request log
+        tokio::spawn(async move {
+            loop {
+                let batch = match dropped_rx.recv().await {
+                    Some(batch) => batch,
+                    None => return,
+                };
+
+                let finished_txn_notifier = match finished_txn_notifier.upgrade() {
+                    Some(finished_txn_notifier) => finished_txn_notifier,
+                    None => return,
+                };
+
+                // Dropping the notifiers fails the waiting clients
+                let mut finished_txn_notifier = finished_txn_notifier.lock().unwrap();
+                for uuid in batch
+                    .requests
+                    .iter()
+                    .flat_map(|req| Uuid::parse_str(&req.uuid))
+                {
+                    finished_txn_notifier.remove(&uuid);
+                }
+            }
+        });
+
+        {
+            let mut pending_epoch = self.pending_epoch.lock().unwrap();
+            pending_epoch.sequencer_id = raft::SEQUENCER_ID.to_string();
+            pending_epoch.global_req_log = raft_node.global_req_log();
+            pending_epoch.raft_node = Some(raft_node);
         }
+
+        self
     }
 
-    pub fn new(global_req_log_tx: Sender<RunStmtRequestWithUuid>) -> Self {
+    /// Appends every request to the global request log as its own batch, as soon as it arrives.
+    pub fn new(global_req_log: GlobalRequestLog) -> Self {
         Self {
-            global_req_log_tx,
             finished_txn_notifier: Arc::new(Mutex::new(HashMap::default())),
+            pending_epoch: Arc::new(Mutex::new(PendingEpoch::new(global_req_log))),
+            epoch_duration: None,
+            executor: Mutex::new(None),
+        }
+    }
+
+    /// Collects requests for `epoch_duration` and appends them to the global request log as one
+    /// numbered batch per epoch. Epochs without requests are skipped.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn new_with_epochs(global_req_log: GlobalRequestLog, epoch_duration: Duration) -> Self {
+        let sequencer_server = Self {
+            epoch_duration: Some(epoch_duration),
+            ..Self::new(global_req_log)
+        };
+
+        let pending_epoch = Arc::downgrade(&sequencer_server.pending_epoch);
+        let finished_txn_notifier = sequencer_server.finished_txn_notifier.clone();
+
+        tokio::spawn(async move {
+            let mut epoch_interval = tokio::time::interval(epoch_duration);
+            loop {
+                epoch_interval.tick().await;
+
+                // The SequencerServer has gone away, so no more requests will arrive
+                let pending_epoch = match pending_epoch.upgrade() {
+                    Some(pending_epoch) => pending_epoch,
+                    None => return,
+                };
+
+                let requests = std::mem::take(&mut pending_epoch.lock().unwrap().requests);
+                if requests.is_empty() {
+                    continue;
+                }
+
+                let uuids: Vec<String> = requests.iter().map(|req| req.uuid.clone()).collect();
+
+                if let Err(err) = PendingEpoch::sequence_batch(&pending_epoch, requests).await {
+                    eprintln!("Failed to sequence epoch: {:#}", err);
+
+                    // Dropping the notifiers fails the waiting clients
+                    let mut finished_txn_notifier = finished_txn_notifier.lock().unwrap();
+                    for uuid in uuids.iter().flat_map(|uuid| Uuid::parse_str(uuid)) {
+                        finished_txn_notifier.remove(&uuid);
+                    }
+                }
+            }
+        });
+
+        sequencer_server
+    }
+
+    // Only the Raft leader may append to the global request log. Returns a client for the leader
+    // if this node is a follower.
+    async fn leader_client(
+        &self,
+    ) -> Result<Option<SequencerGrpcServiceClient<Channel>>, tonic::Status> {
+        let raft_node = self.pending_epoch.lock().unwrap().raft_node.clone();
+        let raft_node = match raft_node {
+            Some(raft_node) => raft_node,
+            None => return Ok(None),
+        };
+
+        match raft_node.leader_id() {
+            Some(leader_id) if leader_id == raft_node.id() => Ok(None),
+            Some(leader_id) => {
+                let leader_client = SequencerGrpcServiceClient::connect(leader_id)
+                    .await
+                    .map_err(|err| tonic::Status::unavailable(err.to_string()))?;
+                Ok(Some(leader_client))
+            }
+            None => Err(tonic::Status::unavailable(
+                "no raft leader has been elected yet",
+            )),
+        }
+    }
+
+    // Appends a request to the global request log and waits until it has been run.
+    async fn sequence(
+        &self,
+        req: RunStmtRequestWithUuid,
+    ) -> Result<tonic::Response<RunStmtResponse>, tonic::Status> {
+        let txn_uuid = Uuid::parse_str(&req.uuid).map_err(|_| {
+            tonic::Status::invalid_argument(format!("invalid txn uuid {}", req.uuid))
+        })?;
+
+        let (finished_txn_tx, finished_txn_rx) = sync::oneshot::channel();
+
+        {
+            let mut finished_txn_notifier = self.finished_txn_notifier.lock().unwrap();
+            finished_txn_notifier.insert(txn_uuid, finished_txn_tx);
+        }
+
+        // Without epochs, the request is sequenced immediately as a batch of one
+        let sequenced = match self.epoch_duration {
+            Some(_) => {
+                self.pending_epoch.lock().unwrap().requests.push(req);
+                Ok(())
+            }
+            None => PendingEpoch::sequence_batch(&self.pending_epoch, vec![req]).await,
+        };
+
+        if let Err(err) = sequenced {
+            self.finished_txn_notifier.lock().unwrap().remove(&txn_uuid);
+            return Err(tonic::Status::unavailable(err.to_string()));
         }
+
+        let res = finished_txn_rx
+            .await
+            .map_err(|_| tonic::Status::internal("txn was dropped before it was sequenced"))?;
+
+        Ok(Response::new(res))
     }
 }
 
 impl Default for SequencerServer {
     fn default() -> Self {
-        let (global_req_log_tx, _) = sync::broadcast::channel(1);
-        Self::new(global_req_log_tx)
+        Self::new(GlobalRequestLog::new(1))
     }
 }
 
@@ -83,25 +547,95 @@ impl SequencerGrpcService for SequencerServer {
     ) -> Result<tonic::Response<RunStmtResponse>, tonic::Status> {
         let run_stmt_request = request.into_inner();
 
-        let txn_uuid = Uuid::new_v4();
+        if let Some(mut leader_client) = self.leader_client().await? {
+            return leader_client.run_stmt(run_stmt_request).await;
+        }
+
+        let executor = self.executor.lock().unwrap().clone();
+
+        for _ in 0..MAX_RESTARTS {
+            // Queries the reconnaissance cannot make sense of fail the same way once they run
+            let reconnaissance = match &executor {
+                Some(executor) => executor
+                    .reconnoiter(&run_stmt_request.query)
+                    .await
+                    .unwrap_or(None),
+                None => None,
+            };
+
+            let req = RunStmtRequestWithUuid {
+                query: run_stmt_request.query.clone(),
+                uuid: Uuid::new_v4().to_string(),
+                repartition: None,
+                reconnaissance,
+                stamp: None,
+            };
+
+            let res = self.sequence(req).await?;
+            if !matches!(res.get_ref().result, Some(Restart(_))) {
+                return Ok(res);
+            }
+        }
 
-        let req = RunStmtRequestWithUuid {
-            query: run_stmt_request.query.clone(),
-            uuid: txn_uuid.to_string().clone(),
+        let mut run_stmt_err = RunStmtErr {
+            error_code: 0,
+            detailed_message: format!(
+                "the txn restarted {} times because its records kept changing",
+                MAX_RESTARTS
+            ),
         };
+        run_stmt_err.set_error_code(ErrorCode::Busy);
 
-        let (finished_txn_tx, finished_txn_rx) = sync::oneshot::channel();
+        Ok(Response::new(RunStmtResponse {
+            result: Some(Failure(run_stmt_err)),
+        }))
+    }
 
-        {
-            let mut finished_txn_notifier = self.finished_txn_notifier.lock().unwrap();
-            finished_txn_notifier.insert(txn_uuid, finished_txn_tx);
+    // The call is sequenced as the query `CALL name(args)`, so it is logged, reconnoitered and
+    // restarted like any other txn
+    async fn call_procedure(
+        &self,
+        request: tonic::Request<CallProcedureRequest>,
+    ) -> Result<tonic::Response<RunStmtResponse>, tonic::Status> {
+        let call_procedure_request = request.into_inner();
+        let args: Vec<String> = call_procedure_request
+            .args
+            .iter()
+            .map(|arg| expr::literal_of(arg).to_string())
+            .collect();
+        let query = format!(
+            "CALL {}({})",
+            ast::Ident::with_quote('"', call_procedure_request.name),
+            args.join(", ")
+        );
+
+        self.run_stmt(tonic::Request::new(RunStmtRequest { query }))
+            .await
+    }
+
+    async fn repartition(
+        &self,
+        request: tonic::Request<RepartitionRequest>,
+    ) -> Result<tonic::Response<RunStmtResponse>, tonic::Status> {
+        let repartition_request = request.into_inner();
+
+        if let Some(mut leader_client) = self.leader_client().await? {
+            return leader_client.repartition(repartition_request).await;
         }
 
-        self.global_req_log_tx.send(req).unwrap();
+        let partition_map = repartition_request
+            .partition_map
+            .ok_or_else(|| tonic::Status::invalid_argument("a partition map is required"))?;
 
-        let res = finished_txn_rx.await.unwrap();
+        let req = RunStmtRequestWithUuid {
+            query: String::new(),
+            uuid: Uuid::new_v4().to_string(),
+            repartition: Some(partition_map),
+            reconnaissance: None,
+            stamp: None,
+        };
 
-        Ok(Response::new(res))
+        self.sequence(req).await
     }
 }
 
@@ -109,11 +643,23 @@ impl SequencerGrpcService for SequencerServer {
 mod tests {
     use crate::calvinite_tonic::run_stmt_response::Result::Success;
     use crate::calvinite_tonic::sequencer_grpc_service_client::SequencerGrpcServiceClient;
+    use crate::calvinite_tonic::sequencer_grpc_service_server::SequencerGrpcService;
     use crate::calvinite_tonic::sequencer_grpc_service_server::SequencerGrpcServiceServer;
-    use crate::calvinite_tonic::{RunStmtRequest, RunStmtResponse, RunStmtResults};
+    use crate::calvinite_tonic::{
+        EpochBatch, RecordStorage, RunStmtRequest, RunStmtRequestWithUuid, RunStmtResponse,
+        RunStmtResults,
+    };
+    use crate::common;
+    use crate::executor::Executor;
     use crate::scheduler::Scheduler;
-    use crate::sequencer::{Sequencer, SequencerServer};
+    use crate::sequencer::global_request_log::GlobalRequestLog;
+    use crate::sequencer::request_log::RequestLog;
+    use crate::sequencer::{PendingEpoch, SequencerServer};
+    use crate::storage::{EngineKind, StorageConfig};
     use faux::when;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
     use tokio::net::TcpListener;
     use tonic::transport::Server;
     use tonic::Request;
@@ -128,12 +674,16 @@ mod tests {
 
         let mut scheduler = Scheduler::faux();
 
-        when!(scheduler.submit_txn).then_return(Ok(RunStmtResponse {
+        when!(scheduler.submit_batch).then_return(Ok(vec![RunStmtResponse {
             result: Some(Success(RunStmtResults {
                 uuid: uuid::Uuid::new_v4().to_string(),
                 results: vec![],
+                columns: vec![],
+                result_sets: vec![],
+                rows_affected: 0,
             })),
-        }));
+        }]));
+        when!(scheduler.executor).then_return(Executor::default());
 
         let sequencer_server = SequencerServer::default();
         let mut sequencer = sequencer_server.build_sequencer(scheduler);
@@ -166,4 +716,239 @@ mod tests {
             panic!("Results were supposed to be successful")
         }
     }
+
+    #[tokio::test]
+    async fn batches_requests_into_epochs() {
+        let global_req_log = GlobalRequestLog::new(16);
+        let mut global_req_log_rx = global_req_log.subscribe();
+        let sequencer_server = Arc::new(SequencerServer::new_with_epochs(
+            global_req_log,
+            Duration::from_millis(50),
+        ));
+
+        let queries = [
+            "SELECT * FROM foo WHERE id = 1",
+            "SELECT * FROM foo WHERE id = 2",
+        ];
+
+        for query in queries {
+            let sequencer_server = sequencer_server.clone();
+            tokio::spawn(async move {
+                let _ = sequencer_server
+                    .run_stmt(Request::new(RunStmtRequest {
+                        query: query.into(),
+                    }))
+                    .await;
+            });
+        }
+
+        let batch = global_req_log_rx.recv().await.unwrap();
+
+        assert_eq!(batch.epoch, 0);
+        let mut batched_queries: Vec<String> =
+            batch.requests.into_iter().map(|req| req.query).collect();
+        batched_queries.sort();
+        assert_eq!(batched_queries, queries);
+    }
+
+    #[tokio::test]
+    async fn stamps_requests_in_log_order() {
+        let global_req_log = GlobalRequestLog::new(16);
+        let mut global_req_log_rx = global_req_log.subscribe();
+        let pending_epoch = Mutex::new(PendingEpoch::new(global_req_log));
+        // Stamps keep increasing even if the clock is behind the last stamp
+        let last_timestamp_micros = common::now_micros() + 60_000_000;
+        pending_epoch.lock().unwrap().last_timestamp_micros = last_timestamp_micros;
+
+        let mut stamps = Vec::new();
+        for batch_size in [2, 1] {
+            let queries = vec!["SELECT * FROM foo WHERE id = 1"; batch_size];
+            PendingEpoch::sequence_batch(&pending_epoch, batch(0, 1, &queries).requests)
+                .await
+                .unwrap();
+            let batch = global_req_log_rx.try_recv().unwrap();
+            stamps.extend(batch.requests.into_iter().map(|req| req.stamp.unwrap()));
+        }
+
+        let timestamps: Vec<i64> = stamps.iter().map(|stamp| stamp.timestamp_micros).collect();
+        assert_eq!(
+            timestamps,
+            (1..=3)
+                .map(|offset| last_timestamp_micros + offset)
+                .collect::<Vec<_>>()
+        );
+        assert_ne!(stamps[0].seed, stamps[1].seed);
+    }
+
+    #[tokio::test]
+    async fn unlogs_a_batch_nobody_received() {
+        let request_log_dir = tempfile::tempdir().unwrap();
+        let global_req_log = GlobalRequestLog::new(16);
+        let mut pending_epoch = PendingEpoch::new(global_req_log.clone());
+        pending_epoch.request_log = Some(Arc::new(Mutex::new(
+            RequestLog::open(request_log_dir.path()).unwrap(),
+        )));
+        let pending_epoch = Mutex::new(pending_epoch);
+
+        let requests = batch(0, 1, &["SELECT * FROM foo WHERE id = 1"]).requests;
+        assert!(PendingEpoch::sequence_batch(&pending_epoch, requests)
+            .await
+            .is_err());
+
+        let mut global_req_log_rx = global_req_log.subscribe();
+        let requests = batch(0, 1, &["SELECT * FROM foo WHERE id = 2"]).requests;
+        PendingEpoch::sequence_batch(&pending_epoch, requests)
+            .await
+            .unwrap();
+        assert_eq!(global_req_log_rx.try_recv().unwrap().first_lsn, 1);
+
+        let request_log = RequestLog::open(request_log_dir.path()).unwrap();
+        let entries = request_log.entries_after(0).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].request.as_ref().unwrap().query,
+            "SELECT * FROM foo WHERE id = 2"
+        );
+    }
+
+    fn batch(epoch: u64, first_lsn: u64, queries: &[&str]) -> EpochBatch {
+        EpochBatch {
+            epoch,
+            requests: queries
+                .iter()
+                .map(|query| RunStmtRequestWithUuid {
+                    query: query.to_string(),
+                    uuid: uuid::Uuid::new_v4().to_string(),
+                    repartition: None,
+                    reconnaissance: None,
+                    stamp: None,
+                })
+                .collect(),
+            first_lsn,
+            sequencer_id: String::new(),
+        }
+    }
+
+    fn storage_config(data_dir: &Path) -> StorageConfig {
+        StorageConfig {
+            engine: EngineKind::Lsm,
+            data_dir: Some(data_dir.to_path_buf()),
+        }
+    }
+
+    // Restarts a node on its data dir and replays the request log into it
+    async fn restart(request_log_dir: &Path, data_dir: &Path) -> Executor {
+        let request_log = RequestLog::open(request_log_dir).unwrap();
+        let sequencer_server = SequencerServer::default().with_request_log(request_log);
+        let executor = Executor::open(&storage_config(data_dir)).unwrap();
+        let mut sequencer = sequencer_server.build_sequencer(Scheduler::new(executor.clone()));
+        sequencer.replay().await.unwrap();
+        executor
+    }
+
+    async fn assert_val(executor: &Executor, expected_val: i64) {
+        let req = RunStmtRequestWithUuid {
+            query: "SELECT * FROM foo WHERE id = 1".into(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            repartition: None,
+            reconnaissance: None,
+            stamp: None,
+        };
+        match executor.execute(req).await.unwrap().result {
+            Some(Success(result)) => assert_eq!(
+                result.results,
+                vec![RecordStorage {
+                    values: vec![1i64.into(), expected_val.into()]
+                }]
+            ),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_last_applied_batch() {
+        let request_log_dir = tempfile::tempdir().unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let increment = "UPDATE foo SET val = val + 1 WHERE id = 1";
+
+        let sequencer_id = RequestLog::open(request_log_dir.path())
+            .unwrap()
+            .sequencer_id()
+            .to_string();
+        RequestLog::open(request_log_dir.path())
+            .unwrap()
+            .append_batch(&batch(
+                0,
+                1,
+                &[
+                    "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+                    "INSERT INTO foo VALUES (1, 0)",
+                ],
+            ))
+            .unwrap();
+        {
+            let executor = restart(request_log_dir.path(), data_dir.path()).await;
+            assert_eq!(executor.last_applied_lsn(&sequencer_id).unwrap(), 2);
+            assert_val(&executor, 0).await;
+        }
+
+        // The node stops halfway through the next batch, after its first txn was applied
+        let partly_applied = batch(1, 3, &[increment, increment]);
+        RequestLog::open(request_log_dir.path())
+            .unwrap()
+            .append_batch(&partly_applied)
+            .unwrap();
+        {
+            let executor = Executor::open(&storage_config(data_dir.path())).unwrap();
+            executor
+                .execute(partly_applied.requests[0].clone())
+                .await
+                .unwrap();
+        }
+
+        let executor = restart(request_log_dir.path(), data_dir.path()).await;
+        assert_eq!(executor.last_applied_lsn(&sequencer_id).unwrap(), 4);
+        assert_val(&executor, 2).await;
+    }
+
+    #[tokio::test]
+    async fn skips_only_its_own_replayed_batches() {
+        let request_log_dir = tempfile::tempdir().unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let increment = "UPDATE foo SET val = val + 1 WHERE id = 1";
+
+        let mut request_log = RequestLog::open(request_log_dir.path()).unwrap();
+        let sequencer_id = request_log.sequencer_id().to_string();
+        request_log
+            .append_batch(&batch(
+                0,
+                1,
+                &[
+                    "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+                    "INSERT INTO foo VALUES (1, 0)",
+                ],
+            ))
+            .unwrap();
+
+        let global_req_log = GlobalRequestLog::new(16);
+        let executor = Executor::open(&storage_config(data_dir.path())).unwrap();
+        let mut sequencer = SequencerServer::new(global_req_log.clone())
+            .with_request_log(request_log)
+            .build_sequencer(Scheduler::new(executor.clone()));
+        sequencer.replay().await.unwrap();
+        tokio::spawn(async move { sequencer.serve().await });
+
+        // Another sequencer numbers its batches from 1 as well
+        let mut replayed = batch(0, 2, &[increment]);
+        replayed.sequencer_id = sequencer_id;
+        let mut other = batch(0, 1, &[increment]);
+        other.sequencer_id = "other".into();
+        global_req_log.append(replayed).await.unwrap();
+        global_req_log.append(other).await.unwrap();
+
+        while executor.last_applied_lsn("other").unwrap() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_val(&executor, 1).await;
+    }
 }
@@ -0,0 +1,440 @@
+use crate::calvinite_tonic::{EpochBatch, LogEntry};
+use anyhow::anyhow;
+use prost::Message;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const LOG_FILE_NAME: &str = "requests.log";
+const CHECKPOINT_FILE_NAME: &str = "checkpoint";
+const SEQUENCER_ID_FILE_NAME: &str = "sequencer_id";
+
+/// Durable, append-only copy of the global request log.
+///
+/// Every sequenced request is stored as a length delimited `LogEntry` numbered by a log sequence
+/// number (LSN). LSNs start at 1, so an LSN of 0 means "nothing has been logged". A whole
+/// `EpochBatch` is written with a single fsync. A checkpoint drops the entries before it, so the
+/// log only grows with the requests that have not been applied yet.
+///
+/// LSNs only order the batches of the sequencer that numbered them, so the log keeps the id of
+/// that sequencer too.
+#[derive(Debug)]
+pub struct RequestLog {
+    dir: PathBuf,
+    sequencer_id: String,
+    file: File,
+    file_len: u64,
+    next_lsn: u64,
+    next_epoch: u64,
+}
+
+impl RequestLog {
+    pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE_NAME))?;
+
+        // A crash in the middle of an append leaves a torn entry at the tail. It was never
+        // acknowledged to a client, so it is safe to drop.
+        let (entries, valid_len) = Self::read_entries(&mut file)?;
+        file.set_len(valid_len)?;
+        file.sync_all()?;
+
+        // A checkpoint may have dropped every entry, but never the LSN and epoch to go on with
+        let (checkpoint_lsn, checkpoint_next_epoch) = Self::read_checkpoint(&dir)?;
+        let (next_lsn, next_epoch) = entries
+            .last()
+            .map(|entry| (entry.lsn + 1, entry.epoch + 1))
+            .unwrap_or_default();
+        let next_lsn = next_lsn.max(checkpoint_lsn + 1);
+        let next_epoch = next_epoch.max(checkpoint_next_epoch);
+
+        let sequencer_id = match fs::read_to_string(dir.join(SEQUENCER_ID_FILE_NAME)) {
+            Ok(sequencer_id) => sequencer_id,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let sequencer_id = Uuid::new_v4().to_string();
+                Self::replace_file(&dir, SEQUENCER_ID_FILE_NAME, sequencer_id.as_bytes())?;
+                sequencer_id
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            dir,
+            sequencer_id,
+            file,
+            file_len: valid_len,
+            next_lsn,
+            next_epoch,
+        })
+    }
+
+    /// The id of the sequencer that numbers the batches of this log, the same across restarts.
+    pub fn sequencer_id(&self) -> &str {
+        &self.sequencer_id
+    }
+
+    pub fn next_lsn(&self) -> u64 {
+        self.next_lsn
+    }
+
+    pub fn next_epoch(&self) -> u64 {
+        self.next_epoch
+    }
+
+    /// Durably appends every request of the batch. Returns once the batch has been fsync'd.
+    pub fn append_batch(&mut self, batch: &EpochBatch) -> anyhow::Result<()> {
+        if batch.first_lsn != self.next_lsn {
+            return Err(anyhow!(
+                "batch starts at lsn {} but the log expects lsn {}",
+                batch.first_lsn,
+                self.next_lsn
+            ));
+        }
+
+        let buf = Self::encode_batch(batch)?;
+        let write_result = self
+            .file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data());
+
+        // Never leave a partial batch behind that later appends would be written after
+        if let Err(err) = write_result {
+            let _ = self.file.set_len(self.file_len);
+            return Err(err.into());
+        }
+
+        self.file_len += buf.len() as u64;
+        self.next_lsn += batch.requests.len() as u64;
+        self.next_epoch = batch.epoch + 1;
+
+        Ok(())
+    }
+
+    /// Drops `batch`, the last one appended, because it could not be handed on. A restart then
+    /// does not replay a request whose client was told it failed.
+    pub fn remove_last_batch(&mut self, batch: &EpochBatch) -> anyhow::Result<()> {
+        if batch.first_lsn + batch.requests.len() as u64 != self.next_lsn {
+            return Err(anyhow!(
+                "batch at lsn {} is not the last batch of the log",
+                batch.first_lsn
+            ));
+        }
+
+        let batch_len = Self::encode_batch(batch)?.len() as u64;
+        self.file.set_len(self.file_len - batch_len)?;
+        self.file.sync_data()?;
+
+        self.file_len -= batch_len;
+        self.next_lsn = batch.first_lsn;
+        self.next_epoch = batch.epoch;
+
+        Ok(())
+    }
+
+    /// Returns every logged entry with an LSN greater than `lsn`, in log order.
+    pub fn entries_after(&self, lsn: u64) -> anyhow::Result<Vec<LogEntry>> {
+        let mut file = File::open(self.dir.join(LOG_FILE_NAME))?;
+        let (entries, _) = Self::read_entries(&mut file)?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.lsn > lsn)
+            .collect())
+    }
+
+    /// Regroups consecutive log entries into the epoch batches they were sequenced in.
+    pub fn batches_of(entries: Vec<LogEntry>) -> Vec<EpochBatch> {
+        let mut batches: Vec<EpochBatch> = Vec::new();
+
+        for entry in entries.into_iter() {
+            let request = entry.request.unwrap_or_default();
+            match batches.last_mut() {
+                Some(batch) if batch.epoch == entry.epoch => batch.requests.push(request),
+                _ => batches.push(EpochBatch {
+                    epoch: entry.epoch,
+                    requests: vec![request],
+                    first_lsn: entry.lsn,
+                    sequencer_id: String::new(),
+                }),
+            }
+        }
+
+        batches
+    }
+
+    /// Records that the effects of every entry up to and including `lsn` are durable elsewhere
+    /// and drops those entries, so a restart only needs to replay the entries after it.
+    pub fn checkpoint(&mut self, lsn: u64) -> anyhow::Result<()> {
+        // The checkpoint is written first, so the log never loses track of where it goes on
+        let checkpoint = [lsn.to_le_bytes(), self.next_epoch.to_le_bytes()].concat();
+        Self::replace_file(&self.dir, CHECKPOINT_FILE_NAME, &checkpoint)?;
+
+        let mut buf = Vec::new();
+        for entry in self.entries_after(lsn)? {
+            entry.encode_length_delimited(&mut buf)?;
+        }
+        Self::replace_file(&self.dir, LOG_FILE_NAME, &buf)?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(self.dir.join(LOG_FILE_NAME))?;
+        self.file_len = buf.len() as u64;
+
+        Ok(())
+    }
+
+    /// Returns the LSN of the last checkpoint, or 0 if no checkpoint was ever taken.
+    pub fn last_checkpoint(&self) -> anyhow::Result<u64> {
+        Ok(Self::read_checkpoint(&self.dir)?.0)
+    }
+
+    // Returns the LSN of the last checkpoint and the epoch that followed it.
+    fn read_checkpoint(dir: &Path) -> anyhow::Result<(u64, u64)> {
+        let bytes = match fs::read(dir.join(CHECKPOINT_FILE_NAME)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+            Err(err) => return Err(err.into()),
+        };
+
+        let checkpoint: [u8; 16] = bytes
+            .try_into()
+            .map_err(|_| anyhow!("checkpoint file is corrupt"))?;
+        let (lsn, next_epoch) = checkpoint.split_at(8);
+
+        Ok((
+            u64::from_le_bytes(lsn.try_into()?),
+            u64::from_le_bytes(next_epoch.try_into()?),
+        ))
+    }
+
+    // Atomically replaces the contents of a file of the log dir.
+    fn replace_file(dir: &Path, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", name));
+
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(contents)?;
+        tmp_file.sync_all()?;
+
+        fs::rename(tmp_path, dir.join(name))?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    fn encode_batch(batch: &EpochBatch) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        for (offset, request) in batch.requests.iter().enumerate() {
+            let entry = LogEntry {
+                lsn: batch.first_lsn + offset as u64,
+                epoch: batch.epoch,
+                request: Some(request.clone()),
+            };
+            entry.encode_length_delimited(&mut buf)?;
+        }
+
+        Ok(buf)
+    }
+
+    // Returns every complete entry and the length of the file prefix they occupy.
+    fn read_entries(file: &mut File) -> anyhow::Result<(Vec<LogEntry>, u64)> {
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut entries = Vec::new();
+        let mut buf = bytes.as_slice();
+        while !buf.is_empty() {
+            // The decoder may consume part of a torn entry before failing, so decode from a copy
+            let mut entry_buf = buf;
+            match LogEntry::decode_length_delimited(&mut entry_buf) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    buf = entry_buf;
+                }
+                Err(_) => break,
+            }
+        }
+
+        Ok((entries, (bytes.len() - buf.len()) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calvinite_tonic::{EpochBatch, RunStmtRequestWithUuid};
+    use crate::sequencer::request_log::{RequestLog, LOG_FILE_NAME};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    fn batch(epoch: u64, first_lsn: u64, queries: &[&str]) -> EpochBatch {
+        EpochBatch {
+            epoch,
+            requests: queries
+                .iter()
+                .map(|query| RunStmtRequestWithUuid {
+                    query: query.to_string(),
+                    uuid: uuid::Uuid::new_v4().to_string(),
+                    repartition: None,
+                    reconnaissance: None,
+                    stamp: None,
+                })
+                .collect(),
+            first_lsn,
+            sequencer_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn reopened_log_keeps_appended_batches() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let batch0 = batch(0, 1, &["INSERT INTO foo VALUES (1, 2)"]);
+        let batch1 = batch(1, 2, &["SELECT * FROM foo WHERE id = 1", "SELECT 1"]);
+
+        {
+            let mut log = RequestLog::open(dir.path()).unwrap();
+            log.append_batch(&batch0).unwrap();
+            log.append_batch(&batch1).unwrap();
+        }
+
+        let log = RequestLog::open(dir.path()).unwrap();
+
+        assert_eq!(log.next_lsn(), 4);
+        assert_eq!(log.next_epoch(), 2);
+        assert_eq!(
+            RequestLog::batches_of(log.entries_after(0).unwrap()),
+            vec![batch0, batch1.clone()]
+        );
+        assert_eq!(
+            RequestLog::batches_of(log.entries_after(1).unwrap()),
+            vec![batch1]
+        );
+    }
+
+    #[test]
+    fn reopened_log_keeps_its_sequencer_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let sequencer_id = RequestLog::open(dir.path())
+            .unwrap()
+            .sequencer_id()
+            .to_string();
+
+        assert_eq!(
+            RequestLog::open(dir.path()).unwrap().sequencer_id(),
+            sequencer_id
+        );
+        assert_ne!(
+            RequestLog::open(tempfile::tempdir().unwrap().path())
+                .unwrap()
+                .sequencer_id(),
+            sequencer_id
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_order_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = RequestLog::open(dir.path()).unwrap();
+
+        assert!(log.append_batch(&batch(0, 2, &["SELECT 1"])).is_err());
+    }
+
+    #[test]
+    fn drops_torn_tail_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let batch0 = batch(0, 1, &["INSERT INTO foo VALUES (1, 2)"]);
+
+        {
+            let mut log = RequestLog::open(dir.path()).unwrap();
+            log.append_batch(&batch0).unwrap();
+        }
+
+        // Simulate a crash half way through writing the next entry
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(LOG_FILE_NAME))
+            .unwrap();
+        file.write_all(&[42, 1, 2, 3]).unwrap();
+
+        let mut log = RequestLog::open(dir.path()).unwrap();
+        assert_eq!(log.next_lsn(), 2);
+
+        let batch1 = batch(1, 2, &["SELECT * FROM foo WHERE id = 1"]);
+        log.append_batch(&batch1).unwrap();
+
+        assert_eq!(
+            RequestLog::batches_of(log.entries_after(0).unwrap()),
+            vec![batch0, batch1]
+        );
+    }
+
+    #[test]
+    fn checkpoint_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let mut log = RequestLog::open(dir.path()).unwrap();
+            assert_eq!(log.last_checkpoint().unwrap(), 0);
+            log.checkpoint(7).unwrap();
+        }
+
+        let log = RequestLog::open(dir.path()).unwrap();
+        assert_eq!(log.last_checkpoint().unwrap(), 7);
+        assert_eq!(log.next_lsn(), 8);
+    }
+
+    #[test]
+    fn checkpoint_drops_the_entries_before_it() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let batch0 = batch(0, 1, &["INSERT INTO foo VALUES (1, 2)", "SELECT 1"]);
+        let batch1 = batch(1, 3, &["SELECT * FROM foo WHERE id = 1"]);
+        let batch2 = batch(2, 4, &["SELECT 2"]);
+
+        {
+            let mut log = RequestLog::open(dir.path()).unwrap();
+            log.append_batch(&batch0).unwrap();
+            log.append_batch(&batch1).unwrap();
+            log.checkpoint(2).unwrap();
+            log.append_batch(&batch2).unwrap();
+            assert_eq!(
+                RequestLog::batches_of(log.entries_after(0).unwrap()),
+                vec![batch1, batch2.clone()]
+            );
+            log.checkpoint(4).unwrap();
+        }
+
+        let log = RequestLog::open(dir.path()).unwrap();
+        assert!(log.entries_after(0).unwrap().is_empty());
+        assert_eq!(log.next_lsn(), 5);
+        assert_eq!(log.next_epoch(), 3);
+    }
+
+    #[test]
+    fn removes_a_batch_that_was_not_handed_on() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let batch0 = batch(0, 1, &["INSERT INTO foo VALUES (1, 2)"]);
+        let batch1 = batch(1, 2, &["SELECT * FROM foo WHERE id = 1", "SELECT 1"]);
+
+        let mut log = RequestLog::open(dir.path()).unwrap();
+        log.append_batch(&batch0).unwrap();
+        log.append_batch(&batch1).unwrap();
+        assert!(log.remove_last_batch(&batch0).is_err());
+        log.remove_last_batch(&batch1).unwrap();
+        assert_eq!((log.next_lsn(), log.next_epoch()), (2, 1));
+
+        let log = RequestLog::open(dir.path()).unwrap();
+        assert_eq!(
+            RequestLog::batches_of(log.entries_after(0).unwrap()),
+            vec![batch0]
+        );
+    }
+}
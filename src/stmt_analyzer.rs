@@ -1,37 +1,122 @@
+use crate::calvinite_tonic::{ColumnValue, Reconnaissance, RecordStorage, RunStmtRequestWithUuid};
+use crate::catalog::{Catalog, CatalogErr, ColumnType, TableSchema};
 use crate::common::Record;
+use crate::expr;
+use crate::index::{Index, IndexLookup};
+use crate::procedure::{Procedure, ProcedureDdl};
 use sqlparser::ast;
 use sqlparser::ast::Expr;
 use sqlparser::dialect::GenericDialect;
-use sqlparser::parser::Parser;
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::ops::RangeInclusive;
+
+// The range of primary keys of a predicate that holds for no row
+#[allow(clippy::reversed_empty_ranges)]
+fn no_ids() -> RangeInclusive<i64> {
+    i64::MAX..=i64::MIN
+}
 
 /// Stores an analyzed SQL string made of many SQL Statements.
 #[derive(Clone, Debug)]
 pub struct SqlStmt {
-    str_stmt: String,
+    pub str_stmt: String,
     pub ast_stmts: Vec<ast::Statement>,
     pub selected_records: Vec<Record>,
     pub inserted_records: Vec<Record>,
     pub updated_records: Vec<Record>,
+    pub deleted_records: Vec<Record>,
+    /// Key ranges scanned by statements that are not keyed by primary key. A range lock keeps
+    /// other txns from inserting into the range while it is scanned. Index locks are ranges too,
+    /// of records that belong to no table, see [`Index::value_lock`].
+    pub read_ranges: Vec<RangeInclusive<Record>>,
+    pub write_ranges: Vec<RangeInclusive<Record>>,
+    /// Values looked up in indexes, to find the rows of a statement or to check that a unique
+    /// index stays unique.
+    pub index_lookups: Vec<IndexLookup>,
+    /// A CREATE or DROP PROCEDURE, which is the only statement of its request. `ast_stmts` is
+    /// empty then.
+    pub procedure_ddl: Option<ProcedureDdl>,
+}
+
+/// A table joined to the rows of a query, see [`SqlStmt::find_joins`].
+#[derive(Clone, Debug)]
+pub struct JoinedTable {
+    pub schema: TableSchema,
+    /// The alias or name that qualifies the columns of the table.
+    pub qualifier: String,
+    pub constraint: ast::Expr,
+    /// Whether the join is a LEFT JOIN, which keeps the rows no row of the table matches.
+    pub is_outer: bool,
+    /// The primary key of the row of the table a row of the tables joined before it matches, if
+    /// the constraint equates it with a column of those tables. The rows it finds are only known
+    /// once the query runs, so they are predicted by reconnaissance. A join without a key reads
+    /// every row of its table instead.
+    pub key: Option<ast::Expr>,
 }
 
 impl SqlStmt {
-    pub fn from_string(str_stmt: String) -> anyhow::Result<Self> {
-        let ast_stmts = Parser::parse_sql(&GenericDialect {}, &str_stmt)?;
+    /// Records are resolved against the tables in `catalog`. Statements on tables that do not
+    /// exist touch no records.
+    pub fn from_string(str_stmt: String, catalog: &Catalog) -> anyhow::Result<Self> {
+        let procedure_ddl = ProcedureDdl::parse(&str_stmt)?;
+        let ast_stmts = match procedure_ddl {
+            Some(_) => Vec::new(),
+            None => Self::parse(&str_stmt)?,
+        };
 
-        let inserted_records = ast_stmts
-            .iter()
-            .flat_map(Self::find_inserted_records)
-            .collect();
+        let mut selected_records = Vec::new();
+        let mut inserted_records = Vec::new();
+        let mut updated_records = Vec::new();
+        let mut deleted_records = Vec::new();
+        let mut read_ranges = Vec::new();
+        let mut write_ranges = Vec::new();
+        let mut index_lookups = Vec::new();
 
-        let updated_records = ast_stmts
-            .iter()
-            .flat_map(Self::find_updated_records)
-            .collect();
+        for stmt in Self::with_subqueries(&ast_stmts).iter() {
+            if let Some(schema) = Self::table_schema(stmt, catalog)? {
+                selected_records.extend(Self::find_selected_records(stmt, &schema));
+                inserted_records.extend(Self::find_inserted_records(stmt, &schema, catalog)?);
+                updated_records.extend(Self::find_updated_records(stmt, &schema));
+                deleted_records.extend(Self::find_deleted_records(stmt, &schema));
 
-        let selected_records = ast_stmts
-            .iter()
-            .flat_map(Self::find_selected_records)
-            .collect();
+                let (stmt_read_ranges, stmt_write_ranges) = Self::find_ranges(stmt, &schema);
+                read_ranges.extend(stmt_read_ranges);
+                write_ranges.extend(stmt_write_ranges);
+
+                let (index_read_locks, index_write_locks) = Self::find_index_locks(stmt, &schema);
+                read_ranges.extend(index_read_locks);
+                write_ranges.extend(index_write_locks);
+                index_lookups.extend(Self::find_index_scan(stmt, &schema));
+                index_lookups.extend(Self::find_unique_checks(stmt, &schema));
+
+                // Joins that cannot be evaluated fail the txn once it runs
+                for join in Self::find_joins(stmt, catalog).unwrap_or_default() {
+                    if join.key.is_none() {
+                        read_ranges.push(join.schema.all_records());
+                    }
+                }
+            }
+
+            if let ast::Statement::Insert { source, .. } = stmt {
+                if let Some((_, source_record)) = Self::insert_source(source, catalog)? {
+                    selected_records.push(source_record);
+                }
+            }
+
+            // Calls that cannot be evaluated fail the txn once it runs
+            if let Some((procedure, parameters)) = Self::called_procedure(stmt, catalog)? {
+                let footprint = procedure
+                    .args_of(parameters)
+                    .and_then(|args| procedure.footprint_of(catalog, &args));
+                if let Ok(Some((reads, writes))) = footprint {
+                    selected_records.extend(reads);
+                    updated_records.extend(writes);
+                }
+                write_ranges.extend(Self::find_procedure_index_locks(&procedure, catalog)?);
+            }
+        }
 
         Ok(Self {
             str_stmt,
@@ -39,10 +124,683 @@ impl SqlStmt {
             selected_records,
             inserted_records,
             updated_records,
+            deleted_records,
+            read_ranges,
+            write_ranges,
+            index_lookups,
+            procedure_ddl,
         })
     }
 
-    fn find_selected_records(stmt: &ast::Statement) -> Vec<Record> {
+    /// Parses `sql` like `Parser::parse_sql`, but also accepts `ABORT IF <condition>`, which
+    /// aborts the txn if the condition holds. It is parsed as `ASSERT NOT (<condition>)`. A
+    /// `CALL <procedure>(<args>)` is parsed as `EXECUTE <procedure>(<args>)`.
+    pub fn parse(sql: &str) -> Result<Vec<ast::Statement>, ParserError> {
+        let dialect = GenericDialect {};
+        let mut parser = Parser::new(Tokenizer::new(&dialect, sql).tokenize()?, &dialect);
+        let mut stmts = Vec::new();
+
+        loop {
+            while parser.consume_token(&Token::SemiColon) {}
+            if parser.peek_token() == Token::EOF {
+                return Ok(stmts);
+            }
+
+            let stmt = if parser.parse_keywords(&[Keyword::ABORT, Keyword::IF]) {
+                let condition = parser.parse_expr()?;
+                ast::Statement::Assert {
+                    message: Some(ast::Expr::Value(ast::Value::SingleQuotedString(format!(
+                        "ABORT IF {}",
+                        condition
+                    )))),
+                    condition: ast::Expr::UnaryOp {
+                        op: ast::UnaryOperator::Not,
+                        expr: Box::new(ast::Expr::Nested(Box::new(condition))),
+                    },
+                }
+            } else if parser.parse_keyword(Keyword::CALL) {
+                let name = parser.parse_identifier()?;
+                parser.expect_token(&Token::LParen)?;
+                let parameters = match parser.consume_token(&Token::RParen) {
+                    true => Vec::new(),
+                    false => {
+                        let parameters = parser.parse_comma_separated(Parser::parse_expr)?;
+                        parser.expect_token(&Token::RParen)?;
+                        parameters
+                    }
+                };
+                ast::Statement::Execute { name, parameters }
+            } else {
+                parser.parse_statement()?
+            };
+            stmts.push(stmt);
+
+            match parser.peek_token() {
+                Token::SemiColon | Token::EOF => {}
+                token => {
+                    return Err(ParserError::ParserError(format!(
+                        "Expected end of statement, found: {}",
+                        token
+                    )))
+                }
+            }
+        }
+    }
+
+    /// The statements along with a SELECT for every subquery of an ASSERT, which reads records
+    /// like any other query.
+    pub fn with_subqueries(ast_stmts: &[ast::Statement]) -> Vec<ast::Statement> {
+        let mut stmts = ast_stmts.to_vec();
+
+        for stmt in ast_stmts {
+            if let ast::Statement::Assert { condition, message } = stmt {
+                for expr in std::iter::once(condition).chain(message.iter()) {
+                    stmts.extend(
+                        expr::subqueries(expr)
+                            .into_iter()
+                            .map(|(_, query)| ast::Statement::Query(Box::new(query.clone()))),
+                    );
+                }
+            }
+        }
+
+        stmts
+    }
+
+    /// Like `from_string`, but also adds the records the request's reconnaissance predicted its
+    /// dependent statements touch.
+    pub fn from_request(req: &RunStmtRequestWithUuid, catalog: &Catalog) -> anyhow::Result<Self> {
+        let mut sql_stmt = Self::from_string(req.query.clone(), catalog)?;
+
+        if let Some(reconnaissance) = &req.reconnaissance {
+            sql_stmt.add_reconnaissance(reconnaissance)?;
+        }
+
+        Ok(sql_stmt)
+    }
+
+    fn add_reconnaissance(&mut self, reconnaissance: &Reconnaissance) -> anyhow::Result<()> {
+        for record in reconnaissance.write_records.iter() {
+            self.updated_records.push(bincode::deserialize(record)?);
+        }
+        for record in reconnaissance.read_records.iter() {
+            self.selected_records.push(bincode::deserialize(record)?);
+        }
+
+        Ok(())
+    }
+
+    /// Whether any statement needs a reconnaissance read to find the records it touches.
+    pub fn is_dependent(&self, catalog: &Catalog) -> anyhow::Result<bool> {
+        for stmt in Self::with_subqueries(&self.ast_stmts).iter() {
+            if Self::is_dependent_stmt(stmt, catalog)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// An UPDATE or DELETE is dependent unless its predicate bounds the primary key: the records
+    /// it writes are only known once the predicate is evaluated on every row. A SELECT is only
+    /// dependent if it finds its rows through an index or joins a table by primary key, otherwise
+    /// it scans a range of keys under a range lock. An `INSERT ... SELECT` is dependent unless it
+    /// copies the primary key of the selected row. Any statement that writes a value to a unique
+    /// index is dependent, as it reads the rows that already hold the value. A CALL is dependent
+    /// unless its procedure declares its footprint. Statements on tables or procedures that do not
+    /// exist are not dependent.
+    pub fn is_dependent_stmt(stmt: &ast::Statement, catalog: &Catalog) -> anyhow::Result<bool> {
+        if let Some((procedure, _)) = Self::called_procedure(stmt, catalog)? {
+            return Ok(procedure.footprint.is_none());
+        }
+
+        let schema = match Self::table_schema(stmt, catalog)? {
+            Some(schema) => schema,
+            None => return Ok(false),
+        };
+
+        let is_dependent = match stmt {
+            ast::Statement::Query(_) => {
+                Self::find_index_scan(stmt, &schema).is_some()
+                    || Self::find_joins(stmt, catalog)
+                        .unwrap_or_default()
+                        .iter()
+                        .any(|join| join.key.is_some())
+            }
+            ast::Statement::Update { selection, .. } | ast::Statement::Delete { selection, .. } => {
+                match selection {
+                    Some(selection) => Self::find_id_range_in_expr(selection, &schema).is_none(),
+                    None => true,
+                }
+            }
+            ast::Statement::Insert { source, .. } => {
+                Self::insert_source(source, catalog)?.is_some()
+                    && Self::find_inserted_records(stmt, &schema, catalog)?.is_empty()
+            }
+            _ => false,
+        };
+
+        Ok(is_dependent || !Self::find_unique_checks(stmt, &schema).is_empty())
+    }
+
+    /// The procedure a CALL runs and its arguments, if the procedure exists.
+    pub fn called_procedure<'a>(
+        stmt: &'a ast::Statement,
+        catalog: &Catalog,
+    ) -> anyhow::Result<Option<(Procedure, &'a [ast::Expr])>> {
+        match stmt {
+            ast::Statement::Execute { name, parameters } => Ok(catalog
+                .procedure(&name.value)?
+                .map(|procedure| (procedure, parameters.as_slice()))),
+            _ => Ok(None),
+        }
+    }
+
+    /// A procedure may write any column of the rows it writes, so a call locks every entry of
+    /// every index of the tables its footprint writes, or of every table if it declares none.
+    fn find_procedure_index_locks(
+        procedure: &Procedure,
+        catalog: &Catalog,
+    ) -> anyhow::Result<Vec<RangeInclusive<Record>>> {
+        let schemas = match &procedure.footprint {
+            Some(footprint) => footprint
+                .writes
+                .iter()
+                .filter_map(|key_ref| catalog.table(&key_ref.table).transpose())
+                .collect::<Result<Vec<_>, _>>()?,
+            None => catalog.tables()?,
+        };
+
+        let mut locks = Vec::new();
+        for schema in schemas {
+            for lock in schema.indexes.iter().map(Index::lock_all) {
+                if !locks.contains(&lock) {
+                    locks.push(lock);
+                }
+            }
+        }
+
+        Ok(locks)
+    }
+
+    /// The table and record the `source` of an `INSERT ... SELECT` copies, if its SELECT is keyed
+    /// by primary key and joins no table.
+    pub fn insert_source(
+        source: &ast::Query,
+        catalog: &Catalog,
+    ) -> anyhow::Result<Option<(TableSchema, Record)>> {
+        let select = match &source.body {
+            ast::SetExpr::Select(select) => select,
+            _ => return Ok(None),
+        };
+        if select.from.iter().any(|from| !from.joins.is_empty()) {
+            return Ok(None);
+        }
+
+        let source_stmt = ast::Statement::Query(Box::new(source.clone()));
+        let source_schema = match Self::table_schema(&source_stmt, catalog)? {
+            Some(source_schema) => source_schema,
+            None => return Ok(None),
+        };
+
+        Ok(select
+            .selection
+            .as_ref()
+            .and_then(|selection| Self::find_id_in_expr(selection, &source_schema))
+            .map(|source_record| (source_schema, source_record)))
+    }
+
+    /// The ranges a SELECT, UPDATE or DELETE that is not keyed by primary key reads and writes.
+    /// A predicate that bounds the primary key narrows the range. A predicate that looks up an
+    /// index scans no range, it locks the index instead. An UPDATE or DELETE whose predicate does
+    /// neither reads every record of its table, while its reconnaissance predicts the records it
+    /// writes.
+    fn find_ranges(
+        stmt: &ast::Statement,
+        schema: &TableSchema,
+    ) -> (Vec<RangeInclusive<Record>>, Vec<RangeInclusive<Record>>) {
+        let selection = match stmt {
+            ast::Statement::Query(query) => match &query.body {
+                ast::SetExpr::Select(select) => &select.selection,
+                _ => return (Vec::new(), Vec::new()),
+            },
+            ast::Statement::Update { selection, .. } | ast::Statement::Delete { selection, .. } => {
+                selection
+            }
+            _ => return (Vec::new(), Vec::new()),
+        };
+
+        let ids = match selection {
+            Some(selection) if Self::find_id_in_expr(selection, schema).is_some() => {
+                return (Vec::new(), Vec::new())
+            }
+            Some(selection) => Self::find_id_range_in_expr(selection, schema),
+            None => None,
+        };
+
+        match (stmt, ids) {
+            (_, None) if Self::find_index_scan(stmt, schema).is_some() => (Vec::new(), Vec::new()),
+            (ast::Statement::Query(_), ids) => (
+                vec![schema.record_range(ids.unwrap_or(i64::MIN..=i64::MAX))],
+                Vec::new(),
+            ),
+            (_, Some(ids)) => (Vec::new(), vec![schema.record_range(ids)]),
+            (_, None) => (vec![schema.all_records()], Vec::new()),
+        }
+    }
+
+    /// The index lookup a SELECT, UPDATE or DELETE whose predicate does not bound the primary key
+    /// finds its rows with: an equality between an indexed column and a literal, on its own or in
+    /// a conjunction. Its reconnaissance predicts the rows the lookup finds.
+    pub fn find_index_scan(stmt: &ast::Statement, schema: &TableSchema) -> Option<IndexLookup> {
+        let selection = match stmt {
+            ast::Statement::Query(query) => match &query.body {
+                ast::SetExpr::Select(select) => select.selection.as_ref()?,
+                _ => return None,
+            },
+            ast::Statement::Update { selection, .. } | ast::Statement::Delete { selection, .. } => {
+                selection.as_ref()?
+            }
+            _ => return None,
+        };
+
+        if Self::find_id_range_in_expr(selection, schema).is_some() {
+            return None;
+        }
+        Self::find_indexed_equality(selection, schema)
+    }
+
+    fn find_indexed_equality(expr: &ast::Expr, schema: &TableSchema) -> Option<IndexLookup> {
+        match expr {
+            ast::Expr::Nested(expr) => Self::find_indexed_equality(expr, schema),
+            ast::Expr::BinaryOp {
+                left,
+                op: ast::BinaryOperator::And,
+                right,
+            } => Self::find_indexed_equality(left, schema)
+                .or_else(|| Self::find_indexed_equality(right, schema)),
+            ast::Expr::BinaryOp {
+                left,
+                op: ast::BinaryOperator::Eq,
+                right,
+            } => [(left, right), (right, left)]
+                .into_iter()
+                .find_map(|(column, literal)| {
+                    let column_idx = schema.column_of(column).ok()?;
+                    let index = schema.index_on(column_idx)?;
+                    let column = &schema.columns[column_idx];
+                    let value = column
+                        .column_type
+                        .value_from_expr(&column.name, literal)
+                        .ok()?;
+
+                    Some(IndexLookup {
+                        index: index.clone(),
+                        value,
+                    })
+                }),
+            _ => None,
+        }
+    }
+
+    /// The values an INSERT or UPDATE writes to unique indexes, if they are literals. They are
+    /// looked up to tell whether another row already holds them.
+    pub fn find_unique_checks(stmt: &ast::Statement, schema: &TableSchema) -> Vec<IndexLookup> {
+        let mut lookups = Vec::new();
+        let mut check = |index: &Index, value: &ColumnValue| {
+            let lookup = IndexLookup {
+                index: index.clone(),
+                value: value.clone(),
+            };
+            if index.unique && !lookups.contains(&lookup) {
+                lookups.push(lookup);
+            }
+        };
+
+        match stmt {
+            ast::Statement::Insert {
+                columns, source, ..
+            } => {
+                for row in Self::values_to_insert(schema, columns, source) {
+                    for index in schema.indexes.iter() {
+                        if let Some(value) = index.value_of(&row) {
+                            check(index, value);
+                        }
+                    }
+                }
+            }
+            ast::Statement::Update { assignments, .. } => {
+                for assignment in assignments {
+                    let column_idx = match Self::assigned_column(assignment, schema) {
+                        Some(column_idx) => column_idx,
+                        None => continue,
+                    };
+                    let column = &schema.columns[column_idx];
+                    if let Ok(value) = column
+                        .column_type
+                        .value_from_expr(&column.name, &assignment.value)
+                    {
+                        for index in schema.indexes.iter() {
+                            if index.column == column_idx {
+                                check(index, &value);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        lookups
+    }
+
+    /// The index locks a statement takes for reading and writing. A lookup locks the entries of
+    /// its value. An INSERT of literal values locks the entries it adds. An UPDATE of an indexed
+    /// column, a DELETE, an `INSERT ... SELECT` or an INSERT of computed values like `NOW()`
+    /// locks every entry of the index, as it only learns the values whose entries it adds or
+    /// removes once it runs.
+    fn find_index_locks(
+        stmt: &ast::Statement,
+        schema: &TableSchema,
+    ) -> (Vec<RangeInclusive<Record>>, Vec<RangeInclusive<Record>>) {
+        let read_locks = Self::find_index_scan(stmt, schema)
+            .iter()
+            .map(IndexLookup::lock)
+            .collect();
+
+        let write_locks = match stmt {
+            ast::Statement::Insert {
+                columns, source, ..
+            } => match &source.body {
+                ast::SetExpr::Values(ast::Values(values)) => {
+                    let column_indexes = schema.insert_column_indexes(columns).unwrap_or_default();
+                    let computed_columns: Vec<usize> = values
+                        .iter()
+                        .flat_map(|value| column_indexes.iter().zip(value))
+                        .filter(|(_, expr)| !ColumnType::is_literal(expr))
+                        .map(|(column_idx, _)| *column_idx)
+                        .collect();
+                    let mut locks: Vec<_> = schema
+                        .indexes
+                        .iter()
+                        .filter(|index| computed_columns.contains(&index.column))
+                        .map(Index::lock_all)
+                        .collect();
+                    for row in Self::values_to_insert(schema, columns, source) {
+                        for index in schema.indexes.iter() {
+                            if let Some(lock) = index.value_of(&row).map(|v| index.value_lock(v)) {
+                                if !locks.contains(&lock) {
+                                    locks.push(lock);
+                                }
+                            }
+                        }
+                    }
+                    locks
+                }
+                _ => schema.indexes.iter().map(Index::lock_all).collect(),
+            },
+            ast::Statement::Update { assignments, .. } => schema
+                .indexes
+                .iter()
+                .filter(|index| {
+                    assignments.iter().any(|assignment| {
+                        Self::assigned_column(assignment, schema) == Some(index.column)
+                    })
+                })
+                .map(Index::lock_all)
+                .collect(),
+            ast::Statement::Delete { .. } => schema.indexes.iter().map(Index::lock_all).collect(),
+            _ => Vec::new(),
+        };
+
+        (read_locks, write_locks)
+    }
+
+    // The rows of an INSERT's VALUES list, with values that are not literals left NULL. Rows that
+    // do not match the schema fail the txn once it runs, so they write no value.
+    fn values_to_insert(
+        schema: &TableSchema,
+        columns: &[ast::Ident],
+        source: &ast::Query,
+    ) -> Vec<RecordStorage> {
+        match &source.body {
+            ast::SetExpr::Values(ast::Values(values)) => values
+                .iter()
+                .filter_map(|value| schema.row_of_literals(columns, value).ok())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn assigned_column(assignment: &ast::Assignment, schema: &TableSchema) -> Option<usize> {
+        let column_name = assignment
+            .id
+            .iter()
+            .map(|ident| ident.value.clone())
+            .collect::<Vec<_>>()
+            .join(".");
+        schema.column_index(&column_name)
+    }
+
+    /// Whether the txn locks `record`, on its own or in a range.
+    pub fn is_locked(&self, record: &Record) -> bool {
+        self.read_set().contains(record)
+            || self.write_set().contains(record)
+            || self
+                .read_ranges
+                .iter()
+                .chain(self.write_ranges.iter())
+                .any(|range| range.contains(record))
+    }
+
+    /// Every record this statement reads without writing it.
+    pub fn read_set(&self) -> Vec<Record> {
+        self.selected_records.clone()
+    }
+
+    /// Every record this statement writes. Updated and deleted records are also read, but the
+    /// write lock subsumes the read lock.
+    pub fn write_set(&self) -> Vec<Record> {
+        [
+            self.inserted_records.clone(),
+            self.updated_records.clone(),
+            self.deleted_records.clone(),
+        ]
+        .concat()
+    }
+
+    /// Whether any statement creates or drops a table, index or procedure.
+    pub fn changes_schema(&self) -> bool {
+        self.procedure_ddl.is_some()
+            || self.ast_stmts.iter().any(|stmt| {
+                matches!(
+                    stmt,
+                    ast::Statement::CreateTable { .. }
+                        | ast::Statement::CreateIndex { .. }
+                        | ast::Statement::Drop {
+                            object_type: ast::ObjectType::Table | ast::ObjectType::Index,
+                            ..
+                        }
+                )
+            })
+    }
+
+    /// The table a SELECT, INSERT, UPDATE or DELETE statement reads or writes.
+    pub fn table_name(stmt: &ast::Statement) -> Option<String> {
+        match stmt {
+            ast::Statement::Query(query) => match &query.body {
+                ast::SetExpr::Select(select) => match select.from.first() {
+                    Some(ast::TableWithJoins {
+                        relation: ast::TableFactor::Table { name, .. },
+                        ..
+                    }) => Some(name.to_string()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            ast::Statement::Insert { table_name, .. } => Some(table_name.to_string()),
+            ast::Statement::Update {
+                table:
+                    ast::TableWithJoins {
+                        relation: ast::TableFactor::Table { name, .. },
+                        ..
+                    },
+                ..
+            } => Some(name.to_string()),
+            ast::Statement::Delete { table_name, .. } => Some(table_name.to_string()),
+            _ => None,
+        }
+    }
+
+    /// The schema of the table a SELECT, INSERT, UPDATE or DELETE statement reads or writes, if
+    /// it exists. A query refers to its table by its alias, if it has one.
+    pub fn table_schema(
+        stmt: &ast::Statement,
+        catalog: &Catalog,
+    ) -> anyhow::Result<Option<TableSchema>> {
+        let mut schema = match Self::table_name(stmt) {
+            Some(table_name) => match catalog.table(&table_name)? {
+                Some(schema) => schema,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        if let Some(alias) = Self::table_alias(stmt) {
+            schema.name = alias;
+        }
+
+        Ok(Some(schema))
+    }
+
+    /// The alias a query gives its table.
+    pub fn table_alias(stmt: &ast::Statement) -> Option<String> {
+        match stmt {
+            ast::Statement::Query(query) => match &query.body {
+                ast::SetExpr::Select(select) => match select.from.first() {
+                    Some(ast::TableWithJoins {
+                        relation:
+                            ast::TableFactor::Table {
+                                alias: Some(alias), ..
+                            },
+                        ..
+                    }) => Some(alias.name.value.clone()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The tables a query joins to the rows of its table, in order. Only JOIN and LEFT JOIN with
+    /// an ON constraint are supported, and a query reads from one table only.
+    pub fn find_joins(
+        stmt: &ast::Statement,
+        catalog: &Catalog,
+    ) -> Result<Vec<JoinedTable>, CatalogErr> {
+        let from = match stmt {
+            ast::Statement::Query(query) => match &query.body {
+                ast::SetExpr::Select(select) => &select.from,
+                _ => return Ok(Vec::new()),
+            },
+            _ => return Ok(Vec::new()),
+        };
+        let from = match from.as_slice() {
+            [] => return Ok(Vec::new()),
+            [from] => from,
+            [_, from, ..] => return Err(CatalogErr::UnsupportedJoin(from.to_string())),
+        };
+
+        let mut qualifiers =
+            Vec::from_iter(Self::table_alias(stmt).or_else(|| Self::table_name(stmt)));
+        let mut joins = Vec::with_capacity(from.joins.len());
+        for join in from.joins.iter() {
+            let (name, alias) = match &join.relation {
+                ast::TableFactor::Table {
+                    name, alias, args, ..
+                } if args.is_empty() => (name.to_string(), alias),
+                _ => return Err(CatalogErr::UnsupportedJoin(join.to_string())),
+            };
+            let (constraint, is_outer) = match &join.join_operator {
+                ast::JoinOperator::Inner(ast::JoinConstraint::On(constraint)) => {
+                    (constraint, false)
+                }
+                ast::JoinOperator::LeftOuter(ast::JoinConstraint::On(constraint)) => {
+                    (constraint, true)
+                }
+                _ => return Err(CatalogErr::UnsupportedJoin(join.to_string())),
+            };
+
+            let schema = catalog.require_table(&name)?;
+            let qualifier = match alias {
+                Some(alias) => alias.name.value.clone(),
+                None => name,
+            };
+            let key = Self::find_join_key(constraint, &schema, &qualifier, &qualifiers);
+            qualifiers.push(qualifier.clone());
+
+            joins.push(JoinedTable {
+                schema,
+                qualifier,
+                constraint: constraint.clone(),
+                is_outer,
+                key,
+            });
+        }
+
+        Ok(joins)
+    }
+
+    // The other side of an equality between the qualified primary key of a joined table and a
+    // column qualified with one of the tables joined before it, on its own or in a conjunction
+    fn find_join_key(
+        constraint: &ast::Expr,
+        schema: &TableSchema,
+        qualifier: &str,
+        qualifiers: &[String],
+    ) -> Option<ast::Expr> {
+        match constraint {
+            ast::Expr::Nested(constraint) => {
+                Self::find_join_key(constraint, schema, qualifier, qualifiers)
+            }
+            ast::Expr::BinaryOp {
+                left,
+                op: ast::BinaryOperator::And,
+                right,
+            } => Self::find_join_key(left, schema, qualifier, qualifiers)
+                .or_else(|| Self::find_join_key(right, schema, qualifier, qualifiers)),
+            ast::Expr::BinaryOp {
+                left,
+                op: ast::BinaryOperator::Eq,
+                right,
+            } => [(left, right), (right, left)]
+                .into_iter()
+                .find_map(|(primary_key, key)| {
+                    let is_primary_key = match primary_key.as_ref() {
+                        Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+                            [table, column] => {
+                                table.value == qualifier
+                                    && column.value == schema.primary_key_column().name
+                            }
+                            _ => false,
+                        },
+                        _ => false,
+                    };
+                    let is_joined_before = match key.as_ref() {
+                        Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+                            [table, _] => qualifiers.contains(&table.value),
+                            _ => false,
+                        },
+                        _ => false,
+                    };
+
+                    (is_primary_key && is_joined_before).then(|| key.as_ref().clone())
+                }),
+            _ => None,
+        }
+    }
+
+    fn find_selected_records(stmt: &ast::Statement, schema: &TableSchema) -> Vec<Record> {
         match stmt {
             ast::Statement::Query(query) => match *query.clone() {
                 ast::Query {
@@ -52,7 +810,7 @@ impl SqlStmt {
                     ast::Select {
                         selection: Some(selection),
                         ..
-                    } => Vec::from_iter(Self::find_id_in_expr(&selection).into_iter()),
+                    } => Vec::from_iter(Self::find_id_in_expr(&selection, schema)),
                     _ => Vec::new(),
                 },
                 _ => Vec::new(),
@@ -61,69 +819,201 @@ impl SqlStmt {
         }
     }
 
-    fn find_updated_records(stmt: &ast::Statement) -> Vec<Record> {
+    fn find_updated_records(stmt: &ast::Statement, schema: &TableSchema) -> Vec<Record> {
         match stmt {
             ast::Statement::Update {
                 selection: Some(selection),
                 ..
-            } => Vec::from_iter(Self::find_id_in_expr(selection).into_iter()),
+            } => Vec::from_iter(Self::find_id_in_expr(selection, schema)),
             _ => Vec::new(),
         }
     }
 
-    pub fn find_id_in_expr(expr: &ast::Expr) -> Option<Record> {
+    fn find_deleted_records(stmt: &ast::Statement, schema: &TableSchema) -> Vec<Record> {
+        match stmt {
+            ast::Statement::Delete {
+                selection: Some(selection),
+                ..
+            } => Vec::from_iter(Self::find_id_in_expr(selection, schema)),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Finds the record selected by a `<primary key> = <number>` predicate.
+    pub fn find_id_in_expr(expr: &ast::Expr, schema: &TableSchema) -> Option<Record> {
         match expr {
             ast::Expr::BinaryOp {
                 left,
                 op: ast::BinaryOperator::Eq,
                 right,
-            } => match (*left.clone(), *right.clone()) {
-                (
-                    Expr::Identifier(ast::Ident { value, .. }),
-                    Expr::Value(ast::Value::Number(number, _)),
-                ) => {
-                    if value == "id" {
-                        if let Ok(num) = number.parse() {
-                            Some(Record { id: num })
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                }
-                _ => None,
-            },
+            } if Self::is_primary_key(left, schema) => {
+                Self::expr_to_num(right).map(|id| schema.record(id))
+            }
             _ => None,
         }
     }
 
-    fn find_inserted_records(stmt: &ast::Statement) -> Vec<Record> {
-        match stmt {
-            ast::Statement::Insert { source, .. } => match &source.body {
-                ast::SetExpr::Values(ast::Values(values)) => values
-                    .iter()
-                    .flat_map(Self::first_num_from_value_vec)
-                    .collect(),
-                _ => Vec::new(),
+    fn find_inserted_records(
+        stmt: &ast::Statement,
+        schema: &TableSchema,
+        catalog: &Catalog,
+    ) -> anyhow::Result<Vec<Record>> {
+        let (columns, source) = match stmt {
+            ast::Statement::Insert {
+                columns, source, ..
+            } => (columns, source),
+            _ => return Ok(Vec::new()),
+        };
+
+        // Inserts that name the wrong columns fail once they run
+        let primary_key_position = match schema.insert_column_indexes(columns) {
+            Ok(column_indexes) => column_indexes
+                .iter()
+                .position(|column_idx| *column_idx == schema.primary_key),
+            Err(_) => None,
+        };
+        let primary_key_position = match primary_key_position {
+            Some(primary_key_position) => primary_key_position,
+            None => return Ok(Vec::new()),
+        };
+
+        match &source.body {
+            ast::SetExpr::Values(ast::Values(values)) => Ok(values
+                .iter()
+                .flat_map(|value| {
+                    value
+                        .get(primary_key_position)
+                        .and_then(Self::expr_to_num)
+                        .map(|id| schema.record(id))
+                })
+                .collect()),
+            ast::SetExpr::Select(select) => {
+                let (source_schema, source_record) = match Self::insert_source(source, catalog)? {
+                    Some(insert_source) => insert_source,
+                    None => return Ok(Vec::new()),
+                };
+
+                let copies_primary_key = match source_schema.projection(&select.projection) {
+                    Ok(projection) => projection
+                        .get(primary_key_position)
+                        .map(|projected| projected.index == source_schema.primary_key),
+                    Err(_) => None,
+                };
+
+                match copies_primary_key {
+                    Some(true) => Ok(vec![schema.record(source_record.id)]),
+                    _ => Ok(Vec::new()),
+                }
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Finds the interval of primary keys a predicate can hold for, if it bounds the primary key
+    /// with comparisons, BETWEEN or IN to numbers.
+    pub fn find_id_range_in_expr(
+        expr: &ast::Expr,
+        schema: &TableSchema,
+    ) -> Option<RangeInclusive<i64>> {
+        let is_primary_key = |expr: &ast::Expr| Self::is_primary_key(expr, schema);
+
+        match expr {
+            ast::Expr::Nested(expr) => Self::find_id_range_in_expr(expr, schema),
+            ast::Expr::BinaryOp {
+                left,
+                op: ast::BinaryOperator::And,
+                right,
+            } => match (
+                Self::find_id_range_in_expr(left, schema),
+                Self::find_id_range_in_expr(right, schema),
+            ) {
+                (Some(left), Some(right)) => {
+                    Some(*left.start().max(right.start())..=*left.end().min(right.end()))
+                }
+                (left, right) => left.or(right),
             },
-            _ => Vec::new(),
+            ast::Expr::BinaryOp {
+                left,
+                op: ast::BinaryOperator::Or,
+                right,
+            } => {
+                let left = Self::find_id_range_in_expr(left, schema)?;
+                let right = Self::find_id_range_in_expr(right, schema)?;
+                match (left.is_empty(), right.is_empty()) {
+                    (true, _) => Some(right),
+                    (_, true) => Some(left),
+                    _ => Some(*left.start().min(right.start())..=*left.end().max(right.end())),
+                }
+            }
+            ast::Expr::BinaryOp { left, op, right } => {
+                let (op, id) = if is_primary_key(left) {
+                    (op.clone(), Self::expr_to_num(right)?)
+                } else if is_primary_key(right) {
+                    let op = match op {
+                        ast::BinaryOperator::Gt => ast::BinaryOperator::Lt,
+                        ast::BinaryOperator::GtEq => ast::BinaryOperator::LtEq,
+                        ast::BinaryOperator::Lt => ast::BinaryOperator::Gt,
+                        ast::BinaryOperator::LtEq => ast::BinaryOperator::GtEq,
+                        op => op.clone(),
+                    };
+                    (op, Self::expr_to_num(left)?)
+                } else {
+                    return None;
+                };
+
+                // An empty interval starts after it ends
+                match op {
+                    ast::BinaryOperator::Eq => Some(id..=id),
+                    ast::BinaryOperator::Gt => match id.checked_add(1) {
+                        Some(start) => Some(start..=i64::MAX),
+                        None => Some(no_ids()),
+                    },
+                    ast::BinaryOperator::GtEq => Some(id..=i64::MAX),
+                    ast::BinaryOperator::Lt => match id.checked_sub(1) {
+                        Some(end) => Some(i64::MIN..=end),
+                        None => Some(no_ids()),
+                    },
+                    ast::BinaryOperator::LtEq => Some(i64::MIN..=id),
+                    _ => None,
+                }
+            }
+            ast::Expr::Between {
+                expr,
+                negated: false,
+                low,
+                high,
+            } if is_primary_key(expr) => Some(Self::expr_to_num(low)?..=Self::expr_to_num(high)?),
+            ast::Expr::InList {
+                expr,
+                list,
+                negated: false,
+            } if is_primary_key(expr) => {
+                let ids = list
+                    .iter()
+                    .map(Self::expr_to_num)
+                    .collect::<Option<Vec<i64>>>()?;
+                match (ids.iter().min(), ids.iter().max()) {
+                    (Some(min), Some(max)) => Some(*min..=*max),
+                    _ => Some(no_ids()),
+                }
+            }
+            _ => None,
         }
     }
 
-    fn first_num_from_value_vec(values: &Vec<ast::Expr>) -> Option<Record> {
-        values
-            .first()
-            .and_then(|value| Self::expr_to_num(value).map(|key| Record { id: key }))
+    // A plain or qualified reference to the primary key column
+    fn is_primary_key(expr: &ast::Expr, schema: &TableSchema) -> bool {
+        schema.column_of(expr).ok() == Some(schema.primary_key)
     }
 
     // TODO: Return result
-    pub fn expr_to_num(expr: &ast::Expr) -> Option<u64> {
+    pub fn expr_to_num(expr: &ast::Expr) -> Option<i64> {
         match expr {
-            ast::Expr::Value(ast::Value::Number(value, _)) => match value.parse() {
-                Ok(number_value) => Some(number_value),
-                _ => None,
-            },
+            ast::Expr::Value(ast::Value::Number(value, _)) => value.parse().ok(),
+            ast::Expr::UnaryOp {
+                op: ast::UnaryOperator::Minus,
+                expr,
+            } => Self::expr_to_num(expr).map(|num| -num),
             _ => None,
         }
     }
@@ -131,41 +1021,427 @@ impl SqlStmt {
 
 #[cfg(test)]
 mod tests {
+    use crate::calvinite_tonic::{Reconnaissance, RunStmtRequestWithUuid};
+    use crate::catalog::Catalog;
     use crate::common::Record;
+    use crate::index::IndexLookup;
+    use crate::procedure::ProcedureDdl;
     use crate::stmt_analyzer::SqlStmt;
+    use crate::storage::MemoryEngine;
+    use sqlparser::ast;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn catalog_with_foo() -> Catalog {
+        let catalog = Catalog::open(&MemoryEngine::shared());
+        let create_foo = "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)";
+        if let ast::Statement::CreateTable {
+            name,
+            columns,
+            constraints,
+            ..
+        } = Parser::parse_sql(&GenericDialect {}, create_foo)
+            .unwrap()
+            .remove(0)
+        {
+            catalog
+                .create_table(&name.to_string(), &columns, &constraints, false)
+                .unwrap();
+        }
+        catalog
+    }
+
+    fn foo(id: i64) -> Record {
+        Record { table_id: 0, id }
+    }
 
     #[test]
     fn get_impacted_records_for_insert() {
         let stmt = "INSERT INTO foo VALUES (1)".to_string();
-        let analyzed_stmt = SqlStmt::from_string(stmt).unwrap();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog_with_foo()).unwrap();
 
-        assert_eq!(analyzed_stmt.inserted_records, vec![Record { id: 1 }])
+        assert_eq!(analyzed_stmt.inserted_records, vec![foo(1)])
     }
 
     #[test]
     fn get_impacted_records_for_insert_multiple_values() {
         let stmt = "INSERT INTO foo VALUES (1), (2, 3), (4, 5, 6)".to_string();
-        let analyzed_stmt = SqlStmt::from_string(stmt).unwrap();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog_with_foo()).unwrap();
 
-        assert_eq!(
-            analyzed_stmt.inserted_records,
-            vec![Record { id: 1 }, Record { id: 2 }, Record { id: 4 }]
-        )
+        assert_eq!(analyzed_stmt.inserted_records, vec![foo(1), foo(2), foo(4)])
+    }
+
+    #[test]
+    fn get_impacted_records_for_insert_with_columns() {
+        let stmt = "INSERT INTO foo (val, id) VALUES (1, 2), (3, 4)".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog_with_foo()).unwrap();
+        assert_eq!(analyzed_stmt.inserted_records, vec![foo(2), foo(4)]);
+
+        let stmt = "INSERT INTO foo (val) VALUES (1)".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog_with_foo()).unwrap();
+        assert!(analyzed_stmt.inserted_records.is_empty());
+    }
+
+    #[test]
+    fn get_impacted_records_for_insert_select() {
+        let catalog = catalog_with_foo();
+
+        let stmt = "INSERT INTO foo SELECT id, val FROM foo WHERE id = 1".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog).unwrap();
+        assert_eq!(analyzed_stmt.selected_records, vec![foo(1)]);
+        assert_eq!(analyzed_stmt.inserted_records, vec![foo(1)]);
+        assert!(!analyzed_stmt.is_dependent(&catalog).unwrap());
+
+        // The inserted key is only known once the selected row is read
+        let stmt = "INSERT INTO foo (val, id) SELECT id, val FROM foo WHERE id = 1".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog).unwrap();
+        assert_eq!(analyzed_stmt.selected_records, vec![foo(1)]);
+        assert!(analyzed_stmt.inserted_records.is_empty());
+        assert!(analyzed_stmt.is_dependent(&catalog).unwrap());
     }
 
     #[test]
     fn get_impacted_records_for_update() {
         let stmt = "UPDATE foo SET id = 2 WHERE id = 1".to_string();
-        let analyzed_stmt = SqlStmt::from_string(stmt).unwrap();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog_with_foo()).unwrap();
+
+        assert_eq!(analyzed_stmt.updated_records, vec![foo(1)])
+    }
+
+    #[test]
+    fn get_impacted_records_for_delete() {
+        let catalog = catalog_with_foo();
+
+        let stmt = "DELETE FROM foo WHERE id = 1".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog).unwrap();
+        assert_eq!(analyzed_stmt.deleted_records, vec![foo(1)]);
+        assert_eq!(analyzed_stmt.write_set(), vec![foo(1)]);
+        assert!(!analyzed_stmt.is_dependent(&catalog).unwrap());
 
-        assert_eq!(analyzed_stmt.updated_records, vec![Record { id: 1 }])
+        for stmt in ["DELETE FROM foo WHERE val = 1", "DELETE FROM foo"] {
+            let analyzed_stmt = SqlStmt::from_string(stmt.to_string(), &catalog).unwrap();
+            assert!(analyzed_stmt.deleted_records.is_empty());
+            assert!(analyzed_stmt.is_dependent(&catalog).unwrap(), "{}", stmt);
+        }
+    }
+
+    #[test]
+    fn get_impacted_records_for_assert() {
+        let catalog = catalog_with_foo();
+
+        let stmt = "ABORT IF (SELECT val FROM foo WHERE id = 1) < 0".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog).unwrap();
+        assert_eq!(
+            analyzed_stmt.ast_stmts[0].to_string(),
+            "ASSERT NOT ((SELECT val FROM foo WHERE id = 1) < 0) AS 'ABORT IF (SELECT val FROM foo WHERE id = 1) < 0'"
+        );
+        assert_eq!(analyzed_stmt.selected_records, vec![foo(1)]);
+        assert!(!analyzed_stmt.is_dependent(&catalog).unwrap());
+
+        let stmt = "ASSERT NOT EXISTS (SELECT * FROM foo WHERE val < 0)".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog).unwrap();
+        assert!(analyzed_stmt.selected_records.is_empty());
+        assert_eq!(
+            analyzed_stmt.read_ranges,
+            vec![foo(i64::MIN)..=foo(i64::MAX)]
+        );
+
+        for stmt in ["ABORT IF", "ABORT 1 = 1", "ABORT IF 1 = 1 ROLLBACK"] {
+            assert!(SqlStmt::parse(stmt).is_err(), "{}", stmt);
+        }
     }
 
     #[test]
     fn get_impacted_records_for_select() {
         let stmt = "SELECT * FROM foo WHERE id = 1".to_string();
-        let analyzed_stmt = SqlStmt::from_string(stmt).unwrap();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog_with_foo()).unwrap();
+
+        assert_eq!(analyzed_stmt.selected_records, vec![foo(1)])
+    }
+
+    #[test]
+    fn read_and_write_sets_cover_every_stmt() {
+        let stmt = "SELECT * FROM foo WHERE id = 1; INSERT INTO foo VALUES (2, 3); UPDATE foo SET val = 4 WHERE id = 5".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog_with_foo()).unwrap();
+
+        assert_eq!(analyzed_stmt.read_set(), vec![foo(1)]);
+        assert_eq!(analyzed_stmt.write_set(), vec![foo(2), foo(5)]);
+    }
+
+    #[test]
+    fn unknown_tables_touch_no_records() {
+        let stmt = "SELECT * FROM bar WHERE id = 1; INSERT INTO bar VALUES (2, 3)".to_string();
+        let analyzed_stmt = SqlStmt::from_string(stmt, &catalog_with_foo()).unwrap();
+
+        assert!(analyzed_stmt.read_set().is_empty());
+        assert!(analyzed_stmt.write_set().is_empty());
+        assert!(!analyzed_stmt.changes_schema());
+    }
+
+    #[test]
+    fn adds_reconnaissance_of_dependent_stmts() {
+        let catalog = catalog_with_foo();
+        let req = RunStmtRequestWithUuid {
+            query: "UPDATE foo SET val = 1 WHERE val = 2; SELECT * FROM foo WHERE id = 3".into(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            repartition: None,
+            reconnaissance: Some(Reconnaissance {
+                write_records: vec![bincode::serialize(&foo(2)).unwrap()],
+                read_records: vec![],
+            }),
+            stamp: None,
+        };
+        let analyzed_stmt = SqlStmt::from_request(&req, &catalog).unwrap();
+
+        // The predicate is evaluated on every row, which are read under a range lock
+        assert!(analyzed_stmt.is_dependent(&catalog).unwrap());
+        assert_eq!(analyzed_stmt.read_set(), vec![foo(3)]);
+        assert_eq!(
+            analyzed_stmt.read_ranges,
+            vec![foo(i64::MIN)..=foo(i64::MAX)]
+        );
+        assert_eq!(analyzed_stmt.write_set(), vec![foo(2)]);
+
+        for stmt in [
+            "SELECT * FROM foo WHERE id = 1",
+            "SELECT * FROM foo WHERE val = 1",
+            "UPDATE foo SET val = 1 WHERE id = 1",
+            "UPDATE foo SET val = 1 WHERE id < 1",
+            "INSERT INTO foo VALUES (1, 2)",
+            "SELECT * FROM bar WHERE val = 1",
+        ] {
+            let analyzed_stmt = SqlStmt::from_string(stmt.to_string(), &catalog).unwrap();
+            assert!(!analyzed_stmt.is_dependent(&catalog).unwrap(), "{}", stmt);
+        }
+    }
+
+    #[test]
+    fn get_impacted_ranges() {
+        let catalog = catalog_with_foo();
+
+        for (stmt, read_ranges, write_ranges) in [
+            ("SELECT * FROM foo", vec![i64::MIN..=i64::MAX], vec![]),
+            (
+                "SELECT * FROM foo WHERE id BETWEEN 2 AND 5 ORDER BY id LIMIT 2",
+                vec![2..=5],
+                vec![],
+            ),
+            (
+                "SELECT * FROM foo WHERE (id > 2 AND val = 1) AND 10 >= id",
+                vec![3..=10],
+                vec![],
+            ),
+            (
+                "SELECT * FROM foo WHERE id < 0 OR id IN (4, 7)",
+                vec![i64::MIN..=7],
+                vec![],
+            ),
+            (
+                "SELECT * FROM foo WHERE id < 0 OR val = 1",
+                vec![i64::MIN..=i64::MAX],
+                vec![],
+            ),
+            ("SELECT * FROM foo WHERE id = 1", vec![], vec![]),
+            (
+                "UPDATE foo SET val = 1 WHERE id >= 3",
+                vec![],
+                vec![3..=i64::MAX],
+            ),
+            ("DELETE FROM foo WHERE id < 3", vec![], vec![i64::MIN..=2]),
+            (
+                "DELETE FROM foo WHERE val < 3",
+                vec![i64::MIN..=i64::MAX],
+                vec![],
+            ),
+        ] {
+            let analyzed_stmt = SqlStmt::from_string(stmt.to_string(), &catalog).unwrap();
+            let ranges = |ids: Vec<std::ops::RangeInclusive<i64>>| {
+                ids.into_iter()
+                    .map(|ids| foo(*ids.start())..=foo(*ids.end()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(analyzed_stmt.read_ranges, ranges(read_ranges), "{}", stmt);
+            assert_eq!(analyzed_stmt.write_ranges, ranges(write_ranges), "{}", stmt);
+        }
+
+        // Comparisons that cannot hold make an empty range
+        let analyzed_stmt =
+            SqlStmt::from_string("SELECT * FROM foo WHERE id > 5 AND id < 3".into(), &catalog)
+                .unwrap();
+        assert!(analyzed_stmt.read_ranges[0].is_empty());
+    }
+
+    #[test]
+    fn ddl_changes_schema() {
+        for stmt in [
+            "CREATE TABLE bar (id BIGINT PRIMARY KEY)",
+            "DROP TABLE foo",
+            "CREATE INDEX foo_val ON foo (val)",
+            "DROP INDEX foo_val",
+        ] {
+            let analyzed_stmt =
+                SqlStmt::from_string(stmt.to_string(), &catalog_with_foo()).unwrap();
+            assert!(analyzed_stmt.changes_schema());
+        }
+    }
+
+    #[test]
+    fn resolves_equalities_through_indexes() {
+        let catalog = catalog_with_foo();
+        let columns = [ast::OrderByExpr {
+            expr: ast::Expr::Identifier("val".into()),
+            asc: None,
+            nulls_first: None,
+        }];
+        let index = catalog
+            .create_index("foo_val", "foo", &columns, true, false)
+            .unwrap()
+            .unwrap();
+        let lookup = |val: i64| IndexLookup {
+            index: index.clone(),
+            value: val.into(),
+        };
+
+        // The rows a lookup finds are locked through their reconnaissance, and its value is read
+        // locked instead of the whole table
+        let analyzed_stmt =
+            SqlStmt::from_string("SELECT * FROM foo WHERE val = 2".to_string(), &catalog).unwrap();
+        assert!(analyzed_stmt.is_dependent(&catalog).unwrap());
+        assert_eq!(analyzed_stmt.index_lookups, vec![lookup(2)]);
+        assert_eq!(analyzed_stmt.read_ranges, vec![lookup(2).lock()]);
+
+        // An equality on the primary key is resolved through it instead
+        let analyzed_stmt = SqlStmt::from_string(
+            "SELECT * FROM foo WHERE id = 1 AND val = 2".to_string(),
+            &catalog,
+        )
+        .unwrap();
+        assert!(!analyzed_stmt.is_dependent(&catalog).unwrap());
+        assert!(analyzed_stmt.index_lookups.is_empty());
+
+        // Values written to a unique index are looked up to find duplicates
+        let analyzed_stmt = SqlStmt::from_string(
+            "INSERT INTO foo VALUES (1, 2), (2, NULL), (3, 2)".to_string(),
+            &catalog,
+        )
+        .unwrap();
+        assert!(analyzed_stmt.is_dependent(&catalog).unwrap());
+        assert_eq!(analyzed_stmt.index_lookups, vec![lookup(2)]);
+        assert_eq!(analyzed_stmt.write_ranges, vec![lookup(2).lock()]);
+
+        let analyzed_stmt =
+            SqlStmt::from_string("UPDATE foo SET val = 3 WHERE id = 1".to_string(), &catalog)
+                .unwrap();
+        assert_eq!(analyzed_stmt.index_lookups, vec![lookup(3)]);
+        assert_eq!(analyzed_stmt.write_ranges, vec![index.lock_all()]);
+    }
+
+    #[test]
+    fn finds_join_keys() {
+        let catalog = catalog_with_foo();
+        let create_bar = "CREATE TABLE bar (id BIGINT PRIMARY KEY, val BIGINT)";
+        if let ast::Statement::CreateTable {
+            name,
+            columns,
+            constraints,
+            ..
+        } = Parser::parse_sql(&GenericDialect {}, create_bar)
+            .unwrap()
+            .remove(0)
+        {
+            catalog
+                .create_table(&name.to_string(), &columns, &constraints, false)
+                .unwrap();
+        }
+        let bar = |id| Record { table_id: 1, id };
+        let joins = |stmt: &str| {
+            SqlStmt::find_joins(&SqlStmt::parse(stmt).unwrap()[0], &catalog)
+                .map(|joins| {
+                    joins
+                        .into_iter()
+                        .map(|join| (join.qualifier, join.key.map(|key| key.to_string())))
+                        .collect::<Vec<_>>()
+                })
+                .ok()
+        };
+
+        // The rows a join looks up by primary key are predicted by reconnaissance
+        let stmt = "SELECT * FROM foo AS f JOIN bar ON bar.id = f.val WHERE f.id = 1";
+        assert_eq!(
+            joins(stmt),
+            Some(vec![("bar".to_string(), Some("f.val".to_string()))])
+        );
+        let analyzed_stmt = SqlStmt::from_string(stmt.to_string(), &catalog).unwrap();
+        assert!(analyzed_stmt.is_dependent(&catalog).unwrap());
+        assert_eq!(analyzed_stmt.read_set(), vec![foo(1)]);
+        assert!(analyzed_stmt.read_ranges.is_empty());
+
+        // Any other join reads every row of its table
+        let stmt = "SELECT * FROM foo LEFT JOIN bar b ON b.val = foo.val AND b.id > 1";
+        assert_eq!(joins(stmt), Some(vec![("b".to_string(), None)]));
+        let analyzed_stmt = SqlStmt::from_string(stmt.to_string(), &catalog).unwrap();
+        assert!(!analyzed_stmt.is_dependent(&catalog).unwrap());
+        assert_eq!(
+            analyzed_stmt.read_ranges,
+            vec![foo(i64::MIN)..=foo(i64::MAX), bar(i64::MIN)..=bar(i64::MAX)]
+        );
+
+        for stmt in [
+            "SELECT * FROM foo, bar",
+            "SELECT * FROM foo CROSS JOIN bar",
+            "SELECT * FROM foo JOIN baz ON baz.id = foo.val",
+        ] {
+            assert_eq!(joins(stmt), None, "{}", stmt);
+            assert!(SqlStmt::from_string(stmt.to_string(), &catalog).is_ok());
+        }
+    }
+
+    #[test]
+    fn finds_procedure_footprints() {
+        let catalog = catalog_with_foo();
+        for create_procedure in [
+            "CREATE PROCEDURE declared(a, b) READS foo(a) WRITES foo(b + 1) AS 'return 1'",
+            "CREATE PROCEDURE undeclared(a) AS 'return 1'",
+        ] {
+            match SqlStmt::from_string(create_procedure.to_string(), &catalog)
+                .unwrap()
+                .procedure_ddl
+            {
+                Some(ProcedureDdl::Create { procedure, .. }) => {
+                    catalog.create_procedure(&procedure, false).unwrap()
+                }
+                _ => panic!("not a CREATE PROCEDURE"),
+            }
+        }
+
+        // A declared footprint locks its records like a keyed statement
+        let analyzed_stmt =
+            SqlStmt::from_string("CALL declared(1, 2)".to_string(), &catalog).unwrap();
+        assert!(!analyzed_stmt.is_dependent(&catalog).unwrap());
+        assert_eq!(analyzed_stmt.read_set(), vec![foo(1)]);
+        assert_eq!(analyzed_stmt.write_set(), vec![foo(3)]);
+
+        // Any other call is reconnoitered
+        for stmt in ["CALL undeclared(1)", "EXECUTE undeclared(1)"] {
+            let analyzed_stmt = SqlStmt::from_string(stmt.to_string(), &catalog).unwrap();
+            assert!(analyzed_stmt.is_dependent(&catalog).unwrap(), "{}", stmt);
+            assert!(analyzed_stmt.write_set().is_empty());
+        }
+
+        // Calls that cannot be evaluated touch no records and fail once they run
+        for stmt in [
+            "CALL declared(1)",
+            "CALL declared(1, 'two')",
+            "CALL missing(1)",
+        ] {
+            let analyzed_stmt = SqlStmt::from_string(stmt.to_string(), &catalog).unwrap();
+            assert!(!analyzed_stmt.is_dependent(&catalog).unwrap(), "{}", stmt);
+            assert!(analyzed_stmt.write_set().is_empty());
+        }
 
-        assert_eq!(analyzed_stmt.selected_records, vec![Record { id: 1 }])
+        let analyzed_stmt =
+            SqlStmt::from_string("DROP PROCEDURE declared".to_string(), &catalog).unwrap();
+        assert!(analyzed_stmt.changes_schema());
+        assert!(analyzed_stmt.ast_stmts.is_empty());
     }
 }
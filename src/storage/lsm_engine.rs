@@ -0,0 +1,555 @@
+use crate::storage::{
+    KeyRange, KeyValue, Keyspace, StorageEngine, StorageErr, StorageRead, WriteBatch,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+const WAL_FILE_NAME: &str = "wal.log";
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const TABLE_FILE_EXTENSION: &str = "sst";
+const TMP_FILE_EXTENSION: &str = "tmp";
+
+// The memtable is written out to a table once its keys and values take this many bytes
+const MEMTABLE_LIMIT: usize = 4 << 20;
+// Once there are more tables than this, they are merged into one
+const MAX_TABLES: usize = 4;
+
+// Every key space lives in one ordered map, its keys behind the id of the key space. A missing
+// value is a delete that hides the older values of its key.
+type Memtable = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// A log-structured merge tree in pure Rust.
+///
+/// Every batch is appended to a write-ahead log and synced before it is applied to the
+/// memtable. A full memtable is written out to an immutable sorted table, listed in the manifest,
+/// and the log starts over. Reads look at the memtable, then at the tables from newest to oldest.
+/// Tables are merged into one once there are too many of them.
+pub struct LsmEngine {
+    dir: PathBuf,
+    state: RwLock<State>,
+}
+
+struct State {
+    memtable: Arc<Memtable>,
+    memtable_size: usize,
+    // Newest first
+    tables: Vec<Arc<Table>>,
+    wal: File,
+    // Every batch in the log is complete up to here
+    wal_len: u64,
+    next_table_id: u64,
+}
+
+impl LsmEngine {
+    pub fn open(data_dir: &Path) -> Result<Self, StorageErr> {
+        fs::create_dir_all(data_dir)?;
+
+        let table_ids = match fs::read_to_string(data_dir.join(MANIFEST_FILE_NAME)) {
+            Ok(manifest) => manifest
+                .lines()
+                .map(|id| {
+                    id.parse::<u64>()
+                        .map_err(|_| StorageErr::Corrupt(format!("manifest lists table {}", id)))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        // A crash in the middle of a flush or a merge leaves files the manifest does not list
+        for entry in fs::read_dir(data_dir)? {
+            let path = entry?.path();
+            let is_listed = Self::table_id_of(&path).is_some_and(|id| table_ids.contains(&id));
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            if extension == Some(TMP_FILE_EXTENSION)
+                || (extension == Some(TABLE_FILE_EXTENSION) && !is_listed)
+            {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let tables = table_ids
+            .iter()
+            .map(|id| Ok(Arc::new(Table::open(&Self::table_path(data_dir, *id))?)))
+            .collect::<Result<Vec<_>, StorageErr>>()?;
+
+        let mut wal = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(data_dir.join(WAL_FILE_NAME))?;
+        let (batches, valid_len) = Self::read_wal(&mut wal)?;
+        wal.set_len(valid_len)?;
+        wal.sync_all()?;
+
+        let mut state = State {
+            memtable: Arc::default(),
+            memtable_size: 0,
+            tables,
+            wal,
+            wal_len: valid_len,
+            next_table_id: table_ids.iter().max().map_or(0, |id| id + 1),
+        };
+        for batch in batches {
+            state.apply_to_memtable(batch);
+        }
+
+        Ok(Self {
+            dir: data_dir.to_path_buf(),
+            state: RwLock::new(state),
+        })
+    }
+
+    // Every complete batch of the log, and the length of the log without a torn batch at its tail.
+    // Each batch is its length, the checksum of its writes, then its writes.
+    fn read_wal(wal: &mut File) -> Result<(Vec<Memtable>, u64), StorageErr> {
+        let mut bytes = Vec::new();
+        wal.read_to_end(&mut bytes)?;
+
+        let mut batches = Vec::new();
+        let mut offset = 0;
+        while let Some(header) = bytes.get(offset..offset + 12) {
+            let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let checksum: [u8; 8] = header[4..].try_into().unwrap();
+            let writes = match bytes.get(offset + 12..offset + 12 + len) {
+                Some(writes) if Self::checksum(writes) == checksum => writes,
+                _ => break,
+            };
+            batches.push(bincode::deserialize(writes)?);
+            offset += 12 + len;
+        }
+
+        Ok((batches, offset as u64))
+    }
+
+    fn checksum(bytes: &[u8]) -> [u8; 8] {
+        let digest: [u8; 16] = md5::compute(bytes).into();
+        digest[..8].try_into().unwrap()
+    }
+
+    fn internal_key(keyspace: Keyspace, key: &[u8]) -> Vec<u8> {
+        [&[keyspace.id()], key].concat()
+    }
+
+    fn internal_range(keyspace: Keyspace, range: KeyRange) -> KeyRange {
+        let start = match range.0 {
+            Bound::Included(key) => Bound::Included(Self::internal_key(keyspace, &key)),
+            Bound::Excluded(key) => Bound::Excluded(Self::internal_key(keyspace, &key)),
+            Bound::Unbounded => Bound::Included(vec![keyspace.id()]),
+        };
+        let end = match range.1 {
+            Bound::Included(key) => Bound::Included(Self::internal_key(keyspace, &key)),
+            Bound::Excluded(key) => Bound::Excluded(Self::internal_key(keyspace, &key)),
+            Bound::Unbounded => Bound::Excluded(vec![keyspace.id() + 1]),
+        };
+
+        (start, end)
+    }
+
+    fn table_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:08}.{}", id, TABLE_FILE_EXTENSION))
+    }
+
+    fn table_id_of(path: &Path) -> Option<u64> {
+        path.file_stem()?.to_str()?.parse().ok()
+    }
+
+    // Writes out the memtable, and merges every table if there are too many. The log starts over
+    // only once the manifest lists the new table.
+    fn flush(&self, state: &mut State) -> Result<(), StorageErr> {
+        let id = state.next_table_id;
+        state.next_table_id += 1;
+        let path = Self::table_path(&self.dir, id);
+        Table::write(
+            &path,
+            state
+                .memtable
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        )?;
+        state.tables.insert(0, Arc::new(Table::open(&path)?));
+
+        let mut merged = Vec::new();
+        if state.tables.len() > MAX_TABLES {
+            let id = state.next_table_id;
+            state.next_table_id += 1;
+            let path = Self::table_path(&self.dir, id);
+
+            // The merged table is the oldest there is, so it needs no deletes
+            let snapshot = LsmSnapshot {
+                memtable: Arc::default(),
+                tables: state.tables.clone(),
+            };
+            let entries = snapshot.merge((Bound::Unbounded, Bound::Unbounded))?;
+            Table::write(
+                &path,
+                entries
+                    .into_iter()
+                    .filter_map(|(key, value)| value.map(|value| (key, value)))
+                    .map(|(key, value)| Ok((key, Some(value.read()?)))),
+            )?;
+            merged = std::mem::replace(&mut state.tables, vec![Arc::new(Table::open(&path)?)]);
+        }
+
+        self.write_manifest(&state.tables)?;
+        state.wal.set_len(0)?;
+        state.wal.sync_all()?;
+        state.wal_len = 0;
+        state.memtable = Arc::default();
+        state.memtable_size = 0;
+
+        for table in merged {
+            fs::remove_file(&table.path)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_manifest(&self, tables: &[Arc<Table>]) -> Result<(), StorageErr> {
+        let manifest: String = tables
+            .iter()
+            .filter_map(|table| Self::table_id_of(&table.path))
+            .map(|id| format!("{}\n", id))
+            .collect();
+
+        let path = self.dir.join(MANIFEST_FILE_NAME);
+        let tmp_path = path.with_extension(TMP_FILE_EXTENSION);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(manifest.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    fn snapshot_of(&self) -> LsmSnapshot {
+        let state = self.state.read().unwrap();
+        LsmSnapshot {
+            memtable: state.memtable.clone(),
+            tables: state.tables.clone(),
+        }
+    }
+}
+
+impl State {
+    fn apply_to_memtable(&mut self, writes: Memtable) {
+        let memtable = Arc::make_mut(&mut self.memtable);
+        for (key, value) in writes {
+            self.memtable_size += key.len() + value.as_ref().map_or(0, Vec::len);
+            memtable.insert(key, value);
+        }
+    }
+}
+
+impl fmt::Debug for LsmEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LsmEngine").field("dir", &self.dir).finish()
+    }
+}
+
+impl StorageRead for LsmEngine {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>, StorageErr> {
+        self.snapshot_of().get(keyspace, key)
+    }
+
+    fn scan(&self, keyspace: Keyspace, range: KeyRange) -> Result<Vec<KeyValue>, StorageErr> {
+        self.snapshot_of().scan(keyspace, range)
+    }
+}
+
+impl StorageEngine for LsmEngine {
+    fn apply(&self, batch: WriteBatch) -> Result<(), StorageErr> {
+        // Later writes of a key in the batch win, like they would if applied one by one
+        let writes: Memtable = batch
+            .writes()
+            .iter()
+            .map(|(keyspace, key, value)| (Self::internal_key(*keyspace, key), value.clone()))
+            .collect();
+        let bytes = bincode::serialize(&writes)?;
+
+        let mut state = self.state.write().unwrap();
+        let mut record = Vec::with_capacity(12 + bytes.len());
+        record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        record.extend_from_slice(&Self::checksum(&bytes));
+        record.extend_from_slice(&bytes);
+        // A batch that was not logged in full is cut off, or the next one would follow it
+        if let Err(err) = state
+            .wal
+            .write_all(&record)
+            .and_then(|()| state.wal.sync_data())
+        {
+            let _ = state.wal.set_len(state.wal_len);
+            return Err(err.into());
+        }
+        state.wal_len += record.len() as u64;
+
+        state.apply_to_memtable(writes);
+        if state.memtable_size >= MEMTABLE_LIMIT {
+            self.flush(&mut state)?;
+        }
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn StorageRead>, StorageErr> {
+        Ok(Box::new(self.snapshot_of()))
+    }
+}
+
+/// The memtable and tables as of some point. Tables are never changed, and the memtable is
+/// copied before a batch changes it while a snapshot holds it.
+struct LsmSnapshot {
+    memtable: Arc<Memtable>,
+    tables: Vec<Arc<Table>>,
+}
+
+// The newest value of a key in a snapshot, before it is read from its table
+enum Located {
+    Memtable(Vec<u8>),
+    Table(Arc<Table>, u64, u32),
+}
+
+impl Located {
+    fn read(self) -> Result<Vec<u8>, StorageErr> {
+        match self {
+            Located::Memtable(value) => Ok(value),
+            Located::Table(table, offset, len) => table.read(offset, len),
+        }
+    }
+}
+
+impl LsmSnapshot {
+    // Every key in the internal `range` with its newest value, or `None` if it was deleted
+    fn merge(&self, range: KeyRange) -> Result<BTreeMap<Vec<u8>, Option<Located>>, StorageErr> {
+        let mut merged = BTreeMap::new();
+        if super::is_empty_range(&range) {
+            return Ok(merged);
+        }
+
+        for table in self.tables.iter().rev() {
+            for (key, location) in table.index.range(range.clone()) {
+                let located =
+                    location.map(|(offset, len)| Located::Table(table.clone(), offset, len));
+                merged.insert(key.clone(), located);
+            }
+        }
+        for (key, value) in self.memtable.range(range) {
+            merged.insert(key.clone(), value.clone().map(Located::Memtable));
+        }
+
+        Ok(merged)
+    }
+}
+
+impl StorageRead for LsmSnapshot {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>, StorageErr> {
+        let key = LsmEngine::internal_key(keyspace, key);
+        if let Some(value) = self.memtable.get(&key) {
+            return Ok(value.clone());
+        }
+
+        for table in self.tables.iter() {
+            if let Some(location) = table.index.get(&key) {
+                return location
+                    .map(|(offset, len)| table.read(offset, len))
+                    .transpose();
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn scan(&self, keyspace: Keyspace, range: KeyRange) -> Result<Vec<KeyValue>, StorageErr> {
+        self.merge(LsmEngine::internal_range(keyspace, range))?
+            .into_iter()
+            .filter_map(|(key, located)| located.map(|located| (key, located)))
+            .map(|(key, located)| Ok((key[1..].to_vec(), located.read()?)))
+            .collect()
+    }
+}
+
+/// An immutable sorted table. Its keys and where their values are stay in memory, its values
+/// are read from the file as needed.
+///
+/// Each entry of the file is the length of its key, its key, then 0 for a delete or 1, the
+/// length of its value and its value.
+struct Table {
+    path: PathBuf,
+    file: File,
+    index: BTreeMap<Vec<u8>, Option<(u64, u32)>>,
+}
+
+impl Table {
+    // Entries must come in key order. The table only shows up under `path` once it is complete.
+    fn write(
+        path: &Path,
+        entries: impl Iterator<Item = Result<(Vec<u8>, Option<Vec<u8>>), StorageErr>>,
+    ) -> Result<(), StorageErr> {
+        let tmp_path = path.with_extension(TMP_FILE_EXTENSION);
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        for entry in entries {
+            let (key, value) = entry?;
+            file.write_all(&(key.len() as u32).to_le_bytes())?;
+            file.write_all(&key)?;
+            match value {
+                Some(value) => {
+                    file.write_all(&[1])?;
+                    file.write_all(&(value.len() as u32).to_le_bytes())?;
+                    file.write_all(&value)?;
+                }
+                None => file.write_all(&[0])?,
+            }
+        }
+        file.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    fn open(path: &Path) -> Result<Self, StorageErr> {
+        let mut file = File::open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let corrupt = || StorageErr::Corrupt(format!("table {} is truncated", path.display()));
+        let read_len = |offset: usize| -> Result<usize, StorageErr> {
+            let len = bytes.get(offset..offset + 4).ok_or_else(corrupt)?;
+            Ok(u32::from_le_bytes(len.try_into().unwrap()) as usize)
+        };
+
+        let mut index = BTreeMap::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let key_len = read_len(offset)?;
+            let key = bytes
+                .get(offset + 4..offset + 4 + key_len)
+                .ok_or_else(corrupt)?;
+            offset += 4 + key_len;
+            match bytes.get(offset).ok_or_else(corrupt)? {
+                0 => {
+                    index.insert(key.to_vec(), None);
+                    offset += 1;
+                }
+                _ => {
+                    let value_len = read_len(offset + 1)?;
+                    let value_offset = offset + 5;
+                    if value_offset + value_len > bytes.len() {
+                        return Err(corrupt());
+                    }
+                    index.insert(key.to_vec(), Some((value_offset as u64, value_len as u32)));
+                    offset = value_offset + value_len;
+                }
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            index,
+        })
+    }
+
+    fn read(&self, offset: u64, len: u32) -> Result<Vec<u8>, StorageErr> {
+        let mut value = vec![0; len as usize];
+        self.file.read_exact_at(&mut value, offset)?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::lsm_engine::{LsmEngine, MAX_TABLES, WAL_FILE_NAME};
+    use crate::storage::{Keyspace, StorageEngine, StorageRead, WriteBatch};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::ops::Bound;
+
+    fn put(engine: &LsmEngine, key: u32, value: &[u8]) {
+        let mut batch = WriteBatch::default();
+        batch.put(Keyspace::Records, key.to_be_bytes(), value);
+        engine.apply(batch).unwrap();
+    }
+
+    #[test]
+    fn recovers_batches_from_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let engine = LsmEngine::open(dir.path()).unwrap();
+            put(&engine, 1, b"a");
+            put(&engine, 2, b"b");
+        }
+
+        // A batch torn by a crash was never applied
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(WAL_FILE_NAME))
+            .unwrap();
+        wal.write_all(&[100, 0, 0, 0, 1, 2]).unwrap();
+
+        let engine = LsmEngine::open(dir.path()).unwrap();
+        assert_eq!(
+            engine.get(Keyspace::Records, &1u32.to_be_bytes()).unwrap(),
+            Some(b"a".to_vec())
+        );
+        put(&engine, 3, b"c");
+        drop(engine);
+
+        let engine = LsmEngine::open(dir.path()).unwrap();
+        assert_eq!(
+            engine
+                .scan(Keyspace::Records, (Bound::Unbounded, Bound::Unbounded))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn flushes_and_merges_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let value = vec![7; 1 << 20];
+
+        {
+            let engine = LsmEngine::open(dir.path()).unwrap();
+            for key in 0..(4 * (MAX_TABLES as u32 + 1)) {
+                put(&engine, key, &value);
+            }
+            let mut batch = WriteBatch::default();
+            batch.delete(Keyspace::Records, 0u32.to_be_bytes());
+            batch.put(Keyspace::Records, 1u32.to_be_bytes(), b"new".to_vec());
+            engine.apply(batch).unwrap();
+
+            let state = engine.state.read().unwrap();
+            assert!(state.tables.len() <= MAX_TABLES);
+        }
+
+        let engine = LsmEngine::open(dir.path()).unwrap();
+        let entries = engine
+            .scan(Keyspace::Records, (Bound::Unbounded, Bound::Unbounded))
+            .unwrap();
+        assert_eq!(entries.len(), 4 * (MAX_TABLES + 1) - 1);
+        assert_eq!(entries[0], (1u32.to_be_bytes().to_vec(), b"new".to_vec()));
+        assert_eq!(
+            engine.get(Keyspace::Records, &2u32.to_be_bytes()).unwrap(),
+            Some(value)
+        );
+        assert_eq!(
+            engine.get(Keyspace::Records, &0u32.to_be_bytes()).unwrap(),
+            None
+        );
+        assert_eq!(
+            engine.get(Keyspace::Catalog, &2u32.to_be_bytes()).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use crate::storage::{
+    KeyRange, KeyValue, Keyspace, Storage, StorageEngine, StorageErr, StorageRead, WriteBatch,
+};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+type Maps = [BTreeMap<Vec<u8>, Vec<u8>>; Keyspace::ALL.len()];
+
+/// Keeps every key space in a `BTreeMap` and loses it once dropped, for tests and simulations.
+///
+/// A snapshot shares the maps until the next batch, which then copies them.
+#[derive(Debug, Default)]
+pub struct MemoryEngine {
+    maps: RwLock<Arc<Maps>>,
+}
+
+impl MemoryEngine {
+    /// A new, empty engine to hand to an executor.
+    pub fn shared() -> Storage {
+        Arc::new(Self::default())
+    }
+}
+
+impl StorageRead for MemoryEngine {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>, StorageErr> {
+        self.maps.read().unwrap().get(keyspace, key)
+    }
+
+    fn scan(&self, keyspace: Keyspace, range: KeyRange) -> Result<Vec<KeyValue>, StorageErr> {
+        self.maps.read().unwrap().scan(keyspace, range)
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn apply(&self, batch: WriteBatch) -> Result<(), StorageErr> {
+        let mut maps = self.maps.write().unwrap();
+        let maps = Arc::make_mut(&mut maps);
+        for (keyspace, key, value) in batch.writes() {
+            let map = &mut maps[*keyspace as usize];
+            match value {
+                Some(value) => map.insert(key.clone(), value.clone()),
+                None => map.remove(key),
+            };
+        }
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn StorageRead>, StorageErr> {
+        Ok(Box::new(self.maps.read().unwrap().clone()))
+    }
+}
+
+impl StorageRead for Arc<Maps> {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>, StorageErr> {
+        Ok(self[keyspace as usize].get(key).cloned())
+    }
+
+    fn scan(&self, keyspace: Keyspace, range: KeyRange) -> Result<Vec<KeyValue>, StorageErr> {
+        if super::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        Ok(self[keyspace as usize]
+            .range(range)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
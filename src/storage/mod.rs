@@ -0,0 +1,302 @@
+use std::fmt;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub mod lsm_engine;
+pub mod memory_engine;
+pub mod sled_engine;
+
+pub use lsm_engine::LsmEngine;
+pub use memory_engine::MemoryEngine;
+pub use sled_engine::SledEngine;
+
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+/// A put, or a delete when it has no value.
+pub type Write = (Keyspace, Vec<u8>, Option<Vec<u8>>);
+
+/// The storage engine of an executor, shared by its catalog, index store and partition.
+pub type Storage = Arc<dyn StorageEngine>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum StorageErr {
+    #[error(transparent)]
+    Sled(#[from] sled::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Encoding(#[from] bincode::Error),
+    #[error("corrupt storage: {0}")]
+    Corrupt(String),
+    #[error("unknown storage engine {0}, expected sled, memory or lsm")]
+    UnknownEngine(String),
+    #[error("the {0} engine needs a data dir")]
+    NoDataDir(EngineKind),
+    #[error("the {0} engine does not keep anything in a data dir")]
+    NotPersistent(EngineKind),
+}
+
+/// The key spaces an executor stores. Keys of different key spaces never collide, and a batch
+/// can write to any of them at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Keyspace {
+    Records,
+    Catalog,
+    Indexes,
+    Applied,
+    Placement,
+}
+
+impl Keyspace {
+    pub const ALL: [Keyspace; 5] = [
+        Keyspace::Records,
+        Keyspace::Catalog,
+        Keyspace::Indexes,
+        Keyspace::Applied,
+        Keyspace::Placement,
+    ];
+
+    /// The byte engines that store every key space in one ordered map put in front of its keys.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+}
+
+/// Puts and deletes that are applied at once, in the order they were added.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteBatch {
+    writes: Vec<Write>,
+}
+
+impl WriteBatch {
+    pub fn put(&mut self, keyspace: Keyspace, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.push((keyspace, key.into(), Some(value.into())));
+    }
+
+    pub fn delete(&mut self, keyspace: Keyspace, key: impl Into<Vec<u8>>) {
+        self.writes.push((keyspace, key.into(), None));
+    }
+
+    pub fn append(&mut self, other: WriteBatch) {
+        self.writes.extend(other.writes);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn writes(&self) -> &[Write] {
+        &self.writes
+    }
+}
+
+/// Reads of a storage engine, or of a snapshot of one.
+pub trait StorageRead: Send + Sync {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>, StorageErr>;
+
+    /// Every entry of `keyspace` in `range`, in key order.
+    fn scan(&self, keyspace: Keyspace, range: KeyRange) -> Result<Vec<KeyValue>, StorageErr>;
+
+    fn contains_key(&self, keyspace: Keyspace, key: &[u8]) -> Result<bool, StorageErr> {
+        Ok(self.get(keyspace, key)?.is_some())
+    }
+
+    /// Every entry of `keyspace` whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, keyspace: Keyspace, prefix: &[u8]) -> Result<Vec<KeyValue>, StorageErr> {
+        self.scan(keyspace, prefix_range(prefix))
+    }
+}
+
+/// An ordered key value store an executor keeps its records, catalog, index entries, applied log
+/// and placement in. A batch is atomic: after a crash an engine recovers either every write of a batch or
+/// none of them.
+pub trait StorageEngine: StorageRead + fmt::Debug {
+    fn apply(&self, batch: WriteBatch) -> Result<(), StorageErr>;
+
+    /// A view of every key space as of now, which later batches do not change.
+    fn snapshot(&self) -> Result<Box<dyn StorageRead>, StorageErr>;
+}
+
+/// The range of every key that starts with `prefix`.
+pub fn prefix_range(prefix: &[u8]) -> KeyRange {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+// Ordered maps panic on a range that ends before it starts, which holds no key anyway
+fn is_empty_range(range: &KeyRange) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EngineKind {
+    #[default]
+    Sled,
+    Memory,
+    Lsm,
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineKind::Sled => write!(f, "sled"),
+            EngineKind::Memory => write!(f, "memory"),
+            EngineKind::Lsm => write!(f, "lsm"),
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = StorageErr;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "sled" => Ok(EngineKind::Sled),
+            "memory" => Ok(EngineKind::Memory),
+            "lsm" => Ok(EngineKind::Lsm),
+            _ => Err(StorageErr::UnknownEngine(name.to_string())),
+        }
+    }
+}
+
+/// Which engine an executor stores its data with, and where.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StorageConfig {
+    pub engine: EngineKind,
+    /// Without a data dir, a sled engine is deleted once it is dropped. The LSM engine always
+    /// needs one, the memory engine never takes one.
+    pub data_dir: Option<PathBuf>,
+}
+
+impl StorageConfig {
+    pub fn open(&self) -> Result<Storage, StorageErr> {
+        Ok(match (self.engine, &self.data_dir) {
+            (EngineKind::Sled, Some(data_dir)) => Arc::new(SledEngine::open(data_dir)?),
+            (EngineKind::Sled, None) => Arc::new(SledEngine::temporary()?),
+            (EngineKind::Memory, None) => MemoryEngine::shared(),
+            (EngineKind::Memory, Some(_)) => {
+                return Err(StorageErr::NotPersistent(EngineKind::Memory))
+            }
+            (EngineKind::Lsm, Some(data_dir)) => Arc::new(LsmEngine::open(data_dir)?),
+            (EngineKind::Lsm, None) => return Err(StorageErr::NoDataDir(EngineKind::Lsm)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        prefix_range, EngineKind, Keyspace, Storage, StorageConfig, StorageEngine, WriteBatch,
+    };
+    use std::ops::Bound;
+
+    fn engines(data_dir: &tempfile::TempDir) -> Vec<Storage> {
+        vec![
+            StorageConfig::default().open().unwrap(),
+            StorageConfig {
+                engine: EngineKind::Memory,
+                data_dir: None,
+            }
+            .open()
+            .unwrap(),
+            StorageConfig {
+                engine: EngineKind::Lsm,
+                data_dir: Some(data_dir.path().to_path_buf()),
+            }
+            .open()
+            .unwrap(),
+        ]
+    }
+
+    fn put(engine: &dyn StorageEngine, keyspace: Keyspace, key: &[u8], value: &[u8]) {
+        let mut batch = WriteBatch::default();
+        batch.put(keyspace, key, value);
+        engine.apply(batch).unwrap();
+    }
+
+    #[test]
+    fn engines_agree() {
+        let data_dir = tempfile::tempdir().unwrap();
+
+        for engine in engines(&data_dir) {
+            put(&*engine, Keyspace::Records, b"b", b"1");
+            put(&*engine, Keyspace::Catalog, b"b", b"2");
+
+            let mut batch = WriteBatch::default();
+            batch.put(Keyspace::Records, b"a", b"3");
+            batch.put(Keyspace::Records, b"c", b"4");
+            batch.delete(Keyspace::Records, b"b");
+            batch.put(Keyspace::Indexes, b"ab", b"");
+            batch.put(Keyspace::Indexes, b"b", b"");
+            engine.apply(batch).unwrap();
+
+            let snapshot = engine.snapshot().unwrap();
+            put(&*engine, Keyspace::Records, b"a", b"5");
+
+            assert_eq!(engine.get(Keyspace::Records, b"b").unwrap(), None);
+            assert_eq!(
+                engine.get(Keyspace::Catalog, b"b").unwrap(),
+                Some(b"2".to_vec())
+            );
+            assert_eq!(
+                engine
+                    .scan(Keyspace::Records, (Bound::Unbounded, Bound::Unbounded))
+                    .unwrap(),
+                vec![
+                    (b"a".to_vec(), b"5".to_vec()),
+                    (b"c".to_vec(), b"4".to_vec())
+                ]
+            );
+            assert_eq!(
+                engine
+                    .scan(
+                        Keyspace::Records,
+                        (
+                            Bound::Excluded(b"c".to_vec()),
+                            Bound::Included(b"a".to_vec())
+                        )
+                    )
+                    .unwrap(),
+                vec![]
+            );
+            assert_eq!(
+                engine.scan_prefix(Keyspace::Indexes, b"a").unwrap(),
+                vec![(b"ab".to_vec(), Vec::new())]
+            );
+            assert_eq!(
+                snapshot.get(Keyspace::Records, b"a").unwrap(),
+                Some(b"3".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn ranges_every_key_with_a_prefix() {
+        assert_eq!(
+            prefix_range(&[1, u8::MAX]),
+            (Bound::Included(vec![1, u8::MAX]), Bound::Excluded(vec![2]))
+        );
+        assert_eq!(
+            prefix_range(&[u8::MAX]),
+            (Bound::Included(vec![u8::MAX]), Bound::Unbounded)
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::storage::memory_engine::MemoryEngine;
+use crate::storage::{
+    KeyRange, KeyValue, Keyspace, StorageEngine, StorageErr, StorageRead, WriteBatch,
+};
+use sled::transaction::{TransactionError, TransactionResult, Transactional};
+use std::path::Path;
+use std::time::Duration;
+
+// sled releases the lock on a data dir from its background flusher after the last handle to the
+// db is dropped, so a dir that was just closed may still be locked for a moment
+const LOCKED_OPEN_ATTEMPTS: u32 = 50;
+const LOCKED_OPEN_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// Keeps every key space in its own sled tree, records in the default one.
+#[derive(Debug)]
+pub struct SledEngine {
+    trees: [sled::Tree; Keyspace::ALL.len()],
+    // Held alongside the trees so the db stays open as long as the engine
+    _db: sled::Db,
+}
+
+impl SledEngine {
+    pub fn open(data_dir: &Path) -> Result<Self, StorageErr> {
+        let mut attempts = 1;
+        loop {
+            match sled::open(data_dir) {
+                Ok(db) => return Self::from_db(db),
+                Err(err) if Self::is_locked(&err) && attempts < LOCKED_OPEN_ATTEMPTS => {
+                    attempts += 1;
+                    std::thread::sleep(LOCKED_OPEN_RETRY_DELAY);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// An engine whose files are deleted once it is dropped.
+    pub fn temporary() -> Result<Self, StorageErr> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: sled::Db) -> Result<Self, StorageErr> {
+        let trees = [
+            (*db).clone(),
+            db.open_tree("catalog")?,
+            db.open_tree("indexes")?,
+            db.open_tree("applied")?,
+            db.open_tree("placement")?,
+        ];
+
+        Ok(Self { trees, _db: db })
+    }
+
+    // sled reports a data dir locked by someone else as an error of kind `Other`
+    fn is_locked(err: &sled::Error) -> bool {
+        match err {
+            sled::Error::Io(err) => err.to_string().starts_with("could not acquire lock"),
+            _ => false,
+        }
+    }
+
+    fn tree(&self, keyspace: Keyspace) -> &sled::Tree {
+        &self.trees[keyspace as usize]
+    }
+}
+
+impl StorageRead for SledEngine {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>, StorageErr> {
+        Ok(self.tree(keyspace).get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn scan(&self, keyspace: Keyspace, range: KeyRange) -> Result<Vec<KeyValue>, StorageErr> {
+        if super::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        self.tree(keyspace)
+            .range(range)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+}
+
+impl StorageEngine for SledEngine {
+    // Writes to one tree are atomic on their own, writes to several take a transaction
+    fn apply(&self, batch: WriteBatch) -> Result<(), StorageErr> {
+        let mut tree_batches: [sled::Batch; Keyspace::ALL.len()] = Default::default();
+        let mut written = [false; Keyspace::ALL.len()];
+        for (keyspace, key, value) in batch.writes() {
+            let tree_batch = &mut tree_batches[*keyspace as usize];
+            match value {
+                Some(value) => tree_batch.insert(key.as_slice(), value.as_slice()),
+                None => tree_batch.remove(key.as_slice()),
+            }
+            written[*keyspace as usize] = true;
+        }
+
+        if written.iter().filter(|written| **written).count() <= 1 {
+            for (keyspace, tree_batch) in tree_batches.into_iter().enumerate() {
+                if written[keyspace] {
+                    self.trees[keyspace].apply_batch(tree_batch)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let applied: TransactionResult<()> = self.trees.as_slice().transaction(|trees| {
+            for (tree, tree_batch) in trees.iter().zip(tree_batches.iter()) {
+                tree.apply_batch(tree_batch)?;
+            }
+            Ok(())
+        });
+
+        applied.map_err(|err| match err {
+            TransactionError::Storage(err) => err.into(),
+            TransactionError::Abort(()) => unreachable!("applying batches never aborts"),
+        })
+    }
+
+    // sled has no snapshots, so this copies every key space
+    fn snapshot(&self) -> Result<Box<dyn StorageRead>, StorageErr> {
+        let snapshot = MemoryEngine::default();
+        let mut batch = WriteBatch::default();
+        for keyspace in Keyspace::ALL {
+            for entry in self.tree(keyspace).iter() {
+                let (key, value) = entry?;
+                batch.put(keyspace, key.to_vec(), value.to_vec());
+            }
+        }
+        snapshot.apply(batch)?;
+
+        Ok(Box::new(snapshot))
+    }
+}
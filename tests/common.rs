@@ -1,49 +1,94 @@
-extern crate core;
-
-use core::num;
-
+use calvinite::calvinite_tonic::partition_grpc_service_server::PartitionGrpcServiceServer;
 use calvinite::calvinite_tonic::run_stmt_response::Result::Success;
 use calvinite::calvinite_tonic::sequencer_grpc_service_client::SequencerGrpcServiceClient;
 use calvinite::calvinite_tonic::sequencer_grpc_service_server::SequencerGrpcServiceServer;
-use calvinite::calvinite_tonic::{RecordStorage, RunStmtRequest, RunStmtRequestWithUuid};
-use calvinite::sequencer::SequencerServer;
-
+use calvinite::calvinite_tonic::{
+    CallProcedureRequest, ColumnValue, RecordStorage, RepartitionRequest, RunStmtRequest,
+    RunStmtResponse, RunStmtResults,
+};
+use calvinite::executor::partition::{Partition, PartitionInbox};
+use calvinite::executor::partition_map::PartitionMap;
+use calvinite::executor::peer::{Peer, PeerManager};
+use calvinite::executor::Executor;
+use calvinite::scheduler::Scheduler;
+use calvinite::sequencer::global_request_log::GlobalRequestLog;
+use calvinite::sequencer::request_log::RequestLog;
+use calvinite::sequencer::{Sequencer, SequencerServer};
+use calvinite::storage::{EngineKind, StorageConfig};
+
+use std::path::Path;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
-use tokio::sync;
-use tokio::sync::broadcast::Sender;
 use tonic::transport::{Channel, Server};
 use tonic::Request;
+use uuid::Uuid;
+
+pub const CREATE_FOO: &str = "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)";
+
+pub fn foo_row(id: i64, val: i64) -> RecordStorage {
+    RecordStorage {
+        values: vec![id.into(), val.into()],
+    }
+}
 
+#[derive(Clone)]
 pub struct CalvinSingleInstance {
     client: SequencerGrpcServiceClient<Channel>,
 }
 
 impl CalvinSingleInstance {
     pub async fn default() -> Self {
-        let (global_req_log_tx, _) = sync::broadcast::channel(1);
-        Self::new_with_global_req_log(global_req_log_tx).await
+        Self::new_with_global_req_log(GlobalRequestLog::new(1)).await
     }
 
-    pub async fn new_with_global_req_log(
-        global_req_log_tx: Sender<RunStmtRequestWithUuid>,
-    ) -> Self {
-        let sequencer_server = SequencerServer::new(global_req_log_tx);
-        let mut sequencer = sequencer_server.build_default_sequencer();
+    pub async fn new_with_global_req_log(global_req_log: GlobalRequestLog) -> Self {
+        Self::new_with_sequencer_server(SequencerServer::new(global_req_log)).await
+    }
+
+    pub async fn new_with_epochs(epoch_duration: Duration) -> Self {
+        Self::new_with_sequencer_server(SequencerServer::new_with_epochs(
+            GlobalRequestLog::new(1),
+            epoch_duration,
+        ))
+        .await
+    }
+
+    pub async fn new_with_request_log(request_log_dir: &Path) -> Self {
+        let request_log = RequestLog::open(request_log_dir).unwrap();
+        Self::new_with_sequencer_server(
+            SequencerServer::new(GlobalRequestLog::new(1)).with_request_log(request_log),
+        )
+        .await
+    }
 
+    pub async fn new_with_sequencer_server(sequencer_server: SequencerServer) -> Self {
+        let sequencer = sequencer_server.build_default_sequencer();
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        Self::serve(listener, sequencer_server, sequencer, None).await
+    }
+
+    async fn serve(
+        listener: TcpListener,
+        sequencer_server: SequencerServer,
+        mut sequencer: Sequencer,
+        partition_inbox: Option<PartitionInbox>,
+    ) -> Self {
         let listener_address = listener.local_addr().unwrap();
         let listener_stream = tokio_stream::wrappers::TcpListenerStream::new(listener);
 
         let listener_http_address = format!("http://127.0.0.1:{}", listener_address.port());
 
         tokio::spawn(async move {
+            sequencer.replay().await.unwrap();
             sequencer.serve().await;
         });
 
         tokio::spawn(async move {
             Server::builder()
                 .add_service(SequencerGrpcServiceServer::new(sequencer_server))
+                .add_optional_service(partition_inbox.map(PartitionGrpcServiceServer::new))
                 .serve_with_incoming(listener_stream)
                 .await
                 .unwrap();
@@ -56,36 +101,139 @@ impl CalvinSingleInstance {
         Self { client }
     }
 
-    pub async fn assert_query(&mut self, query: &str, expected_results: Vec<RecordStorage>) {
+    pub async fn repartition(&mut self, partition_map: &PartitionMap) -> RunStmtResponse {
+        let req = Request::new(RepartitionRequest {
+            partition_map: Some(partition_map.into()),
+        });
+        self.client.repartition(req).await.unwrap().into_inner()
+    }
+
+    pub async fn run_stmt(&mut self, query: &str) -> RunStmtResponse {
         let req = Request::new(RunStmtRequest {
             query: query.to_string(),
         });
-        let res = self.client.run_stmt(req).await.unwrap();
+        self.client.run_stmt(req).await.unwrap().into_inner()
+    }
+
+    pub async fn call_procedure(&mut self, name: &str, args: Vec<ColumnValue>) -> RunStmtResponse {
+        let req = Request::new(CallProcedureRequest {
+            name: name.to_string(),
+            args,
+        });
+        self.client.call_procedure(req).await.unwrap().into_inner()
+    }
 
-        if let Some(Success(result)) = res.into_inner().result {
-            assert_eq!(result.results, expected_results);
+    pub async fn query(&mut self, query: &str) -> RunStmtResults {
+        if let Some(Success(result)) = self.run_stmt(query).await.result {
+            result
         } else {
             panic!("Results were supposed to be successful")
         }
     }
+
+    pub async fn assert_query(&mut self, query: &str, expected_results: Vec<RecordStorage>) {
+        assert_eq!(self.query(query).await.results, expected_results);
+    }
 }
 
 pub struct CalvinMultipleInstances {
     pub instances: Vec<CalvinSingleInstance>,
+    // The peer of each partitioned instance
+    pub peers: Vec<Peer>,
+    pub partition_map: Option<PartitionMap>,
+    global_req_log: GlobalRequestLog,
 }
 
 impl CalvinMultipleInstances {
     pub async fn new(num_instances: usize) -> Self {
-        let (global_req_log_tx, _) = sync::broadcast::channel(1);
+        let global_req_log = GlobalRequestLog::new(1024);
 
         let mut instances = Vec::new();
 
         for _ in 0..num_instances {
-            instances.push(
-                CalvinSingleInstance::new_with_global_req_log(global_req_log_tx.clone()).await,
-            );
+            instances
+                .push(CalvinSingleInstance::new_with_global_req_log(global_req_log.clone()).await);
+        }
+
+        Self {
+            instances,
+            peers: Vec::new(),
+            partition_map: None,
+            global_req_log,
+        }
+    }
+
+    /// Every instance owns a slice of the records rather than a full replica of them.
+    pub async fn new_partitioned(num_instances: usize) -> Self {
+        let global_req_log = GlobalRequestLog::new(1024);
+
+        let mut listeners = Vec::new();
+        for _ in 0..num_instances {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+
+        let peers: Vec<Peer> = listeners.iter().map(Self::peer_for).collect();
+        let partition_map = PartitionMap::new(peers.clone()).unwrap();
+
+        let mut calvinites = Self {
+            instances: Vec::new(),
+            peers: Vec::new(),
+            partition_map: Some(partition_map),
+            global_req_log,
+        };
+
+        for (listener, me) in listeners.into_iter().zip(peers) {
+            calvinites.start_partitioned_instance(listener, me).await;
+        }
+
+        calvinites
+    }
+
+    /// Starts an instance that follows the global request log but owns no records until it is
+    /// added to the partition map.
+    pub async fn add_partitioned_instance(&mut self) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let me = Self::peer_for(&listener);
+
+        self.start_partitioned_instance(listener, me.clone()).await;
+
+        me
+    }
+
+    fn peer_for(listener: &TcpListener) -> Peer {
+        Peer {
+            id: Uuid::new_v4(),
+            address: format!("http://{}", listener.local_addr().unwrap()),
         }
+    }
 
-        Self { instances }
+    async fn start_partitioned_instance(&mut self, listener: TcpListener, me: Peer) {
+        let partition_map = self.partition_map.clone().unwrap();
+        let peer_manager = PeerManager::new(me.clone(), partition_map);
+        let partition_inbox = PartitionInbox::default();
+
+        let executor = Executor::new_partitioned(
+            &StorageConfig {
+                engine: EngineKind::Memory,
+                data_dir: None,
+            },
+            Partition::new(peer_manager.clone(), partition_inbox.clone()),
+        )
+        .unwrap();
+        let scheduler = Scheduler::new_partitioned(executor, peer_manager);
+
+        let sequencer_server = SequencerServer::new(self.global_req_log.clone());
+        let sequencer = sequencer_server.build_sequencer(scheduler);
+
+        self.instances.push(
+            CalvinSingleInstance::serve(
+                listener,
+                sequencer_server,
+                sequencer,
+                Some(partition_inbox),
+            )
+            .await,
+        );
+        self.peers.push(me);
     }
 }
@@ -0,0 +1,194 @@
+use calvinite::calvinite_tonic::column_value::Value;
+use calvinite::calvinite_tonic::run_stmt_response::Result::Success;
+use calvinite::calvinite_tonic::sequencer_grpc_service_server::SequencerGrpcService;
+use calvinite::calvinite_tonic::{
+    RecordStorage, RunStmtRequest, RunStmtRequestWithUuid, RunStmtResponse,
+};
+use calvinite::scheduler::Scheduler;
+use calvinite::sequencer::global_request_log::GlobalRequestLog;
+use calvinite::sequencer::SequencerServer;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Request;
+
+const NUM_UPDATES: i64 = 50;
+const NUM_SELECTS: i64 = 50;
+
+fn request(query: String) -> RunStmtRequestWithUuid {
+    RunStmtRequestWithUuid {
+        query,
+        uuid: uuid::Uuid::new_v4().to_string(),
+        repartition: None,
+        reconnaissance: None,
+        stamp: None,
+    }
+}
+
+async fn run_stmt(sequencer_server: &SequencerServer, query: &str) -> RunStmtResponse {
+    sequencer_server
+        .run_stmt(Request::new(RunStmtRequest {
+            query: query.into(),
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+}
+
+async fn submit(scheduler: &Scheduler, query: String) -> RunStmtResponse {
+    scheduler.submit_txn(request(query)).await.unwrap()
+}
+
+fn val_of(row: &RecordStorage) -> i64 {
+    match row.values[1].value {
+        Some(Value::Bigint(val)) => val,
+        _ => panic!("val is a BIGINT"),
+    }
+}
+
+fn results_of(res: RunStmtResponse) -> Vec<RecordStorage> {
+    if let Some(Success(result)) = res.result {
+        result.results
+    } else {
+        panic!("Results were supposed to be successful")
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_interleaved_updates_and_selects_are_serializable() {
+    // Requests that arrive together share a batch and run concurrently. A second subscriber sees
+    // the batches in log order.
+    let global_req_log = GlobalRequestLog::new(1024);
+    let mut log_rx = global_req_log.subscribe();
+    let sequencer_server = Arc::new(SequencerServer::new_with_epochs(
+        global_req_log,
+        Duration::from_millis(5),
+    ));
+    let mut sequencer = sequencer_server.build_default_sequencer();
+    tokio::spawn(async move { sequencer.serve().await });
+
+    run_stmt(
+        &sequencer_server,
+        "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)",
+    )
+    .await;
+    run_stmt(&sequencer_server, "INSERT INTO foo VALUES (1, 0)").await;
+
+    let mut handles = Vec::new();
+    for i in 0..(NUM_UPDATES + NUM_SELECTS) {
+        let sequencer_server = sequencer_server.clone();
+        let query = if i % 2 == 0 {
+            "UPDATE foo SET val = val + 1 WHERE id = 1"
+        } else {
+            "SELECT * FROM foo WHERE id = 1"
+        };
+        handles.push(tokio::spawn(async move {
+            run_stmt(&sequencer_server, query).await
+        }));
+    }
+
+    let mut reads_by_txn = HashMap::new();
+    for handle in handles {
+        let result = match handle.await.unwrap().result {
+            Some(Success(result)) => result,
+            _ => panic!("Results were supposed to be successful"),
+        };
+        reads_by_txn.insert(result.uuid, result.results);
+    }
+
+    // Every update increments the value it reads, so in the serial log order each read sees
+    // exactly the updates logged before it
+    let mut logged_txns = Vec::new();
+    while let Ok(batch) = log_rx.try_recv() {
+        logged_txns.extend(batch.requests);
+    }
+    let mut updates_before = 0;
+    for txn in logged_txns.iter().skip(2) {
+        if txn.query.starts_with("UPDATE") {
+            updates_before += 1;
+            continue;
+        }
+
+        let rows = &reads_by_txn[&txn.uuid];
+        assert_eq!(rows.len(), 1);
+        assert_eq!(val_of(&rows[0]), updates_before, "read by txn {}", txn.uuid);
+    }
+    assert_eq!(logged_txns.len() as i64, 2 + NUM_UPDATES + NUM_SELECTS);
+    assert_eq!(updates_before, NUM_UPDATES);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_multi_stmt_txns_are_atomic() {
+    let scheduler = Scheduler::default();
+
+    submit(
+        &scheduler,
+        "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)".into(),
+    )
+    .await;
+    submit(
+        &scheduler,
+        "INSERT INTO foo VALUES (1, 100); INSERT INTO foo VALUES (2, 0)".into(),
+    )
+    .await;
+
+    // Every write moves part of the total between the two rows, so the total never changes
+    let mut handles = Vec::new();
+    for i in 0..(NUM_UPDATES + NUM_SELECTS) {
+        let scheduler = scheduler.clone();
+        let query = if i % 2 == 0 {
+            format!(
+                "UPDATE foo SET val = {} WHERE id = 1; UPDATE foo SET val = {} WHERE id = 2",
+                100 - i,
+                i
+            )
+        } else {
+            "SELECT * FROM foo WHERE id = 1; SELECT * FROM foo WHERE id = 2".to_string()
+        };
+        handles.push(tokio::spawn(async move { submit(&scheduler, query).await }));
+    }
+
+    for handle in handles {
+        let result = match handle.await.unwrap().result {
+            Some(Success(result)) => result,
+            _ => panic!("Results were supposed to be successful"),
+        };
+
+        if result.result_sets[0].rows.is_empty() {
+            continue;
+        }
+        assert_eq!(result.result_sets.len(), 2);
+        let total: i64 = result
+            .result_sets
+            .iter()
+            .flat_map(|result_set| result_set.rows.iter().map(val_of))
+            .sum();
+        assert_eq!(total, 100);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_increments_are_not_lost() {
+    let scheduler = Scheduler::default();
+
+    submit(
+        &scheduler,
+        "CREATE TABLE foo (id BIGINT PRIMARY KEY, val BIGINT)".into(),
+    )
+    .await;
+    submit(&scheduler, "INSERT INTO foo VALUES (1, 0)".into()).await;
+
+    // Each increment reads the value the previous one wrote
+    let mut handles = Vec::new();
+    for _ in 0..NUM_UPDATES {
+        let scheduler = scheduler.clone();
+        let query = "UPDATE foo SET val = val + 1 WHERE id = 1".to_string();
+        handles.push(tokio::spawn(async move { submit(&scheduler, query).await }));
+    }
+    for handle in handles {
+        results_of(handle.await.unwrap());
+    }
+
+    let final_value = results_of(submit(&scheduler, "SELECT * FROM foo WHERE id = 1".into()).await);
+    assert_eq!(val_of(&final_value[0]), NUM_UPDATES);
+}
@@ -1,4 +1,7 @@
-use calvinite::calvinite_tonic::RecordStorage;
+use calvinite::calvinite_tonic::run_stmt_err::ErrorCode;
+use calvinite::calvinite_tonic::run_stmt_response::Result::{Failure, Success};
+use calvinite::calvinite_tonic::{ColumnType, ColumnValue, RecordStorage};
+use std::time::Duration;
 
 mod common;
 
@@ -6,34 +9,117 @@ mod common;
 async fn test_write_then_read() {
     let mut calvinite = common::CalvinSingleInstance::default().await;
 
+    calvinite.assert_query(common::CREATE_FOO, Vec::new()).await;
     calvinite
         .assert_query("INSERT INTO foo VALUES (1, 2)", Vec::new())
         .await;
     calvinite
         .assert_query(
             "SELECT * FROM foo WHERE id = 1",
-            vec![RecordStorage { val: 2 }],
+            vec![common::foo_row(1, 2)],
         )
         .await;
 }
 
+#[tokio::test]
+async fn test_select_columns() {
+    let mut calvinite = common::CalvinSingleInstance::default().await;
+
+    calvinite
+        .assert_query(
+            "CREATE TABLE users (id BIGINT PRIMARY KEY, name TEXT NOT NULL, email TEXT, admin BOOLEAN)",
+            Vec::new(),
+        )
+        .await;
+    calvinite
+        .assert_query(
+            "INSERT INTO users (id, name, admin) VALUES (1, 'ada', true)",
+            Vec::new(),
+        )
+        .await;
+
+    let result = calvinite
+        .query("SELECT name, email, admin FROM users WHERE id = 1")
+        .await;
+    assert_eq!(
+        result
+            .columns
+            .iter()
+            .map(|column| (column.name.as_str(), column.column_type(), column.nullable))
+            .collect::<Vec<_>>(),
+        vec![
+            ("name", ColumnType::Text, false),
+            ("email", ColumnType::Text, true),
+            ("admin", ColumnType::Boolean, true),
+        ]
+    );
+    assert_eq!(
+        result.results,
+        vec![RecordStorage {
+            values: vec!["ada".into(), ColumnValue { value: None }, true.into()]
+        }]
+    );
+}
+
 #[tokio::test]
 async fn test_write_then_read_then_read() {
     let mut calvinite = common::CalvinSingleInstance::default().await;
 
+    calvinite.assert_query(common::CREATE_FOO, Vec::new()).await;
     calvinite
         .assert_query("INSERT INTO foo VALUES (1, 2)", Vec::new())
         .await;
     calvinite
         .assert_query(
             "SELECT * FROM foo WHERE id = 1",
-            vec![RecordStorage { val: 2 }],
+            vec![common::foo_row(1, 2)],
         )
         .await;
     calvinite
         .assert_query(
             "SELECT * FROM foo WHERE id = 1",
-            vec![RecordStorage { val: 2 }],
+            vec![common::foo_row(1, 2)],
+        )
+        .await;
+}
+
+#[tokio::test]
+async fn test_write_then_read_with_epochs() {
+    let mut calvinite =
+        common::CalvinSingleInstance::new_with_epochs(Duration::from_millis(10)).await;
+
+    calvinite.assert_query(common::CREATE_FOO, Vec::new()).await;
+    calvinite
+        .assert_query("INSERT INTO foo VALUES (1, 2)", Vec::new())
+        .await;
+    calvinite
+        .assert_query(
+            "SELECT * FROM foo WHERE id = 1",
+            vec![common::foo_row(1, 2)],
+        )
+        .await;
+}
+
+#[tokio::test]
+async fn test_write_then_restart_then_read() {
+    let request_log_dir = tempfile::tempdir().unwrap();
+
+    {
+        let mut calvinite =
+            common::CalvinSingleInstance::new_with_request_log(request_log_dir.path()).await;
+        calvinite.assert_query(common::CREATE_FOO, Vec::new()).await;
+        calvinite
+            .assert_query("INSERT INTO foo VALUES (1, 2)", Vec::new())
+            .await;
+    }
+
+    // A fresh instance starts with empty executor state and recovers it from the request log
+    let mut calvinite =
+        common::CalvinSingleInstance::new_with_request_log(request_log_dir.path()).await;
+    calvinite
+        .assert_query(
+            "SELECT * FROM foo WHERE id = 1",
+            vec![common::foo_row(1, 2)],
         )
         .await;
 }
@@ -42,19 +128,825 @@ async fn test_write_then_read_then_read() {
 async fn test_multiple_write_then_read() {
     let mut calvinites = common::CalvinMultipleInstances::new(2).await;
 
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
     calvinites.instances[0]
         .assert_query("INSERT INTO foo VALUES (1, 2)", Vec::new())
         .await;
     calvinites.instances[0]
         .assert_query(
             "SELECT * FROM foo WHERE id = 1",
-            vec![RecordStorage { val: 2 }],
+            vec![common::foo_row(1, 2)],
         )
         .await;
     calvinites.instances[1]
         .assert_query(
             "SELECT * FROM foo WHERE id = 1",
-            vec![RecordStorage { val: 2 }],
+            vec![common::foo_row(1, 2)],
         )
         .await;
 }
+
+#[tokio::test]
+async fn test_write_then_read_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+
+    for id in 1..=6 {
+        calvinites.instances[0]
+            .assert_query(
+                &format!("INSERT INTO foo VALUES ({}, {})", id, id * 10),
+                Vec::new(),
+            )
+            .await;
+    }
+
+    for instance in calvinites.instances.iter_mut() {
+        for id in 1..=6 {
+            instance
+                .assert_query(
+                    &format!("SELECT * FROM foo WHERE id = {}", id),
+                    vec![common::foo_row(id, id * 10)],
+                )
+                .await;
+        }
+    }
+
+    for (idx, instance) in calvinites.instances.iter_mut().enumerate() {
+        let id = idx as i64 + 1;
+        instance
+            .assert_query(
+                &format!("UPDATE foo SET val = {} WHERE id = {}", id * 100, id),
+                Vec::new(),
+            )
+            .await;
+    }
+
+    for id in 1..=3 {
+        calvinites.instances[2]
+            .assert_query(
+                &format!("SELECT * FROM foo WHERE id = {}", id),
+                vec![common::foo_row(id, id * 100)],
+            )
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn test_server_stays_healthy_after_failures() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(2).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    calvinites.instances[0]
+        .assert_query("INSERT INTO foo VALUES (1, 10)", Vec::new())
+        .await;
+
+    for (query, error_code) in [
+        ("SELEKT * FROM foo", ErrorCode::Error),
+        ("SELECT * FROM foo WHERE id = ", ErrorCode::Error),
+        ("SELECT 1", ErrorCode::Error),
+        ("INSERT INTO foo VALUES (1, 11)", ErrorCode::Constraint),
+        ("INSERT INTO foo VALUES (NULL, 11)", ErrorCode::Constraint),
+        ("UPDATE foo SET id = 2 WHERE id = 1", ErrorCode::Constraint),
+        ("SELECT * FROM bar WHERE id = 1", ErrorCode::Notfound),
+        (
+            "UPDATE foo SET missing = 1 WHERE id = 1",
+            ErrorCode::Notfound,
+        ),
+        ("INSERT INTO foo VALUES (2, 'two')", ErrorCode::Mismatch),
+        (
+            "UPDATE foo SET val = val / 0 WHERE id = 1",
+            ErrorCode::Error,
+        ),
+        ("CREATE TABLE foo (id BIGINT PRIMARY KEY)", ErrorCode::Error),
+        (
+            "CREATE TABLE bar (id BIGINT PRIMARY KEY); SELECT * FROM foo",
+            ErrorCode::Misuse,
+        ),
+    ] {
+        for instance in calvinites.instances.iter_mut() {
+            match instance.run_stmt(query).await.result {
+                Some(Failure(err)) => assert_eq!(
+                    err.error_code(),
+                    error_code,
+                    "{}: {}",
+                    query,
+                    err.detailed_message
+                ),
+                _ => panic!("{} should fail", query),
+            }
+
+            // Every failure leaves the server able to serve the next request
+            instance
+                .assert_query(
+                    "SELECT * FROM foo WHERE id = 1",
+                    vec![common::foo_row(1, 10)],
+                )
+                .await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_select_not_on_id() {
+    let mut calvinite = common::CalvinSingleInstance::default().await;
+
+    calvinite.assert_query(common::CREATE_FOO, Vec::new()).await;
+    for id in 1..=4 {
+        calvinite
+            .assert_query(
+                &format!("INSERT INTO foo VALUES ({}, {})", id, id % 2),
+                Vec::new(),
+            )
+            .await;
+    }
+
+    calvinite
+        .assert_query(
+            "SELECT * FROM foo WHERE val = 1",
+            vec![common::foo_row(1, 1), common::foo_row(3, 1)],
+        )
+        .await;
+    calvinite
+        .assert_query("UPDATE foo SET val = 5 WHERE val = 0", Vec::new())
+        .await;
+    calvinite
+        .assert_query(
+            "SELECT * FROM foo WHERE val > 1",
+            vec![common::foo_row(2, 5), common::foo_row(4, 5)],
+        )
+        .await;
+}
+
+#[tokio::test]
+async fn test_update_not_on_id_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    for id in 1..=9 {
+        calvinites.instances[0]
+            .assert_query(
+                &format!("INSERT INTO foo VALUES ({}, {})", id, id * 10),
+                Vec::new(),
+            )
+            .await;
+    }
+
+    calvinites.instances[1]
+        .assert_query("UPDATE foo SET val = 0 WHERE val > 50", Vec::new())
+        .await;
+
+    let expected_rows: Vec<_> = (1..=9)
+        .map(|id| common::foo_row(id, if id > 5 { 0 } else { id * 10 }))
+        .collect();
+    for instance in calvinites.instances.iter_mut() {
+        instance
+            .assert_query("SELECT * FROM foo", expected_rows.clone())
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn test_multi_row_insert_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    let values: Vec<_> = (1..=9).map(|id| format!("({}, {})", id, id * 10)).collect();
+    calvinites.instances[0]
+        .assert_query(
+            &format!("INSERT INTO foo VALUES {}", values.join(", ")),
+            Vec::new(),
+        )
+        .await;
+
+    // Every partition rejects the whole statement, not only the partition owning the taken key
+    let res = calvinites.instances[1]
+        .run_stmt("INSERT INTO foo VALUES (10, 100), (11, 110), (5, 0)")
+        .await;
+    match res.result {
+        Some(Failure(err)) => assert_eq!(err.error_code(), ErrorCode::Constraint),
+        _ => panic!("a taken primary key should fail the insert"),
+    }
+
+    // The inserted key is only known once the partition owning row 3 reads it
+    calvinites.instances[2]
+        .assert_query(
+            "INSERT INTO foo (val, id) SELECT id, val FROM foo WHERE id = 3",
+            Vec::new(),
+        )
+        .await;
+
+    // Reconnaissance may not see a row inserted by the previous txn yet, keyed selects always do
+    let expected_rows: Vec<_> = (1..=9).map(|id| common::foo_row(id, id * 10)).collect();
+    for instance in calvinites.instances.iter_mut() {
+        instance
+            .assert_query("SELECT * FROM foo WHERE id < 10", expected_rows.clone())
+            .await;
+        instance
+            .assert_query(
+                "SELECT * FROM foo WHERE id = 30",
+                vec![common::foo_row(30, 3)],
+            )
+            .await;
+        for id in [10, 11] {
+            instance
+                .assert_query(&format!("SELECT * FROM foo WHERE id = {}", id), Vec::new())
+                .await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_delete_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    for id in 1..=9 {
+        calvinites.instances[0]
+            .assert_query(
+                &format!("INSERT INTO foo VALUES ({}, {})", id, id * 10),
+                Vec::new(),
+            )
+            .await;
+    }
+
+    let deleted = calvinites.instances[1]
+        .query("DELETE FROM foo WHERE id = 1")
+        .await;
+    assert_eq!(deleted.rows_affected, 1);
+    let deleted = calvinites.instances[2]
+        .query("DELETE FROM foo WHERE val > 50")
+        .await;
+    assert_eq!(deleted.rows_affected, 4);
+
+    let expected_rows: Vec<_> = (2..=5).map(|id| common::foo_row(id, id * 10)).collect();
+    for instance in calvinites.instances.iter_mut() {
+        instance
+            .assert_query("SELECT * FROM foo", expected_rows.clone())
+            .await;
+        instance
+            .assert_query("SELECT * FROM foo WHERE id = 9", Vec::new())
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn test_range_scans_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    let values: Vec<_> = (1..=20)
+        .map(|id| format!("({}, {})", id, id * 10))
+        .collect();
+    calvinites.instances[0]
+        .assert_query(
+            &format!("INSERT INTO foo VALUES {}", values.join(", ")),
+            Vec::new(),
+        )
+        .await;
+
+    let rows = |ids: &[i64]| -> Vec<RecordStorage> {
+        ids.iter().map(|id| common::foo_row(*id, id * 10)).collect()
+    };
+    for instance in calvinites.instances.iter_mut() {
+        instance
+            .assert_query(
+                "SELECT * FROM foo WHERE id > 5 ORDER BY id LIMIT 5",
+                rows(&[6, 7, 8, 9, 10]),
+            )
+            .await;
+        instance
+            .assert_query(
+                "SELECT * FROM foo WHERE id BETWEEN 3 AND 8 ORDER BY id DESC LIMIT 3 OFFSET 1",
+                rows(&[7, 6, 5]),
+            )
+            .await;
+    }
+
+    // Page through the table by primary key
+    let mut last_id = 0;
+    let mut pages = Vec::new();
+    loop {
+        let page = calvinites.instances[1]
+            .query(&format!(
+                "SELECT id FROM foo WHERE id > {} ORDER BY id LIMIT 7",
+                last_id
+            ))
+            .await
+            .results;
+        match page.last() {
+            Some(RecordStorage { values }) => match values[0].value {
+                Some(calvinite::calvinite_tonic::column_value::Value::Bigint(id)) => last_id = id,
+                _ => panic!("id is a BIGINT"),
+            },
+            None => break,
+        }
+        pages.push(page.len());
+    }
+    assert_eq!(pages, vec![7, 7, 6]);
+}
+
+#[tokio::test]
+async fn test_range_scans_see_no_phantoms() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(2).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+
+    // Every txn inserts two rows, likely owned by different partitions, so a scan that saw only
+    // some of the writes of a txn would count an odd number of rows
+    let mut handles = Vec::new();
+    for i in 0..20 {
+        let mut instance = calvinites.instances[i % 2].clone();
+        handles.push(tokio::spawn(async move {
+            if i % 2 == 0 {
+                let id = i as i64;
+                instance
+                    .query(&format!(
+                        "INSERT INTO foo VALUES ({}, 0), ({}, 0)",
+                        id,
+                        id + 1000
+                    ))
+                    .await;
+                None
+            } else {
+                Some(
+                    instance
+                        .query("SELECT * FROM foo WHERE id >= 0")
+                        .await
+                        .results
+                        .len(),
+                )
+            }
+        }));
+    }
+
+    for handle in handles {
+        if let Some(row_count) = handle.await.unwrap() {
+            assert_eq!(row_count % 2, 0, "saw {} rows", row_count);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_abort_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    calvinites.instances[0]
+        .assert_query(
+            "INSERT INTO foo VALUES (1, 100), (2, 0), (3, 0)",
+            Vec::new(),
+        )
+        .await;
+
+    // Transfers only go through if the payer can afford them
+    let transfer = |amount: i64, payee: i64| {
+        format!(
+            "UPDATE foo SET val = val - {amount} WHERE id = 1; UPDATE foo SET val = val + {amount} WHERE id = {payee}; ABORT IF (SELECT val FROM foo WHERE id = 1) < 0",
+            amount = amount,
+            payee = payee
+        )
+    };
+    calvinites.instances[1]
+        .assert_query(&transfer(60, 2), Vec::new())
+        .await;
+    match calvinites.instances[2]
+        .run_stmt(&transfer(60, 3))
+        .await
+        .result
+    {
+        Some(Failure(err)) => assert_eq!(err.error_code(), ErrorCode::Abort),
+        _ => panic!("an overdrawing transfer should abort"),
+    }
+
+    for instance in calvinites.instances.iter_mut() {
+        for (id, val) in [(1, 40), (2, 60), (3, 0)] {
+            instance
+                .assert_query(
+                    &format!("SELECT * FROM foo WHERE id = {}", id),
+                    vec![common::foo_row(id, val)],
+                )
+                .await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unique_index_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    calvinites.instances[0]
+        .assert_query(
+            "INSERT INTO foo VALUES (1, 10), (2, 20), (3, 10)",
+            Vec::new(),
+        )
+        .await;
+
+    // Rows on different partitions already share a value
+    match calvinites.instances[1]
+        .run_stmt("CREATE UNIQUE INDEX foo_val ON foo (val)")
+        .await
+        .result
+    {
+        Some(Failure(err)) => assert_eq!(err.error_code(), ErrorCode::Constraint),
+        _ => panic!("a unique index over duplicates should not be created"),
+    }
+    calvinites.instances[1]
+        .assert_query("DELETE FROM foo WHERE id = 3", Vec::new())
+        .await;
+    calvinites.instances[1]
+        .assert_query("CREATE UNIQUE INDEX foo_val ON foo (val)", Vec::new())
+        .await;
+
+    for (id, val) in [(4, 10), (5, 20)] {
+        match calvinites.instances[2]
+            .run_stmt(&format!("INSERT INTO foo VALUES ({}, {})", id, val))
+            .await
+            .result
+        {
+            Some(Failure(err)) => assert_eq!(err.error_code(), ErrorCode::Constraint),
+            _ => panic!("a duplicate value should not be inserted"),
+        }
+    }
+    calvinites.instances[2]
+        .assert_query("INSERT INTO foo VALUES (4, 40)", Vec::new())
+        .await;
+    calvinites.instances[0]
+        .assert_query("UPDATE foo SET val = 30 WHERE val = 20", Vec::new())
+        .await;
+
+    for instance in calvinites.instances.iter_mut() {
+        for (val, expected_rows) in [
+            (10, vec![common::foo_row(1, 10)]),
+            (20, vec![]),
+            (30, vec![common::foo_row(2, 30)]),
+            (40, vec![common::foo_row(4, 40)]),
+        ] {
+            instance
+                .assert_query(
+                    &format!("SELECT * FROM foo WHERE val = {}", val),
+                    expected_rows,
+                )
+                .await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_aggregates_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    let values: Vec<_> = (1..=12).map(|id| format!("({}, {})", id, id % 3)).collect();
+    calvinites.instances[0]
+        .assert_query(
+            &format!("INSERT INTO foo VALUES {}", values.join(", ")),
+            Vec::new(),
+        )
+        .await;
+
+    let row = |values: Vec<i64>| RecordStorage {
+        values: values.into_iter().map(Into::into).collect(),
+    };
+    for instance in calvinites.instances.iter_mut() {
+        instance
+            .assert_query(
+                "SELECT COUNT(*), SUM(id), MIN(id), MAX(id) FROM foo",
+                vec![row(vec![12, 78, 1, 12])],
+            )
+            .await;
+        instance
+            .assert_query(
+                "SELECT val, COUNT(*), SUM(id) AS total FROM foo WHERE id > 3 GROUP BY val HAVING SUM(id) > 20 ORDER BY total DESC",
+                vec![row(vec![0, 3, 27]), row(vec![2, 3, 24]), row(vec![1, 3, 21])],
+            )
+            .await;
+    }
+
+    // Aggregates decide aborts alike on every partition
+    match calvinites.instances[1]
+        .run_stmt("DELETE FROM foo WHERE val = 0; ABORT IF (SELECT COUNT(*) FROM foo) < 10")
+        .await
+        .result
+    {
+        Some(Failure(err)) => assert_eq!(err.error_code(), ErrorCode::Abort),
+        _ => panic!("deleting too many rows should abort"),
+    }
+    calvinites.instances[2]
+        .assert_query(
+            "SELECT COUNT(*) FROM foo GROUP BY val ORDER BY val",
+            vec![row(vec![4]), row(vec![4]), row(vec![4])],
+        )
+        .await;
+}
+
+#[tokio::test]
+async fn test_join_across_partitions() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+    calvinites.instances[0]
+        .assert_query(
+            "CREATE TABLE bar (id BIGINT PRIMARY KEY, val BIGINT)",
+            Vec::new(),
+        )
+        .await;
+    calvinites.instances[1]
+        .assert_query(
+            "INSERT INTO foo VALUES (1, 11), (2, 12), (3, 13), (4, 14), (5, 99)",
+            Vec::new(),
+        )
+        .await;
+    calvinites.instances[2]
+        .assert_query(
+            "INSERT INTO bar VALUES (11, 100), (12, 200), (13, 300), (14, 400)",
+            Vec::new(),
+        )
+        .await;
+
+    let row = |values: Vec<Option<i64>>| RecordStorage {
+        values: values
+            .into_iter()
+            .map(|value| value.map(Into::into).unwrap_or_default())
+            .collect(),
+    };
+    for instance in calvinites.instances.iter_mut() {
+        instance
+            .assert_query(
+                "SELECT foo.id, bar.val FROM foo JOIN bar ON bar.id = foo.val WHERE foo.id = 2",
+                vec![row(vec![Some(2), Some(200)])],
+            )
+            .await;
+        instance
+            .assert_query(
+                "SELECT f.id, b.val FROM foo f LEFT JOIN bar b ON b.id = f.val WHERE f.id > 2",
+                vec![
+                    row(vec![Some(3), Some(300)]),
+                    row(vec![Some(4), Some(400)]),
+                    row(vec![Some(5), None]),
+                ],
+            )
+            .await;
+        instance
+            .assert_query(
+                "SELECT foo.id, bar.id FROM foo JOIN bar ON bar.val = foo.id * 100 ORDER BY foo.id DESC LIMIT 2",
+                vec![row(vec![Some(4), Some(14)]), row(vec![Some(3), Some(13)])],
+            )
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn test_call_procedures() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(3).await;
+
+    for query in [
+        "CREATE TABLE accounts (id BIGINT PRIMARY KEY, owner TEXT, balance BIGINT)",
+        "INSERT INTO accounts VALUES (1, 'ann', 100), (2, 'bob', 0), (3, 'cat', 0)",
+        "CREATE PROCEDURE transfer(src, dst, amount) READS accounts(src) WRITES accounts(src), accounts(dst) AS '
+            local from, to = db.get(\"accounts\", src), db.get(\"accounts\", dst)
+            if from.balance < amount then error(\"insufficient funds\") end
+            from.balance, to.balance = from.balance - amount, to.balance + amount
+            db.put(\"accounts\", from)
+            db.put(\"accounts\", to)
+            return to.owner .. \" has \" .. to.balance'",
+    ] {
+        calvinites.instances[0].assert_query(query, Vec::new()).await;
+    }
+
+    // Every instance sequences calls like any other txn
+    for (instance, dst, expected) in [
+        (0, 2, "bob has 30"),
+        (1, 3, "cat has 30"),
+        (2, 2, "bob has 60"),
+    ] {
+        match calvinites.instances[instance]
+            .call_procedure("transfer", vec![1i64.into(), dst.into(), 30i64.into()])
+            .await
+            .result
+        {
+            Some(Success(result)) => {
+                assert_eq!(
+                    result.results,
+                    vec![RecordStorage {
+                        values: vec![expected.into()]
+                    }]
+                );
+                assert_eq!(result.columns[0].column_type(), ColumnType::Text);
+                assert_eq!(result.rows_affected, 2);
+            }
+            res => panic!("transfer should succeed, got {:?}", res),
+        }
+    }
+
+    match calvinites.instances[1]
+        .call_procedure("transfer", vec![1i64.into(), 2i64.into(), 30i64.into()])
+        .await
+        .result
+    {
+        Some(Failure(err)) => {
+            assert_eq!(err.error_code(), ErrorCode::Abort);
+            assert!(err.detailed_message.contains("insufficient funds"));
+        }
+        res => panic!("transfer should fail, got {:?}", res),
+    }
+    for instance in calvinites.instances.iter_mut() {
+        instance
+            .assert_query(
+                "SELECT id, balance FROM accounts",
+                vec![
+                    RecordStorage {
+                        values: vec![1i64.into(), 10i64.into()],
+                    },
+                    RecordStorage {
+                        values: vec![2i64.into(), 60i64.into()],
+                    },
+                    RecordStorage {
+                        values: vec![3i64.into(), 30i64.into()],
+                    },
+                ],
+            )
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn test_call_procedures_without_footprint() {
+    let mut calvinite = common::CalvinSingleInstance::default().await;
+
+    for query in [
+        common::CREATE_FOO,
+        "INSERT INTO foo VALUES (1, 2), (2, 3), (3, 3)",
+        // The rows it writes depend on the rows it reads, so calls are reconnoitered
+        "CREATE PROCEDURE follow(id, tag) AS '
+            local row = db.get(\"foo\", id)
+            while row.val ~= row.id do row = db.get(\"foo\", row.val) end
+            db.delete(\"foo\", row.id)
+            return tag'",
+    ] {
+        calvinite.assert_query(query, Vec::new()).await;
+    }
+
+    match calvinite
+        .call_procedure("follow", vec![1i64.into(), "it's done".into()])
+        .await
+        .result
+    {
+        Some(Success(result)) => {
+            assert_eq!(
+                result.results,
+                vec![RecordStorage {
+                    values: vec!["it's done".into()]
+                }]
+            );
+            assert_eq!(result.rows_affected, 1);
+        }
+        res => panic!("follow should succeed, got {:?}", res),
+    }
+    calvinite
+        .assert_query(
+            "SELECT * FROM foo",
+            vec![common::foo_row(1, 2), common::foo_row(2, 3)],
+        )
+        .await;
+
+    match calvinite
+        .call_procedure("follow", vec![ColumnValue::from(1i64)])
+        .await
+        .result
+    {
+        Some(Failure(err)) => assert_eq!(err.error_code(), ErrorCode::Mismatch),
+        res => panic!("follow should fail, got {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn test_replicas_compute_the_same_stamped_values() {
+    let mut calvinites = common::CalvinMultipleInstances::new(2).await;
+
+    for query in [
+        "CREATE TABLE events (id BIGINT PRIMARY KEY, token TEXT, at TEXT, weight DOUBLE)",
+        "INSERT INTO events VALUES (1, GEN_RANDOM_UUID(), NOW(), RANDOM()), \
+         (2, GEN_RANDOM_UUID(), CURRENT_TIMESTAMP, RANDOM())",
+    ] {
+        calvinites.instances[0]
+            .assert_query(query, Vec::new())
+            .await;
+    }
+    calvinites.instances[1]
+        .assert_query(
+            "INSERT INTO events VALUES (3, GEN_RANDOM_UUID(), NOW(), RANDOM())",
+            Vec::new(),
+        )
+        .await;
+
+    let first = calvinites.instances[0]
+        .query("SELECT * FROM events")
+        .await
+        .results;
+    let second = calvinites.instances[1]
+        .query("SELECT * FROM events")
+        .await
+        .results;
+    assert_eq!(first.len(), 3);
+    assert_eq!(first, second);
+
+    // Rows of one txn share its timestamp, a later txn has a later one
+    assert_eq!(first[0].values[2], first[1].values[2]);
+    assert!(first[1].values[2].to_string() < first[2].values[2].to_string());
+    assert_ne!(first[0].values[1], first[1].values[1]);
+}
+
+#[tokio::test]
+async fn test_repartition_while_serving() {
+    let mut calvinites = common::CalvinMultipleInstances::new_partitioned(2).await;
+
+    calvinites.instances[0]
+        .assert_query(common::CREATE_FOO, Vec::new())
+        .await;
+
+    for id in 1..=20 {
+        calvinites.instances[0]
+            .assert_query(
+                &format!("INSERT INTO foo VALUES ({}, {})", id, id * 10),
+                Vec::new(),
+            )
+            .await;
+    }
+
+    // Add a third partition
+    let new_peer = calvinites.add_partitioned_instance().await;
+    let partition_map = calvinites
+        .partition_map
+        .clone()
+        .unwrap()
+        .with_peer(new_peer)
+        .unwrap();
+    let res = calvinites.instances[0].repartition(&partition_map).await;
+    assert!(matches!(res.result, Some(Success(_))));
+
+    // The same change cannot be applied twice
+    let res = calvinites.instances[1].repartition(&partition_map).await;
+    assert!(matches!(res.result, Some(Failure(_))));
+
+    for instance in calvinites.instances.iter_mut() {
+        for id in 1..=20 {
+            instance
+                .assert_query(
+                    &format!("SELECT * FROM foo WHERE id = {}", id),
+                    vec![common::foo_row(id, id * 10)],
+                )
+                .await;
+        }
+    }
+
+    for id in 1..=20 {
+        calvinites.instances[2]
+            .assert_query(
+                &format!("UPDATE foo SET val = {} WHERE id = {}", id * 100, id),
+                Vec::new(),
+            )
+            .await;
+    }
+
+    // Remove the first partition
+    let partition_map = partition_map.without_peer(&calvinites.peers[0].id).unwrap();
+    let res = calvinites.instances[1].repartition(&partition_map).await;
+    assert!(matches!(res.result, Some(Success(_))));
+
+    for instance in calvinites.instances[1..].iter_mut() {
+        for id in 1..=20 {
+            instance
+                .assert_query(
+                    &format!("SELECT * FROM foo WHERE id = {}", id),
+                    vec![common::foo_row(id, id * 100)],
+                )
+                .await;
+        }
+    }
+}
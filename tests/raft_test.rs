@@ -0,0 +1,217 @@
+use std::path::Path;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use calvinite::calvinite_tonic::raft_grpc_service_client::RaftGrpcServiceClient;
+use calvinite::calvinite_tonic::run_stmt_err::ErrorCode;
+use calvinite::calvinite_tonic::run_stmt_response::Result::{Failure, Success};
+use calvinite::calvinite_tonic::sequencer_grpc_service_client::SequencerGrpcServiceClient;
+use calvinite::calvinite_tonic::{RaftStatusRequest, RecordStorage, RunStmtRequest};
+
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// A `calvinite` node running in its own process. The process is killed when dropped.
+struct NodeProcess {
+    address: String,
+    child: Child,
+}
+
+impl NodeProcess {
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for NodeProcess {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+fn free_addresses(size: usize) -> Vec<String> {
+    (0..size)
+        .map(|_| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        })
+        .collect()
+}
+
+fn start_cluster(size: usize) -> Vec<NodeProcess> {
+    start_nodes(&free_addresses(size), &vec![None; size])
+}
+
+// Starts a node on every address with every other one as its peer, and on its data dir if any.
+// A lone node runs without Raft.
+fn start_nodes(listen_addresses: &[String], data_dirs: &[Option<&Path>]) -> Vec<NodeProcess> {
+    listen_addresses
+        .iter()
+        .zip(data_dirs.iter())
+        .map(|(listen_address, data_dir)| {
+            let mut command = Command::new(env!("CARGO_BIN_EXE_calvinite"));
+            command.args(["--listen", listen_address]);
+            for peer in listen_addresses
+                .iter()
+                .filter(|peer| *peer != listen_address)
+            {
+                command.args(["--peer", &format!("http://{}", peer)]);
+            }
+            if let Some(data_dir) = data_dir {
+                command.arg("--data-dir").arg(data_dir);
+            }
+
+            NodeProcess {
+                address: format!("http://{}", listen_address),
+                child: command.spawn().unwrap(),
+            }
+        })
+        .collect()
+}
+
+async fn leader_of(nodes: &[NodeProcess]) -> String {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        for node in nodes.iter() {
+            if let Ok(mut client) = RaftGrpcServiceClient::connect(node.address.clone()).await {
+                if let Ok(status) = client.status(RaftStatusRequest {}).await {
+                    let status = status.into_inner();
+                    if status.leader_id == status.id {
+                        return status.id;
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("no leader was elected")
+}
+
+fn foo_row(id: i64, val: i64) -> RecordStorage {
+    RecordStorage {
+        values: vec![id.into(), val.into()],
+    }
+}
+
+// Retrying after an error is safe: every query used with this helper is idempotent, except for
+// INSERTs, which fail with a duplicate key once an earlier attempt went through
+async fn run_stmt(nodes: &[NodeProcess], query: &str) -> Vec<RecordStorage> {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        for node in nodes.iter() {
+            let mut client = match SequencerGrpcServiceClient::connect(node.address.clone()).await {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+
+            // A request that is never answered fails the test instead of hanging it
+            let req = RunStmtRequest {
+                query: query.to_string(),
+            };
+            let res = tokio::time::timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                client.run_stmt(req),
+            )
+            .await;
+            if let Ok(Ok(res)) = res {
+                match res.into_inner().result {
+                    Some(Success(result)) => return result.results,
+                    Some(Failure(err)) if err.error_code() == ErrorCode::Constraint => {
+                        return vec![]
+                    }
+                    _ => {}
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("query {} never succeeded", query)
+}
+
+#[tokio::test]
+async fn test_order_survives_leader_being_killed() {
+    let mut nodes = start_cluster(3);
+
+    run_stmt(
+        &nodes,
+        "CREATE TABLE IF NOT EXISTS foo (id BIGINT PRIMARY KEY, val BIGINT)",
+    )
+    .await;
+    run_stmt(&nodes, "INSERT INTO foo VALUES (1, 10)").await;
+    run_stmt(&nodes, "INSERT INTO foo VALUES (2, 20)").await;
+    run_stmt(&nodes, "UPDATE foo SET val = 11 WHERE id = 1").await;
+
+    let old_leader = leader_of(&nodes).await;
+    nodes.retain_mut(|node| {
+        if node.address == old_leader {
+            node.kill();
+            false
+        } else {
+            true
+        }
+    });
+
+    let new_leader = leader_of(&nodes).await;
+    assert_ne!(new_leader, old_leader);
+
+    assert_eq!(
+        run_stmt(&nodes, "SELECT * FROM foo WHERE id = 1").await,
+        vec![foo_row(1, 11)]
+    );
+    assert_eq!(
+        run_stmt(&nodes, "SELECT * FROM foo WHERE id = 2").await,
+        vec![foo_row(2, 20)]
+    );
+
+    run_stmt(&nodes, "UPDATE foo SET val = 21 WHERE id = 2").await;
+    assert_eq!(
+        run_stmt(&nodes, "SELECT * FROM foo WHERE id = 2").await,
+        vec![foo_row(2, 21)]
+    );
+}
+
+async fn test_restart_on_data_dirs(size: usize) {
+    let listen_addresses = free_addresses(size);
+    let data_dirs: Vec<tempfile::TempDir> =
+        (0..size).map(|_| tempfile::tempdir().unwrap()).collect();
+    let data_dirs: Vec<Option<&Path>> = data_dirs.iter().map(|dir| Some(dir.path())).collect();
+
+    {
+        let nodes = start_nodes(&listen_addresses, &data_dirs);
+        run_stmt(
+            &nodes,
+            "CREATE TABLE IF NOT EXISTS foo (id BIGINT PRIMARY KEY, val BIGINT)",
+        )
+        .await;
+        run_stmt(&nodes, "INSERT INTO foo VALUES (1, 10)").await;
+        run_stmt(&nodes, "INSERT INTO foo VALUES (2, 20)").await;
+    }
+
+    // The restarted nodes keep their rows and answer the requests that follow
+    let nodes = start_nodes(&listen_addresses, &data_dirs);
+    assert_eq!(
+        run_stmt(&nodes, "SELECT * FROM foo WHERE id = 1").await,
+        vec![foo_row(1, 10)]
+    );
+
+    run_stmt(&nodes, "INSERT INTO foo VALUES (3, 30)").await;
+    run_stmt(&nodes, "UPDATE foo SET val = 21 WHERE id = 2").await;
+    assert_eq!(
+        run_stmt(&nodes, "SELECT * FROM foo WHERE id = 2").await,
+        vec![foo_row(2, 21)]
+    );
+    assert_eq!(
+        run_stmt(&nodes, "SELECT * FROM foo WHERE id = 3").await,
+        vec![foo_row(3, 30)]
+    );
+}
+
+#[tokio::test]
+async fn test_lone_node_restarts_on_its_data_dir() {
+    test_restart_on_data_dirs(1).await;
+}
+
+#[tokio::test]
+async fn test_cluster_restarts_on_its_data_dirs() {
+    test_restart_on_data_dirs(3).await;
+}